@@ -0,0 +1,277 @@
+use crate::alloc::{alloc_pages_zeroed, free_pages};
+use crate::config::{PAGE_SIZE, USER_BASE, USER_END, USER_HEAP_SIZE, USER_HEAP_START};
+use crate::log;
+use crate::memory::memcpy;
+use crate::paging::{self, PageTable, PTE_RW, PTE_USER};
+use crate::vfs;
+use rust_alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+// mod addrspace.rs
+// Address spaces for user programs on top of the Sv39 page tables
+// Each owns a root table sharing the kernel mappings and the user pages
+// mapped into [USER_BASE, USER_END), which are returned when it is dropped
+// Pages shared copy-on-write with another space are freed by their last user
+// The heap grows from USER_HEAP_START up to the program break, its pages are
+// mapped zeroed on first access
+
+static mut CURRENT: *mut AddressSpace = core::ptr::null_mut();
+
+pub struct AddressSpace {
+    root: *mut PageTable,
+    // User virtual page -> physical page owned by this address space
+    pages: BTreeMap<usize, usize>,
+    // Ranges populated on first access by the page fault handler
+    lazy: Vec<LazyRegion>,
+    // End of the heap
+    brk: usize,
+}
+
+// Where the contents of a lazily mapped page come from
+#[derive(Clone)]
+pub enum Backing {
+    Zero,
+    // The file at path, the region start maps to offset
+    File { path: String, offset: u32 },
+}
+
+#[derive(Clone)]
+struct LazyRegion {
+    start: usize,
+    end: usize,
+    flags: u64,
+    backing: Backing,
+}
+
+fn page_round_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn is_user_page(vaddr: usize) -> bool {
+    vaddr & (PAGE_SIZE - 1) == 0 && (USER_BASE..USER_END).contains(&vaddr)
+}
+
+impl AddressSpace {
+    // An empty user address space with the kernel mappings shared in
+    pub fn new() -> Option<Self> {
+        let root = PageTable::new();
+        if root.is_null() {
+            return None;
+        }
+        let kernel = paging::kernel_root();
+        if !kernel.is_null() {
+            unsafe { (*root).entries = (*kernel).entries };
+        }
+        Some(Self {
+            root,
+            pages: BTreeMap::new(),
+            lazy: Vec::new(),
+            brk: USER_HEAP_START,
+        })
+    }
+
+    pub fn root(&self) -> *mut PageTable {
+        self.root
+    }
+
+    pub fn satp(&self) -> usize {
+        paging::satp(self.root)
+    }
+
+    // Map a fresh zeroed page at vaddr, returns its physical address
+    pub fn map_user(&mut self, vaddr: usize, flags: u64) -> Option<usize> {
+        if !is_user_page(vaddr) || self.pages.contains_key(&vaddr) {
+            log::error!("Invalid user mapping at 0x{:x}", vaddr);
+            return None;
+        }
+        let page = alloc_pages_zeroed(1);
+        if page.is_null() {
+            return None;
+        }
+        if !paging::map(self.root, vaddr, page as usize, flags | PTE_USER) {
+            free_pages(page);
+            return None;
+        }
+        self.pages.insert(vaddr, page as usize);
+        Some(page as usize)
+    }
+
+    // Remove a user page and return it to the allocator
+    pub fn unmap_user(&mut self, vaddr: usize) -> bool {
+        match self.pages.remove(&vaddr) {
+            Some(page) => {
+                paging::unmap(self.root, vaddr);
+                if paging::release(page) {
+                    free_pages(page as *mut u8);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        paging::translate(self.root, vaddr)
+    }
+
+    // PTE flags of the page holding vaddr, if it is mapped
+    pub fn flags(&self, vaddr: usize) -> Option<u64> {
+        paging::flags(self.root, vaddr)
+    }
+
+    // Number of user pages owned
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    // Reserve pages starting at vaddr to be mapped on first access
+    pub fn map_lazy(&mut self, vaddr: usize, pages: usize, flags: u64, backing: Backing) -> bool {
+        let end = vaddr + pages * PAGE_SIZE;
+        if !is_user_page(vaddr) || end > USER_END {
+            log::error!("Invalid lazy mapping at 0x{:x}", vaddr);
+            return false;
+        }
+        self.lazy.push(LazyRegion {
+            start: vaddr,
+            end,
+            flags,
+            backing,
+        });
+        true
+    }
+
+    // Unmap the pages and forget the lazy regions in [start, end)
+    pub fn unmap_range(&mut self, start: usize, end: usize) {
+        let mapped: Vec<usize> = self.pages.range(start..end).map(|(&v, _)| v).collect();
+        for vaddr in mapped {
+            self.unmap_user(vaddr);
+        }
+        self.lazy.retain(|r| r.start < start || r.end > end);
+        for region in self.lazy.iter_mut() {
+            if region.start < start && region.end > start {
+                region.end = start;
+            } else if region.start < end && region.end > end {
+                if let Backing::File { offset, .. } = &mut region.backing {
+                    *offset += (end - region.start) as u32;
+                }
+                region.start = end;
+            }
+        }
+    }
+
+    // The current program break
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+
+    // Move the program break to addr, None if it is outside the heap
+    // Growing reserves demand zero pages, shrinking frees the pages past the break
+    pub fn set_brk(&mut self, addr: usize) -> Option<usize> {
+        if !(USER_HEAP_START..=USER_HEAP_START + USER_HEAP_SIZE).contains(&addr) {
+            return None;
+        }
+        let old_end = page_round_up(self.brk);
+        let new_end = page_round_up(addr);
+        if new_end > old_end {
+            let pages = (new_end - old_end) / PAGE_SIZE;
+            if !self.map_lazy(old_end, pages, PTE_RW, Backing::Zero) {
+                return None;
+            }
+        } else if new_end < old_end {
+            self.unmap_range(new_end, old_end);
+        }
+        self.brk = addr;
+        Some(addr)
+    }
+
+    // Populate the page holding vaddr if it lies in a lazy region, or copy it
+    // if it is shared copy-on-write
+    // Returns false if the access was not to a lazy or copy-on-write page
+    pub fn handle_fault(&mut self, vaddr: usize) -> bool {
+        let page_vaddr = vaddr & !(PAGE_SIZE - 1);
+        if self.pages.contains_key(&page_vaddr) {
+            return match paging::resolve_cow(self.root, page_vaddr) {
+                Some(page) => {
+                    self.pages.insert(page_vaddr, page);
+                    true
+                }
+                None => false,
+            };
+        }
+        let region = match self.lazy.iter().find(|r| (r.start..r.end).contains(&vaddr)) {
+            Some(r) => r.clone(),
+            None => return false,
+        };
+        let page = match self.map_user(page_vaddr, region.flags) {
+            Some(p) => p,
+            None => return false,
+        };
+        if let Backing::File { path, offset } = &region.backing {
+            let file_offset = *offset + (page_vaddr - region.start) as u32;
+            // Reading past the end of the file leaves the rest of the page zeroed
+            vfs::read_file(path, page as *mut u8, PAGE_SIZE as u32, file_offset);
+        }
+        true
+    }
+
+    // A copy of this address space with every user page duplicated
+    pub fn try_clone(&self) -> Option<Self> {
+        let mut copy = Self::new()?;
+        copy.lazy = self.lazy.clone();
+        copy.brk = self.brk;
+        for (&vaddr, &page) in self.pages.iter() {
+            let flags = paging::flags(self.root, vaddr)?;
+            let new_page = copy.map_user(vaddr, flags)?;
+            unsafe { memcpy(new_page as *mut u8, page as *const u8, PAGE_SIZE) };
+        }
+        Some(copy)
+    }
+
+    // A copy of this address space sharing every user page copy-on-write
+    pub fn fork(&self) -> Option<Self> {
+        let mut child = Self::new()?;
+        child.lazy = self.lazy.clone();
+        child.brk = self.brk;
+        for (&vaddr, &page) in self.pages.iter() {
+            if !paging::share(self.root, child.root, vaddr) {
+                return None;
+            }
+            child.pages.insert(vaddr, page);
+        }
+        Some(child)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if core::ptr::eq(unsafe { CURRENT }, self) {
+            set_current(core::ptr::null_mut());
+        }
+        for &page in self.pages.values() {
+            if paging::release(page) {
+                free_pages(page as *mut u8);
+            }
+        }
+        // Only the user part of the tree is private, the rest is the kernel's
+        paging::free_tables(
+            self.root,
+            paging::root_index(USER_BASE),
+            paging::root_index(USER_END - 1) + 1,
+        );
+        free_pages(self.root as *mut u8);
+    }
+}
+
+// Make space the one page faults are resolved against, null for none
+pub fn set_current(space: *mut AddressSpace) {
+    unsafe { CURRENT = space };
+}
+
+// Called from the trap handler for instruction, load and store page faults
+// Returns true if the faulting page was populated and the access can be retried
+pub fn handle_page_fault(vaddr: usize) -> bool {
+    unsafe {
+        CURRENT
+            .as_mut()
+            .is_some_and(|space| space.handle_fault(vaddr))
+    }
+}
@@ -1,9 +1,14 @@
-use crate::config::PAGE_SIZE;
+use crate::config::{DMA_LIMIT, PAGE_SIZE};
 use crate::debug;
-use crate::memory::align_val;
-use crate::uart::serial_info;
+use crate::error::KError;
+use crate::log;
+use crate::memory::{self, align_val, memset};
+use crate::sync::SpinLock;
 use crate::{print, println};
-use core::{mem::size_of, ptr::null_mut};
+use core::{
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+};
 
 // mod alloc.rs
 // This is the kernel page and byte grain heap allocators
@@ -11,8 +16,6 @@ use core::{mem::size_of, ptr::null_mut};
 // Symbols defined in cfg/link.ld & asm/layout.S
 extern "C" {
     static HEAP_START: usize;
-    static HEAP_SIZE: usize;
-    static MEMORY_END: usize;
 }
 
 const PAGE_ORDER: usize = 12;
@@ -21,73 +24,221 @@ const ALLOC_TAKEN: usize = 1 << 63;
 const PAGE_FLAG_EMPTY: u8 = 0;
 const PAGE_FLAG_TAKEN: u8 = 1;
 const PAGE_FLAG_LAST: u8 = 2;
+const PAGE_FLAG_FREE_HEAD: u8 = 4;
+const PAGE_FLAG_ORDER_SHIFT: u8 = 4;
+
+// Largest buddy block is 2^MAX_ORDER pages
+const MAX_ORDER: usize = 11;
 
 // This is the PageGrainAllocator state
-static mut PAGE_GRAIN_ALLOC: PageGrainAllocator = PageGrainAllocator {};
+// Both allocators are used from interrupt handlers, take them with lock_irq()
+// The byte grain allocator is locked before the page grain one, never after
+static PAGE_GRAIN_ALLOC: SpinLock<PageGrainAllocator> = SpinLock::new(PageGrainAllocator {
+    free: [null_mut(); MAX_ORDER + 1],
+    pages: 0,
+});
+
+// A buddy allocator over the pages following the flag array
+// Blocks of 2^order pages are kept in per-order free lists threaded through
+// the free pages themselves. The head page flags of a block record its order
+struct PageGrainAllocator {
+    free: [*mut FreeBlock; MAX_ORDER + 1],
+    pages: usize,
+}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+// Pages from the heap start to the end of RAM, each has a flags entry
+fn heap_pages() -> usize {
+    (memory::end() - unsafe { HEAP_START }) / PAGE_SIZE
+}
+
+// First page handed out by the page grain allocator, right after the flags
+fn pages_start() -> usize {
+    unsafe {
+        let num_pages = heap_pages();
+        align_val(
+            HEAP_START + num_pages * size_of::<PageGrainFlags>(),
+            PAGE_ORDER,
+        )
+    }
+}
 
-struct PageGrainAllocator {}
+// Smallest order whose block holds the requested pages
+fn order_for(pages: usize) -> usize {
+    pages.next_power_of_two().trailing_zeros() as usize
+}
 
 impl PageGrainAllocator {
     fn init() {
-        serial_info("init kernel memory allocator");
+        log::info!("init kernel memory allocator");
         unsafe {
-            let num_pages = HEAP_SIZE / PAGE_SIZE;
+            let num_pages = heap_pages();
             let ptr = HEAP_START as *mut PageGrainFlags;
             for i in 0..num_pages {
                 (*ptr.add(i)).clear();
             }
+            let pages = ((memory::end() - pages_start()) / PAGE_SIZE).min(num_pages);
+            let mut page_alloc = PAGE_GRAIN_ALLOC.lock_irq();
+            page_alloc.free = [null_mut(); MAX_ORDER + 1];
+            page_alloc.pages = pages;
+            // Carve the pages into the largest aligned blocks that fit
+            let mut idx = 0;
+            while idx < pages {
+                let mut order = MAX_ORDER;
+                while idx & ((1 << order) - 1) != 0 || idx + (1 << order) > pages {
+                    order -= 1;
+                }
+                page_alloc.push(idx, order);
+                idx += 1 << order;
+            }
+        }
+    }
+
+    fn flags(&self, idx: usize) -> *mut PageGrainFlags {
+        unsafe { (HEAP_START as *mut PageGrainFlags).add(idx) }
+    }
+
+    fn block(&self, idx: usize) -> *mut FreeBlock {
+        (pages_start() + idx * PAGE_SIZE) as *mut FreeBlock
+    }
+
+    // Put the block at idx on the free list of its order
+    fn push(&mut self, idx: usize, order: usize) {
+        unsafe {
+            let block = self.block(idx);
+            (*block).prev = null_mut();
+            (*block).next = self.free[order];
+            if !self.free[order].is_null() {
+                (*self.free[order]).prev = block;
+            }
+            self.free[order] = block;
+            (*self.flags(idx)).set_free_head(order);
+        }
+    }
+
+    // Take the block at idx off the free list of its order
+    fn unlink(&mut self, idx: usize, order: usize) {
+        unsafe {
+            let block = self.block(idx);
+            if (*block).prev.is_null() {
+                self.free[order] = (*block).next;
+            } else {
+                (*(*block).prev).next = (*block).next;
+            }
+            if !(*block).next.is_null() {
+                (*(*block).next).prev = (*block).prev;
+            }
+            (*self.flags(idx)).clear();
         }
     }
 
-    fn alloc(&self, pages: usize) -> *mut u8 {
+    fn alloc(&mut self, pages: usize) -> *mut u8 {
         assert!(pages > 0);
+        let order = order_for(pages);
+        if order > MAX_ORDER {
+            return null_mut();
+        }
+        let found = match (order..=MAX_ORDER).find(|&o| !self.free[o].is_null()) {
+            Some(o) => o,
+            None => return null_mut(),
+        };
+        let idx = (self.free[found] as usize - pages_start()) / PAGE_SIZE;
+        self.unlink(idx, found);
+        // Split the block, returning the upper halves to the smaller orders
+        for o in (order..found).rev() {
+            self.push(idx + (1 << o), o);
+        }
         unsafe {
-            let num_pages = HEAP_SIZE / PAGE_SIZE;
-            let ptr = HEAP_START as *mut PageGrainFlags;
-            for i in 0..=num_pages - pages {
-                let mut found = false;
-                if (*ptr.add(i)).is_free() {
-                    found = true;
-                    for j in i..i + pages {
-                        if (*ptr.add(j)).is_taken() {
-                            found = false;
-                            break;
-                        }
-                    }
-                }
-                if found {
-                    for k in i..=i + pages - 1 {
-                        (*ptr.add(k)).set_flag(PAGE_FLAG_TAKEN);
-                    }
-                    (*ptr.add(i + pages - 1)).set_flag(PAGE_FLAG_LAST);
-                    return (BYTE_GRAIN_ALLOC.get_start() + PAGE_SIZE * i) as *mut u8;
+            let count = 1 << order;
+            for k in idx..idx + count {
+                (*self.flags(k)).set_flag(PAGE_FLAG_TAKEN);
+            }
+            (*self.flags(idx + count - 1)).set_flag(PAGE_FLAG_LAST);
+            (*self.flags(idx)).set_order(order);
+        }
+        self.block(idx) as *mut u8
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let start = pages_start();
+        let addr = ptr as usize;
+        assert!(addr >= start && addr & (PAGE_SIZE - 1) == 0);
+        let mut idx = (addr - start) / PAGE_SIZE;
+        assert!(idx < self.pages);
+        unsafe {
+            if (*self.flags(idx)).is_free() {
+                log::error!("Trying to free unallocated pages at {:p}", ptr);
+                return;
+            }
+            let mut order = (*self.flags(idx)).order();
+            for k in idx..idx + (1 << order) {
+                (*self.flags(k)).clear();
+            }
+            // Merge with the buddy for as long as it is free and whole
+            while order < MAX_ORDER {
+                let buddy = idx ^ (1 << order);
+                if buddy + (1 << order) > self.pages || !(*self.flags(buddy)).is_free_head(order) {
+                    break;
                 }
+                self.unlink(buddy, order);
+                idx = idx.min(buddy);
+                order += 1;
             }
+            self.push(idx, order);
         }
-        null_mut()
     }
 
-    fn zalloc(&self, pages: usize) -> *mut u8 {
-        let ret = alloc_pages(pages);
+    // Number of free blocks of every order
+    fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut block = self.free[order];
+            while !block.is_null() {
+                *count += 1;
+                block = unsafe { (*block).next };
+            }
+        }
+        counts
+    }
+
+    fn zalloc(&mut self, pages: usize) -> *mut u8 {
+        let ret = self.alloc(pages);
         if !ret.is_null() {
-            let size = (PAGE_SIZE * pages) / 8;
-            let big_ptr = ret as *mut u64;
-            for i in 0..size {
-                unsafe {
-                    (*big_ptr.add(i)) = 0;
+            unsafe { memset(ret, 0, PAGE_SIZE * pages) };
+        }
+        ret
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        unsafe {
+            let num_pages = heap_pages();
+            let ptr = HEAP_START as *const PageGrainFlags;
+            let avail_pages = (memory::end() - pages_start()) / PAGE_SIZE;
+            let mut used_pages = 0;
+            for i in 0..num_pages {
+                if (*ptr.add(i)).is_taken() {
+                    used_pages += 1;
                 }
             }
+            (avail_pages, used_pages)
         }
-        ret
     }
 
-    fn print(&self) {
+    // bga is the head of the byte grain allocator, its pages are labelled
+    fn print(&self, bga: *mut ByteGrainFlags) {
         unsafe {
-            let num_pages = HEAP_SIZE / PAGE_SIZE;
+            let num_pages = heap_pages();
             let mut beg = HEAP_START as *const PageGrainFlags;
             let end = beg.add(num_pages);
-            let alloc_beg = BYTE_GRAIN_ALLOC.get_start();
-            let alloc_end = MEMORY_END;
+            let alloc_beg = pages_start();
+            let alloc_end = memory::end();
             let avail_pages = (alloc_end - alloc_beg) / 4096;
             debug::dbg(
                 "Kernel Allocator Memory Map\n\nRANGE:       START         END           PAGES",
@@ -108,8 +259,8 @@ impl PageGrainAllocator {
             while beg < end {
                 if (*beg).is_taken() {
                     let start = beg as usize;
-                    let memaddr = BYTE_GRAIN_ALLOC.get_start() + (start - HEAP_START) * PAGE_SIZE;
-                    let name = if memaddr as *mut ByteGrainFlags == BYTE_GRAIN_ALLOC.get_head() {
+                    let memaddr = pages_start() + (start - HEAP_START) * PAGE_SIZE;
+                    let name = if memaddr as *mut ByteGrainFlags == bga {
                         "BGA"
                     } else {
                         "   "
@@ -119,10 +270,8 @@ impl PageGrainAllocator {
                         num += 1;
                         if (*beg).is_last() {
                             let end = beg as usize;
-                            let memaddr = BYTE_GRAIN_ALLOC.get_start()
-                                + (end - HEAP_START) * PAGE_SIZE
-                                + PAGE_SIZE
-                                - 1;
+                            let memaddr =
+                                pages_start() + (end - HEAP_START) * PAGE_SIZE + PAGE_SIZE - 1;
                             print!("0x{:x}: {:>7}", memaddr, (end - start + 1));
                             println!("");
                             break;
@@ -139,21 +288,24 @@ impl PageGrainAllocator {
                 avail_pages,
                 num * 100 / avail_pages
             );
+            println!("\nFree blocks per order");
+            println!("----------------------------------------------");
+            for (order, count) in self.free_blocks().iter().enumerate() {
+                println!("- ORDER {:>2} {:>7} pages: {:>5}", order, 1 << order, count);
+            }
         }
     }
 }
 
 // This is the ByteGrainAllocator state
-static mut BYTE_GRAIN_ALLOC: ByteGrainAllocator = ByteGrainAllocator {
+static BYTE_GRAIN_ALLOC: SpinLock<ByteGrainAllocator> = SpinLock::new(ByteGrainAllocator {
     head: null_mut(),
     alloc: 0,
-    start: 0,
-};
+});
 
 struct ByteGrainAllocator {
     head: *mut ByteGrainFlags,
     alloc: usize,
-    start: usize,
 }
 
 impl ByteGrainAllocator {
@@ -169,10 +321,6 @@ impl ByteGrainAllocator {
         self.alloc
     }
 
-    fn get_start(&self) -> usize {
-        self.start
-    }
-
     fn set_head(&mut self, head: *mut ByteGrainFlags) {
         self.head = head;
     }
@@ -181,23 +329,20 @@ impl ByteGrainAllocator {
         self.alloc = alloc;
     }
 
-    fn set_start(&mut self, start: usize) {
-        self.start = start;
-    }
-
     fn init() {
+        let mut byte_alloc = BYTE_GRAIN_ALLOC.lock_irq();
         unsafe {
-            let num_pages = HEAP_SIZE / PAGE_SIZE;
-            BYTE_GRAIN_ALLOC.set_start(align_val(
-                HEAP_START + num_pages * size_of::<PageGrainFlags>(),
-                PAGE_ORDER,
-            ));
-            BYTE_GRAIN_ALLOC.set_alloc(512);
-            let k_alloc = alloc_pages_zeroed(BYTE_GRAIN_ALLOC.get_alloc());
+            byte_alloc.set_alloc(512);
+            let k_alloc = alloc_pages_zeroed(byte_alloc.get_alloc());
             assert!(!k_alloc.is_null());
-            BYTE_GRAIN_ALLOC.set_head(k_alloc as *mut ByteGrainFlags);
-            (*BYTE_GRAIN_ALLOC.get_head()).set_free();
-            (*BYTE_GRAIN_ALLOC.get_head()).set_size(BYTE_GRAIN_ALLOC.get_alloc() * PAGE_SIZE);
+            byte_alloc.set_head(k_alloc as *mut ByteGrainFlags);
+            (*byte_alloc.get_head()).set_free();
+            (*byte_alloc.get_head()).set_size(byte_alloc.get_alloc() * PAGE_SIZE);
+            (*byte_alloc.get_head()).mark(BYTE_MAGIC_FREE);
+            poison(
+                byte_alloc.get_head().add(1) as *mut u8,
+                byte_alloc.get_alloc() * PAGE_SIZE - size_of::<ByteGrainFlags>(),
+            );
         }
     }
 
@@ -206,11 +351,7 @@ impl ByteGrainAllocator {
         let ret = self.kmalloc(size);
 
         if !ret.is_null() {
-            for i in 0..size {
-                unsafe {
-                    (*ret.add(i)) = 0;
-                }
-            }
+            unsafe { memset(ret, 0, size) };
         }
         ret
     }
@@ -225,11 +366,14 @@ impl ByteGrainAllocator {
                 if (*head).is_free() && size <= (*head).get_size() {
                     let chunk_size = (*head).get_size();
                     let rem = chunk_size - size;
+                    check_poison(head.add(1) as *const u8, size - size_of::<ByteGrainFlags>());
                     (*head).set_taken();
+                    (*head).mark(BYTE_MAGIC_TAKEN);
                     if rem > size_of::<ByteGrainFlags>() {
                         let next = (head as *mut u8).add(size) as *mut ByteGrainFlags;
                         (*next).set_free();
                         (*next).set_size(rem);
+                        (*next).mark(BYTE_MAGIC_FREE);
                         (*head).set_size(size);
                     } else {
                         (*head).set_size(chunk_size);
@@ -247,8 +391,11 @@ impl ByteGrainAllocator {
         unsafe {
             if !ptr.is_null() {
                 let p = (ptr as *mut ByteGrainFlags).offset(-1);
+                check_free(p, ptr);
                 if (*p).is_taken() {
                     (*p).set_free();
+                    (*p).mark(BYTE_MAGIC_FREE);
+                    poison(ptr, (*p).get_size() - size_of::<ByteGrainFlags>());
                 }
                 self.coalesce();
             }
@@ -267,12 +414,35 @@ impl ByteGrainAllocator {
                     break;
                 } else if (*head).is_free() && (*next).is_free() {
                     (*head).set_size((*head).get_size() + (*next).get_size());
+                    poison(next as *mut u8, size_of::<ByteGrainFlags>());
+                    // The grown chunk may reach another free chunk
+                    continue;
                 }
                 head = (head as *mut u8).add((*head).get_size()) as *mut ByteGrainFlags;
             }
         }
     }
 
+    // Total, used bytes and the number of chunks, free or taken
+    fn usage(&self) -> (usize, usize, usize) {
+        unsafe {
+            let mut head = self.get_head();
+            let tail = self.get_head_u8().add(self.get_alloc() * PAGE_SIZE) as *mut ByteGrainFlags;
+            let mut total_bytes = 0;
+            let mut used_bytes = 0;
+            let mut chunks = 0;
+            while head < tail && (*head).get_size() != 0 {
+                chunks += 1;
+                total_bytes += (*head).get_size();
+                if (*head).is_taken() {
+                    used_bytes += (*head).get_size();
+                }
+                head = (head as *mut u8).add((*head).get_size()) as *mut ByteGrainFlags;
+            }
+            (total_bytes, used_bytes, chunks)
+        }
+    }
+
     fn print(&self) {
         unsafe {
             println!("\nByte Grain Allocator (BGA)               BYTES");
@@ -309,10 +479,63 @@ impl ByteGrainAllocator {
 }
 
 // This structure is used to track byte grain allocations within a page grained allocation
+// Debug builds add a magic value to tell taken, free and corrupted chunks apart
 struct ByteGrainFlags {
     flags: usize,
+    #[cfg(feature = "debug-full")]
+    magic: usize,
+}
+
+// Debug builds fill free chunks with POISON and check it is intact on reuse
+#[cfg(feature = "debug-full")]
+const POISON: u8 = 0xa5;
+const BYTE_MAGIC_TAKEN: usize = 0x7a4e_7a4e_7a4e_7a4e;
+const BYTE_MAGIC_FREE: usize = 0xf4ee_f4ee_f4ee_f4ee;
+
+#[cfg(feature = "debug-full")]
+fn poison(ptr: *mut u8, len: usize) {
+    unsafe { memset(ptr, POISON, len) };
 }
+
+#[cfg(not(feature = "debug-full"))]
+fn poison(_ptr: *mut u8, _len: usize) {}
+
+// Panic if a free chunk about to be handed out was written to
+#[cfg(feature = "debug-full")]
+fn check_poison(ptr: *const u8, len: usize) {
+    for i in 0..len {
+        let addr = unsafe { ptr.add(i) };
+        if unsafe { addr.read() } != POISON {
+            panic!("Write to freed memory at {:p}", addr);
+        }
+    }
+}
+
+#[cfg(not(feature = "debug-full"))]
+fn check_poison(_ptr: *const u8, _len: usize) {}
+
+// Panic on double frees and frees of pointers that were never allocated
+#[cfg(feature = "debug-full")]
+fn check_free(header: *const ByteGrainFlags, ptr: *mut u8) {
+    match unsafe { (*header).magic } {
+        BYTE_MAGIC_TAKEN => {}
+        BYTE_MAGIC_FREE => panic!("Double free of {:p}", ptr),
+        _ => panic!("Free of unallocated or corrupted memory at {:p}", ptr),
+    }
+}
+
+#[cfg(not(feature = "debug-full"))]
+fn check_free(_header: *const ByteGrainFlags, _ptr: *mut u8) {}
+
 impl ByteGrainFlags {
+    #[cfg(feature = "debug-full")]
+    fn mark(&mut self, magic: usize) {
+        self.magic = magic;
+    }
+
+    #[cfg(not(feature = "debug-full"))]
+    fn mark(&mut self, _magic: usize) {}
+
     fn is_taken(&self) -> bool {
         self.flags & ALLOC_TAKEN != 0
     }
@@ -367,9 +590,56 @@ impl PageGrainFlags {
     fn set_flag(&mut self, flag: u8) {
         self.flags |= flag;
     }
+
+    fn order(&self) -> usize {
+        (self.flags >> PAGE_FLAG_ORDER_SHIFT) as usize
+    }
+
+    fn set_order(&mut self, order: usize) {
+        self.flags |= (order as u8) << PAGE_FLAG_ORDER_SHIFT;
+    }
+
+    fn set_free_head(&mut self, order: usize) {
+        self.flags = PAGE_FLAG_FREE_HEAD;
+        self.set_order(order);
+    }
+
+    fn is_free_head(&self, order: usize) -> bool {
+        self.flags & PAGE_FLAG_FREE_HEAD != 0 && self.order() == order
+    }
+}
+
+// Physically contiguous memory handed to devices
+// virt is where the kernel accesses it, phys is the address given to the device
+#[derive(Clone, Copy)]
+pub struct DmaRegion {
+    pub virt: *mut u8,
+    pub phys: u64,
+    pub size: usize,
+}
+
+impl DmaRegion {
+    // Device address of the byte at offset into the region
+    pub fn phys_at(&self, offset: usize) -> u64 {
+        self.phys + offset as u64
+    }
+}
+
+// Snapshot of kernel heap usage
+pub struct HeapStats {
+    pub pages_total: usize,
+    pub pages_used: usize,
+    pub bytes_total: usize,
+    pub bytes_used: usize,
+    // Chunks the byte heap is split into, grows with fragmentation
+    pub byte_chunks: usize,
 }
 
 // Beginning of public alloc API
+// The try_ functions report failure as a KError and are what new code should
+// use. alloc_pages, alloc_bytes and their zeroed forms keep returning null:
+// GlobalAlloc below and the slab caches are built on them and must hand null
+// on to their own callers, and the drivers that still use them check for it
 pub fn init() {
     PageGrainAllocator::init();
     ByteGrainAllocator::init();
@@ -377,35 +647,114 @@ pub fn init() {
 
 // Allocate kernel memory pages
 pub fn alloc_pages(pages: usize) -> *mut u8 {
-    unsafe { PAGE_GRAIN_ALLOC.alloc(pages) }
+    PAGE_GRAIN_ALLOC.lock_irq().alloc(pages)
+}
+
+// Free buddy blocks of each order, index i holds blocks of 2^i pages
+#[allow(dead_code)]
+pub fn free_blocks() -> [usize; MAX_ORDER + 1] {
+    PAGE_GRAIN_ALLOC.lock_irq().free_blocks()
+}
+
+// Allocate kernel memory pages, reporting exhaustion instead of returning null
+// Asking for no pages is InvalidArgument, running out NoMemory
+pub fn try_alloc_pages(pages: usize) -> Result<NonNull<u8>, KError> {
+    if pages == 0 {
+        return Err(KError::InvalidArgument);
+    }
+    NonNull::new(alloc_pages(pages)).ok_or(KError::NoMemory)
 }
 
 // Allocate zeroed kernel memory pages
 pub fn alloc_pages_zeroed(pages: usize) -> *mut u8 {
-    unsafe { PAGE_GRAIN_ALLOC.zalloc(pages) }
+    PAGE_GRAIN_ALLOC.lock_irq().zalloc(pages)
+}
+
+// Free kernel memory pages returned by alloc_pages or alloc_pages_zeroed
+pub fn free_pages(ptr: *mut u8) {
+    PAGE_GRAIN_ALLOC.lock_irq().dealloc(ptr);
+}
+
+// Allocate zeroed, physically contiguous memory of size bytes for a device
+// align must be a power of two, alignments above PAGE_SIZE are only met when the
+// buddy block happens to be aligned. Fails with NoMemory if no suitable memory is found
+pub fn alloc_dma(size: usize, align: usize) -> Result<DmaRegion, KError> {
+    let pages = size.max(align).div_ceil(PAGE_SIZE);
+    let virt = alloc_pages_zeroed(pages);
+    if virt.is_null() {
+        return Err(KError::NoMemory);
+    }
+    match dma_address(virt, size) {
+        Some(phys) if phys & (align as u64 - 1) == 0 => Ok(DmaRegion { virt, phys, size }),
+        _ => {
+            log::error!("Unusable dma region at {:p}", virt);
+            free_pages(virt);
+            Err(KError::NoMemory)
+        }
+    }
+}
+
+// Free a region returned by alloc_dma
+pub fn free_dma(region: DmaRegion) {
+    free_pages(region.virt);
+}
+
+// Device address of size bytes of kernel memory at ptr, if a device can reach them
+// Kernel memory is identity mapped so this only checks the range is addressable
+pub fn dma_address(ptr: *const u8, size: usize) -> Option<u64> {
+    let phys = ptr as usize;
+    if phys.checked_add(size)? > DMA_LIMIT {
+        return None;
+    }
+    Some(phys as u64)
+}
+
+// True if addr lies in the memory managed by the allocators
+pub fn is_heap_address(addr: usize) -> bool {
+    unsafe { (HEAP_START..memory::end()).contains(&addr) }
 }
 
 // Allocate zeroed bytes from kernel byte allocator
 pub fn alloc_bytes_zeroed(sz: usize) -> *mut u8 {
-    unsafe { BYTE_GRAIN_ALLOC.kzmalloc(sz) }
+    BYTE_GRAIN_ALLOC.lock_irq().kzmalloc(sz)
 }
 
 // Allocate bytes from kernel byte allocator
 pub fn alloc_bytes(sz: usize) -> *mut u8 {
-    unsafe { BYTE_GRAIN_ALLOC.kmalloc(sz) }
+    BYTE_GRAIN_ALLOC.lock_irq().kmalloc(sz)
+}
+
+// Allocate bytes, reporting exhaustion instead of returning null
+pub fn try_alloc_bytes(sz: usize) -> Result<NonNull<u8>, KError> {
+    if sz == 0 {
+        return Err(KError::InvalidArgument);
+    }
+    NonNull::new(alloc_bytes(sz)).ok_or(KError::NoMemory)
 }
 
 // Free bytes from kernel byte allocator
 pub fn free_bytes(ptr: *mut u8) {
-    unsafe { BYTE_GRAIN_ALLOC.kfree(ptr) };
+    BYTE_GRAIN_ALLOC.lock_irq().kfree(ptr);
+}
+
+// Current usage of both the page and byte grain allocators
+pub fn stats() -> HeapStats {
+    let (bytes_total, bytes_used, byte_chunks) = BYTE_GRAIN_ALLOC.lock_irq().usage();
+    let (pages_total, pages_used) = PAGE_GRAIN_ALLOC.lock_irq().usage();
+    HeapStats {
+        pages_total,
+        pages_used,
+        bytes_total,
+        bytes_used,
+        byte_chunks,
+    }
 }
 
 // Helpful debugging aid to visualize kernel memory heap
 pub fn debug_heap() {
-    unsafe {
-        PAGE_GRAIN_ALLOC.print();
-        BYTE_GRAIN_ALLOC.print();
-    }
+    let bga = BYTE_GRAIN_ALLOC.lock_irq().get_head();
+    PAGE_GRAIN_ALLOC.lock_irq().print(bga);
+    BYTE_GRAIN_ALLOC.lock_irq().print();
 }
 
 use core::alloc::{GlobalAlloc, Layout};
@@ -5,11 +5,21 @@ use core::arch::{asm, global_asm};
 // And provides wrappers for common riscv asm calls
 
 // Incorporate bootloader into rust as a module so cargo can compile it
+#[cfg(not(feature = "sbi"))]
 global_asm!(include_str!("asm/boot.S"));
+// Or the supervisor mode entry from OpenSBI
+#[cfg(feature = "sbi")]
+global_asm!(include_str!("asm/sbi.S"));
 // Incorporate trap vector
 global_asm!(include_str!("asm/trap.S"));
+// Incorporate context switch routine
+global_asm!(include_str!("asm/switch.S"));
 // Incorporate linker symbols
 global_asm!(include_str!("asm/layout.S"));
+// Incorporate the supervisor mode entry, trap vector and machine mode stub
+// Under OpenSBI only the trap vector is used
+#[cfg(feature = "supervisor")]
+global_asm!(include_str!("asm/supervisor.S"));
 
 // Wrapper to perform no operation
 // Used currently as a crude sleep until multi threaded
@@ -27,6 +37,321 @@ pub fn wait_for_interrupt() {
     }
 }
 
+// Wrappers for the thread pointer, it holds this hart's block in src/hart.rs
+pub fn read_tp() -> usize {
+    let tp: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) tp);
+    }
+    tp
+}
+
+pub fn write_tp(tp: usize) {
+    unsafe {
+        asm!("mv tp, {}", in(reg) tp);
+    }
+}
+
+// Wrapper to install a page table root and flush stale translations
+pub fn write_satp(satp: usize) {
+    unsafe {
+        asm!("csrw satp, {}", "sfence.vma", in(reg) satp);
+    }
+}
+
+// Wrapper to flush all cached address translations
+pub fn sfence_vma() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+// Wrapper to make stores to code visible to instruction fetches on this hart
+// Used after patching in breakpoints
+#[allow(dead_code)]
+pub fn fence_instructions() {
+    unsafe {
+        asm!("fence.i");
+    }
+}
+
+// Wrappers for the interrupt enable bits, mie or sie in supervisor mode
+#[cfg(not(feature = "supervisor"))]
+pub fn read_ie() -> usize {
+    let ie: usize;
+    unsafe {
+        asm!("csrr {}, mie", out(reg) ie);
+    }
+    ie
+}
+
+#[cfg(feature = "supervisor")]
+pub fn read_ie() -> usize {
+    let ie: usize;
+    unsafe {
+        asm!("csrr {}, sie", out(reg) ie);
+    }
+    ie
+}
+
+#[cfg(not(feature = "supervisor"))]
+pub fn write_ie(ie: usize) {
+    unsafe {
+        asm!("csrw mie, {}", in(reg) ie);
+    }
+}
+
+#[cfg(feature = "supervisor")]
+pub fn write_ie(ie: usize) {
+    unsafe {
+        asm!("csrw sie, {}", in(reg) ie);
+    }
+}
+
+// Wrappers to set and clear the global interrupt enable, mstatus.MIE or sstatus.SIE
+#[cfg(not(feature = "supervisor"))]
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("csrsi mstatus, 1 << 3");
+    }
+}
+
+#[cfg(feature = "supervisor")]
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("csrsi sstatus, 1 << 1");
+    }
+}
+
+#[cfg(not(feature = "supervisor"))]
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("csrci mstatus, 1 << 3");
+    }
+}
+
+#[cfg(feature = "supervisor")]
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("csrci sstatus, 1 << 1");
+    }
+}
+
+// True if the global interrupt enable is set
+#[cfg(not(feature = "supervisor"))]
+pub fn interrupts_enabled() -> bool {
+    let mstatus: usize;
+    unsafe {
+        asm!("csrr {}, mstatus", out(reg) mstatus);
+    }
+    mstatus & (1 << 3) != 0
+}
+
+#[cfg(feature = "supervisor")]
+pub fn interrupts_enabled() -> bool {
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus);
+    }
+    sstatus & (1 << 1) != 0
+}
+
+// Wrapper to read the trap CSRs as (status, cause, tval, epc), the m* ones or
+// the s* ones in supervisor mode. Outside a trap they describe the last one
+#[cfg(not(feature = "supervisor"))]
+pub fn read_trap_csrs() -> (usize, usize, usize, usize) {
+    let (status, cause, tval, epc): (usize, usize, usize, usize);
+    unsafe {
+        asm!(
+            "csrr {}, mstatus",
+            "csrr {}, mcause",
+            "csrr {}, mtval",
+            "csrr {}, mepc",
+            out(reg) status,
+            out(reg) cause,
+            out(reg) tval,
+            out(reg) epc,
+        );
+    }
+    (status, cause, tval, epc)
+}
+
+#[cfg(feature = "supervisor")]
+pub fn read_trap_csrs() -> (usize, usize, usize, usize) {
+    let (status, cause, tval, epc): (usize, usize, usize, usize);
+    unsafe {
+        asm!(
+            "csrr {}, sstatus",
+            "csrr {}, scause",
+            "csrr {}, stval",
+            "csrr {}, sepc",
+            out(reg) status,
+            out(reg) cause,
+            out(reg) tval,
+            out(reg) epc,
+        );
+    }
+    (status, cause, tval, epc)
+}
+
+pub fn read_satp() -> usize {
+    let satp: usize;
+    unsafe {
+        asm!("csrr {}, satp", out(reg) satp);
+    }
+    satp
+}
+
+// Wrapper to set the scratch register the trap entry swaps with sp
+// Supervisor traps use sscratch, mscratch belongs to the machine mode stub
+#[cfg(not(feature = "supervisor"))]
+pub fn write_scratch(value: usize) {
+    unsafe {
+        asm!("csrw mscratch, {}", in(reg) value);
+    }
+}
+
+#[cfg(feature = "supervisor")]
+pub fn write_scratch(value: usize) {
+    unsafe {
+        asm!("csrw sscratch, {}", in(reg) value);
+    }
+}
+
+// Wrapper to acknowledge a supervisor software interrupt raised by the stub
+#[cfg(feature = "supervisor")]
+pub fn clear_software_pending() {
+    unsafe {
+        asm!("csrci sip, 1 << 1");
+    }
+}
+
+// Extension and function of the one call the machine mode stub serves, the
+// ids SBI firmware uses for it
+#[cfg(all(feature = "supervisor", not(feature = "sbi")))]
+pub const MACHINE_EXT_TIME: usize = 0x5449_4d45;
+#[cfg(all(feature = "supervisor", not(feature = "sbi")))]
+pub const MACHINE_TIME_SET_TIMER: usize = 0;
+
+// Call function of extension in the machine mode stub, see src/asm/supervisor.S
+// Returns 0, or -2 for a call the stub does not serve
+#[cfg(all(feature = "supervisor", not(feature = "sbi")))]
+pub fn machine_call(extension: usize, function: usize, arg: usize) -> isize {
+    let error;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg => error,
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+    error
+}
+
+// Ask the machine mode stub to program this hart's mtimecmp
+#[cfg(all(feature = "supervisor", not(feature = "sbi")))]
+pub fn machine_set_timer(when: u64) {
+    machine_call(MACHINE_EXT_TIME, MACHINE_TIME_SET_TIMER, when as usize);
+}
+
+// Leave machine mode and continue in entry in supervisor mode
+// see src/asm/supervisor.S
+#[cfg(all(feature = "supervisor", not(feature = "sbi")))]
+pub fn enter_supervisor(entry: usize) -> ! {
+    extern "C" {
+        fn _supervisor_main(entry: usize) -> !;
+    }
+    unsafe { _supervisor_main(entry) }
+}
+
+// Already in supervisor mode under OpenSBI, take interrupts and continue in
+// entry, see src/asm/sbi.S
+#[cfg(feature = "sbi")]
+pub fn enter_supervisor(entry: usize) -> ! {
+    extern "C" {
+        fn _sbi_supervisor_main(entry: usize) -> !;
+    }
+    unsafe { _sbi_supervisor_main(entry) }
+}
+
+// Where the firmware starts secondary harts, see src/asm/sbi.S
+#[cfg(feature = "sbi")]
+pub fn hart_start_address() -> usize {
+    extern "C" {
+        fn _sbi_hart_start();
+    }
+    _sbi_hart_start as usize
+}
+
+// Wrapper to read the time CSR, the firmware keeps mtime from S mode
+#[cfg(feature = "sbi")]
+pub fn read_time() -> u64 {
+    let time: u64;
+    unsafe {
+        asm!("csrr {}, time", out(reg) time);
+    }
+    time
+}
+
+// Wrapper to read the cycle counter, mcycle or the cycle CSR in supervisor mode
+// Counts at the core's clock rather than the timebase, see src/random.rs
+#[cfg(not(feature = "supervisor"))]
+pub fn read_cycle() -> u64 {
+    let cycle: u64;
+    unsafe {
+        asm!("csrr {}, mcycle", out(reg) cycle);
+    }
+    cycle
+}
+
+#[cfg(feature = "supervisor")]
+pub fn read_cycle() -> u64 {
+    let cycle: u64;
+    unsafe {
+        asm!("csrr {}, cycle", out(reg) cycle);
+    }
+    cycle
+}
+
+// Call function of extension in the SBI firmware, see src/sbi.rs
+// Returns the error code and the value
+#[cfg(feature = "sbi")]
+pub fn sbi_call(extension: usize, function: usize, args: [usize; 3]) -> (isize, usize) {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+    (error, value)
+}
+
+// Make syscall number through ecall with up to three arguments, see
+// src/syscall.rs. In supervisor mode an ecall goes to the machine mode stub
+// or the SBI firmware instead
+// Used to test syscalls
+#[cfg(not(feature = "supervisor"))]
+#[allow(dead_code)]
+pub fn syscall(number: usize, args: [usize; 3]) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => ret,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a7") number,
+        );
+    }
+    ret
+}
+
 // Wrapper to trigger an illegal load
 // Used to test traps
 pub fn trigger_illegal_load() {
@@ -43,9 +368,11 @@ pub fn trigger_illegal_store() {
     }
 }
 
-// Used to trigger a shutdown in the qemu virt platform
-pub fn trigger_shutdown() {
+// Wrapper to trigger a breakpoint trap
+// Used to test traps and to enter the debug monitor or the gdb stub
+#[allow(dead_code)]
+pub fn trigger_breakpoint() {
     unsafe {
-        asm!("li a0, 0x100000", "li a1, 0x5555", "sw a1, 0(a0)");
+        asm!("ebreak");
     }
 }
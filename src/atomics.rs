@@ -0,0 +1,87 @@
+use core::arch::asm;
+
+// mod atomics.rs
+// Atomic operations on 64 bit words with the RISC-V A extension
+// Every read-modify-write is a single AMO instruction or an LR/SC loop, the
+// .aq and .rl bits give them acquire and release ordering so they also order
+// the plain memory accesses around them
+// The words must be 8 byte aligned, AMOs on misaligned addresses fault
+
+// Plain load that is ordered before every later access
+pub fn load_acquire(ptr: *const usize) -> usize {
+    let value: usize;
+    unsafe {
+        asm!("ld {}, 0({})", "fence r, rw", out(reg) value, in(reg) ptr);
+    }
+    value
+}
+
+// Plain store that is ordered after every earlier access
+pub fn store_release(ptr: *mut usize, value: usize) {
+    unsafe {
+        asm!("fence rw, w", "sd {}, 0({})", in(reg) value, in(reg) ptr);
+    }
+}
+
+// Store value, returns the previous value
+pub fn swap_acquire(ptr: *mut usize, value: usize) -> usize {
+    let old: usize;
+    unsafe {
+        asm!("amoswap.d.aq {}, {}, ({})", out(reg) old, in(reg) value, in(reg) ptr);
+    }
+    old
+}
+
+// Add value, returns the previous value
+pub fn fetch_add(ptr: *mut usize, value: usize) -> usize {
+    let old: usize;
+    unsafe {
+        asm!("amoadd.d.aqrl {}, {}, ({})", out(reg) old, in(reg) value, in(reg) ptr);
+    }
+    old
+}
+
+// Store new if the word holds current
+// Ok with the previous value on success, Err with the value found otherwise
+pub fn compare_and_swap(ptr: *mut usize, current: usize, new: usize) -> Result<usize, usize> {
+    let old: usize;
+    unsafe {
+        asm!(
+            "2:",
+            "lr.d.aqrl {old}, ({ptr})",
+            "bne {old}, {current}, 3f",
+            "sc.d.rl {fail}, {new}, ({ptr})",
+            "bnez {fail}, 2b",
+            "3:",
+            old = out(reg) old,
+            fail = out(reg) _,
+            ptr = in(reg) ptr,
+            current = in(reg) current,
+            new = in(reg) new,
+        );
+    }
+    if old == current {
+        Ok(old)
+    } else {
+        Err(old)
+    }
+}
+
+// Load the word and reserve it for a following store_conditional
+pub fn load_reserved(ptr: *mut usize) -> usize {
+    let value: usize;
+    unsafe {
+        asm!("lr.d.aq {}, ({})", out(reg) value, in(reg) ptr);
+    }
+    value
+}
+
+// Store value if the reservation of the last load_reserved on this hart still
+// holds, true if it did. Keep the code in between short, a trap breaks it
+pub fn store_conditional(ptr: *mut usize, value: usize) -> bool {
+    let fail: usize;
+    unsafe {
+        asm!("sc.d.rl {}, {}, ({})", out(reg) fail, in(reg) value, in(reg) ptr);
+    }
+    fail == 0
+}
@@ -1,43 +1,84 @@
-use crate::alloc::{alloc_bytes, alloc_pages_zeroed, free_bytes};
-use crate::assembly;
-use crate::config::PAGE_SIZE;
-use crate::uart::serial_info;
-use crate::{print, println};
+use crate::alloc::dma_address;
+use crate::buffer;
+use crate::config::{BLOCK_TIMEOUT_MS, BLOCK_WATCHDOG_MS};
+use crate::error::KError;
+use crate::irqlog::{self, IrqSource};
+use crate::log;
+use crate::minixfs3::BLOCK_SIZE;
+use crate::process;
+use crate::slab::{Slab, SlabStats};
+use crate::sync::SpinLock;
+use crate::time;
+use crate::trace::{self, trace_event};
+use crate::virtio::{self, Features, MmioDevice};
+use crate::virtqueue::{Segment, VirtQueue, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_SIZE};
+use crate::waitqueue::WaitQueue;
+use crate::watchdog;
 use core::mem::size_of;
+use core::time::Duration;
 
 // mod block.rs
-// This is an extremely simple block driver using virtio legacy mmio
+// This is an extremely simple block driver using virtio mmio
 
 // Static handle for default configured block device
-static mut BLOCK_DEVICE: Option<BlockDevice> = None;
-
-const MMIO_HOST_FEATURES: usize = 0x010 / 4;
-const MMIO_GUEST_FEATURES: usize = 0x020 / 4;
-const MMIO_GUEST_PAGE_SIZE: usize = 0x028 / 4;
-const MMIO_QUEUE_SELECT: usize = 0x030 / 4;
-const MMIO_QUEUE_NUMBER_MAX: usize = 0x034 / 4;
-const MMIO_QUEUE_NUMBER: usize = 0x038 / 4;
-const MMIO_QUEUE_PFN: usize = 0x040 / 4;
-const MMIO_QUEUE_NOTIFY: usize = 0x050 / 4;
-const MMIO_STATUS: usize = 0x070 / 4;
-
-const VIRTIO_DESC_FLAG_NEXT: u16 = 1;
-const VIRTIO_DESC_FLAG_WRITE: u16 = 2;
+// The interrupt handler takes it too, so it is never held while sleeping
+static BLOCK_DEVICE: SpinLock<Option<BlockDevice>> = SpinLock::new(None);
+// Locked on its own, teardown frees into it after the device is taken out
+static REQUEST_CACHE: SpinLock<Slab<Request>> = SpinLock::new(Slab::new("block-request"));
+// Tasks sleeping until their request completes
+static mut WAITERS: WaitQueue = WaitQueue::new();
+static IO_STATS: SpinLock<IoStats> = SpinLock::new(IoStats {
+    reads: 0,
+    writes: 0,
+    flushes: 0,
+    bytes_read: 0,
+    bytes_written: 0,
+    errors: 0,
+});
 
 const VIRTIO_BLK_TYPE_IN: u32 = 0;
 const VIRTIO_BLK_TYPE_OUT: u32 = 1;
+const VIRTIO_BLK_TYPE_FLUSH: u32 = 4;
 
-const STATUS_FIELD_ACKNOWLEDGE: u32 = 1;
-const STATUS_FIELD_DRIVER_OK: u32 = 4;
-const STATUS_FIELD_FEATURES_OK: u32 = 8;
-const STATUS_FIELD_FAILED: u32 = 128;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+// Written into the status before submitting, the device overwrites it
+const STATUS_PENDING: u8 = 111;
 
-const VIRTIO_FEATURE_RO: u32 = 1 << 5;
-const VIRTIO_RING_SIZE: usize = 1 << 7;
+const VIRTIO_FEATURE_RO: u64 = 1 << 5;
+const VIRTIO_FEATURE_FLUSH: u64 = 1 << 9;
+const FEATURES: Features = Features::new(
+    VIRTIO_FEATURE_RO | VIRTIO_FEATURE_FLUSH | VIRTIO_RING_F_EVENT_IDX,
+    0,
+);
 
 const READ: bool = false;
 const WRITE: bool = true;
 
+pub const SECTOR_SIZE: u64 = 512;
+
+// The interface every block storage backend provides
+// Offsets and capacity are in bytes
+pub trait BlockDriver {
+    fn read(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError>;
+    fn write(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError>;
+    fn capacity(&self) -> u64;
+    fn flush(&mut self) -> Result<(), KError>;
+}
+
+// Requests of the default block device since boot, for iostat
+// Only those that completed are counted by kind, every failure as an error
+#[derive(Clone, Copy)]
+pub struct IoStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+}
+
 #[repr(C)]
 pub struct Header {
     blktype: u32,
@@ -64,239 +105,286 @@ pub struct Request {
     watcher: u16,
 }
 
-#[repr(C)]
-pub struct Descriptor {
-    pub addr: u64,
-    pub len: u32,
-    pub flags: u16,
-    pub next: u16,
-}
-
-#[repr(C)]
-pub struct Available {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [u16; VIRTIO_RING_SIZE],
-    pub event: u16,
-}
-
-#[repr(C)]
-pub struct UsedElem {
-    pub id: u32,
-    pub len: u32,
-}
-
-#[repr(C)]
-pub struct Used {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [UsedElem; VIRTIO_RING_SIZE],
-    pub event: u16,
-}
-
-#[repr(C)]
-pub struct Queue {
-    pub desc: [Descriptor; VIRTIO_RING_SIZE],
-    pub avail: Available,
-    pub padding0:
-        [u8; PAGE_SIZE - size_of::<Descriptor>() * VIRTIO_RING_SIZE - size_of::<Available>()],
-    pub used: Used,
-}
-
 pub struct BlockDevice {
-    queue: *mut Queue,
-    dev: *mut u32,
-    idx: u16,
-    ack_used_idx: u16,
+    queue: VirtQueue,
+    dev: MmioDevice,
     read_only: bool,
-    ready: [bool; VIRTIO_RING_SIZE],
+    // Set when a request timed out, cleared by reset()
+    wedged: bool,
+    can_flush: bool,
+    // Status the device gave the last request of each head descriptor, kept
+    // as the request itself is freed when it completes
+    status: [u8; VIRTIO_RING_SIZE],
 }
 
 impl BlockDevice {
-    unsafe fn init_status(ptr: *mut u32) -> u32 {
-        ptr.add(MMIO_STATUS).write_volatile(0);
-
-        let mut status_bits = STATUS_FIELD_ACKNOWLEDGE;
-        ptr.add(MMIO_STATUS).write_volatile(status_bits);
-
-        status_bits |= STATUS_FIELD_DRIVER_OK;
-        ptr.add(MMIO_STATUS).write_volatile(status_bits);
-        status_bits
+    fn init(ptr: *mut u32) -> Result<(), KError> {
+        log::info!("init block device");
+        let dev = MmioDevice::new(ptr)?;
+        dev.begin_init();
+        let guest_features = dev.negotiate(FEATURES)?;
+
+        let mut queue = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        if guest_features & VIRTIO_RING_F_EVENT_IDX != 0 {
+            queue.enable_event_idx();
+        }
+        dev.setup_queue(0, &queue)?;
+
+        *BLOCK_DEVICE.lock_irq() = Some(BlockDevice {
+            queue,
+            dev,
+            read_only: guest_features & VIRTIO_FEATURE_RO != 0,
+            wedged: false,
+            can_flush: guest_features & VIRTIO_FEATURE_FLUSH != 0,
+            status: [STATUS_PENDING; VIRTIO_RING_SIZE],
+        });
+        dev.driver_ok();
+        Ok(())
     }
 
-    unsafe fn init_guest_features(ptr: *mut u32) -> bool {
-        let host_features = ptr.add(MMIO_HOST_FEATURES).read_volatile();
-        let guest_features = host_features & !(VIRTIO_FEATURE_RO);
-        ptr.add(MMIO_GUEST_FEATURES).write_volatile(guest_features);
-        host_features & (VIRTIO_FEATURE_RO) != 0
+    unsafe fn use_queue(&mut self) {
+        self.dev.ack_interrupt();
+        self.reap();
     }
 
-    unsafe fn init_status_check(ptr: *mut u32, status_bits: u32) -> (bool, u32) {
-        let sb_out = status_bits | STATUS_FIELD_FEATURES_OK;
-        ptr.add(MMIO_STATUS).write_volatile(sb_out);
-
-        let status_ok = ptr.add(MMIO_STATUS).read_volatile();
-        if (status_ok & STATUS_FIELD_FEATURES_OK) == 0 {
-            print!("features fail...");
-            ptr.add(MMIO_STATUS).write_volatile(STATUS_FIELD_FAILED);
-            return (false, 0);
+    // Free the requests the device has completed and wake their waiters
+    unsafe fn reap(&mut self) {
+        let mut completed = 0;
+        while let Some((head, _len)) = self.queue.pop_used() {
+            let rq = self.queue.descriptor_address(head) as *mut Request;
+            self.status[head as usize] = (*rq).status.status;
+            REQUEST_CACHE.lock_irq().free(rq);
+            completed += 1;
         }
-        (true, sb_out)
-    }
-
-    unsafe fn init_queue_check(ptr: *mut u32) -> bool {
-        let qnmax = ptr.add(MMIO_QUEUE_NUMBER_MAX).read_volatile();
-        if VIRTIO_RING_SIZE > qnmax.try_into().unwrap() {
-            print!("queue size fail...");
-            return false;
+        if completed > 0 {
+            trace_event!(trace::BLOCK_COMPLETE, completed);
+            WAITERS.wake_all();
         }
-        ptr.add(MMIO_QUEUE_NUMBER)
-            .write_volatile(VIRTIO_RING_SIZE.try_into().unwrap());
-        ptr.add(MMIO_QUEUE_SELECT).write_volatile(0);
-        true
     }
 
-    unsafe fn init_pfn(ptr: *mut u32) -> *mut Queue {
-        let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-        let queue_ptr = alloc_pages_zeroed(num_pages) as *mut Queue;
-        let queue_pfn = queue_ptr as u32;
-        ptr.add(MMIO_GUEST_PAGE_SIZE)
-            .write_volatile(PAGE_SIZE.try_into().unwrap());
-        ptr.add(MMIO_QUEUE_PFN)
-            .write_volatile(queue_pfn / PAGE_SIZE as u32);
-        queue_ptr
+    // Whether the chain at head is complete, asking for an interrupt when it
+    // is not so the caller can sleep until it is
+    unsafe fn poll_complete(&mut self, head_idx: u16) -> bool {
+        if self.queue.is_complete(head_idx) {
+            return true;
+        }
+        self.queue.enable_interrupt();
+        // Anything used before the device saw the request raised no interrupt
+        self.reap();
+        self.queue.is_complete(head_idx)
     }
 
-    unsafe fn init_bd(ptr: *mut u32, queue_ptr: *mut Queue, ro: bool) {
-        let bd = BlockDevice {
-            queue: queue_ptr,
-            dev: ptr,
-            idx: 0,
-            ack_used_idx: 0,
-            read_only: ro,
-            ready: [true; VIRTIO_RING_SIZE],
-        };
-        BLOCK_DEVICE = Some(bd);
+    unsafe fn block_request(
+        &mut self,
+        buffer: *mut u8,
+        offset: u64,
+        blktype: u32,
+    ) -> Result<*mut Request, KError> {
+        let blk_request = REQUEST_CACHE.lock_irq().alloc();
+        if blk_request.is_null() {
+            return Err(KError::NoMemory);
+        }
+        (*blk_request).header.sector = offset / SECTOR_SIZE;
+        (*blk_request).header.blktype = blktype;
+        (*blk_request).data.data = buffer;
+        (*blk_request).header.reserved = 0;
+        (*blk_request).status.status = STATUS_PENDING;
+        Ok(blk_request)
     }
 
-    unsafe fn init_notify(ptr: *mut u32, status_bits: u32) -> bool {
-        ptr.add(MMIO_STATUS)
-            .write_volatile(status_bits | STATUS_FIELD_DRIVER_OK);
-        true
+    unsafe fn header_segment(blk_request: *mut Request) -> Segment {
+        Segment::readable(
+            &(*blk_request).header as *const Header as u64,
+            size_of::<Header>() as u32,
+        )
     }
 
-    fn init(ptr: *mut u32) -> bool {
-        serial_info("init block device");
-        unsafe {
-            let status_bits = BlockDevice::init_status(ptr);
-            let ro = BlockDevice::init_guest_features(ptr);
-
-            let (pass, status_bits) = BlockDevice::init_status_check(ptr, status_bits);
-            if !pass {
-                return false;
-            }
-
-            if !BlockDevice::init_queue_check(ptr) {
-                return false;
-            }
-
-            BlockDevice::init_bd(ptr, BlockDevice::init_pfn(ptr), ro);
-
-            BlockDevice::init_notify(ptr, status_bits)
-        }
+    unsafe fn status_segment(blk_request: *mut Request) -> Segment {
+        Segment::writable(
+            &(*blk_request).status as *const Status as u64,
+            size_of::<Status>() as u32,
+        )
     }
 
-    unsafe fn use_queue(&mut self) {
-        let queue = &(*self.queue);
-        while self.ack_used_idx != queue.used.idx {
-            let idx = self.ack_used_idx as usize % VIRTIO_RING_SIZE;
-            let elem = &queue.used.ring[idx];
-            self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
-            self.ready.as_mut_ptr().add(idx).write_volatile(true);
-            let rq = queue.desc[elem.id as usize].addr as *const Request;
-            free_bytes(rq as *mut u8);
+    // The submitter sleeps until the chain completes, so it wants an interrupt
+    unsafe fn block_notify(&mut self, head_idx: u16) {
+        self.queue.enable_interrupt();
+        self.queue.submit(head_idx);
+        irqlog::record(IrqSource::BlockSubmit);
+        trace_event!(trace::BLOCK_SUBMIT, head_idx);
+        if self.queue.kick_needed() {
+            self.dev.notify(0);
         }
     }
 
-    unsafe fn block_header(
+    // Submit a transfer, returns the chain to wait for
+    unsafe fn block_operation(
         &mut self,
         buffer: *mut u8,
+        size: u32,
         offset: u64,
         write: bool,
-    ) -> (*mut Request, u16) {
-        let sector = offset / 512;
-        let blk_request_size = size_of::<Request>();
-        let blk_request = alloc_bytes(blk_request_size) as *mut Request;
-        let desc = Descriptor {
-            addr: &(*blk_request).header as *const Header as u64,
-            len: size_of::<Header>() as u32,
-            flags: VIRTIO_DESC_FLAG_NEXT,
-            next: 0,
-        };
-        let head_idx = self.fill_next_descriptor(desc);
-        (*blk_request).header.sector = sector;
-        (*blk_request).header.blktype = if write {
+    ) -> Result<u16, KError> {
+        if self.wedged {
+            log::error!("Block device is wedged, reset it first");
+            return Err(KError::IoError);
+        }
+        if self.read_only && write {
+            log::warn!("Trying to write to read/only!");
+            return Err(KError::ReadOnly);
+        }
+        let blktype = if write {
             VIRTIO_BLK_TYPE_OUT
         } else {
             VIRTIO_BLK_TYPE_IN
         };
-        (*blk_request).data.data = buffer;
-        (*blk_request).header.reserved = 0;
-        (*blk_request).status.status = 111;
-        (blk_request, head_idx)
+        let addr = match dma_address(buffer, size as usize) {
+            Some(a) => a,
+            None => {
+                log::error!("Block buffer {:p} is not reachable by the device", buffer);
+                return Err(KError::InvalidArgument);
+            }
+        };
+        let blk_request = self.block_request(buffer, offset, blktype)?;
+        let data = if write {
+            Segment::readable(addr, size)
+        } else {
+            Segment::writable(addr, size)
+        };
+        let head_idx = self.queue.add_chain(&[
+            BlockDevice::header_segment(blk_request),
+            data,
+            BlockDevice::status_segment(blk_request),
+        ]);
+        log::trace!(
+            "block {} of {} bytes at 0x{:x}, chain {}",
+            if write { "write" } else { "read" },
+            size,
+            offset,
+            head_idx
+        );
+        self.block_notify(head_idx);
+        Ok(head_idx)
     }
 
-    unsafe fn block_data(&mut self, buffer: *mut u8, size: u32, write: bool) {
-        let desc = Descriptor {
-            addr: buffer as u64,
-            len: size,
-            flags: VIRTIO_DESC_FLAG_NEXT | if !write { VIRTIO_DESC_FLAG_WRITE } else { 0 },
-            next: 0,
-        };
-        let _data_idx = self.fill_next_descriptor(desc);
+    // Submit a flush, returns the chain to wait for
+    // None if the device has no write cache to flush
+    unsafe fn block_flush(&mut self) -> Result<Option<u16>, KError> {
+        if self.wedged {
+            return Err(KError::IoError);
+        }
+        if !self.can_flush {
+            return Ok(None);
+        }
+        let blk_request = self.block_request(core::ptr::null_mut(), 0, VIRTIO_BLK_TYPE_FLUSH)?;
+        let head_idx = self.queue.add_chain(&[
+            BlockDevice::header_segment(blk_request),
+            BlockDevice::status_segment(blk_request),
+        ]);
+        self.block_notify(head_idx);
+        Ok(Some(head_idx))
     }
 
-    unsafe fn block_status(&mut self, blk_request: *mut Request) {
-        let desc = Descriptor {
-            addr: &(*blk_request).status as *const Status as u64,
-            len: size_of::<Status>() as u32,
-            flags: VIRTIO_DESC_FLAG_WRITE,
-            next: 0,
-        };
-        let _status_idx = self.fill_next_descriptor(desc);
+    // The virtio block config space starts with the capacity in 512 byte sectors
+    fn capacity(&self) -> u64 {
+        let low = self.dev.config_read(0) as u64;
+        let high = self.dev.config_read(4) as u64;
+        ((high << 32) | low) * SECTOR_SIZE
     }
 
-    unsafe fn block_notify(&mut self, head_idx: u16) -> usize {
-        let idx = (*self.queue).avail.idx as usize % VIRTIO_RING_SIZE;
-        (*self.queue).avail.ring[idx] = head_idx;
-        (*self.queue).avail.idx = (*self.queue).avail.idx.wrapping_add(1);
-        self.ready.as_mut_ptr().add(idx).write_volatile(false);
-        self.dev.add(MMIO_QUEUE_NOTIFY).write_volatile(0);
-        idx
+    // Stop the device and free the requests still in flight
+    unsafe fn teardown(&self) {
+        self.dev.reset();
+        for head in self.queue.pending() {
+            let rq = self.queue.descriptor_address(head) as *mut Request;
+            REQUEST_CACHE.lock_irq().free(rq);
+        }
     }
 
-    unsafe fn block_operation(&mut self, buffer: *mut u8, size: u32, offset: u64, write: bool) {
-        if self.read_only && write {
-            println!("Trying to write to read/only!");
-            return;
+    // Abandon everything in flight and bring the device up again
+    unsafe fn reset(self) -> Result<(), KError> {
+        self.teardown();
+        virtio::init_with_retry(self.dev.ptr(), init)
+    }
+}
+
+// Sleep until the device has used a chain, giving up after BLOCK_TIMEOUT_MS
+// The device stays unlocked meanwhile so the interrupt handler can complete it
+fn block_wait(head_idx: u16) -> Result<(), KError> {
+    let start = time::monotonic();
+    let deadline = time::deadline(Duration::from_millis(BLOCK_TIMEOUT_MS));
+    // Trips only if the wait outlives its own timeout by far
+    let _watch = watchdog::start("block wait", Duration::from_millis(BLOCK_WATCHDOG_MS));
+    let complete = || {
+        BLOCK_DEVICE
+            .lock_irq()
+            .as_mut()
+            .is_some_and(|bdev| unsafe { bdev.poll_complete(head_idx) })
+    };
+    // The device writes into the caller's buffer, a kill waits until it is done
+    let completed =
+        process::with_signals_held(|| unsafe { WAITERS.wait_until(Some(deadline), complete) });
+    if !completed {
+        log::error!(
+            "Block request timed out after {:?}, device needs a reset",
+            time::since(start)
+        );
+        if let Some(bdev) = BLOCK_DEVICE.lock_irq().as_mut() {
+            bdev.wedged = true;
         }
-        let (blk_request, head_idx) = self.block_header(buffer, offset, write);
-        self.block_data(buffer, size, write);
-        self.block_status(blk_request);
-        let idx = self.block_notify(head_idx);
-        while !self.ready.as_ptr().add(idx).read_volatile() {
-            assembly::no_operation();
+        return Err(KError::TimedOut);
+    }
+    let status = BLOCK_DEVICE
+        .lock_irq()
+        .as_ref()
+        .map_or(VIRTIO_BLK_S_IOERR, |bdev| bdev.status[head_idx as usize]);
+    request_result(status)
+}
+
+// What the status the device wrote into a request means for its caller
+fn request_result(status: u8) -> Result<(), KError> {
+    match status {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_UNSUPP => Err(KError::Unsupported),
+        _ => {
+            log::error!("Block request failed with status {}", status);
+            Err(KError::IoError)
         }
     }
+}
 
-    unsafe fn fill_next_descriptor(&mut self, desc: Descriptor) -> u16 {
-        self.idx = (self.idx + 1) % VIRTIO_RING_SIZE as u16;
-        (*self.queue).desc[self.idx as usize] = desc;
-        if (*self.queue).desc[self.idx as usize].flags & VIRTIO_DESC_FLAG_NEXT != 0 {
-            (*self.queue).desc[self.idx as usize].next = (self.idx + 1) % VIRTIO_RING_SIZE as u16;
+// Count the outcome of a request, bytes is 0 for a flush
+fn account(result: Result<(), KError>, blktype: u32, bytes: u32) -> Result<(), KError> {
+    let mut stats = IO_STATS.lock_irq();
+    match (result, blktype) {
+        (Err(_), _) => stats.errors += 1,
+        (Ok(()), VIRTIO_BLK_TYPE_IN) => {
+            stats.reads += 1;
+            stats.bytes_read += bytes as u64;
+        }
+        (Ok(()), VIRTIO_BLK_TYPE_OUT) => {
+            stats.writes += 1;
+            stats.bytes_written += bytes as u64;
         }
-        self.idx
+        (Ok(()), _) => stats.flushes += 1,
     }
+    result
+}
+
+fn transfer(buffer: *mut u8, size: u32, offset: u64, write: bool) -> Result<(), KError> {
+    let submitted = match BLOCK_DEVICE.lock_irq().as_mut() {
+        Some(bdev) => unsafe { bdev.block_operation(buffer, size, offset, write) },
+        None => {
+            log::error!("Unable to retrieve default block device");
+            return Err(KError::NoDevice);
+        }
+    };
+    let blktype = if write {
+        VIRTIO_BLK_TYPE_OUT
+    } else {
+        VIRTIO_BLK_TYPE_IN
+    };
+    account(submitted.and_then(block_wait), blktype, size)
 }
 
 // ====================================================
@@ -307,43 +395,137 @@ impl BlockDevice {
 // and interrupt API. It is called by virtio::init() when
 // initializing the default block device
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
-pub fn init(ptr: *mut u32) -> bool {
+pub fn init(ptr: *mut u32) -> Result<(), KError> {
     BlockDevice::init(ptr)
 }
 
+// Called by virtio::rescan() when the block device has gone away
+pub fn remove() {
+    let bdev = BLOCK_DEVICE.lock_irq().take();
+    if let Some(bdev) = bdev {
+        unsafe { bdev.teardown() };
+    }
+}
+
 // The block device specific logic for virtio interrupt handling
 // Called from virtio::interrupt_handler() for device 8
 // which is the default block device interrupt
 pub fn interrupt_handler() {
-    unsafe {
-        if let Some(bdev) = BLOCK_DEVICE.as_mut() {
-            bdev.use_queue();
-        } else {
-            println!("Unable to retrieve default block device");
-        }
+    if let Some(bdev) = BLOCK_DEVICE.lock_irq().as_mut() {
+        unsafe { bdev.use_queue() };
+    } else {
+        log::error!("Unable to retrieve default block device");
     }
 }
 
 // Read data from disk device to buffer
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
-pub fn read(buffer: *mut u8, size: u32, offset: u64) {
-    unsafe {
-        if let Some(bdev) = BLOCK_DEVICE.as_mut() {
-            bdev.block_operation(buffer, size, offset, READ);
-        } else {
-            println!("Unable to retrieve default block device");
-        }
-    }
+pub fn read(buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+    transfer(buffer, size, offset, READ)
 }
 
 // Write data from buffer to disk device
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
-pub fn write(buffer: *mut u8, size: u32, offset: u64) {
-    unsafe {
-        if let Some(bdev) = BLOCK_DEVICE.as_mut() {
-            bdev.block_operation(buffer, size, offset, WRITE);
-        } else {
-            println!("Unable to retrieve default block device");
+pub fn write(buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+    transfer(buffer, size, offset, WRITE)
+}
+
+// Capacity of the default block device in bytes
+pub fn capacity() -> Result<u64, KError> {
+    if let Some(bdev) = BLOCK_DEVICE.lock_irq().as_ref() {
+        Ok(bdev.capacity())
+    } else {
+        log::error!("Unable to retrieve default block device");
+        Err(KError::NoDevice)
+    }
+}
+
+// Ask the default block device to persist any cached writes
+// Succeeds at once if the device has no write cache
+pub fn flush() -> Result<(), KError> {
+    let submitted = match BLOCK_DEVICE.lock_irq().as_mut() {
+        Some(bdev) => unsafe { bdev.block_flush() },
+        None => {
+            log::error!("Unable to retrieve default block device");
+            return Err(KError::NoDevice);
         }
+    };
+    let result = submitted.and_then(|head_idx| match head_idx {
+        Some(head_idx) => block_wait(head_idx),
+        None => Ok(()),
+    });
+    account(result, VIRTIO_BLK_TYPE_FLUSH, 0)
+}
+
+// True once the default block device has been initialized
+pub fn is_present() -> bool {
+    BLOCK_DEVICE.lock_irq().is_some()
+}
+
+// True if a request timed out and the device has not been reset since
+#[allow(dead_code)]
+pub fn is_wedged() -> bool {
+    BLOCK_DEVICE
+        .lock_irq()
+        .as_ref()
+        .is_some_and(|bdev| bdev.wedged)
+}
+
+// Reset the block device and initialize it again
+// Requests still in flight are dropped, fails if the device stays down
+#[allow(dead_code)]
+pub fn reset() -> Result<(), KError> {
+    // Taken out first, initializing the device again locks it
+    let bdev = BLOCK_DEVICE.lock_irq().take();
+    if let Some(bdev) = bdev {
+        unsafe { bdev.reset() }
+    } else {
+        log::error!("Unable to retrieve default block device");
+        Err(KError::NoDevice)
+    }
+}
+
+// The default block device as a BlockDriver
+pub struct VirtioBlock;
+
+impl BlockDriver for VirtioBlock {
+    fn read(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+        read(buffer, size, offset)
     }
+
+    fn write(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+        write(buffer, size, offset)
+    }
+
+    fn capacity(&self) -> u64 {
+        capacity().unwrap_or(0)
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        flush()
+    }
+}
+
+// Copy the start of from into to, as much as both hold, and flush to
+// Returns the bytes copied
+pub fn copy(from: &mut impl BlockDriver, to: &mut impl BlockDriver) -> Result<u64, KError> {
+    let mut buffer = buffer::block_buffer();
+    let size = from.capacity().min(to.capacity());
+    for offset in (0..size).step_by(BLOCK_SIZE as usize) {
+        let len = (size - offset).min(BLOCK_SIZE as u64) as u32;
+        from.read(buffer.get_mut(), len, offset)?;
+        to.write(buffer.get_mut(), len, offset)?;
+    }
+    to.flush()?;
+    Ok(size)
+}
+
+// Requests the default block device has served since boot
+pub fn io_stats() -> IoStats {
+    *IO_STATS.lock_irq()
+}
+
+// Usage of the cache block requests are allocated from
+pub fn request_cache_stats() -> SlabStats {
+    REQUEST_CACHE.lock_irq().stats()
 }
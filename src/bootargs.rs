@@ -0,0 +1,152 @@
+use crate::console::{self, ConsoleBackend};
+use crate::fdt;
+use crate::log::{self, Level};
+
+// mod bootargs.rs
+// The kernel command line, /chosen/bootargs in the device tree, which QEMU
+// fills from -append, e.g. cargo run -- -append "loglevel=debug test=fdt"
+// Options are space separated key=value pairs, a key alone means key=1
+//   loglevel=error|warn|info|debug|trace   runtime log level
+//   console=uart|virtio|fb                 console backend
+//   plain=0|1                              console without colours
+//   root=vda|host|none                     what vfs::init mounts at /
+//   test=on|off|<name>                     run the test suite, or only the
+//                                          tests whose name contains <name>
+// They override config.rs for one boot. init() parses them into the store
+// below without allocating, so the log level applies from early boot, the
+// other modules consult it when they start
+
+// What vfs::init mounts at /
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Root {
+    // The Minix file system on the virtio block device
+    Block,
+    // The 9p share of the host
+    Host,
+    Nothing,
+}
+
+// Which tests run with --features "test-suite"
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tests {
+    All,
+    Off,
+    Matching(&'static str),
+}
+
+// The options given, None where config.rs decides
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BootArgs {
+    pub log_level: Option<Level>,
+    pub console: Option<ConsoleBackend>,
+    pub plain: Option<bool>,
+    pub root: Option<Root>,
+    pub tests: Option<Tests>,
+}
+
+impl BootArgs {
+    const fn new() -> Self {
+        Self {
+            log_level: None,
+            console: None,
+            plain: None,
+            root: None,
+            tests: None,
+        }
+    }
+}
+
+static mut ARGS: BootArgs = BootArgs::new();
+
+fn level(value: &str) -> Option<Level> {
+    match value {
+        "error" | "1" => Some(Level::Error),
+        "warn" | "2" => Some(Level::Warn),
+        "info" | "3" => Some(Level::Info),
+        "debug" | "4" => Some(Level::Debug),
+        "trace" | "5" => Some(Level::Trace),
+        _ => None,
+    }
+}
+
+fn backend(value: &str) -> Option<ConsoleBackend> {
+    match value {
+        "uart" => Some(ConsoleBackend::Uart),
+        "virtio" => Some(ConsoleBackend::Virtio),
+        "fb" => Some(ConsoleBackend::Framebuffer),
+        _ => None,
+    }
+}
+
+fn flag(value: &str) -> Option<bool> {
+    match value {
+        "1" | "on" => Some(true),
+        "0" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn root(value: &str) -> Option<Root> {
+    match value {
+        "vda" => Some(Root::Block),
+        "host" => Some(Root::Host),
+        "none" => Some(Root::Nothing),
+        _ => None,
+    }
+}
+
+fn tests(value: &'static str) -> Option<Tests> {
+    match value {
+        "1" | "on" => Some(Tests::All),
+        "0" | "off" => Some(Tests::Off),
+        "" => None,
+        name => Some(Tests::Matching(name)),
+    }
+}
+
+// ====================================================
+// The public interface for bootargs is here...
+// ====================================================
+
+// Read /chosen/bootargs and apply the log level and plain console right away
+// Runs after fdt::init, before the allocators
+pub fn init() {
+    let Some(line) = fdt::chosen("bootargs").and_then(|p| p.strings().next()) else {
+        return;
+    };
+    let args = parse(line);
+    unsafe { ARGS = args };
+    if let Some(level) = args.log_level {
+        log::set_level(level);
+    }
+    if let Some(plain) = args.plain {
+        console::set_plain(plain);
+    }
+    log::info!("bootargs: {}", line);
+}
+
+// The options in line, the last of a repeated key wins. Unknown keys and
+// values are logged and left out
+pub fn parse(line: &'static str) -> BootArgs {
+    let mut args = BootArgs::new();
+    for option in line.split_whitespace() {
+        let (key, value) = option.split_once('=').unwrap_or((option, "1"));
+        let known = match key {
+            "loglevel" => level(value).map(|v| args.log_level = Some(v)),
+            "console" => backend(value).map(|v| args.console = Some(v)),
+            "plain" => flag(value).map(|v| args.plain = Some(v)),
+            "root" => root(value).map(|v| args.root = Some(v)),
+            "test" => tests(value).map(|v| args.tests = Some(v)),
+            _ => None,
+        };
+        if known.is_none() {
+            log::warn!("ignoring bootarg {}={}", key, value);
+        }
+    }
+    args
+}
+
+// The options this boot was given
+pub fn args() -> BootArgs {
+    unsafe { ARGS }
+}
@@ -1,13 +1,28 @@
-use crate::alloc::{alloc_bytes, free_bytes};
+use crate::alloc::{alloc_bytes, alloc_bytes_zeroed, free_bytes, try_alloc_bytes};
+use crate::config::BLOCK_BUFFER_POOL;
+use crate::error::KError;
 use crate::memory::memcpy;
 use crate::minixfs3::BLOCK_SIZE;
+use crate::slab::{Slab, SlabStats};
+use crate::sync::SpinLock;
 use crate::{print, println};
 use core::{
-    ops::{Index, IndexMut},
+    ops::{Deref, DerefMut, Index, IndexMut},
     ptr::null_mut,
+    slice,
 };
+use rust_alloc::vec::Vec;
 
 // Buffer memory collection module
+// Buffer is a fixed size allocation read and written through slices, get()
+// and get_mut() hand its memory to the drivers. BLOCK_SIZE buffers, by far the
+// most common, come from a slab cache and every other size from the byte heap
+// DynBuffer grows as bytes are appended and BufferPool keeps buffers of one
+// size around so hot paths do not allocate and free one per call
+
+static BLOCK_BUFFERS: BufferPool = BufferPool::new(BLOCK_SIZE as usize, BLOCK_BUFFER_POOL);
+static BLOCK_CACHE: SpinLock<Slab<[u8; BLOCK_SIZE as usize]>> =
+    SpinLock::new(Slab::new("block-buffer"));
 
 pub struct Buffer {
     buffer: *mut u8,
@@ -16,10 +31,38 @@ pub struct Buffer {
 
 impl Buffer {
     pub fn new(sz: usize) -> Self {
-        Self {
-            buffer: alloc_bytes(sz),
-            len: sz,
+        let buffer = if sz == BLOCK_SIZE as usize {
+            BLOCK_CACHE.lock_irq().alloc() as *mut u8
+        } else {
+            alloc_bytes(sz)
+        };
+        Self { buffer, len: sz }
+    }
+
+    // A buffer of sz bytes that reads as zeros
+    pub fn new_zeroed(sz: usize) -> Self {
+        let buffer = if sz == BLOCK_SIZE as usize {
+            BLOCK_CACHE.lock_irq().alloc_zeroed() as *mut u8
+        } else {
+            alloc_bytes_zeroed(sz)
+        };
+        Self { buffer, len: sz }
+    }
+
+    // A buffer of sz bytes, or an error when memory is too tight to provide one
+    #[allow(dead_code)]
+    pub fn try_new(sz: usize) -> Result<Self, KError> {
+        if sz == BLOCK_SIZE as usize {
+            let buffer = Self::new(sz);
+            return match buffer.buffer.is_null() {
+                true => Err(KError::NoMemory),
+                false => Ok(buffer),
+            };
         }
+        Ok(Self {
+            buffer: try_alloc_bytes(sz)?.as_ptr(),
+            len: sz,
+        })
     }
 
     pub fn get_mut(&mut self) -> *mut u8 {
@@ -34,11 +77,39 @@ impl Buffer {
         self.len
     }
 
+    // The contents, empty if the allocation failed
+    pub fn as_slice(&self) -> &[u8] {
+        if self.buffer.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.buffer, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.buffer.is_null() {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.buffer, self.len) }
+    }
+
+    // Copy bytes in at offset, InvalidArgument if they do not fit
+    pub fn copy_from_slice(&mut self, offset: usize, bytes: &[u8]) -> Result<(), KError> {
+        let end = offset
+            .checked_add(bytes.len())
+            .ok_or(KError::InvalidArgument)?;
+        let dest = self
+            .as_mut_slice()
+            .get_mut(offset..end)
+            .ok_or(KError::InvalidArgument)?;
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn print(&self) {
         println!("len: {}", self.len);
-        for i in 0..self.len {
-            print!("{}", unsafe { self.buffer.add(i).read() } as char);
+        for &byte in self.as_slice() {
+            print!("{}", byte as char);
         }
         println!();
     }
@@ -46,16 +117,16 @@ impl Buffer {
     #[allow(dead_code)]
     pub fn print_hex(&self) {
         println!("len: {}", self.len);
-        for i in 0..self.len {
-            print!("{:02x} ", unsafe { self.buffer.add(i).read() });
+        for byte in self.as_slice() {
+            print!("{:02x} ", byte);
         }
         println!();
     }
     #[allow(dead_code)]
     pub fn print_binary(&self) {
         println!("len: {}", self.len);
-        for i in 0..self.len {
-            print!("{:08b} ", unsafe { self.buffer.add(i).read() });
+        for byte in self.as_slice() {
+            print!("{:08b} ", byte);
         }
         println!();
     }
@@ -70,22 +141,19 @@ impl Default for Buffer {
 impl Index<usize> for Buffer {
     type Output = u8;
     fn index(&self, idx: usize) -> &Self::Output {
-        unsafe { self.get().add(idx).as_ref().unwrap() }
+        &self.as_slice()[idx]
     }
 }
 
 impl IndexMut<usize> for Buffer {
     fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
-        unsafe { self.get_mut().add(idx).as_mut().unwrap() }
+        &mut self.as_mut_slice()[idx]
     }
 }
 
 impl Clone for Buffer {
     fn clone(&self) -> Self {
-        let mut new = Self {
-            buffer: alloc_bytes(self.len()),
-            len: self.len(),
-        };
+        let mut new = Self::new(self.len());
         unsafe {
             memcpy(new.get_mut(), self.get(), self.len());
         }
@@ -95,9 +163,162 @@ impl Clone for Buffer {
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        if !self.buffer.is_null() {
+        if self.buffer.is_null() {
+            return;
+        }
+        if self.len == BLOCK_SIZE as usize {
+            BLOCK_CACHE
+                .lock_irq()
+                .free(self.buffer as *mut [u8; BLOCK_SIZE as usize]);
+        } else {
             free_bytes(self.buffer);
-            self.buffer = null_mut();
         }
+        self.buffer = null_mut();
     }
 }
+
+// A Buffer that grows as bytes are appended, doubling its allocation
+pub struct DynBuffer {
+    buffer: Buffer,
+    len: usize,
+}
+
+impl DynBuffer {
+    pub fn new() -> Self {
+        Self {
+            buffer: Buffer {
+                buffer: null_mut(),
+                len: 0,
+            },
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Make room for at least additional more bytes
+    pub fn reserve(&mut self, additional: usize) -> Result<(), KError> {
+        let needed = self.len.checked_add(additional).ok_or(KError::NoMemory)?;
+        if needed <= self.capacity() {
+            return Ok(());
+        }
+        let mut grown = Buffer::try_new(needed.max(2 * self.capacity()))?;
+        grown.as_mut_slice()[..self.len].copy_from_slice(self.as_slice());
+        self.buffer = grown;
+        Ok(())
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) -> Result<(), KError> {
+        self.reserve(bytes.len())?;
+        self.buffer.copy_from_slice(self.len, bytes)?;
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer.as_slice()[..self.len]
+    }
+
+    #[allow(dead_code)]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = self.len;
+        &mut self.buffer.as_mut_slice()[..len]
+    }
+}
+
+// Buffers of one size kept for reuse, up to limit of them
+// Taken buffers go back to the pool when dropped, their old contents intact
+pub struct BufferPool {
+    size: usize,
+    limit: usize,
+    free: SpinLock<Vec<Buffer>>,
+}
+
+impl BufferPool {
+    pub const fn new(size: usize, limit: usize) -> Self {
+        Self {
+            size,
+            limit,
+            free: SpinLock::new(Vec::new()),
+        }
+    }
+
+    // A buffer from the pool, or a new one if the pool is empty
+    pub fn take(&self) -> PooledBuffer<'_> {
+        let buffer = self.free.lock().pop();
+        PooledBuffer {
+            buffer: Some(buffer.unwrap_or_else(|| Buffer::new(self.size))),
+            pool: self,
+        }
+    }
+
+    fn put(&self, buffer: Buffer) {
+        let mut free = self.free.lock();
+        if !buffer.buffer.is_null() && free.len() < self.limit {
+            free.push(buffer);
+        }
+    }
+
+    // Buffers waiting in the pool
+    pub fn available(&self) -> usize {
+        self.free.lock().len()
+    }
+}
+
+// A buffer on loan from a BufferPool
+pub struct PooledBuffer<'a> {
+    buffer: Option<Buffer>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Buffer;
+    fn deref(&self) -> &Buffer {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Buffer {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.put(buffer);
+        }
+    }
+}
+
+// ====================================================
+// The public interface for buffers is here...
+// ====================================================
+
+// A BLOCK_SIZE buffer from the shared pool, its contents are stale
+pub fn block_buffer() -> PooledBuffer<'static> {
+    BLOCK_BUFFERS.take()
+}
+
+// Block buffers waiting in the shared pool
+#[allow(dead_code)]
+pub fn block_buffers_available() -> usize {
+    BLOCK_BUFFERS.available()
+}
+
+// Usage of the cache BLOCK_SIZE buffers are allocated from
+pub fn block_cache_stats() -> SlabStats {
+    BLOCK_CACHE.lock_irq().stats()
+}
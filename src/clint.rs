@@ -0,0 +1,149 @@
+#[cfg(feature = "supervisor")]
+use crate::assembly;
+use crate::config::{BOARD, MAX_HARTS};
+use crate::fdt;
+use crate::ipi;
+use crate::log;
+#[cfg(feature = "sbi")]
+use crate::sbi;
+use crate::smp;
+use crate::{print, println};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// mod clint.rs
+// The core local interruptor
+// Owns the per hart software interrupt (MSIP) and timer compare registers and
+// the shared mtime counter. Software interrupts are used as inter processor
+// interrupts, they deliver the messages of src/ipi.rs and are otherwise passed
+// to the handler registered here
+// In supervisor mode the machine mode stub programs mtimecmp and forwards
+// both interrupts, see src/asm/supervisor.S
+// The base comes from the device tree, the assembly that runs before it is
+// parsed (src/asm/boot.S, src/asm/supervisor.S) keeps QEMU virt's address
+// Under OpenSBI the CLINT belongs to the firmware, the time CSR stands in for
+// mtime and the timer and IPIs are SBI calls, see src/sbi.rs
+
+// Offsets from the base of the CLINT
+#[cfg_attr(feature = "sbi", allow(dead_code))]
+const CLINT_MSIP: usize = 0x0000;
+// Only machine mode may write mtimecmp, see src/asm/supervisor.S
+#[cfg_attr(feature = "supervisor", allow(dead_code))]
+const CLINT_MTIMECMP: usize = 0x4000;
+#[cfg_attr(feature = "sbi", allow(dead_code))]
+const CLINT_MTIME: usize = 0xbff8;
+// Device tree compatible strings of the CLINT
+const COMPATIBLE: [&str; 2] = ["riscv,clint0", "sifive,clint0"];
+
+// Register window of the CLINT, start and size
+static BASE: AtomicUsize = AtomicUsize::new(BOARD.clint.0);
+static SIZE: AtomicUsize = AtomicUsize::new(BOARD.clint.1);
+
+static mut IPI_HANDLER: Option<fn(usize)> = None;
+
+fn base() -> usize {
+    BASE.load(Ordering::Relaxed)
+}
+
+#[cfg_attr(feature = "sbi", allow(dead_code))]
+fn msip(hart: usize) -> *mut u32 {
+    assert!(hart < MAX_HARTS);
+    (base() + CLINT_MSIP + hart * 4) as *mut u32
+}
+
+// ====================================================
+// The public interface for the clint is here...
+// ====================================================
+
+// Find the CLINT in the device tree, before the timer and other harts start
+pub fn init() {
+    log::info!("init clint");
+    match fdt::find_reg(&COMPATIBLE) {
+        Some((base, size)) => {
+            BASE.store(base, Ordering::Relaxed);
+            SIZE.store(size, Ordering::Relaxed);
+        }
+        None => log::warn!("no clint in the device tree, using 0x{:x}", base()),
+    }
+}
+
+// Start and end of the CLINT registers, for the kernel mapping
+pub fn window() -> (usize, usize) {
+    (base(), base() + SIZE.load(Ordering::Relaxed))
+}
+
+// Current value of the machine timer
+#[cfg(not(feature = "sbi"))]
+pub fn mtime() -> u64 {
+    unsafe { ((base() + CLINT_MTIME) as *const u64).read_volatile() }
+}
+
+#[cfg(feature = "sbi")]
+pub fn mtime() -> u64 {
+    assembly::read_time()
+}
+
+// Raise the next timer interrupt on hart once mtime reaches when
+#[cfg(not(feature = "supervisor"))]
+pub fn set_mtimecmp(hart: usize, when: u64) {
+    assert!(hart < MAX_HARTS);
+    unsafe { ((base() + CLINT_MTIMECMP + hart * 8) as *mut u64).write_volatile(when) };
+}
+
+// The stub can only program the calling hart's compare register
+#[cfg(all(feature = "supervisor", not(feature = "sbi")))]
+pub fn set_mtimecmp(hart: usize, when: u64) {
+    assert!(hart < MAX_HARTS);
+    assembly::machine_set_timer(when);
+}
+
+// The firmware too can only program the calling hart's timer
+#[cfg(feature = "sbi")]
+pub fn set_mtimecmp(hart: usize, when: u64) {
+    assert!(hart < MAX_HARTS);
+    sbi::set_timer(when);
+}
+
+// Raise a software interrupt on hart
+#[cfg(not(feature = "sbi"))]
+pub fn send_ipi(hart: usize) {
+    unsafe { msip(hart).write_volatile(1) };
+}
+
+#[cfg(feature = "sbi")]
+pub fn send_ipi(hart: usize) {
+    assert!(hart < MAX_HARTS);
+    sbi::send_ipi(hart);
+}
+
+#[cfg(not(feature = "sbi"))]
+pub fn clear_ipi(hart: usize) {
+    unsafe { msip(hart).write_volatile(0) };
+}
+
+// The firmware acknowledged it in the CLINT before passing it on
+#[cfg(feature = "sbi")]
+pub fn clear_ipi(_hart: usize) {}
+
+// Handler called with the receiving hart for every software interrupt
+#[allow(dead_code)]
+pub fn set_ipi_handler(handler: Option<fn(usize)>) {
+    unsafe { IPI_HANDLER = handler };
+}
+
+// Called from the trap handler on a machine software interrupt
+pub fn interrupt_handler(hart: usize) {
+    clear_ipi(hart);
+    #[cfg(feature = "supervisor")]
+    assembly::clear_software_pending();
+    if ipi::receive(hart) {
+        return;
+    }
+    // A parked hart is only interrupted to wake it
+    if !smp::is_online(hart) {
+        return;
+    }
+    match unsafe { IPI_HANDLER } {
+        Some(handler) => handler(hart),
+        None => println!("Unhandled IPI on CPU#{}", hart),
+    }
+}
@@ -1,10 +1,118 @@
+use crate::console::ConsoleBackend;
+use crate::log::Level;
+use crate::platform::{Board, QEMU_VIRT};
+
 // mod config.rs
 // A module centralizing all project configuration
 
 // Main Configuration
 pub const VERSION: &str = "v0.2.0";
-pub const PLATFORM: &str = "RISCV-64 QEMU Virt";
+// The machine the kernel is built for, see platform.rs
+pub const BOARD: Board = QEMU_VIRT;
 pub const PAGE_SIZE: usize = 0x1000;
+pub const SLAB_PAGES: usize = 1;
+// Block sized buffers kept around for reuse by buffer::block_buffer()
+pub const BLOCK_BUFFER_POOL: usize = 8;
+pub const USER_BASE: usize = 0x1_0000_0000;
+pub const USER_END: usize = 0x40_0000_0000;
+// Where the program break of a user address space starts and how far it may grow
+pub const USER_HEAP_START: usize = 0x2_0000_0000;
+pub const USER_HEAP_SIZE: usize = 0x1000_0000;
+pub const RAM_DISK_PAGES: usize = 256;
+pub const MAX_HARTS: usize = 4;
+// How long the boot hart waits for a secondary hart to come online
+pub const HART_START_TIMEOUT_MS: u64 = 100;
+// How long an inter processor call waits for the other hart to answer
+pub const IPI_TIMEOUT_MS: u64 = 100;
+// Per hart stack traps run on, nested traps stay on it
+pub const IRQ_STACK_SIZE: usize = 0x4000;
+pub const MAX_IRQ_NESTING: usize = 4;
+// Kernel stack of each spawned task
+pub const TASK_STACK_PAGES: usize = 4;
+// Timer ticks a task at nice 0 runs before it should yield
+pub const TIMESLICE_TICKS: u64 = 10;
+// Written to the bottom of the boot stack by boot.S
+pub const STACK_CANARY: u64 = 0x5ca1_ab1e_c0ff_ee00;
+// Highest address (exclusive) devices are handed for DMA
+pub const DMA_LIMIT: usize = 0x1_0000_0000;
+
+// Console Configuration
+pub const CONSOLE: ConsoleBackend = ConsoleBackend::Uart;
+// Show the uart output on the framebuffer text console too
+pub const CONSOLE_MIRROR: bool = false;
+pub const VCONSOLE_BUFFER_SIZE: usize = 256;
+pub const VCONSOLE_RX_BUFFERS: usize = 4;
+// Base and PLIC source of a second uart, for logs or a debugger
+pub const UART_AUX: Option<(usize, u32)> = None;
+pub const UART_RX_BUFFER_SIZE: usize = 256;
+
+// Log Configuration
+// Messages above LOG_LEVEL_MAX are compiled out, LOG_LEVEL is where the
+// runtime level starts
+pub const LOG_LEVEL_MAX: Level = Level::Trace;
+pub const LOG_LEVEL: Level = Level::Info;
+// Prefix log lines with seconds.millis since boot and the emitting hart
+pub const LOG_UPTIME: bool = true;
+pub const LOG_HART: bool = true;
+// Send log messages to the aux serial port instead of the console if there is one
+pub const LOG_TO_AUX: bool = false;
+// Lines kept for recall with the up arrow
+pub const CONSOLE_HISTORY: usize = 16;
+
+// Test Configuration
+// QEMU exits with this when a test fails under --features "test-suite", and
+// with 0 once the suite has passed and the kernel shuts down
+#[cfg_attr(not(feature = "test-suite"), allow(dead_code))]
+pub const TEST_FAILURE_EXIT_CODE: u16 = 1;
+// Only tests whose name contains this run, "" runs them all
+#[cfg_attr(not(feature = "test-suite"), allow(dead_code))]
+pub const TEST_FILTER: &str = "";
+
+// Trace Configuration
+// trace_event! compiles to nothing when off
+pub const TRACE_EVENTS: bool = true;
+// Events kept, the oldest are overwritten
+pub const TRACE_BUFFER_SIZE: usize = 1024;
+
+// GDB Stub Configuration (--features "gdbstub")
+// Software breakpoints the debugger can have inserted at once
+#[cfg_attr(not(feature = "gdbstub"), allow(dead_code))]
+pub const GDB_BREAKPOINTS: usize = 16;
+// Largest packet exchanged, a register dump takes 528 bytes
+#[cfg_attr(not(feature = "gdbstub"), allow(dead_code))]
+pub const GDB_PACKET_SIZE: usize = 1024;
+// Stop in the debugger before the kernel starts its work
+#[cfg_attr(not(feature = "gdbstub"), allow(dead_code))]
+pub const GDB_BREAK_AT_BOOT: bool = true;
+
+// Input Configuration
+pub const INPUT_QUEUE_SIZE: usize = 64;
+pub const INPUT_EVENT_BUFFERS: usize = 32;
+
+// Display Configuration (used when the gpu reports no enabled scanout)
+pub const SCREEN_WIDTH: u32 = 640;
+pub const SCREEN_HEIGHT: u32 = 480;
+
+// Platform Timer Configuration
+pub const TIMER_INTERVAL_MS: u64 = 10;
+pub const TIMER_CALLBACKS: usize = 16;
+// Watches that can be armed at once, see src/watchdog.rs
+pub const WATCHDOG_SLOTS: usize = 8;
+pub const IRQ_LOG_SIZE: usize = 256;
+// Interrupt sources of the board's PLIC, source 0 is reserved, this caps the
+// tables sized by source
+pub const PLIC_SOURCES: usize = BOARD.plic_sources;
+pub const VIRTIO_INIT_RETRIES: usize = 2;
+pub const BLOCK_TIMEOUT_MS: u64 = 1000;
+// The watchdog on a block wait is the backstop for BLOCK_TIMEOUT_MS, it only
+// trips if the wait fails to time out by itself
+pub const BLOCK_WATCHDOG_MS: u64 = 3 * BLOCK_TIMEOUT_MS;
+const _: () = assert!(BLOCK_WATCHDOG_MS > BLOCK_TIMEOUT_MS);
+pub const P9_MSIZE: u32 = 8192;
+// Bytes asked of the entropy device per request and how long to wait for them
+pub const RNG_BUFFER_SIZE: usize = 64;
+pub const RNG_TIMEOUT_MS: u64 = 100;
+pub const P9_MOUNT_POINT: &str = "/host";
 pub const BANNER: &str = "
                               _             
                              (_)            
@@ -17,9 +125,12 @@ pub const BANNER: &str = "
 // Colour Print Labels
 pub const MAIN: &str = "[\x1b[38;5;214mMAIN\x1b[39m]";
 pub const STEP: &str = "[\x1b[38;5;130mSTEP\x1b[39m]";
-pub const INFO: &str = "[\x1b[38;5;167mINFO\x1b[39m]";
 pub const TEST: &str = "[\x1b[38;5;202mTEST\x1b[39m]";
+pub const ERROR: &str = "[\x1b[38;5;196mERROR\x1b[39m]";
+pub const WARN: &str = "[\x1b[38;5;208mWARN\x1b[39m]";
+pub const INFO: &str = "[\x1b[38;5;167mINFO\x1b[39m]";
 pub const DEBUG: &str = "[\x1b[38;5;97mDEBUG\x1b[39m]";
+pub const TRACE: &str = "[\x1b[38;5;244mTRACE\x1b[39m]";
 pub const TEST_PASSED: &str = "  ... [\x1b[38;5;41mPASSED\x1b[39m]";
 pub const TRAP_COLOUR: &str = "\x1b[38;5;222m";
 pub const RESET_COLOUR: &str = "\x1b[39m";
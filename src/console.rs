@@ -0,0 +1,111 @@
+use crate::bootargs;
+use crate::config::{CONSOLE, CONSOLE_MIRROR};
+use crate::fbcon;
+use crate::uart;
+use crate::vconsole;
+use core::fmt::{Display, Error, Formatter, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// mod console.rs
+// The kernel console that print! writes to
+// Output goes to the backend selected in config.rs or by console= in the
+// bootargs, falling back to the uart until it has been initialized. CONSOLE_MIRROR also shows
+// uart output on the framebuffer console
+// The labels in config.rs carry ANSI colour sequences, they are printed
+// through styled(), which drops them in plain mode for logs captured to
+// files. Plain mode starts on with --features "plain-console"
+
+static PLAIN: AtomicBool = AtomicBool::new(cfg!(feature = "plain-console"));
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConsoleBackend {
+    Uart,
+    Virtio,
+    Framebuffer,
+}
+
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        write_bytes(out.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn get_console() -> Console {
+    Console
+}
+
+// Text with colour sequences, see styled()
+pub struct Styled(&'static str);
+
+impl Display for Styled {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        if !is_plain() {
+            return f.write_str(self.0);
+        }
+        let mut rest = self.0;
+        while let Some(start) = rest.find('\x1b') {
+            f.write_str(&rest[..start])?;
+            // A CSI sequence ends with its first letter
+            rest = match rest[start..].find(|c: char| c.is_ascii_alphabetic()) {
+                Some(end) => &rest[start + end + 1..],
+                None => "",
+            };
+        }
+        f.write_str(rest)
+    }
+}
+
+// Print text with its colours, or without them in plain mode
+pub fn styled(text: &'static str) -> Styled {
+    Styled(text)
+}
+
+// The backend print! writes to once it is ready
+pub fn backend() -> ConsoleBackend {
+    bootargs::args().console.unwrap_or(CONSOLE)
+}
+
+pub fn is_plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+// Switch plain mode at runtime
+pub fn set_plain(plain: bool) {
+    PLAIN.store(plain, Ordering::Relaxed);
+}
+
+// Write bytes that are not known to be UTF-8, such as syscall buffers
+pub fn write_bytes(bytes: &[u8]) {
+    let backend = backend();
+    if backend == ConsoleBackend::Virtio && vconsole::ready() {
+        vconsole::write(bytes);
+    } else if backend == ConsoleBackend::Framebuffer && fbcon::ready() {
+        fbcon::write(bytes);
+    } else {
+        {
+            let mut uart = uart::get_uart();
+            for &b in bytes {
+                uart.put(b);
+            }
+        }
+        if CONSOLE_MIRROR && fbcon::ready() {
+            fbcon::write(bytes);
+        }
+    }
+}
+
+// Read the bytes already received by the uart without waiting for more
+pub fn read_bytes(buffer: &mut [u8]) -> usize {
+    let mut count = 0;
+    while count < buffer.len() {
+        match uart::read_byte() {
+            Some(b) => buffer[count] = b,
+            None => break,
+        }
+        count += 1;
+    }
+    count
+}
@@ -1,12 +1,67 @@
 use crate::alloc;
+use crate::block;
+use crate::buffer;
+use crate::console;
+use crate::json::JsonWriter;
+use crate::log;
+use crate::memory;
 use crate::minixfs3;
-use crate::uart;
+use crate::paging;
+use crate::process;
+use crate::slab;
+use crate::trap;
+use crate::vfs;
+use crate::virtio;
+use crate::{print, println};
+use core::fmt::{Result, Write};
+use rust_alloc::vec;
 
 // Collection of helpers to aid the debugging process
 
 #[allow(dead_code)]
 pub fn heap() {
     alloc::debug_heap();
+    slab::debug_stats(&[block::request_cache_stats(), buffer::block_cache_stats()]);
+}
+
+#[allow(dead_code)]
+pub fn traps() {
+    trap::debug_stats();
+}
+
+#[allow(dead_code)]
+pub fn tasks() {
+    process::debug_tasks();
+}
+
+#[allow(dead_code)]
+pub fn iostat() {
+    let stats = block::io_stats();
+    println!("\nBlock I/O          REQUESTS          BYTES");
+    println!("-------------------------------------------");
+    println!("- reads    {:>16} {:>14}", stats.reads, stats.bytes_read);
+    println!(
+        "- writes   {:>16} {:>14}",
+        stats.writes, stats.bytes_written
+    );
+    println!("- flushes  {:>16}", stats.flushes);
+    println!("- errors   {:>16}", stats.errors);
+    println!("-------------------------------------------");
+}
+
+#[allow(dead_code)]
+pub fn devices() {
+    println!("\nVirtio devices         ADDRESS   ID  NAME");
+    println!("-------------------------------------------");
+    for (addr, device_type) in virtio::devices() {
+        println!(
+            "- {:>28x} {:>4}  {}",
+            addr,
+            device_type,
+            virtio::device_name(device_type)
+        );
+    }
+    println!("-------------------------------------------");
 }
 
 #[allow(dead_code)]
@@ -21,15 +76,260 @@ pub fn fs() {
 
 #[allow(dead_code)]
 pub fn dbg(text: &str) {
-    uart::serial_debug(text);
+    log::debug!("{}", text);
 }
 
 #[allow(dead_code)]
 pub fn number(label: &str, number: usize) {
-    uart::serial_debug_number(label, number);
+    log::debug!("{}:{}", label, number);
 }
 
 #[allow(dead_code)]
 pub fn text(label: &str, text: &str) {
-    uart::serial_debug_text(label, text);
+    log::debug!("{}:{}", label, text);
+}
+
+// Bytes per hexdump line
+const HEXDUMP_WIDTH: usize = 16;
+// Bytes of a file read at a time by xxd
+const XXD_CHUNK: usize = 512;
+
+// Canonical hexdump output, offset, hex bytes and ASCII, 16 bytes a line
+// A run of lines equal to the one before is printed as a single *
+struct HexDump {
+    previous: Option<[u8; HEXDUMP_WIDTH]>,
+    skipping: bool,
+}
+
+impl HexDump {
+    fn new() -> Self {
+        Self {
+            previous: None,
+            skipping: false,
+        }
+    }
+
+    // Dump bytes found at offset, offset is a multiple of HEXDUMP_WIDTH
+    // unless this is the last call
+    fn write(&mut self, out: &mut impl Write, bytes: &[u8], offset: usize) -> Result {
+        for (i, line) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+            if line.len() == HEXDUMP_WIDTH && self.previous.is_some_and(|p| p == line) {
+                if !self.skipping {
+                    out.write_str("*\r\n")?;
+                    self.skipping = true;
+                }
+                continue;
+            }
+            self.skipping = false;
+            self.previous = line.try_into().ok();
+            write!(out, "{:08x} ", offset + i * HEXDUMP_WIDTH)?;
+            for column in 0..HEXDUMP_WIDTH {
+                if column % 8 == 0 {
+                    out.write_char(' ')?;
+                }
+                match line.get(column) {
+                    Some(byte) => write!(out, "{:02x} ", byte)?,
+                    None => out.write_str("   ")?,
+                }
+            }
+            out.write_str(" |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                out.write_char(c)?;
+            }
+            out.write_str("|\r\n")?;
+        }
+        Ok(())
+    }
+
+    // The offset just past the data, closes a trailing run of equal lines
+    fn finish(&self, out: &mut impl Write, end: usize) -> Result {
+        write!(out, "{:08x}\r\n", end)
+    }
+}
+
+// Write bytes as a canonical hexdump, offsets counted from base
+pub fn hexdump_to(out: &mut impl Write, bytes: &[u8], base: usize) -> Result {
+    let mut dump = HexDump::new();
+    dump.write(out, bytes, base)?;
+    dump.finish(out, base + bytes.len())
+}
+
+// Print len bytes of memory at addr, offsets are the addresses
+#[allow(dead_code)]
+pub fn hexdump(addr: usize, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    let _ = hexdump_to(&mut console::get_console(), bytes, addr);
+}
+
+// True if addr..addr + len is MMIO of a single known device
+fn is_mmio(addr: usize, len: usize) -> bool {
+    let last = addr.wrapping_add(len).wrapping_sub(1);
+    len > 0
+        && last >= addr
+        && paging::mmio_window(addr).is_some()
+        && paging::mmio_window(addr) == paging::mmio_window(last)
+}
+
+// Print len bytes at addr, RAM as a hexdump and MMIO as 32 bit registers
+// Anything outside RAM and the known MMIO windows is refused, false then
+// Reading some registers has side effects, a read of the PLIC claim register
+// claims an interrupt
+#[allow(dead_code)]
+pub fn peek(addr: usize, len: usize) -> bool {
+    if len > 0 && memory::is_kernel_memory(addr, len) {
+        hexdump(addr, len);
+        return true;
+    }
+    let words = len.div_ceil(4);
+    if !addr.is_multiple_of(4) || !is_mmio(addr, words * 4) {
+        println!("peek: 0x{:x}+{} is not RAM or MMIO", addr, len);
+        return false;
+    }
+    for line in (0..words).step_by(4) {
+        print!("{:08x}:", addr + line * 4);
+        for word in line..words.min(line + 4) {
+            let reg = (addr + word * 4) as *const u32;
+            print!(" {:08x}", unsafe { reg.read_volatile() });
+        }
+        println!();
+    }
+    true
+}
+
+// Write a 32 bit word at addr, the width of every MMIO register here
+// False unless it is aligned and in writable RAM or a known MMIO window
+#[allow(dead_code)]
+pub fn poke(addr: usize, value: u32) -> bool {
+    let ok = addr.is_multiple_of(4) && (memory::is_kernel_writable(addr, 4) || is_mmio(addr, 4));
+    if !ok {
+        println!("poke: 0x{:x} is not writable RAM or MMIO", addr);
+        return false;
+    }
+    unsafe { (addr as *mut u32).write_volatile(value) };
+    true
+}
+
+// Print the file at path as a hexdump, false if it does not exist
+#[allow(dead_code)]
+pub fn xxd(path: &str) -> bool {
+    let size = match vfs::file_size(path) {
+        Some(size) => size as usize,
+        None => {
+            println!("xxd: no such file {}", path);
+            return false;
+        }
+    };
+    let mut out = console::get_console();
+    let mut dump = HexDump::new();
+    let mut buffer = vec![0u8; XXD_CHUNK];
+    let mut offset = 0;
+    while offset < size {
+        let want = XXD_CHUNK.min(size - offset);
+        let read = vfs::read_file(path, buffer.as_mut_ptr(), want as u32, offset as u32) as usize;
+        if read == 0 {
+            break;
+        }
+        let _ = dump.write(&mut out, &buffer[..read], offset);
+        offset += read;
+    }
+    let _ = dump.finish(&mut out, offset);
+    true
+}
+
+// Structured variants of the debug helpers above
+// Each prints a single line JSON document for host side tooling
+
+#[allow(dead_code)]
+pub fn heap_json() {
+    let stats = alloc::stats();
+    let mut json = JsonWriter::new();
+    json.begin_object(None);
+    json.string(Some("type"), "meminfo");
+    json.number(Some("pages_total"), stats.pages_total);
+    json.number(Some("pages_used"), stats.pages_used);
+    json.number(Some("bytes_total"), stats.bytes_total);
+    json.number(Some("bytes_used"), stats.bytes_used);
+    json.end_object();
+    json.finish();
+}
+
+#[allow(dead_code)]
+pub fn devices_json() {
+    let mut json = JsonWriter::new();
+    json.begin_object(None);
+    json.string(Some("type"), "devices");
+    json.begin_array(Some("virtio"));
+    for (addr, device_type) in virtio::devices() {
+        json.begin_object(None);
+        json.number(Some("address"), addr);
+        json.number(Some("device_id"), device_type as usize);
+        json.string(Some("name"), virtio::device_name(device_type));
+        json.end_object();
+    }
+    json.end_array();
+    json.end_object();
+    json.finish();
+}
+
+#[allow(dead_code)]
+pub fn iostat_json() {
+    let stats = block::io_stats();
+    let mut json = JsonWriter::new();
+    json.begin_object(None);
+    json.string(Some("type"), "iostat");
+    json.number(Some("reads"), stats.reads as usize);
+    json.number(Some("writes"), stats.writes as usize);
+    json.number(Some("flushes"), stats.flushes as usize);
+    json.number(Some("bytes_read"), stats.bytes_read as usize);
+    json.number(Some("bytes_written"), stats.bytes_written as usize);
+    json.number(Some("errors"), stats.errors as usize);
+    json.end_object();
+    json.finish();
+}
+
+#[allow(dead_code)]
+pub fn tasks_json() {
+    let mut json = JsonWriter::new();
+    json.begin_object(None);
+    json.string(Some("type"), "ps");
+    json.begin_array(Some("tasks"));
+    for task in process::list() {
+        json.begin_object(None);
+        json.number(Some("pid"), task.pid.0);
+        json.string(Some("name"), task.name);
+        json.string(Some("state"), task.state.label());
+        json.string(Some("priority"), task.priority.label());
+        json.signed(Some("nice"), task.nice as isize);
+        json.number(Some("stack_used"), task.stack_used);
+        json.number(Some("stack_size"), task.stack_size);
+        json.number(Some("ticks"), task.ticks as usize);
+        json.end_object();
+    }
+    json.end_array();
+    json.end_object();
+    json.finish();
+}
+
+#[allow(dead_code)]
+pub fn fs_cache_json() {
+    let mut json = JsonWriter::new();
+    json.begin_object(None);
+    json.string(Some("type"), "fs_cache");
+    json.begin_array(Some("files"));
+    for (path, node) in minixfs3::cached_files() {
+        json.begin_object(None);
+        json.string(Some("path"), &path);
+        json.number(Some("mode"), node.mode as usize);
+        json.number(Some("size"), node.size as usize);
+        json.number(Some("nlinks"), node.nlinks as usize);
+        json.end_object();
+    }
+    json.end_array();
+    json.end_object();
+    json.finish();
 }
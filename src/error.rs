@@ -0,0 +1,45 @@
+use core::fmt;
+
+// mod error.rs
+// The error kernel APIs return, shared by every subsystem
+// A failure is logged where it is best understood and handed up as a KError,
+// so the caller decides whether it is fatal, worth a retry or fine to ignore
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KError {
+    // The allocators are out of memory large enough
+    NoMemory,
+    // A size, offset or buffer the callee cannot work with, e.g. zero bytes
+    InvalidArgument,
+    // The device failed or needs a reset before it is usable again
+    IoError,
+    // No such file, inode or capability
+    NotFound,
+    // The driver has no device to talk to
+    NoDevice,
+    // In use, try again later
+    Busy,
+    // The device did not answer in time
+    TimedOut,
+    // Writing to something read only
+    ReadOnly,
+    // A device, feature or operation this kernel does not speak
+    Unsupported,
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            KError::NoMemory => "out of memory",
+            KError::InvalidArgument => "invalid argument",
+            KError::IoError => "i/o error",
+            KError::NotFound => "not found",
+            KError::NoDevice => "no device",
+            KError::Busy => "busy",
+            KError::TimedOut => "timed out",
+            KError::ReadOnly => "read only",
+            KError::Unsupported => "unsupported",
+        };
+        f.write_str(text)
+    }
+}
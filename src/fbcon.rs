@@ -0,0 +1,178 @@
+use crate::config::CONSOLE_MIRROR;
+use crate::console::{self, ConsoleBackend};
+use crate::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::gpu::{self, Framebuffer, Pixel, Rect};
+use crate::hart;
+use crate::log;
+use crate::sync::SpinLock;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// mod fbcon.rs
+// A text console drawn on the virtio-gpu framebuffer with the 8x8 font in
+// src/font.rs, scrolling up when the cursor runs off the last row
+// It replaces the uart with CONSOLE = ConsoleBackend::Framebuffer in config.rs
+// or console=fb in the bootargs, or shows the same output as the uart with CONSOLE_MIRROR
+// ANSI escape sequences, like the colours of the labels, are skipped
+
+const FOREGROUND: Pixel = Pixel::rgb(0xd0, 0xd0, 0xd0);
+const BACKGROUND: Pixel = Pixel::rgb(0x10, 0x10, 0x18);
+const TAB_WIDTH: u32 = 8;
+
+// Printing takes the console with interrupts disabled
+static FBCON: SpinLock<Option<TextConsole>> = SpinLock::new(None);
+static READY: AtomicBool = AtomicBool::new(false);
+// Hart id + 1 of the hart drawing, a gpu error printed while drawing would
+// otherwise wait on the console forever
+static DRAWING: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TextConsole {
+    fb: Framebuffer,
+    columns: u32,
+    rows: u32,
+    column: u32,
+    row: u32,
+    // Inside an escape sequence, skipping until its final letter
+    escape: bool,
+    // Rows drawn on since the last flush, first and last
+    dirty: Option<(u32, u32)>,
+}
+
+impl TextConsole {
+    pub fn new(mut fb: Framebuffer) -> Self {
+        fb.fill_rect(fb.bounds(), BACKGROUND);
+        Self {
+            fb,
+            columns: fb.width() / GLYPH_WIDTH,
+            rows: fb.height() / GLYPH_HEIGHT,
+            column: 0,
+            row: 0,
+            escape: false,
+            dirty: Some((0, fb.height() / GLYPH_HEIGHT - 1)),
+        }
+    }
+
+    pub fn cursor(&self) -> (u32, u32) {
+        (self.column, self.row)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.put(byte);
+        }
+    }
+
+    fn put(&mut self, byte: u8) {
+        if self.escape {
+            self.escape = !byte.is_ascii_alphabetic();
+            return;
+        }
+        match byte {
+            0x1b => self.escape = true,
+            b'\r' => self.column = 0,
+            b'\n' => self.newline(),
+            8 => self.column = self.column.saturating_sub(1),
+            b'\t' => {
+                for _ in 0..TAB_WIDTH - self.column % TAB_WIDTH {
+                    self.put(b' ');
+                }
+            }
+            c => {
+                if self.column == self.columns {
+                    self.column = 0;
+                    self.newline();
+                }
+                self.draw(c);
+                self.column += 1;
+            }
+        }
+    }
+
+    fn draw(&mut self, c: u8) {
+        let (x, y) = (self.column * GLYPH_WIDTH, self.row * GLYPH_HEIGHT);
+        for (dy, bits) in font::glyph(c).iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                let pixel = if bits & (1 << dx) != 0 {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+                self.fb.set_pixel(x + dx, y + dy as u32, pixel);
+            }
+        }
+        self.touch(self.row, self.row);
+    }
+
+    fn newline(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.fb.scroll_up(GLYPH_HEIGHT, BACKGROUND);
+            self.touch(0, self.rows - 1);
+        }
+    }
+
+    fn touch(&mut self, first: u32, last: u32) {
+        self.dirty = Some(match self.dirty {
+            Some((f, l)) => (f.min(first), l.max(last)),
+            None => (first, last),
+        });
+    }
+
+    // Push the rows drawn on to the display
+    fn flush(&mut self) {
+        if let Some((first, last)) = self.dirty.take() {
+            let rect = Rect::new(
+                0,
+                first * GLYPH_HEIGHT,
+                self.fb.width(),
+                (last - first + 1) * GLYPH_HEIGHT,
+            );
+            gpu::flush_rect(rect);
+        }
+    }
+}
+
+// ====================================================
+// The public interface for fbcon is here...
+// ====================================================
+
+// Take over the framebuffer if config.rs asks for a text console on it
+pub fn init() -> bool {
+    if console::backend() != ConsoleBackend::Framebuffer && !CONSOLE_MIRROR {
+        return false;
+    }
+    match gpu::framebuffer() {
+        Some(fb) => {
+            *FBCON.lock_irq() = Some(TextConsole::new(fb));
+            READY.store(true, Ordering::Release);
+            log::info!("init framebuffer console");
+            true
+        }
+        None => false,
+    }
+}
+
+// True while there is a text console on a display
+pub fn ready() -> bool {
+    READY.load(Ordering::Acquire) && gpu::framebuffer().is_some()
+}
+
+pub fn write(bytes: &[u8]) {
+    let me = hart::id() + 1;
+    if DRAWING.load(Ordering::Relaxed) == me || !ready() {
+        return;
+    }
+    let mut fbcon = FBCON.lock_irq();
+    DRAWING.store(me, Ordering::Relaxed);
+    if let Some(console) = fbcon.as_mut() {
+        console.write(bytes);
+        console.flush();
+    }
+    DRAWING.store(0, Ordering::Relaxed);
+}
+
+// Column and row the next character goes to
+#[allow(dead_code)]
+pub fn cursor() -> Option<(u32, u32)> {
+    FBCON.lock_irq().as_ref().map(|console| console.cursor())
+}
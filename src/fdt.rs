@@ -0,0 +1,397 @@
+use crate::log;
+use crate::{print, println};
+use rust_alloc::vec::Vec;
+
+// mod fdt.rs
+// A minimal flattened device tree reader
+// Walks the structure block of the DTB handed over at boot in a1
+// This is where the kernel learns the hardware it runs on: the size of RAM,
+// the harts, the PLIC, CLINT and serial ports and the virtio-mmio windows, so
+// QEMU's -m and -smp can change without touching config.rs
+// Nodes are collected on the heap, memory(), range(), chosen() and
+// timebase_frequency() walk the blob without allocating as they are needed
+// before the allocators start
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Header offsets in bytes, all fields are big endian u32
+const HEADER_TOTAL_SIZE: usize = 4;
+const HEADER_STRUCT_OFFSET: usize = 8;
+const HEADER_STRINGS_OFFSET: usize = 12;
+
+// Cell counts a node's children get when it does not specify them
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+static mut FDT: Option<Fdt> = None;
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let word = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+// Offset rounded up to the next 4 byte token boundary
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+pub struct Property {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+impl Property {
+    // The idx-th 32 bit cell of the value
+    pub fn cell(&self, idx: usize) -> Option<u32> {
+        be32(self.value, idx * 4)
+    }
+
+    // A number made of `count` cells starting at cell idx
+    pub fn cells(&self, idx: usize, count: u32) -> Option<u64> {
+        (0..count as usize).try_fold(0u64, |acc, i| {
+            Some((acc << 32) | self.cell(idx + i)? as u64)
+        })
+    }
+
+    // The value as a list of NUL terminated strings
+    pub fn strings(&self) -> impl Iterator<Item = &'static str> {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+}
+
+pub struct Node {
+    pub name: &'static str,
+    pub depth: usize,
+    pub properties: Vec<Property>,
+    // Cell counts declared by the parent, used to decode reg
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl Node {
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .is_some_and(|p| p.strings().any(|s| s == compatible))
+    }
+
+    // The first (address, size) pair of the reg property
+    pub fn reg(&self) -> Option<(u64, u64)> {
+        let reg = self.property("reg")?;
+        let address = reg.cells(0, self.address_cells)?;
+        let size = reg.cells(self.address_cells as usize, self.size_cells)?;
+        Some((address, size))
+    }
+
+    // The first interrupt number of the node
+    pub fn interrupt(&self) -> Option<u32> {
+        self.property("interrupts")?.cell(0)
+    }
+
+    // A property holding a single cell, e.g. #address-cells
+    pub fn cell(&self, name: &str) -> Option<u32> {
+        self.property(name)?.cell(0)
+    }
+
+    // Cells of an address in the parent's bus, as the node's reg and ranges
+    // use them
+    pub fn parent_address_cells(&self) -> u32 {
+        self.address_cells
+    }
+}
+
+pub struct Fdt {
+    blob: &'static [u8],
+    struct_offset: usize,
+    strings_offset: usize,
+}
+
+// A token of the structure block, NOPs are skipped
+enum Token {
+    BeginNode(&'static str),
+    EndNode,
+    Property(Property),
+}
+
+// The tokens of the structure block in order, up to FDT_END
+struct Tokens<'a> {
+    fdt: &'a Fdt,
+    offset: usize,
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let blob = self.fdt.blob;
+        loop {
+            let token = be32(blob, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = self.fdt.string(self.offset);
+                    self.offset = align(self.offset + name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = be32(blob, self.offset)? as usize;
+                    let name_offset = be32(blob, self.offset + 4)? as usize;
+                    let start = self.offset + 8;
+                    self.offset = align(start + len);
+                    return Some(Token::Property(Property {
+                        name: self.fdt.string(self.fdt.strings_offset + name_offset),
+                        value: blob.get(start..start + len).unwrap_or(&[]),
+                    }));
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => {
+                    println!("Unknown fdt token 0x{:x}", token);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Fdt {
+    fn new(ptr: *const u8) -> Option<Self> {
+        if ptr.is_null() {
+            return None;
+        }
+        let header = unsafe { core::slice::from_raw_parts(ptr, 40) };
+        if be32(header, 0)? != FDT_MAGIC {
+            print!("bad magic...");
+            return None;
+        }
+        let total_size = be32(header, HEADER_TOTAL_SIZE)? as usize;
+        Some(Self {
+            blob: unsafe { core::slice::from_raw_parts(ptr, total_size) },
+            struct_offset: be32(header, HEADER_STRUCT_OFFSET)? as usize,
+            strings_offset: be32(header, HEADER_STRINGS_OFFSET)? as usize,
+        })
+    }
+
+    // The NUL terminated string at offset
+    fn string(&self, offset: usize) -> &'static str {
+        let bytes = &self.blob[offset.min(self.blob.len())..];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..end]).unwrap_or("")
+    }
+
+    fn tokens(&self) -> Tokens<'_> {
+        Tokens {
+            fdt: self,
+            offset: self.struct_offset,
+        }
+    }
+
+    // Every node of the tree in depth first order
+    pub fn nodes(&self) -> Vec<Node> {
+        let mut nodes: Vec<Node> = Vec::new();
+        // Indices of the currently open nodes and the cells their children use
+        let mut open: Vec<(usize, u32, u32)> = Vec::new();
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    let (address_cells, size_cells) = open
+                        .last()
+                        .map(|&(_, a, s)| (a, s))
+                        .unwrap_or((DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS));
+                    open.push((nodes.len(), DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS));
+                    nodes.push(Node {
+                        name,
+                        depth: open.len() - 1,
+                        properties: Vec::new(),
+                        address_cells,
+                        size_cells,
+                    });
+                }
+                Token::EndNode => {
+                    open.pop();
+                }
+                Token::Property(property) => {
+                    if let Some(current) = open.last_mut() {
+                        let cells = property.cell(0);
+                        match property.name {
+                            "#address-cells" => current.1 = cells.unwrap_or(current.1),
+                            "#size-cells" => current.2 = cells.unwrap_or(current.2),
+                            _ => {}
+                        }
+                        nodes[current.0].properties.push(property);
+                    }
+                }
+            }
+        }
+        nodes
+    }
+
+    // A property of the child node of the root, e.g. /chosen or /cpus, read
+    // without allocating
+    fn top_level(&self, node: &str, name: &str) -> Option<Property> {
+        let mut depth = 0;
+        let mut in_node = false;
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(child) => {
+                    depth += 1;
+                    in_node = depth == 2 && child == node;
+                }
+                Token::EndNode => {
+                    depth -= 1;
+                    in_node = false;
+                }
+                Token::Property(property) if in_node && property.name == name => {
+                    return Some(property)
+                }
+                Token::Property(_) => {}
+            }
+        }
+        None
+    }
+
+    // The reg of the first memory node, read without allocating
+    // Memory nodes are children of the root and use its cell counts, which
+    // come first as properties precede child nodes
+    fn memory(&self) -> Option<(u64, u64)> {
+        let mut depth = 0;
+        let mut in_memory = false;
+        let (mut address_cells, mut size_cells) = (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS);
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    in_memory = depth == 2 && name.split('@').next() == Some("memory");
+                }
+                Token::EndNode => {
+                    depth -= 1;
+                    in_memory = false;
+                }
+                Token::Property(property) => match property.name {
+                    "#address-cells" if depth == 1 => {
+                        address_cells = property.cell(0).unwrap_or(address_cells)
+                    }
+                    "#size-cells" if depth == 1 => {
+                        size_cells = property.cell(0).unwrap_or(size_cells)
+                    }
+                    "reg" if in_memory => {
+                        let address = property.cells(0, address_cells)?;
+                        let size = property.cells(address_cells as usize, size_cells)?;
+                        return Some((address, size));
+                    }
+                    _ => {}
+                },
+            }
+        }
+        None
+    }
+}
+
+// ====================================================
+// The public interface for the device tree is here...
+// ====================================================
+
+// Remember the DTB passed in by the firmware, false if there is none
+pub fn init(dtb: usize) -> bool {
+    log::info!("init fdt");
+    match Fdt::new(dtb as *const u8) {
+        Some(fdt) => {
+            unsafe { FDT = Some(fdt) };
+            true
+        }
+        None => {
+            println!("no device tree found...");
+            false
+        }
+    }
+}
+
+// Start and size of RAM as the memory node gives it, None without a device
+// tree. Does not allocate, so it can size the heap
+pub fn memory() -> Option<(usize, usize)> {
+    let (start, size) = unsafe { FDT.as_ref()?.memory()? };
+    Some((start as usize, size as usize))
+}
+
+// A property of /chosen, where the firmware puts the bootargs
+pub fn chosen(name: &str) -> Option<Property> {
+    unsafe { FDT.as_ref()?.top_level("chosen", name) }
+}
+
+// Ticks per second of the time CSR and mtime, from /cpus
+pub fn timebase_frequency() -> Option<u64> {
+    let property = unsafe { FDT.as_ref()?.top_level("cpus", "timebase-frequency")? };
+    property.cells(0, (property.value.len() / 4) as u32)
+}
+
+// Where the DTB itself lies, start and end, it must not be handed out as heap
+pub fn range() -> Option<(usize, usize)> {
+    let blob = unsafe { FDT.as_ref()?.blob };
+    let start = blob.as_ptr() as usize;
+    Some((start, start + blob.len()))
+}
+
+// Every node of the device tree, empty without one
+pub fn nodes() -> Vec<Node> {
+    unsafe { FDT.as_ref().map(|fdt| fdt.nodes()).unwrap_or_default() }
+}
+
+// Nodes whose compatible list contains the given string
+pub fn compatible(compatible: &str) -> Vec<Node> {
+    let mut nodes = nodes();
+    nodes.retain(|n| n.is_compatible(compatible));
+    nodes
+}
+
+// The first (address, size) of the first node compatible with any of the
+// given strings
+pub fn find_reg(compatible: &[&str]) -> Option<(usize, usize)> {
+    let (address, size) = nodes()
+        .iter()
+        .find(|n| compatible.iter().any(|c| n.is_compatible(c)))?
+        .reg()?;
+    Some((address as usize, size as usize))
+}
+
+// The node a phandle refers to, e.g. the interrupt parent in an interrupt-map
+pub fn phandle(phandle: u32) -> Option<Node> {
+    nodes()
+        .into_iter()
+        .find(|n| n.cell("phandle") == Some(phandle))
+}
+
+// Hart ids of the cpu nodes, just the boot hart without a device tree
+pub fn harts() -> Vec<usize> {
+    let mut harts: Vec<usize> = nodes()
+        .iter()
+        .filter(|n| {
+            n.property("device_type")
+                .is_some_and(|p| p.strings().any(|s| s == "cpu"))
+        })
+        .filter_map(|n| n.reg())
+        .map(|(id, _)| id as usize)
+        .collect();
+    if harts.is_empty() {
+        harts.push(0);
+    }
+    harts
+}
+
+#[allow(dead_code)]
+pub fn debug_nodes() {
+    for node in nodes() {
+        println!("{:indent$}{}", "", node.name, indent = node.depth * 2);
+    }
+}
@@ -0,0 +1,598 @@
+use crate::assembly;
+use crate::config::{GDB_BREAKPOINTS, GDB_BREAK_AT_BOOT, GDB_PACKET_SIZE};
+use crate::log;
+use crate::memory;
+use crate::sync::SpinLock;
+use crate::trap::{self, TrapFrame};
+use crate::uart::{self, AUX_PORT, CONSOLE_PORT};
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+
+// mod gdbstub.rs
+// A minimal GDB remote serial protocol stub, requires --features "gdbstub"
+// It serves the debugger from the breakpoint trap on the aux serial port, or
+// the console port when there is no aux port. QEMU virt has a single uart, so
+// make run-gdb puts it on tcp port 1234 and the console goes there too, then
+//   gdb target/riscv64gc-unknown-none-elf/debug/corrosion -ex 'target remote :1234'
+// Supported are registers through the trap frame (g G p P), memory (m M),
+// software breakpoints patched in with ebreak (Z0 z0), continue and step (c s)
+// Stepping decodes the instruction and breaks on every place it can go next
+// Only the hart that trapped stops, the others keep running
+// Under --features "supervisor" the kernel text is read only, so breakpoints
+// and code patches fail there
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+// Register number of pc after x0-x31
+const PC_REGISTER: usize = 32;
+// A reply for a stop by SIGTRAP
+const STOPPED: &str = "S05";
+
+static SESSION: SpinLock<Session> = SpinLock::new(Session {
+    stub: Stub::new(),
+    input: [0; GDB_PACKET_SIZE],
+    reply: Reply::new(),
+});
+
+struct Session {
+    stub: Stub,
+    input: [u8; GDB_PACKET_SIZE],
+    reply: Reply,
+}
+
+// An ebreak patched over the instruction at addr
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    // 2 for a compressed instruction, else 4
+    len: usize,
+    saved: [u16; 2],
+}
+
+impl Breakpoint {
+    fn insert(addr: usize, len: usize) -> Option<Self> {
+        if (len != 2 && len != 4)
+            || !addr.is_multiple_of(2)
+            || !memory::is_kernel_writable(addr, len)
+        {
+            return None;
+        }
+        let code = addr as *mut u16;
+        let ebreak = if len == 2 {
+            [C_EBREAK, 0]
+        } else {
+            [EBREAK as u16, (EBREAK >> 16) as u16]
+        };
+        let mut saved = [0; 2];
+        for (i, half) in ebreak.iter().enumerate().take(len / 2) {
+            unsafe {
+                saved[i] = code.add(i).read_volatile();
+                code.add(i).write_volatile(*half);
+            }
+        }
+        assembly::fence_instructions();
+        Some(Self { addr, len, saved })
+    }
+
+    fn remove(&self) {
+        let code = self.addr as *mut u16;
+        for (i, half) in self.saved.iter().enumerate().take(self.len / 2) {
+            unsafe { code.add(i).write_volatile(*half) };
+        }
+        assembly::fence_instructions();
+    }
+}
+
+// A reply packet being put together, without the framing
+pub struct Reply {
+    bytes: [u8; GDB_PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; GDB_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Whatever does not fit is cut off
+    fn push_str(&mut self, s: &str) {
+        let len = s.len().min(GDB_PACKET_SIZE - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            let _ = write!(self, "{:02x}", b);
+        }
+    }
+
+    // Registers are sent in target byte order
+    fn push_register(&mut self, value: usize) {
+        self.push_hex(&value.to_le_bytes());
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+// What the debugger asked for with a packet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    // Send the reply and wait for the next packet
+    Reply,
+    // Let the kernel run until the next breakpoint
+    Resume,
+    // Send the reply, forget the breakpoints and let the kernel run
+    Detach,
+}
+
+// The protocol state kept between stops
+pub struct Stub {
+    breakpoints: [Option<Breakpoint>; GDB_BREAKPOINTS],
+    // Placed by a step, removed when the kernel stops again
+    step: [Option<Breakpoint>; 2],
+    // The debugger resumed the kernel and is waiting for a stop reply
+    running: bool,
+}
+
+impl Stub {
+    pub const fn new() -> Self {
+        Self {
+            breakpoints: [None; GDB_BREAKPOINTS],
+            step: [None; 2],
+            running: false,
+        }
+    }
+
+    // Answer one packet for the stop at pc, the reply goes to reply
+    // Changes to the registers are made in frame and pc
+    pub fn handle(
+        &mut self,
+        frame: &mut TrapFrame,
+        pc: &mut usize,
+        packet: &[u8],
+        reply: &mut Reply,
+    ) -> Action {
+        reply.clear();
+        let Some((&command, args)) = packet.split_first() else {
+            return Action::Reply;
+        };
+        match command {
+            b'?' => reply.push_str(STOPPED),
+            b'g' => {
+                for &reg in frame.regs.iter() {
+                    reply.push_register(reg);
+                }
+                reply.push_register(*pc);
+            }
+            b'G' => {
+                let mut values = [0usize; PC_REGISTER + 1];
+                for (i, value) in values.iter_mut().enumerate() {
+                    match args.get(i * 16..i * 16 + 16).and_then(parse_register) {
+                        Some(v) => *value = v,
+                        None => return error(reply),
+                    }
+                }
+                frame.regs[1..].copy_from_slice(&values[1..PC_REGISTER]);
+                *pc = values[PC_REGISTER];
+                reply.push_str("OK");
+            }
+            b'p' => match parse_hex(args) {
+                Some(reg) if reg < PC_REGISTER => reply.push_register(frame.regs[reg]),
+                Some(PC_REGISTER) => reply.push_register(*pc),
+                _ => return error(reply),
+            },
+            b'P' => {
+                let mut parts = args.splitn(2, |&b| b == b'=');
+                let reg = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(parse_register);
+                match (reg, value) {
+                    // x0 is hardwired to zero
+                    (Some(0), Some(_)) => {}
+                    (Some(reg), Some(value)) if reg < PC_REGISTER => frame.regs[reg] = value,
+                    (Some(PC_REGISTER), Some(value)) => *pc = value,
+                    _ => return error(reply),
+                }
+                reply.push_str("OK");
+            }
+            b'm' => {
+                let Some((addr, len)) = parse_range(args) else {
+                    return error(reply);
+                };
+                // Replies may be shorter than asked for
+                let len = len.min(GDB_PACKET_SIZE / 2);
+                if !memory::is_kernel_memory(addr, len) {
+                    return error(reply);
+                }
+                let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+                reply.push_hex(bytes);
+            }
+            b'M' => {
+                let mut parts = args.splitn(2, |&b| b == b':');
+                let range = parts.next().and_then(parse_range);
+                let data = parts.next().unwrap_or(&[]);
+                match range {
+                    Some((addr, len))
+                        if data.len() == len * 2 && memory::is_kernel_writable(addr, len) =>
+                    {
+                        for (i, pair) in data.chunks(2).enumerate() {
+                            let Some(byte) = parse_hex(pair) else {
+                                return error(reply);
+                            };
+                            unsafe { (addr as *mut u8).add(i).write_volatile(byte as u8) };
+                        }
+                        // The bytes written may be code
+                        assembly::fence_instructions();
+                        reply.push_str("OK");
+                    }
+                    _ => return error(reply),
+                }
+            }
+            b'Z' | b'z' => {
+                // Only software breakpoints, an empty reply says so for the rest
+                let Some(args) = args.strip_prefix(b"0,") else {
+                    return Action::Reply;
+                };
+                let Some((addr, len)) = parse_range(args) else {
+                    return error(reply);
+                };
+                let done = if command == b'Z' {
+                    self.insert(addr, len)
+                } else {
+                    self.remove(addr)
+                };
+                if !done {
+                    return error(reply);
+                }
+                reply.push_str("OK");
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    *pc = addr;
+                }
+                // An ebreak compiled into the kernel is run past, else it
+                // would stop the kernel again right away
+                if let Some(len) = ebreak_length(*pc).filter(|_| !self.is_breakpoint(*pc)) {
+                    *pc += len;
+                    if command == b's' {
+                        reply.push_str(STOPPED);
+                        return Action::Reply;
+                    }
+                }
+                if command == b's' {
+                    for (slot, target) in self.step.iter_mut().zip(step_targets(frame, *pc)) {
+                        *slot = target
+                            .filter(|&addr| {
+                                !self.breakpoints.iter().flatten().any(|b| b.addr == addr)
+                            })
+                            .and_then(|addr| {
+                                Breakpoint::insert(addr, trap::instruction_length(addr))
+                            });
+                    }
+                }
+                self.running = true;
+                return Action::Resume;
+            }
+            b'D' => {
+                reply.push_str("OK");
+                self.detach();
+                return Action::Detach;
+            }
+            // Kill, there is nothing to kill and no reply, the debugger just goes away
+            b'k' => {
+                self.detach();
+                return Action::Resume;
+            }
+            b'H' => reply.push_str("OK"),
+            b'q' if args.starts_with(b"Supported") => {
+                let _ = write!(reply, "PacketSize={:x}", GDB_PACKET_SIZE);
+            }
+            b'q' if args == b"Attached" => reply.push_str("1"),
+            // Unsupported, including vCont which makes the debugger use c and s
+            _ => {}
+        }
+        Action::Reply
+    }
+
+    fn is_breakpoint(&self, addr: usize) -> bool {
+        self.breakpoints.iter().flatten().any(|b| b.addr == addr)
+    }
+
+    fn insert(&mut self, addr: usize, len: usize) -> bool {
+        if self.is_breakpoint(addr) {
+            return true;
+        }
+        let Some(slot) = self.breakpoints.iter_mut().find(|b| b.is_none()) else {
+            return false;
+        };
+        *slot = Breakpoint::insert(addr, len);
+        slot.is_some()
+    }
+
+    fn remove(&mut self, addr: usize) -> bool {
+        match self
+            .breakpoints
+            .iter_mut()
+            .find(|b| b.is_some_and(|b| b.addr == addr))
+        {
+            Some(slot) => {
+                slot.take().unwrap().remove();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Take out the breakpoints a step placed
+    fn end_step(&mut self) {
+        for bp in self.step.iter_mut().filter_map(Option::take) {
+            bp.remove();
+        }
+    }
+
+    fn detach(&mut self) {
+        self.end_step();
+        for bp in self.breakpoints.iter_mut().filter_map(Option::take) {
+            bp.remove();
+        }
+        self.running = false;
+    }
+}
+
+fn error(reply: &mut Reply) -> Action {
+    reply.clear();
+    reply.push_str("E01");
+    Action::Reply
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0usize, |acc, &c| Some(acc << 4 | hex_digit(c)? as usize))
+}
+
+// A register value in target byte order, least significant byte first
+fn parse_register(digits: &[u8]) -> Option<usize> {
+    Some(parse_hex(digits)?.swap_bytes() >> (64 - digits.len() * 4))
+}
+
+// addr,len
+fn parse_range(args: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn instruction(pc: usize) -> u32 {
+    let code = pc as *const u16;
+    let low = unsafe { code.read_volatile() } as u32;
+    if low & 0b11 != 0b11 {
+        return low;
+    }
+    low | (unsafe { code.add(1).read_volatile() } as u32) << 16
+}
+
+// Length of the instruction at pc if it is an ebreak
+fn ebreak_length(pc: usize) -> Option<usize> {
+    if !memory::is_kernel_memory(pc, 2) {
+        return None;
+    }
+    match instruction(pc) {
+        EBREAK => Some(4),
+        inst if inst == C_EBREAK as u32 => Some(2),
+        _ => None,
+    }
+}
+
+// Bits hi..=lo of inst, shifted down
+fn bits(inst: u32, hi: u32, lo: u32) -> usize {
+    ((inst >> lo) & ((1 << (hi - lo + 1)) - 1)) as usize
+}
+
+fn sign_extend(value: usize, width: u32) -> usize {
+    let shift = usize::BITS - width;
+    (((value << shift) as isize) >> shift) as usize
+}
+
+// Every address the instruction at pc can continue at, for stepping
+pub fn step_targets(frame: &TrapFrame, pc: usize) -> [Option<usize>; 2] {
+    if !memory::is_kernel_memory(pc, 2) {
+        return [None; 2];
+    }
+    let inst = instruction(pc);
+    let reg = |r: usize| frame.regs[r];
+    if inst & 0b11 == 0b11 {
+        let next = pc + 4;
+        match inst & 0x7f {
+            // jal
+            0x6f => {
+                let imm = bits(inst, 31, 31) << 20
+                    | bits(inst, 19, 12) << 12
+                    | bits(inst, 20, 20) << 11
+                    | bits(inst, 30, 21) << 1;
+                [Some(pc.wrapping_add(sign_extend(imm, 21))), None]
+            }
+            // jalr
+            0x67 => {
+                let imm = sign_extend(bits(inst, 31, 20), 12);
+                [Some(reg(bits(inst, 19, 15)).wrapping_add(imm) & !1), None]
+            }
+            // Conditional branches
+            0x63 => {
+                let imm = bits(inst, 31, 31) << 12
+                    | bits(inst, 7, 7) << 11
+                    | bits(inst, 30, 25) << 5
+                    | bits(inst, 11, 8) << 1;
+                [Some(next), Some(pc.wrapping_add(sign_extend(imm, 13)))]
+            }
+            _ => [Some(next), None],
+        }
+    } else {
+        let next = pc + 2;
+        match (inst & 0b11, bits(inst, 15, 13)) {
+            // c.j
+            (0b01, 0b101) => {
+                let imm = bits(inst, 12, 12) << 11
+                    | bits(inst, 8, 8) << 10
+                    | bits(inst, 10, 9) << 8
+                    | bits(inst, 6, 6) << 7
+                    | bits(inst, 7, 7) << 6
+                    | bits(inst, 2, 2) << 5
+                    | bits(inst, 11, 11) << 4
+                    | bits(inst, 5, 3) << 1;
+                [Some(pc.wrapping_add(sign_extend(imm, 12))), None]
+            }
+            // c.beqz and c.bnez
+            (0b01, 0b110 | 0b111) => {
+                let imm = bits(inst, 12, 12) << 8
+                    | bits(inst, 6, 5) << 6
+                    | bits(inst, 2, 2) << 5
+                    | bits(inst, 11, 10) << 3
+                    | bits(inst, 4, 3) << 1;
+                [Some(next), Some(pc.wrapping_add(sign_extend(imm, 9)))]
+            }
+            // c.jr and c.jalr
+            (0b10, 0b100) if bits(inst, 11, 7) != 0 && bits(inst, 6, 2) == 0 => {
+                [Some(reg(bits(inst, 11, 7)) & !1), None]
+            }
+            _ => [Some(next), None],
+        }
+    }
+}
+
+// The aux port if there is one
+fn port() -> usize {
+    if uart::is_present(AUX_PORT) {
+        AUX_PORT
+    } else {
+        CONSOLE_PORT
+    }
+}
+
+// Interrupts are off in the trap, the uart is polled
+fn read(port: usize) -> u8 {
+    loop {
+        if let Some(byte) = uart::read_port(port) {
+            return byte;
+        }
+        spin_loop();
+    }
+}
+
+// Wait for a packet with a good checksum, acknowledging it
+// Bytes outside packets, like acks and interrupt requests, are skipped
+fn receive(port: usize, buffer: &mut [u8]) -> usize {
+    loop {
+        while read(port) != b'$' {}
+        let (mut len, mut sum, mut overflow) = (0, 0u8, false);
+        loop {
+            let byte = read(port);
+            if byte == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(byte);
+            match buffer.get_mut(len) {
+                Some(slot) => *slot = byte,
+                None => overflow = true,
+            }
+            len += 1;
+        }
+        let checksum = hex_digit(read(port)).zip(hex_digit(read(port)));
+        let ack = match checksum {
+            Some((hi, lo)) if !overflow && hi << 4 | lo == sum => b'+',
+            _ => b'-',
+        };
+        if let Some(mut uart) = uart::get_port(port) {
+            uart.put(ack);
+        }
+        if ack == b'+' {
+            return len;
+        }
+    }
+}
+
+// Send data as a packet until the debugger acknowledges it
+fn send(port: usize, data: &[u8]) {
+    loop {
+        if let Some(mut uart) = uart::get_port(port) {
+            let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+            uart.put(b'$');
+            for &b in data {
+                uart.put(b);
+            }
+            let _ = write!(uart, "#{:02x}", sum);
+        }
+        loop {
+            match read(port) {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+// ====================================================
+// The public interface for the gdb stub is here...
+// ====================================================
+
+// Called from the breakpoint trap, serves the debugger until it resumes the
+// kernel and returns the pc to resume at
+pub fn enter(frame: &mut TrapFrame, epc: usize) -> usize {
+    let mut session = SESSION.lock_irq();
+    let Session { stub, input, reply } = &mut *session;
+    let port = port();
+    let mut pc = epc;
+    stub.end_step();
+    if stub.running {
+        stub.running = false;
+        send(port, STOPPED.as_bytes());
+    } else {
+        log::info!(
+            "gdb: stopped at 0x{:x}, waiting on serial port {}",
+            pc,
+            port
+        );
+    }
+    loop {
+        let len = receive(port, input);
+        match stub.handle(frame, &mut pc, &input[..len], reply) {
+            Action::Reply => send(port, reply.as_bytes()),
+            Action::Resume => return pc,
+            Action::Detach => {
+                send(port, reply.as_bytes());
+                return pc;
+            }
+        }
+    }
+}
+
+// Stop in the debugger during boot if config.rs asks for it
+pub fn init() {
+    if GDB_BREAK_AT_BOOT {
+        log::info!("gdb: waiting for the debugger");
+        assembly::trigger_breakpoint();
+    }
+}
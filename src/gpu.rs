@@ -0,0 +1,422 @@
+use crate::config::{PAGE_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::error::KError;
+use crate::log;
+use crate::print;
+use crate::virtio::{DmaGuard, Features, MmioDevice};
+use crate::virtqueue::{Segment, VirtQueue};
+use core::mem::size_of;
+
+// mod gpu.rs
+// A 2D virtio-gpu driver exposing a linear framebuffer
+// Commands are synchronous, the control queue is polled until the device answers
+
+static mut GPU_DEVICE: Option<GpuDevice> = None;
+
+const CONTROL_QUEUE: u32 = 0;
+const MAX_SCANOUTS: usize = 16;
+const RESOURCE_ID: u32 = 1;
+const SCANOUT_ID: u32 = 0;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const FORMAT_R8G8B8A8_UNORM: u32 = 67;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CtrlHeader {
+    ctrl_type: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHeader {
+    fn command(ctrl_type: u32) -> Self {
+        Self {
+            ctrl_type,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RespDisplayInfo {
+    header: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct AttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+#[repr(C)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pixel {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Pixel {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+}
+
+// A linear framebuffer of R8G8B8A8 pixels shared with the device
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    pixels: *mut Pixel,
+    width: u32,
+    height: u32,
+}
+
+impl Framebuffer {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: Pixel) {
+        if x < self.width && y < self.height {
+            unsafe {
+                self.pixels
+                    .add((y * self.width + x) as usize)
+                    .write_volatile(pixel)
+            }
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Option<Pixel> {
+        if x < self.width && y < self.height {
+            unsafe {
+                Some(
+                    self.pixels
+                        .add((y * self.width + x) as usize)
+                        .read_volatile(),
+                )
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, pixel: Pixel) {
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.set_pixel(x, y, pixel);
+            }
+        }
+    }
+
+    // Move the picture up by lines rows, the rows uncovered at the bottom are filled
+    pub fn scroll_up(&mut self, lines: u32, fill: Pixel) {
+        let lines = lines.min(self.height);
+        let kept = ((self.height - lines) * self.width) as usize;
+        unsafe {
+            core::ptr::copy(
+                self.pixels.add((lines * self.width) as usize),
+                self.pixels,
+                kept,
+            );
+        }
+        self.fill_rect(Rect::new(0, self.height - lines, self.width, lines), fill);
+    }
+
+    fn size(&self) -> usize {
+        (self.width * self.height) as usize * size_of::<Pixel>()
+    }
+}
+
+pub struct GpuDevice {
+    dev: DmaGuard,
+    control: VirtQueue,
+    framebuffer: Option<Framebuffer>,
+}
+
+impl GpuDevice {
+    fn init(ptr: *mut u32) -> Result<(), KError> {
+        log::info!("init gpu device");
+        let dev = MmioDevice::new(ptr)?;
+        dev.begin_init();
+        dev.negotiate(Features::NONE)?;
+        let control = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        dev.setup_queue(CONTROL_QUEUE, &control)?;
+        dev.driver_ok();
+
+        let mut gpu = GpuDevice {
+            dev: DmaGuard::new(dev),
+            control,
+            framebuffer: None,
+        };
+        gpu.init_display()?;
+        unsafe { GPU_DEVICE = Some(gpu) };
+        Ok(())
+    }
+
+    fn init_display(&mut self) -> Result<(), KError> {
+        let (width, height) = self.display_size();
+        // Memory behind the framebuffer
+        let dma = self
+            .dev
+            .alloc_dma((width * height) as usize * size_of::<Pixel>(), PAGE_SIZE)
+            .inspect_err(|_| print!("framebuffer alloc fail..."))?;
+        let pixels = dma.virt as *mut Pixel;
+        let fb = Framebuffer {
+            pixels,
+            width,
+            height,
+        };
+
+        let create = ResourceCreate2d {
+            header: CtrlHeader::command(CMD_RESOURCE_CREATE_2D),
+            resource_id: RESOURCE_ID,
+            format: FORMAT_R8G8B8A8_UNORM,
+            width,
+            height,
+        };
+        let attach = AttachBacking {
+            header: CtrlHeader::command(CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: RESOURCE_ID,
+            nr_entries: 1,
+        };
+        let entry = MemEntry {
+            addr: dma.phys,
+            length: fb.size() as u32,
+            padding: 0,
+        };
+        let scanout = SetScanout {
+            header: CtrlHeader::command(CMD_SET_SCANOUT),
+            rect: fb.bounds(),
+            scanout_id: SCANOUT_ID,
+            resource_id: RESOURCE_ID,
+        };
+        let ok =
+            self.command(&create) && self.command_with(&attach, &entry) && self.command(&scanout);
+        if !ok {
+            print!("display setup fail...");
+            return Err(KError::IoError);
+        }
+        self.framebuffer = Some(fb);
+        if self.flush(fb.bounds()) {
+            Ok(())
+        } else {
+            Err(KError::IoError)
+        }
+    }
+
+    // Size of the first enabled scanout, or the configured default
+    fn display_size(&mut self) -> (u32, u32) {
+        let request = CtrlHeader::command(CMD_GET_DISPLAY_INFO);
+        let mut response = RespDisplayInfo::default();
+        self.exchange(&[
+            Segment::readable(&request as *const _ as u64, size_of::<CtrlHeader>() as u32),
+            Segment::writable(
+                &mut response as *mut _ as u64,
+                size_of::<RespDisplayInfo>() as u32,
+            ),
+        ]);
+        if response.header.ctrl_type == RESP_OK_DISPLAY_INFO {
+            if let Some(mode) = response.pmodes.iter().find(|m| m.enabled != 0) {
+                return (mode.rect.width, mode.rect.height);
+            }
+        }
+        (SCREEN_WIDTH, SCREEN_HEIGHT)
+    }
+
+    // Submit a descriptor chain and poll the control queue until it completes
+    fn exchange(&mut self, segments: &[Segment]) {
+        let head = self.control.add_chain(segments);
+        self.control.submit(head);
+        self.dev.notify(CONTROL_QUEUE);
+        while !self.control.is_complete(head) {
+            self.control.pop_used();
+        }
+    }
+
+    fn command<T>(&mut self, request: &T) -> bool {
+        let mut response = CtrlHeader::default();
+        self.exchange(&[
+            Segment::readable(request as *const T as u64, size_of::<T>() as u32),
+            Segment::writable(
+                &mut response as *mut _ as u64,
+                size_of::<CtrlHeader>() as u32,
+            ),
+        ]);
+        Self::check(&response)
+    }
+
+    fn command_with<T, U>(&mut self, request: &T, payload: &U) -> bool {
+        let mut response = CtrlHeader::default();
+        self.exchange(&[
+            Segment::readable(request as *const T as u64, size_of::<T>() as u32),
+            Segment::readable(payload as *const U as u64, size_of::<U>() as u32),
+            Segment::writable(
+                &mut response as *mut _ as u64,
+                size_of::<CtrlHeader>() as u32,
+            ),
+        ]);
+        Self::check(&response)
+    }
+
+    fn check(response: &CtrlHeader) -> bool {
+        if response.ctrl_type != RESP_OK_NODATA {
+            log::error!("GPU command failed: 0x{:x}", response.ctrl_type);
+            return false;
+        }
+        true
+    }
+
+    // Copy a region of the framebuffer to the host and display it
+    fn flush(&mut self, rect: Rect) -> bool {
+        let fb = match self.framebuffer {
+            Some(fb) => fb,
+            None => return false,
+        };
+        let transfer = TransferToHost2d {
+            header: CtrlHeader::command(CMD_TRANSFER_TO_HOST_2D),
+            rect,
+            offset: ((rect.y * fb.width + rect.x) as usize * size_of::<Pixel>()) as u64,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        };
+        let flush = ResourceFlush {
+            header: CtrlHeader::command(CMD_RESOURCE_FLUSH),
+            rect,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        };
+        self.command(&transfer) && self.command(&flush)
+    }
+}
+
+// ====================================================
+// The public interface for the gpu device is here...
+// ====================================================
+
+// Called by virtio::init() when a gpu device is found
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn init(ptr: *mut u32) -> Result<(), KError> {
+    GpuDevice::init(ptr)
+}
+
+// Called by virtio::rescan() when the gpu device has gone away
+pub fn remove() {
+    unsafe { GPU_DEVICE = None };
+}
+
+// Completions are polled by the submitter, only acknowledge the interrupt
+pub fn interrupt_handler() {
+    unsafe {
+        if let Some(gpu) = GPU_DEVICE.as_ref() {
+            gpu.dev.ack_interrupt();
+        }
+    }
+}
+
+// A handle on the framebuffer if a display is available
+pub fn framebuffer() -> Option<Framebuffer> {
+    unsafe { GPU_DEVICE.as_ref().and_then(|gpu| gpu.framebuffer) }
+}
+
+// Push the given region of the framebuffer to the display
+pub fn flush_rect(rect: Rect) -> bool {
+    unsafe { GPU_DEVICE.as_mut().is_some_and(|gpu| gpu.flush(rect)) }
+}
+
+// Push the whole framebuffer to the display
+pub fn flush() -> bool {
+    match framebuffer() {
+        Some(fb) => flush_rect(fb.bounds()),
+        None => false,
+    }
+}
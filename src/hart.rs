@@ -0,0 +1,72 @@
+use crate::assembly;
+use crate::config::MAX_HARTS;
+use core::ptr::addr_of_mut;
+
+// mod hart.rs
+// Data local to each hart, found through the tp register
+// init points tp at the hart's block before anything else runs on it and tp
+// is not touched again. A block is only written by its own hart, others may
+// read it, e.g. for statistics
+// The trap entry in src/asm/supervisor.S reads the hart id at offset 0
+
+static mut HARTS: [HartLocal; MAX_HARTS] = [const { HartLocal::new() }; MAX_HARTS];
+
+#[repr(C)]
+pub struct HartLocal {
+    pub id: usize,
+    // Pid of the task running on this hart
+    pub current: usize,
+    // Traps being handled with interrupts enabled again, see trap::nested
+    pub nesting: usize,
+    // Critical sections entered, see irq::with_disabled
+    pub irq_depth: usize,
+    // Interrupts were enabled when the outermost section started
+    pub irq_enabled: bool,
+    pub interrupts: u64,
+    pub exceptions: u64,
+    // A syscall asked to yield, see trap::schedule_on_return
+    pub resched: bool,
+}
+
+impl HartLocal {
+    const fn new() -> Self {
+        Self {
+            id: 0,
+            current: 0,
+            nesting: 0,
+            irq_depth: 0,
+            irq_enabled: false,
+            interrupts: 0,
+            exceptions: 0,
+            resched: false,
+        }
+    }
+}
+
+// ====================================================
+// The public interface for hart local data is here...
+// ====================================================
+
+// Called first thing on every hart, with the id boot.S found in mhartid
+pub fn init(hart: usize) {
+    unsafe {
+        HARTS[hart] = HartLocal::new();
+        HARTS[hart].id = hart;
+        assembly::write_tp(addr_of_mut!(HARTS[hart]) as usize);
+    }
+}
+
+// The block of the hart running this code
+pub fn local() -> &'static mut HartLocal {
+    unsafe { &mut *(assembly::read_tp() as *mut HartLocal) }
+}
+
+// Id of the hart running this code
+pub fn id() -> usize {
+    local().id
+}
+
+// The block of any hart, for reading
+pub fn get(hart: usize) -> &'static HartLocal {
+    unsafe { &*addr_of_mut!(HARTS[hart]) }
+}
@@ -0,0 +1,230 @@
+use crate::config::{INPUT_EVENT_BUFFERS, INPUT_QUEUE_SIZE};
+use crate::error::KError;
+use crate::log;
+use crate::virtio::{DmaGuard, Features, MmioDevice};
+use crate::virtqueue::{Segment, VirtQueue};
+use crate::{print, println};
+use core::mem::size_of;
+
+// mod input.rs
+// A virtio-input driver for keyboards and pointing devices
+// Device events are translated into InputEvents and buffered until consumed
+
+const MAX_INPUT_DEVICES: usize = 4;
+const EVENT_QUEUE: u32 = 0;
+const STATUS_QUEUE: u32 = 1;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+
+const KEY_RELEASED: u32 = 0;
+
+static mut INPUT_DEVICES: [Option<InputDevice>; MAX_INPUT_DEVICES] = [None, None, None, None];
+static mut INPUT_QUEUE: EventQueue = EventQueue {
+    events: [InputEvent::KeyRelease(0); INPUT_QUEUE_SIZE],
+    head: 0,
+    tail: 0,
+    dropped: 0,
+};
+
+// The event layout written by the device
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct VirtioInputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+// Kernel representation of an input event
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputEvent {
+    KeyPress(u16),
+    KeyRelease(u16),
+    RelativeMotion { axis: u16, delta: i32 },
+    AbsolutePosition { axis: u16, value: u32 },
+}
+
+impl InputEvent {
+    // Translate a device event, sync markers and unknown types are dropped
+    pub fn translate(event: &VirtioInputEvent) -> Option<Self> {
+        match event.event_type {
+            EV_KEY if event.value == KEY_RELEASED => Some(InputEvent::KeyRelease(event.code)),
+            EV_KEY => Some(InputEvent::KeyPress(event.code)),
+            EV_REL => Some(InputEvent::RelativeMotion {
+                axis: event.code,
+                delta: event.value as i32,
+            }),
+            EV_ABS => Some(InputEvent::AbsolutePosition {
+                axis: event.code,
+                value: event.value,
+            }),
+            EV_SYN => None,
+            _ => None,
+        }
+    }
+}
+
+// Fixed size queue filled from interrupt context and drained by consumers
+struct EventQueue {
+    events: [InputEvent; INPUT_QUEUE_SIZE],
+    head: usize,
+    tail: usize,
+    dropped: usize,
+}
+
+impl EventQueue {
+    fn push(&mut self, event: InputEvent) {
+        let next = (self.tail + 1) % INPUT_QUEUE_SIZE;
+        if next == self.head {
+            self.dropped += 1;
+            return;
+        }
+        self.events[self.tail] = event;
+        self.tail = next;
+    }
+
+    fn pop(&mut self) -> Option<InputEvent> {
+        if self.head == self.tail {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % INPUT_QUEUE_SIZE;
+        Some(event)
+    }
+
+    fn len(&self) -> usize {
+        (self.tail + INPUT_QUEUE_SIZE - self.head) % INPUT_QUEUE_SIZE
+    }
+}
+
+pub struct InputDevice {
+    dev: DmaGuard,
+    events: VirtQueue,
+    // Kept so the device can later be sent LED state updates
+    #[allow(dead_code)]
+    status: VirtQueue,
+}
+
+impl InputDevice {
+    fn init(ptr: *mut u32) -> Result<(), KError> {
+        log::info!("init input device");
+        let slot = unsafe { INPUT_DEVICES.iter().position(|d| d.is_none()) };
+        let slot = match slot {
+            Some(s) => s,
+            None => {
+                print!("too many input devices...");
+                return Err(KError::Busy);
+            }
+        };
+        let dev = MmioDevice::new(ptr)?;
+        dev.begin_init();
+        dev.negotiate(Features::NONE)?;
+        let events = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        let status = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        dev.setup_queue(EVENT_QUEUE, &events)?;
+        dev.setup_queue(STATUS_QUEUE, &status)?;
+        let mut dev = DmaGuard::new(dev);
+        let Ok(buffers) = dev.alloc_bytes(INPUT_EVENT_BUFFERS * size_of::<VirtioInputEvent>())
+        else {
+            print!("event buffer alloc fail...");
+            dev.fail();
+            return Err(KError::NoMemory);
+        };
+
+        let mut input = InputDevice {
+            dev,
+            events,
+            status,
+        };
+        for i in 0..INPUT_EVENT_BUFFERS {
+            let event = unsafe { (buffers as *mut VirtioInputEvent).add(i) };
+            input.give_buffer(event as u64);
+        }
+        input.dev.driver_ok();
+        input.dev.notify(EVENT_QUEUE);
+        unsafe { INPUT_DEVICES[slot] = Some(input) };
+        Ok(())
+    }
+
+    // Hand an event buffer to the device to be filled
+    fn give_buffer(&mut self, addr: u64) {
+        let head = self.events.add_chain(&[Segment::writable(
+            addr,
+            size_of::<VirtioInputEvent>() as u32,
+        )]);
+        self.events.submit(head);
+    }
+
+    fn use_queue(&mut self) {
+        self.dev.ack_interrupt();
+        let mut recycled = false;
+        while let Some((head, _len)) = self.events.pop_used() {
+            let addr = self.events.descriptor_address(head);
+            let event = unsafe { (addr as *const VirtioInputEvent).read_volatile() };
+            if let Some(e) = InputEvent::translate(&event) {
+                unsafe { INPUT_QUEUE.push(e) };
+            }
+            self.give_buffer(addr);
+            recycled = true;
+        }
+        if recycled {
+            self.dev.notify(EVENT_QUEUE);
+        }
+    }
+}
+
+// ====================================================
+// The public interface for input devices is here...
+// ====================================================
+
+// Called by virtio::init() for every input device found
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn init(ptr: *mut u32) -> Result<(), KError> {
+    InputDevice::init(ptr)
+}
+
+// Called by virtio::rescan() when the input device at ptr has gone away
+pub fn remove(ptr: *mut u32) {
+    unsafe {
+        for slot in INPUT_DEVICES.iter_mut() {
+            if slot.as_ref().is_some_and(|input| input.dev.ptr() == ptr) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+// Called from virtio::interrupt_handler() for any input device interrupt
+pub fn interrupt_handler() {
+    unsafe {
+        for input in INPUT_DEVICES.iter_mut().flatten() {
+            input.use_queue();
+        }
+    }
+}
+
+// Take the oldest buffered input event
+pub fn next_event() -> Option<InputEvent> {
+    unsafe { INPUT_QUEUE.pop() }
+}
+
+// Number of buffered input events
+pub fn pending() -> usize {
+    unsafe { INPUT_QUEUE.len() }
+}
+
+// Number of events lost because the queue was full
+#[allow(dead_code)]
+pub fn dropped() -> usize {
+    unsafe { INPUT_QUEUE.dropped }
+}
+
+#[allow(dead_code)]
+pub fn debug_events() {
+    while let Some(event) = next_event() {
+        println!("{:?}", event);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::clint;
+use crate::config::{IPI_TIMEOUT_MS, MAX_HARTS};
+use crate::hart;
+use crate::log;
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::timer;
+
+// mod ipi.rs
+// Messages between harts on top of CLINT software interrupts
+// Every hart has a mailbox holding at most one request, a function to run and
+// its argument. The sender waits for the slot to be free, posts the request
+// and raises a software interrupt. The receiver runs the function from its
+// trap handler and acknowledges it, call() waits for that acknowledgement
+
+static MAILBOXES: [SpinLock<Mailbox>; MAX_HARTS] =
+    [const { SpinLock::new(Mailbox::new()) }; MAX_HARTS];
+
+#[derive(Clone, Copy)]
+struct Request {
+    func: fn(usize),
+    arg: usize,
+}
+
+struct Mailbox {
+    request: Option<Request>,
+    // Requests posted to and completed by this hart so far
+    posted: u64,
+    done: u64,
+}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Self {
+            request: None,
+            posted: 0,
+            done: 0,
+        }
+    }
+}
+
+// Put the request in hart's mailbox once it is free and interrupt the hart
+// Returns the sequence number to wait for, None if the mailbox stayed full
+fn deliver(hart: usize, request: Request, deadline: u64) -> Option<u64> {
+    loop {
+        {
+            let mut mailbox = MAILBOXES[hart].lock_irq();
+            if mailbox.request.is_none() {
+                mailbox.request = Some(request);
+                mailbox.posted += 1;
+                let seq = mailbox.posted;
+                drop(mailbox);
+                clint::send_ipi(hart);
+                return Some(seq);
+            }
+        }
+        if timer::now() >= deadline {
+            return None;
+        }
+    }
+}
+
+fn deadline() -> u64 {
+    timer::now() + timer::ms_to_ticks(IPI_TIMEOUT_MS)
+}
+
+// ====================================================
+// The public interface for ipi is here...
+// ====================================================
+
+// Run func(arg) on hart and wait until it has
+// Returns false if the hart is offline or did not answer within IPI_TIMEOUT_MS
+// Calling the current hart runs func right away
+pub fn call(hart: usize, func: fn(usize), arg: usize) -> bool {
+    if hart == hart::id() {
+        func(arg);
+        return true;
+    }
+    if !smp::is_online(hart) {
+        return false;
+    }
+    let deadline = deadline();
+    let seq = match deliver(hart, Request { func, arg }, deadline) {
+        Some(seq) => seq,
+        None => return false,
+    };
+    while MAILBOXES[hart].lock_irq().done < seq {
+        if timer::now() >= deadline {
+            log::error!("CPU#{} did not acknowledge IPI", hart);
+            return false;
+        }
+    }
+    true
+}
+
+// Ask hart to run func(arg) without waiting for it to happen
+// Returns false if the hart is offline or its mailbox stayed full
+#[allow(dead_code)]
+pub fn post(hart: usize, func: fn(usize), arg: usize) -> bool {
+    smp::is_online(hart) && deliver(hart, Request { func, arg }, deadline()).is_some()
+}
+
+// Called from the software interrupt handler
+// Runs the request waiting in hart's mailbox, false if there was none
+pub fn receive(hart: usize) -> bool {
+    let request = MAILBOXES[hart].lock_irq().request.take();
+    match request {
+        Some(request) => {
+            (request.func)(request.arg);
+            MAILBOXES[hart].lock_irq().done += 1;
+            true
+        }
+        None => false,
+    }
+}
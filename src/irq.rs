@@ -0,0 +1,49 @@
+use crate::assembly;
+use crate::hart;
+
+// mod irq.rs
+// Critical sections against the interrupt handlers of the current hart
+// with_disabled() keeps interrupts off while a closure runs and puts them
+// back the way it found them. Sections nest, only leaving the outermost one
+// can enable interrupts again, so code inside a section may call anything
+// that opens its own
+// Other harts are not kept out, state they share needs a lock too, see
+// SpinLock::lock_irq in src/sync.rs. Do not block or yield inside a section
+
+// ====================================================
+// The public interface for irq is here...
+// ====================================================
+
+// Enter a section, interrupts are disabled until the matching restore()
+pub fn disable() {
+    let enabled = assembly::interrupts_enabled();
+    assembly::disable_interrupts();
+    let local = hart::local();
+    if local.irq_depth == 0 {
+        local.irq_enabled = enabled;
+    }
+    local.irq_depth += 1;
+}
+
+// Leave a section, interrupts are enabled again when the outermost one ends
+// if they were enabled when it started
+pub fn restore() {
+    let local = hart::local();
+    local.irq_depth -= 1;
+    if local.irq_depth == 0 && local.irq_enabled {
+        assembly::enable_interrupts();
+    }
+}
+
+// Run f with interrupts disabled on this hart
+pub fn with_disabled<R>(f: impl FnOnce() -> R) -> R {
+    disable();
+    let result = f();
+    restore();
+    result
+}
+
+// Sections this hart is inside of
+pub fn depth() -> usize {
+    hart::local().irq_depth
+}
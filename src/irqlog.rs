@@ -0,0 +1,151 @@
+use crate::config::IRQ_LOG_SIZE;
+use crate::timer::{self, ms_to_ticks, now};
+use crate::{print, println};
+
+// mod irqlog.rs
+// Records time-stamped interrupt events for a window so tests can assert on
+// interrupt behavior instead of relying on visual inspection of trap prints.
+// Records are dumped in a line based protocol: "IRQ <mtime> <source> <id>"
+
+static mut IRQ_LOG: IrqLog = IrqLog {
+    records: [IrqRecord {
+        time: 0,
+        source: IrqSource::Timer,
+    }; IRQ_LOG_SIZE],
+    len: 0,
+    recording: false,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum IrqSource {
+    Timer,
+    Software,
+    External(u32),
+    BlockSubmit,
+}
+
+impl IrqSource {
+    fn label(&self) -> (&'static str, u32) {
+        match *self {
+            IrqSource::Timer => ("timer", 0),
+            IrqSource::Software => ("software", 0),
+            IrqSource::External(id) => ("external", id),
+            IrqSource::BlockSubmit => ("block-submit", 0),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct IrqRecord {
+    time: u64,
+    source: IrqSource,
+}
+
+struct IrqLog {
+    records: [IrqRecord; IRQ_LOG_SIZE],
+    len: usize,
+    recording: bool,
+}
+
+impl IrqLog {
+    fn push(&mut self, source: IrqSource) {
+        if self.recording && self.len < IRQ_LOG_SIZE {
+            self.records[self.len] = IrqRecord {
+                time: now(),
+                source,
+            };
+            self.len += 1;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &IrqRecord> {
+        self.records[..self.len].iter()
+    }
+}
+
+// Clear the log and begin recording
+pub fn start() {
+    unsafe {
+        IRQ_LOG.len = 0;
+        IRQ_LOG.recording = true;
+    }
+}
+
+// Stop recording, the log is kept for inspection
+pub fn stop() {
+    unsafe { IRQ_LOG.recording = false };
+}
+
+// Called from the trap and driver paths, cheap when not recording
+pub fn record(source: IrqSource) {
+    unsafe { IRQ_LOG.push(source) };
+}
+
+// Number of recorded events from source
+pub fn count(source: IrqSource) -> usize {
+    unsafe { IRQ_LOG.iter().filter(|r| r.source == source).count() }
+}
+
+// Time between the first `from` event and the first `to` event after it
+pub fn latency(from: IrqSource, to: IrqSource) -> Option<u64> {
+    unsafe {
+        let start = IRQ_LOG.iter().find(|r| r.source == from)?.time;
+        let end = IRQ_LOG
+            .iter()
+            .find(|r| r.source == to && r.time >= start)?
+            .time;
+        Some(end - start)
+    }
+}
+
+// Busy wait while recording for the given window in milliseconds
+pub fn record_for(ms: u64) {
+    start();
+    timer::delay_ms(ms);
+    stop();
+}
+
+// Assert at least `min` events from source were recorded
+pub fn assert_at_least(source: IrqSource, min: usize) {
+    let seen = count(source);
+    if seen < min {
+        dump();
+        panic!(
+            "Expected at least {} {} events, saw {}",
+            min,
+            source.label().0,
+            seen
+        );
+    }
+}
+
+// Assert `to` followed `from` within `ms` milliseconds
+pub fn assert_within(from: IrqSource, to: IrqSource, ms: u64) {
+    match latency(from, to) {
+        Some(ticks) if ticks <= ms_to_ticks(ms) => {}
+        Some(ticks) => {
+            dump();
+            panic!(
+                "Expected {} within {}ms of {}, took {} ticks",
+                to.label().0,
+                ms,
+                from.label().0,
+                ticks
+            );
+        }
+        None => {
+            dump();
+            panic!("Expected {} after {}", to.label().0, from.label().0);
+        }
+    }
+}
+
+// Print every record for host side parsing
+pub fn dump() {
+    unsafe {
+        for r in IRQ_LOG.iter() {
+            let (name, id) = r.source.label();
+            println!("IRQ {} {} {}", r.time, name, id);
+        }
+    }
+}
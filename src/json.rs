@@ -0,0 +1,113 @@
+use crate::print;
+
+// mod json.rs
+// A minimal streaming JSON writer that prints straight to the serial console
+// Intended for host side tooling that parses kernel state from the serial output
+
+const MAX_DEPTH: usize = 64;
+
+pub struct JsonWriter {
+    depth: usize,
+    // One bit per nesting level, set once the level holds at least one item
+    has_items: u64,
+}
+
+impl JsonWriter {
+    pub fn new() -> Self {
+        Self {
+            depth: 0,
+            has_items: 0,
+        }
+    }
+
+    fn separator(&mut self, key: Option<&str>) {
+        let bit = 1 << self.depth;
+        if self.has_items & bit != 0 {
+            print!(",");
+        }
+        self.has_items |= bit;
+        if let Some(k) = key {
+            Self::escaped(k);
+            print!(":");
+        }
+    }
+
+    fn escaped(text: &str) {
+        print!("\"");
+        for c in text.chars() {
+            match c {
+                '"' => print!("\\\""),
+                '\\' => print!("\\\\"),
+                '\n' => print!("\\n"),
+                '\r' => print!("\\r"),
+                '\t' => print!("\\t"),
+                c if (c as u32) < 0x20 => print!("\\u{:04x}", c as u32),
+                c => print!("{}", c),
+            }
+        }
+        print!("\"");
+    }
+
+    fn open(&mut self, key: Option<&str>, bracket: char) {
+        assert!(self.depth + 1 < MAX_DEPTH);
+        self.separator(key);
+        print!("{}", bracket);
+        self.depth += 1;
+        self.has_items &= !(1 << self.depth);
+    }
+
+    fn close(&mut self, bracket: char) {
+        assert!(self.depth > 0);
+        self.depth -= 1;
+        print!("{}", bracket);
+    }
+
+    pub fn begin_object(&mut self, key: Option<&str>) {
+        self.open(key, '{');
+    }
+
+    pub fn end_object(&mut self) {
+        self.close('}');
+    }
+
+    pub fn begin_array(&mut self, key: Option<&str>) {
+        self.open(key, '[');
+    }
+
+    pub fn end_array(&mut self) {
+        self.close(']');
+    }
+
+    pub fn string(&mut self, key: Option<&str>, value: &str) {
+        self.separator(key);
+        Self::escaped(value);
+    }
+
+    pub fn number(&mut self, key: Option<&str>, value: usize) {
+        self.separator(key);
+        print!("{}", value);
+    }
+
+    pub fn signed(&mut self, key: Option<&str>, value: isize) {
+        self.separator(key);
+        print!("{}", value);
+    }
+
+    #[allow(dead_code)]
+    pub fn boolean(&mut self, key: Option<&str>, value: bool) {
+        self.separator(key);
+        print!("{}", value);
+    }
+
+    // Terminate the document with a line break so each document is one line
+    pub fn finish(self) {
+        assert!(self.depth == 0);
+        print!("\r\n");
+    }
+}
+
+impl Default for JsonWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
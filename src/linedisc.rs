@@ -0,0 +1,189 @@
+use crate::config::CONSOLE_HISTORY;
+use crate::console;
+use crate::sync::SpinLock;
+use crate::uart;
+use core::fmt::Write;
+use rust_alloc::collections::VecDeque;
+use rust_alloc::vec::Vec;
+
+// mod linedisc.rs
+// Line editing for console input, as typed over qemu -serial stdio
+// Supports backspace, Ctrl-U to kill the line, the left and right arrows to
+// move the cursor and the up and down arrows to step through the last
+// CONSOLE_HISTORY lines entered. Edits are echoed with plain backspaces and
+// reprinting, which any terminal understands
+
+const BACKSPACE: u8 = 8;
+const DELETE: u8 = 127;
+const CTRL_U: u8 = 21;
+const ESCAPE: u8 = 27;
+
+// Lines entered, the newest at the back
+static HISTORY: SpinLock<VecDeque<Vec<u8>>> = SpinLock::new(VecDeque::new());
+
+// Where in an ANSI escape sequence the input is
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Sequence {
+    None,
+    Escape,
+    Csi,
+}
+
+// The line being edited in buffer, bytes that do not fit are dropped
+pub struct LineEditor<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    cursor: usize,
+    escape: Sequence,
+    // History entries stepped back, 0 while editing a new line
+    recalled: usize,
+}
+
+impl<'a> LineEditor<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            len: 0,
+            cursor: 0,
+            escape: Sequence::None,
+            recalled: 0,
+        }
+    }
+
+    pub fn line(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    // Handle one input byte, echoing to out, true once the line is complete
+    pub fn feed(&mut self, byte: u8, out: &mut impl Write) -> bool {
+        match (self.escape, byte) {
+            (Sequence::None, ESCAPE) => self.escape = Sequence::Escape,
+            (Sequence::Escape, b'[') => self.escape = Sequence::Csi,
+            (Sequence::Csi, b'0'..=b'9' | b';') => {}
+            (Sequence::Csi, _) => {
+                self.escape = Sequence::None;
+                match byte {
+                    b'A' => self.recall(self.recalled + 1, out),
+                    b'B' if self.recalled > 0 => self.recall(self.recalled - 1, out),
+                    b'C' if self.cursor < self.len => {
+                        let _ = out.write_char(self.buffer[self.cursor] as char);
+                        self.cursor += 1;
+                    }
+                    b'D' if self.cursor > 0 => {
+                        self.cursor -= 1;
+                        back(out, 1);
+                    }
+                    _ => {}
+                }
+            }
+            (Sequence::Escape, _) => self.escape = Sequence::None,
+            (Sequence::None, b'\r' | b'\n') => {
+                let _ = out.write_str("\r\n");
+                remember(self.line());
+                return true;
+            }
+            (Sequence::None, BACKSPACE | DELETE) if self.cursor > 0 => {
+                self.buffer
+                    .copy_within(self.cursor..self.len, self.cursor - 1);
+                self.cursor -= 1;
+                self.len -= 1;
+                back(out, 1);
+                self.redraw_tail(out, 1);
+            }
+            (Sequence::None, CTRL_U) => self.replace(&[], out),
+            (Sequence::None, c)
+                if (c.is_ascii_graphic() || c == b' ') && self.len < self.buffer.len() =>
+            {
+                self.buffer
+                    .copy_within(self.cursor..self.len, self.cursor + 1);
+                self.buffer[self.cursor] = c;
+                self.len += 1;
+                let _ = out.write_char(c as char);
+                self.cursor += 1;
+                self.redraw_tail(out, 0);
+            }
+            _ => {}
+        }
+        false
+    }
+
+    // Print the line from the cursor on, blank erased columns after it and
+    // move back to the cursor
+    fn redraw_tail(&self, out: &mut impl Write, erased: usize) {
+        for &c in &self.buffer[self.cursor..self.len] {
+            let _ = out.write_char(c as char);
+        }
+        for _ in 0..erased {
+            let _ = out.write_char(' ');
+        }
+        back(out, self.len - self.cursor + erased);
+    }
+
+    // Swap the whole line for text, the cursor ends up after it
+    fn replace(&mut self, text: &[u8], out: &mut impl Write) {
+        back(out, self.cursor);
+        let erased = self.len;
+        let len = text.len().min(self.buffer.len());
+        self.buffer[..len].copy_from_slice(&text[..len]);
+        self.len = len;
+        self.cursor = 0;
+        self.redraw_tail(out, erased.saturating_sub(len));
+        for &c in &self.buffer[..len] {
+            let _ = out.write_char(c as char);
+        }
+        self.cursor = len;
+    }
+
+    // Show the history entry steps back from the newest, 0 is an empty line
+    fn recall(&mut self, steps: usize, out: &mut impl Write) {
+        let entry = {
+            let history = HISTORY.lock();
+            if steps > history.len() {
+                return;
+            }
+            match steps {
+                0 => Vec::new(),
+                _ => history[history.len() - steps].clone(),
+            }
+        };
+        self.recalled = steps;
+        self.replace(&entry, out);
+    }
+}
+
+fn back(out: &mut impl Write, columns: usize) {
+    for _ in 0..columns {
+        let _ = out.write_char(BACKSPACE as char);
+    }
+}
+
+// Add a line to the history, skipping empty lines and repeats
+fn remember(line: &[u8]) {
+    let mut history = HISTORY.lock();
+    if line.is_empty() || history.back().is_some_and(|last| last == line) {
+        return;
+    }
+    if history.len() == CONSOLE_HISTORY {
+        history.pop_front();
+    }
+    history.push_back(line.to_vec());
+}
+
+// ====================================================
+// The public interface for linedisc is here...
+// ====================================================
+
+// Read an edited line from the uart into buffer, returns its length
+// Waits for the end of the line
+#[allow(dead_code)]
+pub fn read_line(buffer: &mut [u8]) -> usize {
+    let mut editor = LineEditor::new(buffer);
+    while !editor.feed(uart::wait_byte(), &mut console::get_console()) {}
+    editor.len
+}
+
+// Lines in the history, the newest last
+#[allow(dead_code)]
+pub fn history() -> Vec<Vec<u8>> {
+    HISTORY.lock().iter().cloned().collect()
+}
@@ -0,0 +1,137 @@
+use crate::config::{
+    DEBUG, ERROR, INFO, LOG_HART, LOG_LEVEL, LOG_LEVEL_MAX, LOG_TO_AUX, LOG_UPTIME, TRACE, WARN,
+};
+use crate::console::styled;
+use crate::hart;
+use crate::time;
+use crate::uart::{self, AUX_PORT};
+use crate::{print, println};
+use core::fmt::{Arguments, Display, Formatter, Result, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// mod log.rs
+// Leveled kernel logging, log::error!, warn!, info!, debug! and trace! take
+// the same arguments as println!
+// Messages above LOG_LEVEL_MAX in config.rs are compiled out, the others print
+// if they are within the runtime level, which starts at LOG_LEVEL and can be
+// changed with set_level(), e.g. to see driver traces for a while
+// Lines start with the uptime and the hart logging, see LOG_UPTIME and
+// LOG_HART, to line up console captures with interrupt activity and I/O
+// With LOG_TO_AUX they go to the aux serial port, away from the console
+// Boot stage headers and test results (serial_step, serial_test) are not log
+// messages and always print
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => ERROR,
+            Level::Warn => WARN,
+            Level::Info => INFO,
+            Level::Debug => DEBUG,
+            Level::Trace => TRACE,
+        }
+    }
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(LOG_LEVEL as usize);
+
+// What goes in front of the level label
+struct Prefix;
+
+impl Display for Prefix {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        if LOG_UPTIME {
+            let ms = time::monotonic().as_millis();
+            write!(f, "[{:>5}.{:03}] ", ms / 1000, ms % 1000)?;
+        }
+        if LOG_HART {
+            write!(f, "CPU#{} ", hart::id())?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! log {
+    ($level:expr, $($args:tt)+) => ({
+        if ($level as usize) <= ($crate::config::LOG_LEVEL_MAX as usize) {
+            $crate::log::record($level, format_args!($($args)+));
+        }
+    });
+}
+
+macro_rules! error {
+    ($($args:tt)+) => ($crate::log::log!($crate::log::Level::Error, $($args)+));
+}
+
+// Named warn when exported, a macro_rules! warn would clash with the warn
+// lint attribute
+macro_rules! warning {
+    ($($args:tt)+) => ($crate::log::log!($crate::log::Level::Warn, $($args)+));
+}
+
+macro_rules! info {
+    ($($args:tt)+) => ($crate::log::log!($crate::log::Level::Info, $($args)+));
+}
+
+macro_rules! debug {
+    ($($args:tt)+) => ($crate::log::log!($crate::log::Level::Debug, $($args)+));
+}
+
+macro_rules! trace {
+    ($($args:tt)+) => ($crate::log::log!($crate::log::Level::Trace, $($args)+));
+}
+
+#[allow(unused_imports)]
+pub(crate) use {debug, error, info, log, trace, warning as warn};
+
+// ====================================================
+// The public interface for log is here...
+// ====================================================
+
+// Print a message at level, used by the macros above
+pub fn record(level: Level, args: Arguments) {
+    if enabled(level) {
+        match LOG_TO_AUX.then(|| uart::get_port(AUX_PORT)).flatten() {
+            Some(mut aux) => {
+                let _ = write!(aux, "  {}{} {}\r\n", Prefix, styled(level.label()), args);
+            }
+            None => println!("  {}{} {}", Prefix, styled(level.label()), args),
+        }
+    }
+}
+
+// Messages at level print
+pub fn enabled(level: Level) -> bool {
+    level <= LOG_LEVEL_MAX && level <= self::level()
+}
+
+// The runtime level
+pub fn level() -> Level {
+    let level = LEVEL.load(Ordering::Relaxed);
+    Level::ALL
+        .into_iter()
+        .find(|&l| l as usize == level)
+        .unwrap_or(LOG_LEVEL)
+}
+
+// Print messages up to level from now on, those above LOG_LEVEL_MAX stay out
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
@@ -0,0 +1,99 @@
+use crate::block::BlockDriver;
+use crate::error::KError;
+use crate::log;
+use crate::minixfs3::{Inode, MinixFileSystem};
+
+// mod loopdev.rs
+// A loop device exposing a regular file on the mounted filesystem as a block device
+// Writes are rejected until the filesystem driver supports writing
+
+static mut LOOP_DEVICE: Option<LoopDevice> = None;
+
+pub struct LoopDevice {
+    inode: Inode,
+}
+
+impl LoopDevice {
+    fn in_bounds(&self, size: u32, offset: u64) -> bool {
+        offset + size as u64 <= self.capacity()
+    }
+}
+
+impl BlockDriver for LoopDevice {
+    fn read(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+        if !self.in_bounds(size, offset) {
+            log::error!("Loop device read out of bounds @ 0x{:x}", offset);
+            return Err(KError::InvalidArgument);
+        }
+        MinixFileSystem::read(&self.inode, buffer, size, offset as u32)?;
+        Ok(())
+    }
+
+    fn write(&mut self, _buffer: *mut u8, _size: u32, _offset: u64) -> Result<(), KError> {
+        log::warn!("Trying to write to read/only loop device!");
+        Err(KError::ReadOnly)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.inode.size as u64
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+// ====================================================
+// The public interface for the loop device is here...
+// ====================================================
+
+// Back the loop device with the file at the given absolute path
+pub fn attach(file_name: &str) -> Result<(), KError> {
+    log::info!("attach loop device");
+    match MinixFileSystem::lookup(file_name) {
+        Ok(inode) => {
+            unsafe { LOOP_DEVICE = Some(LoopDevice { inode }) };
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Unable to find '{}' for loop device", file_name);
+            Err(e)
+        }
+    }
+}
+
+pub fn detach() {
+    unsafe { LOOP_DEVICE = None };
+}
+
+// Read data from the backing file to buffer
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn read(buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+    unsafe {
+        if let Some(ld) = LOOP_DEVICE.as_mut() {
+            ld.read(buffer, size, offset)
+        } else {
+            log::error!("Unable to retrieve loop device");
+            Err(KError::NoDevice)
+        }
+    }
+}
+
+// Write data from buffer to the backing file
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[allow(dead_code)]
+pub fn write(buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+    unsafe {
+        if let Some(ld) = LOOP_DEVICE.as_mut() {
+            ld.write(buffer, size, offset)
+        } else {
+            log::error!("Unable to retrieve loop device");
+            Err(KError::NoDevice)
+        }
+    }
+}
+
+// Capacity of the loop device in bytes
+pub fn capacity() -> u64 {
+    unsafe { LOOP_DEVICE.as_ref().map_or(0, |ld| ld.capacity()) }
+}
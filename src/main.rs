@@ -7,20 +7,68 @@
 #![feature(panic_info_message, alloc_error_handler, lang_items)]
 
 // Project Rust Modules
+mod addrspace;
 mod alloc;
 mod assembly;
+mod atomics;
 mod block;
+mod bootargs;
 mod buffer;
+mod clint;
 mod config;
+mod console;
 mod debug;
+mod error;
+mod fbcon;
+mod fdt;
+mod font;
+#[cfg(feature = "gdbstub")]
+mod gdbstub;
+mod gpu;
+mod hart;
+mod input;
+mod ipi;
+mod irq;
+mod irqlog;
+mod json;
+mod linedisc;
+mod log;
+mod loopdev;
 mod memory;
 mod minixfs3;
+#[cfg(feature = "debug-monitor")]
+mod monitor;
+mod p9;
+mod paging;
+mod pci;
+mod platform;
 mod plic;
+mod power;
+mod process;
+mod ramdisk;
+mod random;
+mod rng;
+#[cfg(feature = "sbi")]
+mod sbi;
+mod sched;
+mod slab;
+mod smp;
+mod stack;
+mod sync;
+mod syscall;
 #[allow(unused_imports)]
 mod test;
+mod time;
+mod timer;
+mod trace;
 mod trap;
 mod uart;
+mod vconsole;
+mod vfs;
 mod virtio;
+mod virtqueue;
+mod waitqueue;
+mod watchdog;
 
 use crate::uart::serial_step;
 
@@ -34,7 +82,7 @@ macro_rules! print
 {
     ($($args:tt)+) => ({
             use core::fmt::Write;
-                let _ = write!($crate::uart::get_uart(), $($args)+);
+                let _ = write!($crate::console::get_console(), $($args)+);
             });
 }
 #[macro_export]
@@ -52,18 +100,35 @@ macro_rules! println
 }
 
 #[panic_handler]
+// Writes through the uart's emergency path, the console may be held by the
+// code that panicked or not be set up yet
+// Ends with the registers of the trap that panicked, or the trap CSRs
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    print!("Aborting: ");
+    use core::fmt::Write;
+    let mut out = uart::emergency();
+    if !stack::is_intact() {
+        let _ = write!(out, "kernel stack overflow\r\n");
+    }
+    let _ = write!(out, "Aborting: ");
     if let Some(p) = info.location() {
-        println!(
-            "line {}, file {}: {}",
+        let _ = write!(
+            out,
+            "line {}, file {}: {}\r\n",
             p.line(),
             p.file(),
             info.message().unwrap()
         );
     } else {
-        println!("no information available.");
+        let _ = write!(out, "no information available.\r\n");
     }
+    // A test declared to panic passes and its task ends in here
+    #[cfg(feature = "test-suite")]
+    test::panicked(&mut out);
+    let _ = trap::dump_registers(&mut out);
+    // A failed test ends the run, scripts see the exit code
+    #[cfg(feature = "test-suite")]
+    power::halt(config::TEST_FAILURE_EXIT_CODE);
+    #[cfg(not(feature = "test-suite"))]
     abort();
 }
 #[no_mangle]
@@ -75,30 +140,60 @@ extern "C" fn abort() -> ! {
 
 #[no_mangle]
 // Interrupts are disabled here...
-extern "C" fn kernel_init() {
+extern "C" fn kernel_init(dtb: usize) {
+    hart::init(0); // Hart local data through tp
     uart::init(); // Kick off UART for debugging
+    trap::init(0); // Interrupt stack for the boot hart
+    #[cfg(feature = "sbi")]
+    sbi::init(); // Firmware extensions, starts the timer
+    fdt::init(dtb); // Device tree passed in by the firmware
+    time::init(); // Timebase frequency from the device tree
+    bootargs::init(); // Kernel command line from the device tree
+    memory::init(); // Size RAM from the device tree
     alloc::init(); // Kernel Memory Allocator
     plic::init(); // Platform level interrupt controller
+    clint::init(); // Core local interruptor
+    uart::probe(); // Serial ports listed in the device tree
+    pci::init(); // Enumerate the PCIe bus, assign BARs and route INTx
+    virtio::discover(); // Find virtio devices and register their interrupts
     virtio::init(); // Virtio driver
+    random::init(); // Seed the kernel's generator, from virtio-rng if present
+    fbcon::init(); // Text console on the framebuffer, if config.rs asks for one
+    paging::init(); // Kernel identity mapping
+    smp::init(); // Wake the secondary harts
+
+    #[cfg(feature = "supervisor")]
+    assembly::enter_supervisor(kernel_main as extern "C" fn() as usize); // Continue in kernel_main in S-mode
 }
 
 #[no_mangle]
 // Interrupts are enabled here...
 extern "C" fn kernel_main() {
+    process::init(); // Adopt the boot context as task 0
     minixfs3::init(); // Initialize fs cache
+    vfs::init(); // Mount filesystems
+    #[cfg(feature = "gdbstub")]
+    gdbstub::init(); // Wait for the debugger, if config.rs asks to
     
     #[cfg(feature = "test-suite")]
     test::run();
 
     #[cfg(feature = "debug-full")]{
         debug::heap();
+        debug::traps();
+        debug::tasks();
         debug::fs_cache();
         debug::fs();
     }
-    serial_step("Booted successfully!\n");
-    shutdown();
-}
 
-fn shutdown(){
-    assembly::trigger_shutdown();
+    #[cfg(feature = "debug-json")]
+    {
+        debug::heap_json();
+        debug::iostat_json();
+        debug::devices_json();
+        debug::tasks_json();
+        debug::fs_cache_json();
+    }
+    serial_step("Booted successfully!\n");
+    power::shutdown(0);
 }
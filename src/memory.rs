@@ -1,21 +1,145 @@
+use crate::config::{BOARD, PAGE_SIZE};
+use crate::fdt;
+use crate::log;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 // Collection of helpers pertaining to memory manipulations
+// The end of RAM comes from the device tree, the board's RAM is only the
+// fallback without one
+
+// Symbols defined in cfg/link.ld & asm/layout.S
+extern "C" {
+    static TEXT_START: usize;
+    static DATA_START: usize;
+    static HEAP_START: usize;
+}
+
+// End of the RAM the kernel uses, 0 until init() reads it from the device tree
+static END: AtomicUsize = AtomicUsize::new(0);
 
 pub const fn align_val(val: usize, order: usize) -> usize {
     let o = (1usize << order) - 1;
     (val + o) & !o
 }
 
-pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
-    let bytes_as_8 = bytes / 8;
-    let dest_as_8 = dest as *mut u64;
-    let src_as_8 = src as *const u64;
+// True if addr..addr + len lies in the RAM the kernel maps, from its text to
+// the end of memory. Accesses anywhere else may fault
+pub fn is_kernel_memory(addr: usize, len: usize) -> bool {
+    let (start, end) = (unsafe { TEXT_START }, self::end());
+    addr >= start && addr.checked_add(len).is_some_and(|last| last <= end)
+}
+
+// The same for writes, under --features "supervisor" the text and rodata are
+// mapped read only
+pub fn is_kernel_writable(addr: usize, len: usize) -> bool {
+    let start = if cfg!(feature = "supervisor") {
+        unsafe { DATA_START }
+    } else {
+        unsafe { TEXT_START }
+    };
+    addr >= start && is_kernel_memory(addr, len)
+}
+
+// Size RAM from the memory node of the device tree, so QEMU's -m is honoured
+// The DTB is left near the top of RAM, usable memory stops below it
+// Must run after fdt::init and before alloc::init, the heap reaches to end()
+pub fn init() {
+    let heap = unsafe { HEAP_START };
+    let Some((start, size)) = fdt::memory() else {
+        log::warn!("no memory node, RAM assumed to end at 0x{:x}", end());
+        return;
+    };
+    let mut usable = start + size;
+    if let Some((dtb, _)) = fdt::range().filter(|&(dtb, _)| (heap..usable).contains(&dtb)) {
+        usable = dtb & !(PAGE_SIZE - 1);
+    }
+    if usable <= heap {
+        log::error!("RAM ends at 0x{:x}, below the kernel heap", usable);
+        return;
+    }
+    END.store(usable, Ordering::Relaxed);
+    log::info!(
+        "RAM 0x{:x}-0x{:x}, usable up to 0x{:x}",
+        start,
+        start + size,
+        usable
+    );
+}
+
+// End of the RAM the kernel maps and allocates from
+pub fn end() -> usize {
+    match END.load(Ordering::Relaxed) {
+        0 => BOARD.ram_end(),
+        end => end,
+    }
+}
+
+// True if both addresses sit at the same offset within a u64
+fn same_word_offset(a: usize, b: usize) -> bool {
+    (a ^ b) & 7 == 0
+}
 
-    for i in 0..bytes_as_8 {
-        *(dest_as_8.add(i)) = *(src_as_8.add(i));
+// Copy bytes from src to dest, the ranges must not overlap
+// Whole words are copied when both pointers share the same offset within a word,
+// anything else falls back to bytes so no unaligned accesses are made
+pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
+    let mut i = 0;
+    if same_word_offset(dest as usize, src as usize) {
+        while i < bytes && (dest as usize + i) & 7 != 0 {
+            *(dest.add(i)) = *(src.add(i));
+            i += 1;
+        }
+        while i + 8 <= bytes {
+            *(dest.add(i) as *mut u64) = *(src.add(i) as *const u64);
+            i += 8;
+        }
     }
-    let bytes_completed = bytes_as_8 * 8;
-    let bytes_remaining = bytes - bytes_completed;
-    for i in bytes_completed..bytes_remaining {
+    while i < bytes {
         *(dest.add(i)) = *(src.add(i));
+        i += 1;
+    }
+}
+
+// Fill bytes at dest with val, whole words at a time once dest is aligned
+pub unsafe fn memset(dest: *mut u8, val: u8, bytes: usize) {
+    let word = u64::from_ne_bytes([val; 8]);
+    let mut i = 0;
+    while i < bytes && (dest as usize + i) & 7 != 0 {
+        *(dest.add(i)) = val;
+        i += 1;
+    }
+    while i + 8 <= bytes {
+        *(dest.add(i) as *mut u64) = word;
+        i += 8;
+    }
+    while i < bytes {
+        *(dest.add(i)) = val;
+        i += 1;
+    }
+}
+
+// Copy bytes from src to dest where the two ranges may overlap
+// Copies backwards when dest lies inside the source range
+pub unsafe fn memmove(dest: *mut u8, src: *const u8, bytes: usize) {
+    let (d, s) = (dest as usize, src as usize);
+    if d <= s || d >= s + bytes {
+        // Copying forwards never reads a byte it has already overwritten
+        memcpy(dest, src, bytes);
+    } else {
+        let mut i = bytes;
+        if same_word_offset(d, s) {
+            while i > 0 && (d + i) & 7 != 0 {
+                i -= 1;
+                *(dest.add(i)) = *(src.add(i));
+            }
+            while i >= 8 {
+                i -= 8;
+                *(dest.add(i) as *mut u64) = *(src.add(i) as *const u64);
+            }
+        }
+        while i > 0 {
+            i -= 1;
+            *(dest.add(i)) = *(src.add(i));
+        }
     }
 }
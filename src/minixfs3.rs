@@ -1,10 +1,13 @@
 use crate::block;
-use crate::buffer::Buffer;
+use crate::buffer::{self, Buffer, DynBuffer, PooledBuffer};
+use crate::error::KError;
+use crate::log;
 use crate::memory::memcpy;
-use crate::uart::serial_debug;
+use crate::sched;
+use crate::sync::SpinLock;
 use crate::{print, println};
 use core::mem::size_of;
-use rust_alloc::{collections::BTreeMap, string::String};
+use rust_alloc::{collections::BTreeMap, string::String, vec::Vec};
 
 const MAGIC: u16 = 0x4d5a;
 const ROOT_NODE: u32 = 1;
@@ -13,7 +16,9 @@ const FILE_NAME_SIZE: usize = 60;
 const SECTOR_SIZE: usize = 512;
 pub const BLOCK_SIZE: u32 = 1024;
 const PTR_INDEX_MAX: usize = BLOCK_SIZE as usize / 4;
+const S_IFMT: u16 = 0o170_000;
 const S_IFDIR: u16 = 0o040_000;
+const S_IFLNK: u16 = 0o120_000;
 const DIRECT_ZONES: usize = 7;
 const INDIRECT_ZONE: usize = 7;
 const DOUBLE_INDIRECT_ZONE: usize = 8;
@@ -54,24 +59,27 @@ impl SuperBlock {
         (inode_num as usize - 1) % (BLOCK_SIZE as usize / size_of::<Inode>())
     }
 
-    fn inode_offset_and_index(&self, inode_num: u32) -> (usize, usize) {
+    // Byte offset of the block holding the inode and its index in that block
+    pub fn inode_offset_and_index(&self, inode_num: u32) -> (usize, usize) {
         let offset = self.blocks_first_four_areas() * BLOCK_SIZE as usize
             + self.inode_offset(inode_num) * BLOCK_SIZE as usize;
         let index = self.inode_index(inode_num);
         (offset, index)
     }
 
-    fn get_inode(&self, inode_num: u32) -> Option<Inode> {
-        if self.is_minixfs() {
-            let (inode_offset, inode_index) = self.inode_offset_and_index(inode_num);
-            let mut inode_buffer = Buffer::default();
-            let inode_ptr = inode_buffer.get_mut() as *mut Inode;
-            block::read(inode_buffer.get_mut(), BLOCK_SIZE, inode_offset as u64);
-            unsafe { Some(*(inode_ptr.add(inode_index))) }
-        } else {
-            println!("WARNING: Couldn't read superblock as expected");
-            None
+    // Unsupported if the disk holds no minix filesystem
+    fn get_inode(&self, inode_num: u32) -> Result<Inode, KError> {
+        if !self.is_minixfs() {
+            return Err(KError::Unsupported);
+        }
+        if inode_num == 0 || inode_num > self.ninodes {
+            return Err(KError::NotFound);
         }
+        let (inode_offset, inode_index) = self.inode_offset_and_index(inode_num);
+        let mut inode_buffer = buffer::block_buffer();
+        block::read(inode_buffer.get_mut(), BLOCK_SIZE, inode_offset as u64)?;
+        let bytes = &inode_buffer.as_slice()[inode_index * size_of::<Inode>()..][..size_of::<Inode>()];
+        unsafe { Ok((bytes.as_ptr() as *const Inode).read_unaligned()) }
     }
 }
 
@@ -90,17 +98,34 @@ pub struct Inode {
 }
 
 impl Inode {
-    fn get_dirents(&self) -> (*const DirEntry, usize) {
-        let mut buf = Buffer::new(((self.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
-        let dirents = buf.get() as *const DirEntry;
-        let sz = MinixFileSystem::read(self, buf.get_mut(), BLOCK_SIZE, 0);
-        let num_dirents = sz as usize / size_of::<DirEntry>();
-        (dirents, num_dirents)
+    // Every entry of the directory, a block at a time
+    fn get_dirents(&self) -> Result<Vec<DirEntry>, KError> {
+        let mut bytes = DynBuffer::new();
+        let mut chunk = buffer::block_buffer();
+        loop {
+            let offset = bytes.len() as u32;
+            let sz = MinixFileSystem::read(self, chunk.get_mut(), BLOCK_SIZE, offset)?;
+            if sz == 0 {
+                break;
+            }
+            bytes.extend_from_slice(&chunk.as_slice()[..sz as usize])?;
+        }
+        let dirents = bytes
+            .as_slice()
+            .chunks_exact(size_of::<DirEntry>())
+            .map(|entry| unsafe { (entry.as_ptr() as *const DirEntry).read_unaligned() })
+            .collect();
+        Ok(dirents)
     }
 
     fn is_directory(&self) -> bool {
         self.mode & S_IFDIR != 0
     }
+
+    // A symbolic link, its contents are the path it points to
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
 }
 
 #[repr(C)]
@@ -130,7 +155,7 @@ impl DirEntry {
     }
 }
 
-static mut MFS_INODE_CACHE: BTreeMap<String, Inode> = BTreeMap::new();
+static MFS_INODE_CACHE: SpinLock<BTreeMap<String, Inode>> = SpinLock::new(BTreeMap::new());
 static mut MFS_SUPERBLOCK_CACHE: SuperBlock = SuperBlock {
     ninodes: 0,
     pad0: 0,
@@ -151,104 +176,131 @@ struct ReadState {
     offset_byte: u32,
     bytes_read: u32,
     bytes_left: u32,
-    blocks_seen: u32,
     offset_block: u32,
-    direct_buffer: Buffer,
-    indirect_buffer: Buffer,
-    double_indirect_buffer: Buffer,
-    triple_indirect_buffer: Buffer,
-    izones: *const u32,
-    iizones: *const u32,
-    iiizones: *const u32,
+    direct_buffer: PooledBuffer<'static>,
+    // Zone tables one, two and three levels down and the zone each holds,
+    // 0 while none is loaded
+    table_buffers: [PooledBuffer<'static>; 3],
+    tables: [u32; 3],
 }
 
 impl ReadState {
-    fn new(inode_size: u32, size: u32, offset: u32) -> Self {
-        let mut rs = Self {
+    fn new(bytes_in_file: u32, size: u32, offset: u32) -> Self {
+        Self {
             offset_byte: offset % BLOCK_SIZE,
             bytes_read: 0,
-            bytes_left: if size > inode_size { inode_size } else { size },
-            blocks_seen: 0,
+            bytes_left: if size > bytes_in_file { bytes_in_file } else { size },
             offset_block: offset / BLOCK_SIZE,
-            direct_buffer: Buffer::default(),
-            indirect_buffer: Buffer::default(),
-            double_indirect_buffer: Buffer::default(),
-            triple_indirect_buffer: Buffer::default(),
-            izones: core::ptr::null(),
-            iizones: core::ptr::null(),
-            iiizones: core::ptr::null(),
-        };
-        rs.izones = rs.indirect_buffer.get() as *const u32;
-        rs.iizones = rs.double_indirect_buffer.get() as *const u32;
-        rs.iiizones = rs.triple_indirect_buffer.get() as *const u32;
-        rs
+            direct_buffer: buffer::block_buffer(),
+            table_buffers: [buffer::block_buffer(), buffer::block_buffer(), buffer::block_buffer()],
+            tables: [0; 3],
+        }
     }
+
     fn next(&mut self, bytes_to_read: u32) {
         self.offset_byte = 0;
+        self.offset_block += 1;
         self.bytes_read += bytes_to_read;
         self.bytes_left -= bytes_to_read;
     }
 
-    fn seen_block(&mut self) {
-        self.blocks_seen += 1;
+    // Entry index of zone table, 0 if the table itself is a hole
+    fn table_entry(&mut self, level: usize, table: u32, index: usize) -> Result<u32, KError> {
+        if table == 0 {
+            return Ok(0);
+        }
+        if self.tables[level] != table {
+            // Forget the table first, a failed read leaves the buffer half filled
+            self.tables[level] = 0;
+            block::read(
+                self.table_buffers[level].get_mut(),
+                BLOCK_SIZE,
+                table as u64 * BLOCK_SIZE as u64,
+            )?;
+            self.tables[level] = table;
+        }
+        let entry = &self.table_buffers[level].as_slice()[index * 4..][..4];
+        Ok(u32::from_le_bytes(entry.try_into().unwrap()))
     }
+}
 
-    fn in_window(&self) -> bool {
-        self.offset_block <= self.blocks_seen
-    }
+// Where a block of a file is found, the zone in Inode::zones[slot] and then
+// entries[level] of each zone table on the way down, depth tables deep
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ZonePath {
+    pub slot: usize,
+    pub depth: usize,
+    pub entries: [usize; 3],
+}
 
-    fn izone_present(&self, index: usize) -> bool {
-        unsafe { self.izones.add(index).read() != 0 }
+// The path to block of a file, None past the last triple indirect zone
+pub fn zone_path(block: usize) -> Option<ZonePath> {
+    let per_table = PTR_INDEX_MAX;
+    if block < DIRECT_ZONES {
+        return Some(ZonePath { slot: block, depth: 0, entries: [0; 3] });
     }
-
-    fn iizone_present(&self, index: usize) -> bool {
-        unsafe { self.iizones.add(index).read() != 0 }
+    let block = block - DIRECT_ZONES;
+    if block < per_table {
+        return Some(ZonePath { slot: INDIRECT_ZONE, depth: 1, entries: [block, 0, 0] });
     }
-
-    fn iiizone_present(&self, index: usize) -> bool {
-        unsafe { self.iiizones.add(index).read() != 0 }
+    let block = block - per_table;
+    if block < per_table * per_table {
+        let entries = [block / per_table, block % per_table, 0];
+        return Some(ZonePath { slot: DOUBLE_INDIRECT_ZONE, depth: 2, entries });
     }
+    let block = block - per_table * per_table;
+    if block < per_table * per_table * per_table {
+        let entries = [block / (per_table * per_table), block / per_table % per_table, block % per_table];
+        return Some(ZonePath { slot: TRIPLE_INDIRECT_ZONE, depth: 3, entries });
+    }
+    None
 }
 
 pub struct MinixFileSystem;
 impl MinixFileSystem {
-    pub fn get_inode(inode_num: u32) -> Option<Inode> {
+    pub fn get_inode(inode_num: u32) -> Result<Inode, KError> {
         unsafe { MFS_SUPERBLOCK_CACHE.get_inode(inode_num) }
     }
 
-    fn cache_tree(btm: &mut BTreeMap<String, Inode>, cwd: &str, inode_num: u32) {
-        let inode = Self::get_inode(inode_num).expect("To be passed a valid inode_num");
-        let (dirents, num_dirents) = inode.get_dirents();
-        for i in DIR_ENTRY_START..num_dirents {
-            let directory_entry = &(unsafe { *dirents.add(i) });
-            let directory_entry_inode = Self::get_inode(directory_entry.inode).unwrap();
+    fn cache_tree(btm: &mut BTreeMap<String, Inode>, cwd: &str, inode_num: u32) -> Result<(), KError> {
+        let inode = Self::get_inode(inode_num)?;
+        for directory_entry in inode.get_dirents()?.iter().skip(DIR_ENTRY_START) {
+            sched::yield_now();
+            // A removed entry
+            if directory_entry.inode == 0 {
+                continue;
+            }
+            let directory_entry_inode = Self::get_inode(directory_entry.inode)?;
             let new_cwd = directory_entry.abs_name(cwd, inode_num);
             if directory_entry_inode.is_directory() {
-                Self::cache_tree(btm, &new_cwd, directory_entry.inode);
+                Self::cache_tree(btm, &new_cwd, directory_entry.inode)?;
             } else {
                 btm.insert(new_cwd, directory_entry_inode);
             }
         }
+        Ok(())
     }
 
-    fn init_superblock_cache() {
+    fn init_superblock_cache() -> Result<(), KError> {
         let mut buffer = Buffer::new(SECTOR_SIZE);
-        let super_block = unsafe { &*(buffer.get_mut() as *mut SuperBlock) };
-        block::read(buffer.get_mut(), SECTOR_SIZE as u32, BLOCK_SIZE as u64);
-        unsafe { MFS_SUPERBLOCK_CACHE = *super_block };
+        block::read(buffer.get_mut(), SECTOR_SIZE as u32, BLOCK_SIZE as u64)?;
+        let bytes = &buffer.as_slice()[..size_of::<SuperBlock>()];
+        unsafe { MFS_SUPERBLOCK_CACHE = (bytes.as_ptr() as *const SuperBlock).read_unaligned() };
+        Ok(())
     }
 
-    fn init_inode_cache() {
+    fn init_inode_cache() -> Result<(), KError> {
         let mut btm = BTreeMap::new();
         let cwd = String::from("/");
 
-        Self::cache_tree(&mut btm, &cwd, ROOT_NODE);
-        unsafe { MFS_INODE_CACHE = btm };
+        Self::cache_tree(&mut btm, &cwd, ROOT_NODE)?;
+        *MFS_INODE_CACHE.lock() = btm;
+        Ok(())
     }
 
-    pub fn init() {
-        Self::init_superblock_cache();
-        Self::init_inode_cache();
+    pub fn init() -> Result<(), KError> {
+        Self::init_superblock_cache()?;
+        Self::init_inode_cache()
     }
 
     fn read_data(buffer: *mut u8, rs: &mut ReadState) {
@@ -260,181 +312,83 @@ impl MinixFileSystem {
         unsafe {
             memcpy(
                 buffer.add(rs.bytes_read as usize),
-                rs.direct_buffer.get().add(rs.offset_byte as usize),
+                rs.direct_buffer.as_slice()[rs.offset_byte as usize..].as_ptr(),
                 bytes_to_read as usize,
             );
         }
         rs.next(bytes_to_read);
     }
 
-    fn read_direct_data(inode: &Inode, i: usize, buffer: *mut u8, rs: &mut ReadState) {
-        let zone_offset = inode.zones[i] * BLOCK_SIZE;
-        block::read(rs.direct_buffer.get_mut(), BLOCK_SIZE, zone_offset as u64);
-        Self::read_data(buffer, rs);
-    }
-
-    fn read_indirect_data(izones: *const u32, i: usize, buffer: *mut u8, rs: &mut ReadState) {
-        block::read(
-            rs.direct_buffer.get_mut(),
-            BLOCK_SIZE,
-            (BLOCK_SIZE * unsafe { izones.add(i).read() }) as u64,
-        );
-        Self::read_data(buffer, rs);
-    }
-
-    fn read_zone(inode: &Inode, buffer: &mut Buffer, number: usize) {
-        block::read(
-            buffer.get_mut(),
-            BLOCK_SIZE,
-            (BLOCK_SIZE * inode.zones[number]) as u64,
-        );
-    }
-
-    fn read_izone(izones: *const u32, buffer: &mut Buffer, i: usize) {
-        block::read(
-            buffer.get_mut(),
-            BLOCK_SIZE,
-            (BLOCK_SIZE * unsafe { izones.add(i).read() }) as u64,
-        );
+    // Zone holding block of the file, 0 for a hole
+    fn zone(inode: &Inode, block: usize, rs: &mut ReadState) -> Result<u32, KError> {
+        let Some(path) = zone_path(block) else {
+            return Ok(0);
+        };
+        let mut zone = inode.zones[path.slot];
+        for level in 0..path.depth {
+            zone = rs.table_entry(level, zone, path.entries[level])?;
+        }
+        Ok(zone)
     }
 
-    fn direct_zones(inode: &Inode, buffer: *mut u8, rs: &mut ReadState) -> u32 {
-        for i in 0..DIRECT_ZONES {
-            if inode.zones[i] == 0 {
-                continue;
-            }
-            if rs.in_window() {
-                Self::read_direct_data(inode, i, buffer, rs);
-                if rs.bytes_left == 0 {
-                    return rs.bytes_read;
-                }
-            }
-            rs.seen_block()
-        }
-        0
-    }
-
-    fn indirect_zones(inode: &Inode, buffer: *mut u8, rs: &mut ReadState) -> u32 {
-        if inode.zones[INDIRECT_ZONE] != 0 {
-            Self::read_zone(inode, &mut rs.indirect_buffer, INDIRECT_ZONE);
-            for i in 0..PTR_INDEX_MAX {
-                if rs.izone_present(i) {
-                    if rs.in_window() {
-                        Self::read_indirect_data(rs.izones, i, buffer, rs);
-                        if rs.bytes_left == 0 {
-                            return rs.bytes_read;
-                        }
-                    }
-                    rs.seen_block()
-                }
-            }
-        }
-        0
-    }
-
-    fn double_indirect_zones(inode: &Inode, buffer: *mut u8, rs: &mut ReadState) -> u32 {
-        if inode.zones[DOUBLE_INDIRECT_ZONE] != 0 {
-            Self::read_zone(inode, &mut rs.indirect_buffer, DOUBLE_INDIRECT_ZONE);
-            for i in 0..PTR_INDEX_MAX {
-                if rs.izone_present(i) {
-                    Self::read_izone(rs.izones, &mut rs.double_indirect_buffer, i);
-                    for j in 0..PTR_INDEX_MAX {
-                        if rs.iizone_present(j) {
-                            if rs.in_window() {
-                                Self::read_indirect_data(rs.iizones, j, buffer, rs);
-                                if rs.bytes_left == 0 {
-                                    return rs.bytes_read;
-                                }
-                            }
-                            rs.seen_block()
-                        }
-                    }
-                }
-            }
+    // Read up to size bytes from offset into buffer, returns the bytes read
+    // Holes in sparse files read as zeros
+    pub fn read(inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, KError> {
+        if offset >= inode.size {
+            return Ok(0);
         }
-        0
-    }
-
-    fn triple_indirect_zones(inode: &Inode, buffer: *mut u8, rs: &mut ReadState) -> u32 {
-        if inode.zones[TRIPLE_INDIRECT_ZONE] != 0 {
-            Self::read_zone(inode, &mut rs.indirect_buffer, TRIPLE_INDIRECT_ZONE);
-            for i in 0..PTR_INDEX_MAX {
-                if rs.izone_present(i) {
-                    Self::read_izone(rs.izones, &mut rs.double_indirect_buffer, i);
-                    for j in 0..PTR_INDEX_MAX {
-                        if rs.iizone_present(j) {
-                            Self::read_izone(rs.iizones, &mut rs.triple_indirect_buffer, j);
-                            for k in 0..PTR_INDEX_MAX {
-                                if rs.iiizone_present(k) {
-                                    if rs.in_window() {
-                                        Self::read_indirect_data(rs.iiizones, k, buffer, rs);
-                                        if rs.bytes_left == 0 {
-                                            return rs.bytes_read;
-                                        }
-                                    }
-                                    rs.seen_block()
-                                }
-                            }
-                        }
-                    }
-                }
+        let mut rs = ReadState::new(inode.size - offset, size, offset);
+        while rs.bytes_left != 0 {
+            let zone = Self::zone(inode, rs.offset_block as usize, &mut rs)?;
+            if zone == 0 {
+                rs.direct_buffer.as_mut_slice().fill(0);
+            } else {
+                block::read(rs.direct_buffer.get_mut(), BLOCK_SIZE, zone as u64 * BLOCK_SIZE as u64)?;
             }
+            Self::read_data(buffer, &mut rs);
         }
-        0
+        Ok(rs.bytes_read)
     }
 
-    pub fn read(inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-        let mut rs = ReadState::new(inode.size, size, offset);
-
-        let br = Self::direct_zones(inode, buffer, &mut rs);
-        if br != 0 {
-            return br;
-        }
-
-        let br = Self::indirect_zones(inode, buffer, &mut rs);
-        if br != 0 {
-            return br;
-        }
-
-        let br = Self::double_indirect_zones(inode, buffer, &mut rs);
-        if br != 0 {
-            return br;
-        }
-
-        let br = Self::triple_indirect_zones(inode, buffer, &mut rs);
-        if br != 0 {
-            return br;
-        }
-
-        rs.bytes_read
+    pub fn lookup(file_name: &str) -> Result<Inode, KError> {
+        MFS_INODE_CACHE.lock().get(file_name).copied().ok_or(KError::NotFound)
     }
 
-    pub fn read_file(file_name: &str, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-        if let Some(node) = unsafe { MFS_INODE_CACHE.get(file_name) } {
-            Self::read(node, buffer, size, offset)
-        } else {
-            println!("Unable to find '{}' in MFS_INODE_CACHE", file_name);
-            0
-        }
+    pub fn read_file(file_name: &str, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, KError> {
+        // Reading sleeps on the disk, do not hold the cache meanwhile
+        let node = Self::lookup(file_name)?;
+        Self::read(&node, buffer, size, offset)
     }
 
+    // The filesystem is read only so far
     #[allow(dead_code)]
-    pub fn write(&mut self, _desc: &Inode, _buffer: *const u8, _offset: u32, _size: u32) -> u32 {
-        todo!();
+    pub fn write(&mut self, _desc: &Inode, _buffer: *const u8, _offset: u32, _size: u32) -> Result<u32, KError> {
+        Err(KError::Unsupported)
     }
 }
 
 pub fn init() {
-    MinixFileSystem::init();
+    if let Err(e) = MinixFileSystem::init() {
+        log::error!("Unable to read the minixfs3 filesystem: {}", e);
+    }
 }
 
 pub fn debug_cache() {
-    serial_debug("FS Cache");
-    for (strg, node) in unsafe { MFS_INODE_CACHE.iter() } {
+    println!("\nFS Cache");
+    for (strg, node) in MFS_INODE_CACHE.lock().iter() {
         println!("{}: {:?}", strg, node);
     }
 }
 
+// A copy of the cached (absolute path, inode) pairs
+pub fn cached_files() -> Vec<(String, Inode)> {
+    MFS_INODE_CACHE
+        .lock()
+        .iter()
+        .map(|(path, node)| (path.clone(), *node))
+        .collect()
+}
+
 fn bit_count(byte: u8) -> u32 {
     match byte {
         0 => 0,
@@ -449,13 +403,14 @@ fn bit_count(byte: u8) -> u32 {
     }
 }
 
-fn print_bitmap(read_size: u32, offset: u64, items: u32) -> u32 {
+fn print_bitmap(read_size: u32, offset: u64, items: u32) -> Result<u32, KError> {
     let mut buffer = Buffer::new(read_size as usize);
-    block::read(buffer.get_mut(), read_size, offset);
+    block::read(buffer.get_mut(), read_size, offset)?;
     let mut previous_print = true;
     let mut total_bit_count = 0;
     for i in 0..items {
-        let val = unsafe { buffer.get().add(i as usize).read()};
+        sched::yield_now();
+        let val = buffer.as_slice()[i as usize];
         total_bit_count += bit_count(val);
         // Print first, last, and non 0 bytes
         if i == 0 || i == items - 1 || val != 0x0 {
@@ -470,52 +425,62 @@ fn print_bitmap(read_size: u32, offset: u64, items: u32) -> u32 {
             }
         }
     };
-    total_bit_count
+    Ok(total_bit_count)
 }
 
-fn find_first_free_inode() {
+fn find_first_free_inode() -> Result<(), KError> {
     let read_size = BLOCK_SIZE * unsafe{MFS_SUPERBLOCK_CACHE}.imap_blocks as u32;
     let offset = (BLOCK_SIZE * 2) as u64;
     let mut buffer = Buffer::new(read_size as usize);
-    block::read(buffer.get_mut(), read_size, offset);
+    block::read(buffer.get_mut(), read_size, offset)?;
     for byte_idx in 0..unsafe{MFS_SUPERBLOCK_CACHE}.ninodes/8 {
-        let byte = unsafe { buffer.get().add(byte_idx as usize).read()};
+        sched::yield_now();
+        let byte = buffer.as_slice()[byte_idx as usize];
         if byte != 0xff {
             for bit_idx in 0..8 {
                 if (byte & (1 << bit_idx)) == 0 {
                     let inode_idx = (byte_idx * 8 + bit_idx) as u32;
                     println!("First available inode: {}", (inode_idx + 1));
-                    return;
+                    return Ok(());
                 }
             }
         }
     }
     println!("No available inode found!");
+    Ok(())
 }
 
-fn find_first_free_zone() {
+fn find_first_free_zone() -> Result<(), KError> {
     let read_size = BLOCK_SIZE * unsafe{MFS_SUPERBLOCK_CACHE}.zmap_blocks as u32;
     let offset = (BLOCK_SIZE * (2 + unsafe{MFS_SUPERBLOCK_CACHE}.imap_blocks as u32)) as u64;
     let mut buffer = Buffer::new(read_size as usize);
-    block::read(buffer.get_mut(), read_size, offset);
+    block::read(buffer.get_mut(), read_size, offset)?;
     for byte_idx in 0..unsafe{MFS_SUPERBLOCK_CACHE}.zones/8 {
-        let byte = unsafe { buffer.get().add(byte_idx as usize).read()};
+        sched::yield_now();
+        let byte = buffer.as_slice()[byte_idx as usize];
         if byte != 0xff {
             for bit_idx in 0..8 {
                 if (byte & (1 << bit_idx)) == 0 {
                     let inode_idx = (byte_idx * 8 + bit_idx) as u32;
                     println!("First available zone: {}", (inode_idx + 1));
-                    return;
+                    return Ok(());
                 }
             }
         }
     }
     println!("No available zone found!");
+    Ok(())
 }
 
 pub fn debug_fs() {
+    if let Err(e) = print_fs() {
+        println!("\nUnable to read the filesystem: {}", e);
+    }
+}
+
+fn print_fs() -> Result<(), KError> {
     let superblock_cache = unsafe{MFS_SUPERBLOCK_CACHE};
-    serial_debug("FS");
+    println!("\nFS");
     println!("SuperBlock:");
     println!("  # of inodes    : {}", superblock_cache.ninodes);
     println!("  padding 0      : {}", superblock_cache.pad0);
@@ -540,26 +505,23 @@ pub fn debug_fs() {
     println!("\nInode Bitmap:");
     let read_size = BLOCK_SIZE * imap_blocks;
     let offset = (BLOCK_SIZE * 2) as u64;
-    let count = print_bitmap(read_size, offset, inodes/8);
+    let count = print_bitmap(read_size, offset, inodes/8)?;
     println!("\n  Used {} / {} inodes ({}%)", count, inodes, count * 100 / inodes);
 
-    find_first_free_inode();
+    find_first_free_inode()?;
 
     println!("\nZone Bitmap:");
     let read_size = BLOCK_SIZE * zmap_blocks;
     let offset = (BLOCK_SIZE * (2 + imap_blocks)) as u64;
-    let count = print_bitmap(read_size, offset, zones/8 - first_data_zone);    
+    let count = print_bitmap(read_size, offset, zones/8 - first_data_zone)?;    
     println!("\n  Used {} / {} zones ({}%)", count, zones, count * 100 / zones);
 
-    find_first_free_zone();
+    find_first_free_zone()?;
 
     // Print the inode representing the root directory
-    if let Some(node) = superblock_cache.get_inode(1){
-        println!("{:?}", node);
-    }
+    println!("{:?}", superblock_cache.get_inode(1)?);
 
     // Print the test file inside the root directory
-    if let Some(node) = superblock_cache.get_inode(2){
-        println!("{:?}", node);
-    }
+    println!("{:?}", superblock_cache.get_inode(2)?);
+    Ok(())
 }
@@ -0,0 +1,126 @@
+use crate::debug;
+use crate::linedisc;
+use crate::memory;
+use crate::power;
+use crate::trace;
+use crate::trap::TrapFrame;
+use crate::{print, println};
+
+// mod monitor.rs
+// A tiny debug monitor entered on breakpoints, requires --features "debug-monitor"
+// Reads commands from the uart by polling, interrupts are off inside the trap
+// Lines are edited through src/linedisc.rs, the arrows recall earlier commands
+//   r                  print the registers
+//   m <addr> [words]   print RAM as 64 bit words, addresses in hex
+//   peek <addr> [len]  print len bytes of RAM or MMIO, len in hex
+//   poke <addr> <val>  write a 32 bit word to RAM or MMIO
+//   meminfo [--json]   print the heap and slab usage
+//   iostat [--json]    print the block I/O counters
+//   devices [--json]   list the virtio devices
+//   ps [--json]        list the tasks
+//   trace              dump the trace event buffer
+//   xxd <file>         hexdump a file
+//   poweroff [code]    sync and power off, QEMU exits with code
+//   reboot             sync and reset the machine
+//   c                  continue after the breakpoint
+// --json prints a single line JSON document instead, for host side tooling
+
+const LINE_SIZE: usize = 64;
+
+fn parse_hex(word: &str) -> Option<usize> {
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+// Refuses anything outside RAM like peek does, a stray read would fault and
+// take the kernel down, MMIO is left to peek and its 32 bit reads
+fn dump_memory(addr: usize, words: usize) {
+    let addr = addr & !7;
+    let valid = words
+        .checked_mul(8)
+        .is_some_and(|len| len > 0 && memory::is_kernel_memory(addr, len));
+    if !valid {
+        println!(
+            "m: 0x{:x}+{} words is not RAM, peek reads MMIO",
+            addr, words
+        );
+        return;
+    }
+    for i in 0..words {
+        let word = addr + i * 8;
+        if i % 4 == 0 {
+            print!("\n0x{:016x}:", word);
+        }
+        print!(" {:016x}", unsafe { (word as *const u64).read_volatile() });
+    }
+    println!();
+}
+
+// Run the text or, given --json, the JSON form of a report
+fn report(mut words: core::str::SplitWhitespace, text: fn(), json: fn()) {
+    match words.next() {
+        None => text(),
+        Some("--json") => json(),
+        Some(_) => println!("usage: meminfo|iostat|devices|ps [--json]"),
+    }
+}
+
+// Serve commands until asked to continue
+pub fn run(frame: &TrapFrame) {
+    let mut buffer = [0u8; LINE_SIZE];
+    loop {
+        print!("mon> ");
+        let len = linedisc::read_line(&mut buffer);
+        let line = core::str::from_utf8(&buffer[..len]).unwrap_or("");
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("r") => frame.print(),
+            Some("m") => match words.next().and_then(parse_hex) {
+                Some(addr) => {
+                    let count = words.next().and_then(parse_hex).unwrap_or(8);
+                    dump_memory(addr, count);
+                }
+                None => println!("usage: m <addr> [words]"),
+            },
+            Some("peek") => match words.next().and_then(parse_hex) {
+                Some(addr) => {
+                    let len = words.next().and_then(parse_hex).unwrap_or(0x40);
+                    debug::peek(addr, len);
+                }
+                None => println!("usage: peek <addr> [len]"),
+            },
+            Some("poke") => {
+                let addr = words.next().and_then(parse_hex);
+                let value = words.next().and_then(parse_hex);
+                match (addr, value.and_then(|v| u32::try_from(v).ok())) {
+                    (Some(addr), Some(value)) => {
+                        debug::poke(addr, value);
+                    }
+                    _ => println!("usage: poke <addr> <value>"),
+                }
+            }
+            Some("meminfo") => report(words, debug::heap, debug::heap_json),
+            Some("iostat") => report(words, debug::iostat, debug::iostat_json),
+            Some("devices") => report(words, debug::devices, debug::devices_json),
+            Some("ps") => report(words, debug::tasks, debug::tasks_json),
+            Some("trace") => trace::dump(),
+            Some("xxd") => match words.next() {
+                Some(path) => {
+                    debug::xxd(path);
+                }
+                None => println!("usage: xxd <file>"),
+            },
+            Some("poweroff") => match words.next().map(str::parse::<u16>) {
+                None => power::shutdown(0),
+                Some(Ok(code)) => power::shutdown(code),
+                Some(Err(_)) => println!("usage: poweroff [code]"),
+            },
+            Some("reboot") => power::reboot(),
+            Some("c") => return,
+            Some(_) => println!(
+                "commands: r, m <addr> [words], peek <addr> [len], poke <addr> <value>, meminfo, iostat, devices, ps [--json], trace, xxd <file>, poweroff [code], reboot, c"
+            ),
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use crate::config::{P9_MOUNT_POINT, P9_MSIZE};
+use crate::error::KError;
+use crate::log;
+use crate::memory::memcpy;
+use crate::vfs::{self, FileSystem};
+use crate::virtio::{DmaGuard, Features, MmioDevice};
+use crate::virtqueue::{Segment, VirtQueue};
+use crate::{print, println};
+use rust_alloc::{boxed::Box, string::String, vec::Vec};
+
+// mod p9.rs
+// A 9P2000.L client over virtio for host directories shared with -virtfs
+// Requests are synchronous, the request queue is polled until the host answers
+
+static mut P9_DEVICE: Option<P9Device> = None;
+
+const REQUEST_QUEUE: u32 = 0;
+const VIRTIO_9P_MOUNT_TAG: u64 = 1;
+const VERSION: &str = "9P2000.L";
+
+const NOTAG: u16 = 0xffff;
+const NOFID: u32 = 0xffff_ffff;
+const ROOT_FID: u32 = 0;
+const TAG: u16 = 1;
+
+// Largest number of path elements the protocol allows in a single walk
+const MAX_WALK_ELEMENTS: usize = 16;
+// Size of the Tread/Rread header in front of the data
+const IO_HEADER_SIZE: u32 = 24;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TGETATTR: u8 = 24;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TCLUNK: u8 = 120;
+
+const O_RDONLY: u32 = 0;
+const GETATTR_SIZE: u64 = 0x200;
+
+// A message being built in, or parsed from, a request buffer
+struct Message {
+    buffer: *mut u8,
+    pos: usize,
+}
+
+impl Message {
+    fn new(buffer: *mut u8, msg_type: u8, tag: u16) -> Self {
+        let mut msg = Self { buffer, pos: 4 };
+        msg.put_u8(msg_type);
+        msg.put_u16(tag);
+        msg
+    }
+
+    fn reader(buffer: *mut u8) -> Self {
+        Self { buffer, pos: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        unsafe { memcpy(self.buffer.add(self.pos), bytes.as_ptr(), bytes.len()) };
+        self.pos += bytes.len();
+    }
+
+    fn put_u8(&mut self, value: u8) {
+        self.put(&[value]);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn put_str(&mut self, value: &str) {
+        self.put_u16(value.len() as u16);
+        self.put(value.as_bytes());
+    }
+
+    // Write the total size into the header and return it
+    fn finish(&mut self) -> u32 {
+        let size = self.pos as u32;
+        unsafe { memcpy(self.buffer, size.to_le_bytes().as_ptr(), 4) };
+        size
+    }
+
+    fn get<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        unsafe { memcpy(bytes.as_mut_ptr(), self.buffer.add(self.pos), N) };
+        self.pos += N;
+        bytes
+    }
+
+    fn get_u8(&mut self) -> u8 {
+        self.get::<1>()[0]
+    }
+
+    fn get_u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.get())
+    }
+
+    fn get_u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.get())
+    }
+
+    fn get_u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.get())
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.pos += len;
+    }
+}
+
+pub struct P9Device {
+    dev: DmaGuard,
+    queue: VirtQueue,
+    request: *mut u8,
+    response: *mut u8,
+    msize: u32,
+    next_fid: u32,
+    tag: String,
+}
+
+impl P9Device {
+    fn init(ptr: *mut u32) -> Result<(), KError> {
+        log::info!("init 9p device");
+        let dev = MmioDevice::new(ptr)?;
+        dev.begin_init();
+        dev.negotiate(Features::new(0, VIRTIO_9P_MOUNT_TAG))?;
+        let queue = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        dev.setup_queue(REQUEST_QUEUE, &queue)?;
+        let mut dev = DmaGuard::new(dev);
+        let request = dev.alloc_bytes(P9_MSIZE as usize);
+        let response = dev.alloc_bytes(P9_MSIZE as usize);
+        let (Ok(request), Ok(response)) = (request, response) else {
+            print!("buffer alloc fail...");
+            dev.fail();
+            return Err(KError::NoMemory);
+        };
+        dev.driver_ok();
+
+        let tag = Self::mount_tag(&dev);
+        let mut p9 = P9Device {
+            dev,
+            queue,
+            request,
+            response,
+            msize: P9_MSIZE,
+            next_fid: ROOT_FID + 1,
+            tag,
+        };
+        if !p9.version() || !p9.attach() {
+            print!("9p session fail...");
+            return Err(KError::IoError);
+        }
+        unsafe { P9_DEVICE = Some(p9) };
+        Ok(())
+    }
+
+    // The tag the host gave the share, a u16 length followed by the name
+    fn mount_tag(dev: &MmioDevice) -> String {
+        let len = (dev.config_read(0) & 0xffff) as usize;
+        let mut tag = String::new();
+        for i in 0..len {
+            let word = dev.config_read(2 + i);
+            tag.push((word >> (((2 + i) % 4) * 8)) as u8 as char);
+        }
+        tag
+    }
+
+    // Send the message in the request buffer and poll until the reply arrives
+    // Returns a reader positioned after the reply header, Rlerror is reported
+    fn transact(&mut self, msg: &mut Message, reply: u8) -> Option<Message> {
+        let size = msg.finish();
+        let head = self.queue.add_chain(&[
+            Segment::readable(self.request as u64, size),
+            Segment::writable(self.response as u64, self.msize),
+        ]);
+        self.queue.submit(head);
+        self.dev.notify(REQUEST_QUEUE);
+        while !self.queue.is_complete(head) {
+            self.queue.pop_used();
+        }
+
+        let mut response = Message::reader(self.response);
+        response.skip(4);
+        let msg_type = response.get_u8();
+        response.skip(2);
+        if msg_type == RLERROR {
+            println!("9p error: {}", response.get_u32());
+            return None;
+        }
+        if msg_type != reply {
+            println!("9p unexpected reply {} to {}", msg_type, reply - 1);
+            return None;
+        }
+        Some(response)
+    }
+
+    fn version(&mut self) -> bool {
+        let mut msg = Message::new(self.request, TVERSION, NOTAG);
+        msg.put_u32(self.msize);
+        msg.put_str(VERSION);
+        let mut response = match self.transact(&mut msg, TVERSION + 1) {
+            Some(r) => r,
+            None => return false,
+        };
+        self.msize = self.msize.min(response.get_u32());
+        true
+    }
+
+    fn attach(&mut self) -> bool {
+        let mut msg = Message::new(self.request, TATTACH, TAG);
+        msg.put_u32(ROOT_FID);
+        msg.put_u32(NOFID);
+        msg.put_str("root");
+        msg.put_str("");
+        msg.put_u32(0);
+        self.transact(&mut msg, TATTACH + 1).is_some()
+    }
+
+    // Walk from the root to path, returning a new fid for it
+    fn walk(&mut self, path: &str) -> Option<u32> {
+        let fid = self.next_fid;
+        self.next_fid += 1;
+        let names: Vec<&str> = path.split('/').filter(|n| !n.is_empty()).collect();
+        let mut from = ROOT_FID;
+        let mut chunks = names.chunks(MAX_WALK_ELEMENTS);
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let mut msg = Message::new(self.request, TWALK, TAG);
+            msg.put_u32(from);
+            msg.put_u32(fid);
+            msg.put_u16(chunk.len() as u16);
+            for name in chunk {
+                msg.put_str(name);
+            }
+            let mut response = match self.transact(&mut msg, TWALK + 1) {
+                Some(r) => r,
+                None => {
+                    if from == fid {
+                        self.clunk(fid);
+                    }
+                    return None;
+                }
+            };
+            if response.get_u16() as usize != chunk.len() {
+                if from == fid {
+                    self.clunk(fid);
+                }
+                return None;
+            }
+            from = fid;
+            if chunks.len() == 0 {
+                return Some(fid);
+            }
+        }
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        let mut msg = Message::new(self.request, TCLUNK, TAG);
+        msg.put_u32(fid);
+        self.transact(&mut msg, TCLUNK + 1);
+    }
+
+    fn size(&mut self, fid: u32) -> Option<u64> {
+        let mut msg = Message::new(self.request, TGETATTR, TAG);
+        msg.put_u32(fid);
+        msg.put_u64(GETATTR_SIZE);
+        let mut response = self.transact(&mut msg, TGETATTR + 1)?;
+        // valid, qid, mode, uid, gid, nlink and rdev come before the size
+        response.skip(8 + 13 + 4 + 4 + 4 + 8 + 8);
+        Some(response.get_u64())
+    }
+
+    fn open(&mut self, fid: u32) -> bool {
+        let mut msg = Message::new(self.request, TLOPEN, TAG);
+        msg.put_u32(fid);
+        msg.put_u32(O_RDONLY);
+        self.transact(&mut msg, TLOPEN + 1).is_some()
+    }
+
+    // Read up to size bytes at offset into buffer, returns the bytes read
+    fn read(&mut self, fid: u32, buffer: *mut u8, size: u32, offset: u64) -> Option<u32> {
+        let chunk_size = self.msize - IO_HEADER_SIZE;
+        let mut done = 0;
+        while done < size {
+            let mut msg = Message::new(self.request, TREAD, TAG);
+            msg.put_u32(fid);
+            msg.put_u64(offset + done as u64);
+            msg.put_u32(chunk_size.min(size - done));
+            let mut response = self.transact(&mut msg, TREAD + 1)?;
+            let count = response.get_u32();
+            if count == 0 {
+                break;
+            }
+            unsafe {
+                memcpy(
+                    buffer.add(done as usize),
+                    self.response.add(response.pos),
+                    count as usize,
+                )
+            };
+            done += count;
+        }
+        Some(done)
+    }
+
+    fn file_size(&mut self, path: &str) -> Option<u32> {
+        let fid = self.walk(path)?;
+        let size = self.size(fid);
+        self.clunk(fid);
+        size.map(|s| s as u32)
+    }
+
+    fn read_file(&mut self, path: &str, buffer: *mut u8, size: u32, offset: u32) -> Option<u32> {
+        let fid = self.walk(path)?;
+        let read = if self.open(fid) {
+            self.read(fid, buffer, size, offset as u64)
+        } else {
+            None
+        };
+        self.clunk(fid);
+        read
+    }
+}
+
+// The share as seen by the vfs, requests go to the single 9p device
+struct P9FileSystem;
+
+impl FileSystem for P9FileSystem {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn file_size(&mut self, path: &str) -> Option<u32> {
+        unsafe { P9_DEVICE.as_mut()?.file_size(path) }
+    }
+
+    fn read_file(&mut self, path: &str, buffer: *mut u8, size: u32, offset: u32) -> Option<u32> {
+        unsafe { P9_DEVICE.as_mut()?.read_file(path, buffer, size, offset) }
+    }
+}
+
+// ====================================================
+// The public interface for the 9p device is here...
+// ====================================================
+
+// Called by virtio::init() when a 9p device is found
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn init(ptr: *mut u32) -> Result<(), KError> {
+    P9Device::init(ptr)
+}
+
+// Called by virtio::rescan() when the 9p device has gone away
+pub fn remove() {
+    unsafe { P9_DEVICE = None };
+    vfs::unmount(P9_MOUNT_POINT);
+}
+
+// Completions are polled by the submitter, only acknowledge the interrupt
+pub fn interrupt_handler() {
+    unsafe {
+        if let Some(p9) = P9_DEVICE.as_ref() {
+            p9.dev.ack_interrupt();
+        }
+    }
+}
+
+// The share to mount in the vfs if a 9p device is available
+pub fn filesystem() -> Option<Box<dyn FileSystem>> {
+    unsafe { P9_DEVICE.as_ref() }.map(|_| Box::new(P9FileSystem) as Box<dyn FileSystem>)
+}
+
+// The mount tag configured on the host
+#[allow(dead_code)]
+pub fn mount_tag() -> Option<String> {
+    unsafe { P9_DEVICE.as_ref().map(|p9| p9.tag.clone()) }
+}
@@ -0,0 +1,419 @@
+use crate::alloc::{alloc_pages, alloc_pages_zeroed, free_pages};
+use crate::assembly;
+use crate::clint;
+use crate::config::{BOARD, PAGE_SIZE};
+use crate::fdt;
+use crate::log;
+use crate::memory::{self, memcpy};
+use crate::pci;
+use crate::plic;
+use crate::uart;
+use crate::virtio;
+use rust_alloc::collections::BTreeMap;
+
+// mod paging.rs
+// Sv39 page tables and the kernel's identity mapping
+// Three levels of 512 entry tables translate 39 bit virtual addresses
+// The kernel linear map uses 2MB megapages where aligned, split into 4K pages
+// when part of one needs different permissions
+// Note: translation only applies below machine mode, so the kernel mappings
+// only protect the kernel itself when built with --features "supervisor"
+// Otherwise the kernel runs in M-mode and they take effect for U mode code
+
+// Symbols defined in cfg/link.ld & asm/layout.S
+extern "C" {
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+    static RODATA_START: usize;
+    static RODATA_END: usize;
+    static DATA_START: usize;
+}
+
+pub const PTE_VALID: u64 = 1 << 0;
+pub const PTE_READ: u64 = 1 << 1;
+pub const PTE_WRITE: u64 = 1 << 2;
+pub const PTE_EXECUTE: u64 = 1 << 3;
+pub const PTE_USER: u64 = 1 << 4;
+pub const PTE_GLOBAL: u64 = 1 << 5;
+pub const PTE_ACCESSED: u64 = 1 << 6;
+pub const PTE_DIRTY: u64 = 1 << 7;
+// Software bit: the page is shared and gets copied on the first write
+pub const PTE_COW: u64 = 1 << 8;
+
+pub const PTE_RX: u64 = PTE_READ | PTE_EXECUTE;
+pub const PTE_RW: u64 = PTE_READ | PTE_WRITE;
+
+const PTE_LEAF: u64 = PTE_READ | PTE_WRITE | PTE_EXECUTE;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_FLAGS: u64 = (1 << PTE_PPN_SHIFT) - 1;
+const ENTRIES: usize = 512;
+const LEVELS: usize = 3;
+const SATP_MODE_SV39: usize = 8 << 60;
+pub const MEGAPAGE_SIZE: usize = PAGE_SIZE * ENTRIES;
+
+// MMIO window of the board's test device, the CLINT and PLIC ones come from
+// their drivers
+const TEST_DEVICE: Option<(usize, usize)> = match BOARD.test_device {
+    Some(base) => Some((base, base + PAGE_SIZE)),
+    None => None,
+};
+
+static mut KERNEL_ROOT: *mut PageTable = core::ptr::null_mut();
+// Number of mappings of each shared physical page, unshared pages are absent
+static mut SHARED_REFS: BTreeMap<usize, usize> = BTreeMap::new();
+
+#[repr(C)]
+pub struct PageTable {
+    pub entries: [u64; ENTRIES],
+}
+
+impl PageTable {
+    // A zeroed table from the page allocator, null if memory is exhausted
+    pub fn new() -> *mut PageTable {
+        alloc_pages_zeroed(1) as *mut PageTable
+    }
+}
+
+fn is_valid(entry: u64) -> bool {
+    entry & PTE_VALID != 0
+}
+
+fn is_leaf(entry: u64) -> bool {
+    entry & PTE_LEAF != 0
+}
+
+fn entry_address(entry: u64) -> usize {
+    ((entry >> PTE_PPN_SHIFT) << 12) as usize
+}
+
+fn make_entry(paddr: usize, flags: u64) -> u64 {
+    (((paddr >> 12) as u64) << PTE_PPN_SHIFT) | flags | PTE_VALID
+}
+
+// Index into the table at `level` for a virtual address
+fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + 9 * level)) & (ENTRIES - 1)
+}
+
+// Bytes mapped by a leaf entry at level
+fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+// Replace the megapage leaf at entry with a table of 4K leaves with the same flags
+fn split(entry: *mut u64) -> bool {
+    let table = PageTable::new();
+    if table.is_null() {
+        return false;
+    }
+    unsafe {
+        let base = entry_address(*entry);
+        let flags = *entry & PTE_FLAGS & !PTE_VALID;
+        for i in 0..ENTRIES {
+            (*table).entries[i] = make_entry(base + i * PAGE_SIZE, flags);
+        }
+        *entry = make_entry(table as usize, 0);
+    }
+    assembly::sfence_vma();
+    true
+}
+
+// The 4K leaf entry for vaddr, creating intermediate tables and splitting
+// megapages when `create` is set
+fn walk(root: *mut PageTable, vaddr: usize, create: bool) -> Option<*mut u64> {
+    let mut table = root;
+    for level in (1..LEVELS).rev() {
+        let entry = unsafe { &mut (*table).entries[vpn(vaddr, level)] };
+        if !is_valid(*entry) {
+            if !create {
+                return None;
+            }
+            let next = PageTable::new();
+            if next.is_null() {
+                return None;
+            }
+            *entry = make_entry(next as usize, 0);
+        } else if is_leaf(*entry) && (!create || !split(entry)) {
+            return None;
+        }
+        table = entry_address(*entry) as *mut PageTable;
+    }
+    Some(unsafe { &mut (*table).entries[vpn(vaddr, 0)] as *mut u64 })
+}
+
+// The valid leaf entry mapping vaddr at any level and that level
+fn find(root: *mut PageTable, vaddr: usize) -> Option<(u64, usize)> {
+    let mut table = root;
+    for level in (0..LEVELS).rev() {
+        let entry = unsafe { (*table).entries[vpn(vaddr, level)] };
+        if !is_valid(entry) {
+            return None;
+        }
+        if is_leaf(entry) {
+            return Some((entry, level));
+        }
+        table = entry_address(entry) as *mut PageTable;
+    }
+    None
+}
+
+// Map the 4K page at vaddr to paddr, flags should include R, W or X
+pub fn map(root: *mut PageTable, vaddr: usize, paddr: usize, flags: u64) -> bool {
+    assert!(flags & PTE_LEAF != 0);
+    match walk(root, vaddr, true) {
+        Some(entry) => {
+            unsafe { *entry = make_entry(paddr, flags | PTE_ACCESSED | PTE_DIRTY) };
+            true
+        }
+        None => {
+            log::error!("Unable to map 0x{:x} -> 0x{:x}", vaddr, paddr);
+            false
+        }
+    }
+}
+
+// Remove the mapping of the page at vaddr, intermediate tables are kept
+// A megapage covering vaddr is split so only the one page goes
+pub fn unmap(root: *mut PageTable, vaddr: usize) -> bool {
+    let split_needed = find(root, vaddr).is_some_and(|(_, level)| level > 0);
+    match walk(root, vaddr, split_needed) {
+        Some(entry) if unsafe { is_valid(*entry) } => {
+            unsafe { *entry = 0 };
+            assembly::sfence_vma();
+            true
+        }
+        _ => false,
+    }
+}
+
+// Physical address vaddr maps to, if any
+pub fn translate(root: *mut PageTable, vaddr: usize) -> Option<usize> {
+    let (entry, level) = find(root, vaddr)?;
+    Some(entry_address(entry) | (vaddr & (level_size(level) - 1)))
+}
+
+// Flags of the leaf entry for vaddr, if mapped
+pub fn flags(root: *mut PageTable, vaddr: usize) -> Option<u64> {
+    find(root, vaddr).map(|(entry, _)| entry & PTE_FLAGS)
+}
+
+// Size of the page mapping vaddr, PAGE_SIZE or MEGAPAGE_SIZE
+pub fn page_size(root: *mut PageTable, vaddr: usize) -> Option<usize> {
+    find(root, vaddr).map(|(_, level)| level_size(level))
+}
+
+// Map the 2MB megapage at vaddr to paddr, both must be 2MB aligned
+// Fails if part of the range is already mapped with 4K pages
+pub fn map_mega(root: *mut PageTable, vaddr: usize, paddr: usize, flags: u64) -> bool {
+    assert!(flags & PTE_LEAF != 0);
+    assert!((vaddr | paddr) & (MEGAPAGE_SIZE - 1) == 0);
+    let top = unsafe { &mut (*root).entries[vpn(vaddr, LEVELS - 1)] };
+    if !is_valid(*top) {
+        let next = PageTable::new();
+        if next.is_null() {
+            return false;
+        }
+        *top = make_entry(next as usize, 0);
+    } else if is_leaf(*top) {
+        return false;
+    }
+    let table = entry_address(*top) as *mut PageTable;
+    let entry = unsafe { &mut (*table).entries[vpn(vaddr, 1)] };
+    if is_valid(*entry) && !is_leaf(*entry) {
+        return false;
+    }
+    *entry = make_entry(paddr, flags | PTE_ACCESSED | PTE_DIRTY);
+    true
+}
+
+// Map the page at vaddr in src to the same physical page in dst
+// Writable pages become read-only copy-on-write in both tables
+pub fn share(src: *mut PageTable, dst: *mut PageTable, vaddr: usize) -> bool {
+    let entry = match walk(src, vaddr, false) {
+        Some(e) if unsafe { is_valid(*e) } => e,
+        _ => return false,
+    };
+    let mut value = unsafe { *entry };
+    if value & PTE_WRITE != 0 {
+        value = (value & !PTE_WRITE) | PTE_COW;
+        unsafe { *entry = value };
+        assembly::sfence_vma();
+    }
+    let paddr = entry_address(value);
+    if !map(dst, vaddr, paddr, value & PTE_FLAGS & !PTE_VALID) {
+        return false;
+    }
+    unsafe { *SHARED_REFS.entry(paddr).or_insert(1) += 1 };
+    true
+}
+
+// Mappings of the physical page at paddr
+pub fn page_refs(paddr: usize) -> usize {
+    unsafe { SHARED_REFS.get(&paddr).copied().unwrap_or(1) }
+}
+
+// Drop one mapping of the page at paddr, true if it was the last and the
+// caller should free the page
+pub fn release(paddr: usize) -> bool {
+    unsafe {
+        match SHARED_REFS.get_mut(&paddr) {
+            Some(refs) if *refs > 2 => {
+                *refs -= 1;
+                false
+            }
+            Some(_) => {
+                SHARED_REFS.remove(&paddr);
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+pub fn is_cow(root: *mut PageTable, vaddr: usize) -> bool {
+    flags(root, vaddr).is_some_and(|f| f & PTE_COW != 0)
+}
+
+// Give root a private writable copy of the copy-on-write page holding vaddr
+// The last mapping of a page takes it over without copying
+// Returns the physical page now mapped, None if vaddr is not copy-on-write
+pub fn resolve_cow(root: *mut PageTable, vaddr: usize) -> Option<usize> {
+    let entry = walk(root, vaddr, false)?;
+    let value = unsafe { *entry };
+    if !is_valid(value) || value & PTE_COW == 0 {
+        return None;
+    }
+    let shared = entry_address(value);
+    let private = if page_refs(shared) > 1 {
+        let page = alloc_pages(1);
+        if page.is_null() {
+            return None;
+        }
+        unsafe { memcpy(page, shared as *const u8, PAGE_SIZE) };
+        release(shared);
+        page as usize
+    } else {
+        shared
+    };
+    let flags = (value & PTE_FLAGS & !PTE_COW) | PTE_WRITE;
+    unsafe { *entry = make_entry(private, flags) };
+    assembly::sfence_vma();
+    Some(private)
+}
+
+// Free the tables below the root entries in [first, last), leaves are not touched
+// Used to tear down the private part of an address space
+pub fn free_tables(root: *mut PageTable, first: usize, last: usize) {
+    fn free_level(table: *mut PageTable, level: usize) {
+        for i in 0..ENTRIES {
+            let entry = unsafe { (*table).entries[i] };
+            if is_valid(entry) && !is_leaf(entry) && level > 0 {
+                free_level(entry_address(entry) as *mut PageTable, level - 1);
+            }
+        }
+        free_pages(table as *mut u8);
+    }
+    for i in first..last.min(ENTRIES) {
+        let entry = unsafe { (*root).entries[i] };
+        if is_valid(entry) && !is_leaf(entry) {
+            free_level(entry_address(entry) as *mut PageTable, LEVELS - 2);
+        }
+        unsafe { (*root).entries[i] = 0 };
+    }
+}
+
+// Index of the root entry covering vaddr
+pub fn root_index(vaddr: usize) -> usize {
+    vpn(vaddr, LEVELS - 1)
+}
+
+// Map [start, end) onto itself, the range is widened to page boundaries
+// Aligned 2MB stretches not yet mapped with 4K pages become megapages
+pub fn id_map_range(root: *mut PageTable, start: usize, end: usize, flags: u64) -> bool {
+    let mut addr = start & !(PAGE_SIZE - 1);
+    while addr < end {
+        let mega = addr & (MEGAPAGE_SIZE - 1) == 0 && addr + MEGAPAGE_SIZE <= end;
+        if mega && map_mega(root, addr, addr, flags) {
+            addr += MEGAPAGE_SIZE;
+            continue;
+        }
+        if !map(root, addr, addr, flags) {
+            return false;
+        }
+        addr += PAGE_SIZE;
+    }
+    true
+}
+
+// Value for the satp register selecting root as an Sv39 table
+pub fn satp(root: *mut PageTable) -> usize {
+    SATP_MODE_SV39 | (root as usize >> 12)
+}
+
+// Name of the MMIO window addr falls into, if any
+pub fn mmio_window(addr: usize) -> Option<&'static str> {
+    let windows = [
+        TEST_DEVICE.map(|window| ("test device", window)),
+        Some(("clint", clint::window())),
+        Some(("plic", plic::window())),
+    ];
+    let page = |base: usize| (base..base + PAGE_SIZE).contains(&addr);
+    match windows
+        .iter()
+        .flatten()
+        .find(|(_, (start, end))| (*start..*end).contains(&addr))
+    {
+        Some((name, _)) => Some(name),
+        None if uart::mmio_windows().any(page) => Some("uart"),
+        None if pci::mmio_windows()
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&addr)) =>
+        {
+            Some("pci")
+        }
+        None => virtio::mmio_windows().any(page).then_some("virtio"),
+    }
+}
+
+// Build the kernel identity mapping and install it in satp
+pub fn init() -> bool {
+    log::info!("init paging");
+    let root = PageTable::new();
+    if root.is_null() {
+        log::error!("page table alloc fail...");
+        return false;
+    }
+    let ok = unsafe {
+        // rodata first as it may share its first page with the end of text
+        id_map_range(root, RODATA_START, RODATA_END, PTE_READ | PTE_GLOBAL)
+            && id_map_range(root, TEXT_START, TEXT_END, PTE_RX | PTE_GLOBAL)
+            // data, bss, stack and heap are contiguous up to the end of memory
+            && id_map_range(root, DATA_START, memory::end(), PTE_RW | PTE_GLOBAL)
+            // The device tree sits above the end of RAM the kernel uses
+            && fdt::range()
+                .filter(|&(start, _)| start >= memory::end())
+                .is_none_or(|(start, end)| id_map_range(root, start, end, PTE_READ | PTE_GLOBAL))
+            && [TEST_DEVICE, Some(clint::window()), Some(plic::window())]
+                .iter()
+                .flatten()
+                .all(|&(start, end)| id_map_range(root, start, end, PTE_RW | PTE_GLOBAL))
+            && uart::mmio_windows()
+                .all(|addr| id_map_range(root, addr, addr + PAGE_SIZE, PTE_RW | PTE_GLOBAL))
+            && virtio::mmio_windows()
+                .all(|addr| id_map_range(root, addr, addr + PAGE_SIZE, PTE_RW | PTE_GLOBAL))
+            && pci::mmio_windows()
+                .iter()
+                .all(|&(start, end)| id_map_range(root, start, end, PTE_RW | PTE_GLOBAL))
+    };
+    if !ok {
+        return false;
+    }
+    unsafe { KERNEL_ROOT = root };
+    assembly::write_satp(satp(root));
+    true
+}
+
+// Root of the kernel identity mapping, null before init()
+pub fn kernel_root() -> *mut PageTable {
+    unsafe { KERNEL_ROOT }
+}
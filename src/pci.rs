@@ -0,0 +1,434 @@
+use crate::config::BOARD;
+use crate::fdt::{self, Node};
+use crate::log;
+use rust_alloc::vec::Vec;
+
+// mod pci.rs
+// PCI Express enumeration through ECAM
+// The host bridge maps the configuration space of every function, 4K each at
+// bus << 20 | device << 15 | function << 12 from its start. Where is read from
+// the pci-host-ecam-generic node of the device tree, the board is the
+// fallback without one
+// With -bios none there is no firmware setting the bus up, so init() walks it,
+// sizes the memory BARs of every function and assigns them from the bridge's
+// 32 bit memory window, turns on decoding and bus mastering and routes the
+// INTx pin to a PLIC source through the interrupt-map
+// Drivers find their functions in functions(), virtio::discover() binds the
+// virtio-pci ones. Bridges are listed but not configured, QEMU virt puts its
+// devices on bus 0. MSI and MSI-X stay off, functions share the four level
+// triggered INTx lines
+
+const COMPATIBLE: [&str; 1] = ["pci-host-ecam-generic"];
+
+// Offsets into the configuration header
+const VENDOR_ID: usize = 0x00;
+const DEVICE_ID: usize = 0x02;
+const COMMAND: usize = 0x04;
+const STATUS: usize = 0x06;
+const HEADER_TYPE: usize = 0x0e;
+const BAR0: usize = 0x10;
+pub const SUBSYSTEM_ID: usize = 0x2e;
+const CAPABILITIES: usize = 0x34;
+const INTERRUPT_PIN: usize = 0x3d;
+
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_GENERAL: u8 = 0;
+const BAR_IO: u32 = 1;
+const BAR_TYPE_MASK: u32 = 3 << 1;
+const BAR_64: u32 = 2 << 1;
+const BAR_ADDRESS_MASK: u32 = !0xf;
+const NO_DEVICE: u16 = 0xffff;
+
+pub const BARS: usize = 6;
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+const BUS_SIZE: usize = 1 << 20;
+const FUNCTION_SIZE: usize = 1 << 12;
+// Address space code in the first cell of a ranges entry
+const SPACE_MASK: u32 = 3 << 24;
+const SPACE_MEMORY_32: u32 = 2 << 24;
+// Capability lists are short, a looping one is cut off
+const MAX_CAPABILITIES: usize = 48;
+
+// An interrupt-map entry, the masked device address and pin and the PLIC
+// source they are wired to
+#[derive(Clone, Copy)]
+struct Route {
+    address: u32,
+    pin: u32,
+    irq: u32,
+}
+
+struct Host {
+    // Configuration space, start and size, and the bus it starts at
+    ecam: (usize, usize),
+    first_bus: usize,
+    // 32 bit memory window, pci address, cpu address and size
+    memory: (usize, usize, usize),
+    routes: Vec<Route>,
+    // interrupt-map-mask of the device address and of the pin
+    mask: (u32, u32),
+    // Source of INTA at device 0 without an interrupt-map, swizzled by device
+    first_irq: Option<u32>,
+}
+
+// A function found on the bus
+#[derive(Clone, Copy, Debug)]
+pub struct Function {
+    // Address of its configuration space
+    pub config: usize,
+    pub bus: usize,
+    pub device: u8,
+    pub function: u8,
+    pub vendor: u16,
+    pub device_id: u16,
+    // Assigned memory BARs, cpu address and size. A 64 bit BAR takes two
+    // slots and is listed in the first
+    pub bars: [Option<(usize, usize)>; BARS],
+    // PLIC source of its INTx pin, None if it does not interrupt
+    pub irq: Option<u32>,
+}
+
+static mut HOST: Option<Host> = None;
+static mut FUNCTIONS: Vec<Function> = Vec::new();
+
+impl Host {
+    fn from_fdt() -> Option<Self> {
+        let node = fdt::nodes()
+            .into_iter()
+            .find(|n| COMPATIBLE.iter().any(|c| n.is_compatible(c)))?;
+        let (start, size) = node.reg()?;
+        let (routes, mask) = interrupt_map(&node);
+        Some(Self {
+            ecam: (start as usize, size as usize),
+            first_bus: node
+                .property("bus-range")
+                .and_then(|p| p.cell(0))
+                .unwrap_or(0) as usize,
+            memory: memory_window(&node)?,
+            routes,
+            mask,
+            first_irq: None,
+        })
+    }
+
+    fn from_board() -> Option<Self> {
+        let pcie = BOARD.pcie.as_ref()?;
+        Some(Self {
+            ecam: pcie.ecam,
+            first_bus: 0,
+            memory: (pcie.memory.0, pcie.memory.0, pcie.memory.1),
+            routes: Vec::new(),
+            mask: (0, 0),
+            first_irq: Some(pcie.first_irq),
+        })
+    }
+
+    fn config(&self, bus: usize, device: u8, function: u8) -> Option<usize> {
+        let offset = (bus - self.first_bus) * BUS_SIZE
+            + ((device as usize) << 15 | (function as usize) << 12);
+        (offset < self.ecam.1).then_some(self.ecam.0 + offset)
+    }
+
+    // PLIC source of pin 1..4 (INTA..INTD) of a function, None for pin 0
+    fn route(&self, bus: usize, device: u8, function: u8, pin: u8) -> Option<u32> {
+        if pin == 0 {
+            return None;
+        }
+        if let Some(first_irq) = self.first_irq {
+            return Some(first_irq + (device as u32 + pin as u32 - 1) % 4);
+        }
+        let address = (bus as u32) << 16 | (device as u32) << 11 | (function as u32) << 8;
+        let (address, pin) = (address & self.mask.0, pin as u32 & self.mask.1);
+        self.routes
+            .iter()
+            .find(|r| r.address == address && r.pin == pin)
+            .map(|r| r.irq)
+    }
+
+    // Every function on the buses the ECAM window covers, configured
+    fn scan(&self) -> Vec<Function> {
+        let mut functions = Vec::new();
+        let mut next = self.memory.1;
+        for bus in self.first_bus..self.first_bus + self.ecam.1 / BUS_SIZE {
+            for device in 0..DEVICES_PER_BUS {
+                for function in 0..FUNCTIONS_PER_DEVICE {
+                    let Some(config) = self.config(bus, device, function) else {
+                        break;
+                    };
+                    if read16(config, VENDOR_ID) == NO_DEVICE {
+                        if function == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    functions.push(self.configure(config, bus, device, function, &mut next));
+                    let header = read8(config, HEADER_TYPE);
+                    if function == 0 && header & HEADER_MULTI_FUNCTION == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        functions
+    }
+
+    // Assign the memory BARs of a function from next on and enable it
+    fn configure(
+        &self,
+        config: usize,
+        bus: usize,
+        device: u8,
+        function: u8,
+        next: &mut usize,
+    ) -> Function {
+        let mut found = Function {
+            config,
+            bus,
+            device,
+            function,
+            vendor: read16(config, VENDOR_ID),
+            device_id: read16(config, DEVICE_ID),
+            bars: [None; BARS],
+            irq: None,
+        };
+        if read8(config, HEADER_TYPE) & !HEADER_MULTI_FUNCTION != HEADER_GENERAL {
+            return found;
+        }
+        // Decoding stays off while the BARs are sized
+        let command = read16(config, COMMAND) & !(COMMAND_MEMORY | COMMAND_BUS_MASTER);
+        write16(config, COMMAND, command);
+        let (window_end, mut bar) = (self.memory.1 + self.memory.2, 0);
+        while bar < BARS {
+            let offset = BAR0 + bar * 4;
+            let (size, wide) = size_bar(config, offset, bar + 1 < BARS);
+            if size != 0 {
+                let address = next.next_multiple_of(size);
+                if address + size <= window_end {
+                    let pci_address = address - self.memory.1 + self.memory.0;
+                    write32(config, offset, pci_address as u32);
+                    if wide {
+                        write32(config, offset + 4, (pci_address >> 32) as u32);
+                    }
+                    found.bars[bar] = Some((address, size));
+                    *next = address + size;
+                } else {
+                    log::warn!(
+                        "no room for BAR{} of pci {:02x}:{:02x}.{}",
+                        bar,
+                        bus,
+                        device,
+                        function
+                    );
+                }
+            }
+            bar += if wide { 2 } else { 1 };
+        }
+        found.irq = self.route(bus, device, function, read8(config, INTERRUPT_PIN));
+        write16(
+            config,
+            COMMAND,
+            command | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+        found
+    }
+}
+
+// Size of the memory BAR at offset, 0 for an I/O or unimplemented one, and
+// whether it is a 64 bit BAR taking the next slot too
+fn size_bar(config: usize, offset: usize, has_next: bool) -> (usize, bool) {
+    let original = read32(config, offset);
+    if original & BAR_IO != 0 {
+        return (0, false);
+    }
+    let wide = original & BAR_TYPE_MASK == BAR_64 && has_next;
+    write32(config, offset, u32::MAX);
+    let low = read32(config, offset) & BAR_ADDRESS_MASK;
+    write32(config, offset, original);
+    let mask = if wide {
+        let high = read32(config, offset + 4);
+        write32(config, offset + 4, u32::MAX);
+        let mask = (read32(config, offset + 4) as u64) << 32 | low as u64;
+        write32(config, offset + 4, high);
+        mask
+    } else if low != 0 {
+        0xffff_ffff_0000_0000 | low as u64
+    } else {
+        0
+    };
+    match mask {
+        0 => (0, wide),
+        mask => ((!mask).wrapping_add(1) as usize, wide),
+    }
+}
+
+// The first 32 bit memory range of the bridge, pci address, cpu address and
+// size
+fn memory_window(node: &Node) -> Option<(usize, usize, usize)> {
+    let ranges = node.property("ranges")?;
+    let child = node.cell("#address-cells").unwrap_or(3);
+    let parent = node.parent_address_cells();
+    let size = node.cell("#size-cells").unwrap_or(2);
+    let entry = (child + parent + size) as usize;
+    (0..ranges.value.len() / 4 / entry)
+        .map(|idx| idx * entry)
+        .filter(|&at| {
+            ranges
+                .cell(at)
+                .is_some_and(|space| space & SPACE_MASK == SPACE_MEMORY_32)
+        })
+        .find_map(|at| {
+            let pci = ranges.cells(at + 1, child - 1)?;
+            let cpu = ranges.cells(at + child as usize, parent)?;
+            let len = ranges.cells(at + (child + parent) as usize, size)?;
+            Some((pci as usize, cpu as usize, len as usize))
+        })
+}
+
+// The INTx routes of the bridge and the mask applied before looking them up
+// Each entry is the device address, the pin, the phandle of the interrupt
+// controller, its unit address and the interrupt, cell counts of the last two
+// come from the controller
+fn interrupt_map(node: &Node) -> (Vec<Route>, (u32, u32)) {
+    let mut routes = Vec::new();
+    let child = node.cell("#address-cells").unwrap_or(3) as usize;
+    let pin_cells = node.cell("#interrupt-cells").unwrap_or(1) as usize;
+    let mask = node.property("interrupt-map-mask").map_or((0, 0), |m| {
+        (m.cell(0).unwrap_or(0), m.cell(child).unwrap_or(0))
+    });
+    let Some(map) = node.property("interrupt-map") else {
+        return (routes, mask);
+    };
+    // The controller is the same for every entry, look it up once
+    let mut parent: Option<(u32, usize, usize)> = None;
+    let mut at = 0;
+    while let Some(phandle) = map.cell(at + child + pin_cells) {
+        let (address_cells, interrupt_cells) = match parent {
+            Some((known, address, interrupt)) if known == phandle => (address, interrupt),
+            _ => {
+                let Some(controller) = fdt::phandle(phandle) else {
+                    log::warn!("pci interrupt-map refers to unknown phandle {}", phandle);
+                    break;
+                };
+                let address = controller.cell("#address-cells").unwrap_or(0) as usize;
+                let interrupt = controller.cell("#interrupt-cells").unwrap_or(1) as usize;
+                parent = Some((phandle, address, interrupt));
+                (address, interrupt)
+            }
+        };
+        let irq_at = at + child + pin_cells + 1 + address_cells;
+        if let (Some(address), Some(pin), Some(irq)) =
+            (map.cell(at), map.cell(at + child), map.cell(irq_at))
+        {
+            routes.push(Route { address, pin, irq });
+        }
+        at = irq_at + interrupt_cells;
+    }
+    (routes, mask)
+}
+
+// ====================================================
+// The public interface for pci is here...
+// ====================================================
+
+// Find the host bridge and configure every function behind it
+// Runs after the PLIC and before virtio::discover, which binds virtio-pci
+pub fn init() {
+    log::info!("init pci");
+    // Without a device tree the board says where the bridge is, with one a
+    // machine without a bridge has no pcie node
+    let host = match fdt::range() {
+        Some(_) => Host::from_fdt(),
+        None => Host::from_board(),
+    };
+    let Some(host) = host else {
+        log::info!("no PCIe host bridge");
+        return;
+    };
+    let functions = host.scan();
+    for f in functions.iter() {
+        log::info!(
+            "pci {:02x}:{:02x}.{} {:04x}:{:04x}, irq {:?}",
+            f.bus,
+            f.device,
+            f.function,
+            f.vendor,
+            f.device_id,
+            f.irq
+        );
+    }
+    unsafe {
+        HOST = Some(host);
+        FUNCTIONS = functions;
+    }
+}
+
+// Every function found by init()
+pub fn functions() -> Vec<Function> {
+    unsafe { FUNCTIONS.clone() }
+}
+
+// The function whose configuration space is at config
+pub fn function(config: usize) -> Option<Function> {
+    unsafe { FUNCTIONS.iter().find(|f| f.config == config).copied() }
+}
+
+// True if addr lies in the configuration space of the host bridge
+pub fn is_config(addr: usize) -> bool {
+    unsafe {
+        HOST.as_ref()
+            .is_some_and(|host| (host.ecam.0..host.ecam.0 + host.ecam.1).contains(&addr))
+    }
+}
+
+// Offsets of the capabilities in the configuration space of a function
+pub fn capabilities(config: usize) -> impl Iterator<Item = usize> {
+    let first = match read16(config, STATUS) & STATUS_CAPABILITIES {
+        0 => 0,
+        _ => read8(config, CAPABILITIES) as usize & !3,
+    };
+    core::iter::successors(Some(first), move |&at| {
+        Some(read8(config, at + 1) as usize & !3)
+    })
+    .take_while(|&at| at != 0)
+    .take(MAX_CAPABILITIES)
+}
+
+// Configuration spaces and assigned BARs of every function, start and end,
+// for the kernel mapping
+pub fn mmio_windows() -> Vec<(usize, usize)> {
+    let mut windows = Vec::new();
+    for f in unsafe { FUNCTIONS.iter() } {
+        windows.push((f.config, f.config + FUNCTION_SIZE));
+        windows.extend(
+            f.bars
+                .iter()
+                .flatten()
+                .map(|&(start, size)| (start, start + size)),
+        );
+    }
+    windows
+}
+
+pub fn read8(config: usize, offset: usize) -> u8 {
+    unsafe { ((config + offset) as *const u8).read_volatile() }
+}
+
+pub fn read16(config: usize, offset: usize) -> u16 {
+    unsafe { ((config + offset) as *const u16).read_volatile() }
+}
+
+pub fn read32(config: usize, offset: usize) -> u32 {
+    unsafe { ((config + offset) as *const u32).read_volatile() }
+}
+
+pub fn write16(config: usize, offset: usize, value: u16) {
+    unsafe { ((config + offset) as *mut u16).write_volatile(value) }
+}
+
+pub fn write32(config: usize, offset: usize, value: u32) {
+    unsafe { ((config + offset) as *mut u32).write_volatile(value) }
+}
@@ -0,0 +1,82 @@
+// mod platform.rs
+// The boards the kernel can be built for
+// A Board says where a machine keeps what the drivers need before, or
+// without, a device tree: RAM, the console uart, the CLINT and PLIC, the
+// virtio-mmio slots, the PCIe host bridge, the timebase and the test device
+// that powers it off
+// The drivers read these through BOARD in config.rs, porting to another
+// machine, e.g. sifive_u or spike, means describing it here and pointing BOARD
+// at it, plus a link script whose RAM matches
+// Whatever the device tree says wins over the board at boot
+
+// virtio-mmio windows at a fixed stride, the interrupts of consecutive slots
+// are consecutive PLIC sources
+pub struct VirtioSlots {
+    pub base: usize,
+    pub count: usize,
+    pub stride: usize,
+    pub first_irq: u32,
+}
+
+// A PCIe host bridge with ECAM, the interrupts of its four INTx lines are
+// consecutive PLIC sources, swizzled by device number
+pub struct PcieHost {
+    // Configuration space, start and size, 1M per bus from bus 0
+    pub ecam: (usize, usize),
+    // The 32 bit memory window BARs are assigned from, start and size
+    pub memory: (usize, usize),
+    pub first_irq: u32,
+}
+
+pub struct Board {
+    pub name: &'static str,
+    // Start and size of RAM, must match the link script
+    pub ram: (usize, usize),
+    // Base and PLIC source of the console uart, a 16550
+    pub uart: (usize, u32),
+    // Register windows, start and size
+    pub clint: (usize, usize),
+    pub plic: (usize, usize),
+    // Interrupt sources of the PLIC, source 0 included
+    pub plic_sources: usize,
+    // Ticks per second of mtime and the time CSR
+    pub timebase_frequency: u64,
+    pub virtio: VirtioSlots,
+    pub pcie: Option<PcieHost>,
+    // The sifive,test0 device that powers off and resets the machine, if any
+    pub test_device: Option<usize>,
+}
+
+impl Board {
+    // End of RAM, where it stops without a memory node in the device tree
+    pub const fn ram_end(&self) -> usize {
+        self.ram.0 + self.ram.1
+    }
+}
+
+// ====================================================
+// The public interface for platform is here...
+// ====================================================
+
+// QEMU's virt machine, -machine virt with the default 128M of RAM
+pub const QEMU_VIRT: Board = Board {
+    name: "RISCV-64 QEMU Virt",
+    ram: (0x8000_0000, 128 * 1024 * 1024),
+    uart: (0x1000_0000, 10),
+    clint: (0x0200_0000, 0x1_0000),
+    plic: (0x0c00_0000, 0x40_0000),
+    plic_sources: 96,
+    timebase_frequency: 10_000_000,
+    virtio: VirtioSlots {
+        base: 0x1000_1000,
+        count: 8,
+        stride: 0x1000,
+        first_irq: 1,
+    },
+    pcie: Some(PcieHost {
+        ecam: (0x3000_0000, 0x1000_0000),
+        memory: (0x4000_0000, 0x4000_0000),
+        first_irq: 32,
+    }),
+    test_device: Some(0x10_0000),
+};
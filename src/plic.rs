@@ -1,18 +1,95 @@
-use crate::uart::serial_info;
-use crate::virtio;
-use crate::{print, println};
+use crate::config::{BOARD, MAX_HARTS, PLIC_SOURCES};
+use crate::fdt;
+use crate::hart;
+use crate::irqlog::{self, IrqSource};
+use crate::log;
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::trace::{self, trace_event};
+use crate::trap;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
 // mod plic.rs
-// This is a very simple PLIC driver that enables 8 PLIC interrupts
-// @ priority 1 / threshold @ 0.
+// This is a very simple PLIC driver, drivers register a handler for each
+// interrupt they own, which enables it @ priority 1 / threshold @ 0.
+// Enables, threshold and claim are per context, every online hart has its own
+// Each context has one enable bit per source, packed 32 to a word
+// The registers are found from the reg of the device tree node, the board's
+// address is the fallback
 
-const PLIC_PRIORITY: usize = 0x0C00_0000;
-const PLIC_INT_ENABLE: usize = 0x0C00_2000;
-const PLIC_THRESHOLD: usize = 0x0C20_0000;
-const PLIC_CLAIM: usize = 0x0C20_0004;
+// Every hart has a machine mode context followed by a supervisor mode one
+// The kernel takes interrupts in the context of the mode it runs in
+#[cfg(not(feature = "supervisor"))]
+const MODE_CONTEXT: usize = 0;
+#[cfg(feature = "supervisor")]
+const MODE_CONTEXT: usize = 1;
+const CONTEXTS_PER_HART: usize = 2;
 
-fn next_plic_interrupt() -> Option<u32> {
-    let claim_register = PLIC_CLAIM as *const u32;
+// Offsets from the base of the PLIC
+const PLIC_PRIORITY: usize = 0x0000;
+const PLIC_INT_ENABLE: usize = 0x2000;
+const PLIC_INT_ENABLE_STRIDE: usize = 0x80;
+const PLIC_THRESHOLD: usize = 0x20_0000;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+// Priorities and thresholds go from 0 to 7 on QEMU virt, a source at priority 0
+// never interrupts and a hart only takes sources above its threshold
+pub const MAX_PRIORITY: u32 = 7;
+pub const DEFAULT_PRIORITY: u32 = 1;
+const ENABLE_WORDS: usize = PLIC_SOURCES.div_ceil(32);
+
+// Device tree compatible strings of the PLIC
+const COMPATIBLE: [&str; 2] = ["riscv,plic0", "sifive,plic-1.0.0"];
+
+// Sources the PLIC in the device tree implements, source 0 included
+// Capped at PLIC_SOURCES, which is also the fallback without a device tree
+static SOURCES: AtomicU32 = AtomicU32::new(PLIC_SOURCES as u32);
+
+// Register window of the PLIC, start and size
+static BASE: AtomicUsize = AtomicUsize::new(BOARD.plic.0);
+static SIZE: AtomicUsize = AtomicUsize::new(BOARD.plic.1);
+
+// Driver handlers by interrupt number, claimed interrupts are dispatched here
+static HANDLERS: SpinLock<[Option<Handler>; PLIC_SOURCES]> = SpinLock::new([None; PLIC_SOURCES]);
+
+// Priority of each masked interrupt, restored when it is unmasked
+// Masking drops the priority to 0 and leaves the enables alone, so where the
+// interrupt is routed survives it
+static MASKED: SpinLock<[Option<u32>; PLIC_SOURCES]> = SpinLock::new([None; PLIC_SOURCES]);
+
+// Called with the interrupt number it was claimed for
+pub type Handler = fn(u32);
+
+// A PLIC context, the hart and privilege mode interrupts are delivered to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Context(usize);
+
+impl Context {
+    // The context hart takes the kernel's interrupts in
+    pub const fn of(hart: usize) -> Self {
+        Self(hart * CONTEXTS_PER_HART + MODE_CONTEXT)
+    }
+
+    // The enable word holding the bit of source id
+    fn enable_register(self, id: u32) -> *mut u32 {
+        let word = id as usize / 32;
+        (base() + PLIC_INT_ENABLE + self.0 * PLIC_INT_ENABLE_STRIDE + word * 4) as *mut u32
+    }
+
+    fn threshold_register(self) -> *mut u32 {
+        (base() + PLIC_THRESHOLD + self.0 * PLIC_CONTEXT_STRIDE) as *mut u32
+    }
+
+    fn claim_register(self) -> *mut u32 {
+        (base() + PLIC_THRESHOLD + self.0 * PLIC_CONTEXT_STRIDE + 4) as *mut u32
+    }
+}
+
+fn base() -> usize {
+    BASE.load(Ordering::Relaxed)
+}
+
+fn next_plic_interrupt(ctx: Context) -> Option<u32> {
+    let claim_register = ctx.claim_register();
     let claim_number;
     unsafe {
         claim_number = claim_register.read_volatile();
@@ -24,32 +101,48 @@ fn next_plic_interrupt() -> Option<u32> {
     }
 }
 
-fn complete(id: u32) {
-    let claim_register = PLIC_CLAIM as *mut u32;
+fn complete(ctx: Context, id: u32) {
+    let claim_register = ctx.claim_register();
     unsafe {
         claim_register.write_volatile(id);
     }
 }
 
-fn set_threshold(tsh: u32) {
-    let threshold = tsh & 0b111;
-    let threshold_regsiter = PLIC_THRESHOLD as *mut u32;
+fn write_threshold(ctx: Context, tsh: u32) {
+    let threshold = tsh.min(MAX_PRIORITY);
+    let threshold_regsiter = ctx.threshold_register();
     unsafe {
         threshold_regsiter.write_volatile(threshold);
     }
 }
 
-fn enable(id: u32) {
-    let int_enable_register = PLIC_INT_ENABLE as *mut u32;
-    let desired_id = 1 << id;
+fn enable(ctx: Context, id: u32) {
+    let int_enable_register = ctx.enable_register(id);
+    let desired_id = 1 << (id % 32);
     unsafe {
         int_enable_register.write_volatile(int_enable_register.read_volatile() | desired_id);
     }
 }
 
-fn set_priority(id: u32, priority: u32) {
-    let desired_priority = priority & 0b111;
-    let priority_register = PLIC_PRIORITY as *mut u32;
+fn disable(ctx: Context, id: u32) {
+    let int_enable_register = ctx.enable_register(id);
+    unsafe {
+        int_enable_register.write_volatile(int_enable_register.read_volatile() & !(1 << (id % 32)));
+    }
+}
+
+fn is_enabled(ctx: Context, id: u32) -> bool {
+    unsafe { ctx.enable_register(id).read_volatile() & (1 << (id % 32)) != 0 }
+}
+
+// A source drivers can use, 0 means no interrupt
+fn in_range(id: u32) -> bool {
+    id != 0 && id < sources()
+}
+
+fn write_priority(id: u32, priority: u32) {
+    let desired_priority = priority.min(MAX_PRIORITY);
+    let priority_register = (base() + PLIC_PRIORITY) as *mut u32;
     unsafe {
         priority_register
             .add(id as usize)
@@ -57,25 +150,217 @@ fn set_priority(id: u32, priority: u32) {
     }
 }
 
+fn read_priority(id: u32) -> u32 {
+    unsafe {
+        ((base() + PLIC_PRIORITY) as *const u32)
+            .add(id as usize)
+            .read_volatile()
+    }
+}
+
+// Must run before drivers register their interrupts
 pub fn init() {
-    serial_info("init plic");
-    set_threshold(0);
-    for i in 1..=8 {
-        enable(i);
-        set_priority(i, 1);
+    log::info!("init plic");
+    let nodes = fdt::nodes();
+    let plic = nodes
+        .iter()
+        .find(|n| COMPATIBLE.iter().any(|c| n.is_compatible(c)));
+    match plic.and_then(|plic| plic.reg()) {
+        Some((base, size)) => {
+            BASE.store(base as usize, Ordering::Relaxed);
+            SIZE.store(size as usize, Ordering::Relaxed);
+        }
+        None => log::warn!("no plic in the device tree, using 0x{:x}", base()),
+    }
+    // riscv,ndev is the highest source number
+    let ndev = plic.and_then(|plic| plic.property("riscv,ndev")?.cell(0));
+    if let Some(ndev) = ndev {
+        if ndev as usize >= PLIC_SOURCES {
+            log::warn!(
+                "plic has {} sources, using the first {}",
+                ndev + 1,
+                PLIC_SOURCES
+            );
+        }
+        SOURCES.store((ndev + 1).min(PLIC_SOURCES as u32), Ordering::Relaxed);
+    }
+    init_hart(0);
+}
+
+// Start and end of the PLIC registers, for the kernel mapping
+pub fn window() -> (usize, usize) {
+    (base(), base() + SIZE.load(Ordering::Relaxed))
+}
+
+// Number of interrupt sources including the reserved source 0
+pub fn sources() -> u32 {
+    SOURCES.load(Ordering::Relaxed)
+}
+
+// Dispatch irq to handler and enable it, on the boot hart until routed elsewhere
+// False if irq is out of range or another handler has it
+pub fn register(irq: u32, handler: Handler) -> bool {
+    if !in_range(irq) {
+        return false;
+    }
+    {
+        let mut handlers = HANDLERS.lock_irq();
+        if handlers[irq as usize].is_some() {
+            return false;
+        }
+        handlers[irq as usize] = Some(handler);
+    }
+    MASKED.lock_irq()[irq as usize] = None;
+    write_priority(irq, DEFAULT_PRIORITY);
+    enable(Context::of(0), irq);
+    true
+}
+
+// Disable irq everywhere and forget its handler, false if it had none
+pub fn unregister(irq: u32) -> bool {
+    if !in_range(irq) || HANDLERS.lock_irq()[irq as usize].is_none() {
+        return false;
+    }
+    for hart in 0..MAX_HARTS {
+        disable(Context::of(hart), irq);
+    }
+    write_priority(irq, 0);
+    MASKED.lock_irq()[irq as usize] = None;
+    HANDLERS.lock_irq()[irq as usize] = None;
+    true
+}
+
+pub fn is_registered(irq: u32) -> bool {
+    in_range(irq) && HANDLERS.lock_irq()[irq as usize].is_some()
+}
+
+// Called by init for the boot hart and on each secondary hart as it comes online
+// It takes no device interrupts until some are routed to it
+pub fn init_hart(hart: usize) {
+    let ctx = Context::of(hart);
+    for word in 0..ENABLE_WORDS as u32 {
+        unsafe { ctx.enable_register(word * 32).write_volatile(0) };
+    }
+    write_threshold(ctx, 0);
+}
+
+// Raise irq above others, e.g. a latency sensitive device over bulk block I/O
+// Registered interrupts start at DEFAULT_PRIORITY, values above MAX_PRIORITY
+// are capped. False if irq is out of range
+pub fn set_priority(irq: u32, priority: u32) -> bool {
+    if !in_range(irq) {
+        return false;
+    }
+    // A masked interrupt gets it when unmasked
+    match MASKED.lock_irq()[irq as usize].as_mut() {
+        Some(saved) => *saved = priority.min(MAX_PRIORITY),
+        None => write_priority(irq, priority),
+    }
+    true
+}
+
+// The priority irq has, or gets back when unmasked
+pub fn priority(irq: u32) -> Option<u32> {
+    if !in_range(irq) {
+        return None;
+    }
+    let masked = MASKED.lock_irq()[irq as usize];
+    Some(masked.unwrap_or_else(|| read_priority(irq)))
+}
+
+// Silence irq on every hart while its device is reconfigured, keeping the
+// handler, priority and routing. It stays pending in the PLIC until unmasked
+// False if irq is not registered
+pub fn mask(irq: u32) -> bool {
+    if !is_registered(irq) {
+        return false;
+    }
+    let mut masked = MASKED.lock_irq();
+    if masked[irq as usize].is_none() {
+        masked[irq as usize] = Some(read_priority(irq));
+        write_priority(irq, 0);
+    }
+    true
+}
+
+// Undo mask, false if irq is not masked
+pub fn unmask(irq: u32) -> bool {
+    if !in_range(irq) {
+        return false;
+    }
+    match MASKED.lock_irq()[irq as usize].take() {
+        Some(priority) => {
+            write_priority(irq, priority);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn is_masked(irq: u32) -> bool {
+    in_range(irq) && MASKED.lock_irq()[irq as usize].is_some()
+}
+
+// Only interrupts with a priority above threshold reach hart
+// Capped at MAX_PRIORITY, which masks every source. False for a bad hart
+pub fn set_threshold(hart: usize, threshold: u32) -> bool {
+    if hart >= MAX_HARTS {
+        return false;
+    }
+    write_threshold(Context::of(hart), threshold);
+    true
+}
+
+pub fn threshold(hart: usize) -> Option<u32> {
+    (hart < MAX_HARTS).then(|| unsafe { Context::of(hart).threshold_register().read_volatile() })
+}
+
+// Deliver irq to hart only, false if irq is out of range or the hart is not online
+pub fn route(irq: u32, hart: usize) -> bool {
+    if !in_range(irq) || !smp::is_online(hart) {
+        return false;
+    }
+    for other in (0..MAX_HARTS).filter(|&h| h != hart && smp::is_online(h)) {
+        disable(Context::of(other), irq);
+    }
+    enable(Context::of(hart), irq);
+    true
+}
+
+// Move every interrupt delivered to from over to to
+pub fn migrate(from: usize, to: usize) {
+    for irq in 1..sources() {
+        if is_enabled(Context::of(from), irq) {
+            disable(Context::of(from), irq);
+            enable(Context::of(to), irq);
+        }
+    }
+}
+
+// The online hart irq is delivered to, the lowest one if it goes to several
+pub fn target(irq: u32) -> Option<usize> {
+    if !in_range(irq) {
+        return None;
     }
+    (0..MAX_HARTS).find(|&hart| smp::is_online(hart) && is_enabled(Context::of(hart), irq))
 }
 
+// Claims from the context of the hart it runs on
 pub fn interrupt_handler() {
-    if let Some(interrupt) = next_plic_interrupt() {
-        match interrupt {
-            1..=8 => {
-                virtio::interrupt_handler(interrupt);
-            }
-            _ => {
-                println!("Unhandled external interrupt: {}", interrupt);
-            }
+    let ctx = Context::of(hart::id());
+    if let Some(interrupt) = next_plic_interrupt(ctx) {
+        irqlog::record(IrqSource::External(interrupt));
+        trace_event!(trace::IRQ, interrupt);
+        trap::count_external(interrupt);
+        let handler = HANDLERS
+            .lock_irq()
+            .get(interrupt as usize)
+            .copied()
+            .flatten();
+        match handler {
+            Some(handler) => handler(interrupt),
+            None => log::warn!("Unhandled external interrupt: {}", interrupt),
         }
-        complete(interrupt);
+        complete(ctx, interrupt);
     }
 }
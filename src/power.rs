@@ -0,0 +1,95 @@
+use crate::assembly;
+use crate::block;
+#[cfg(not(feature = "sbi"))]
+use crate::config::BOARD;
+use crate::log;
+#[cfg(feature = "sbi")]
+use crate::sbi;
+use crate::uart;
+use crate::vfs;
+
+// mod power.rs
+// Powering the machine off and resetting it, through the sifive test device
+// of the board, or the firmware under --features "sbi"
+// shutdown() and reboot() first write back what would be lost: the mounted
+// filesystems, the write cache of the block device and the uart FIFOs
+// QEMU exits with the code passed to shutdown(), 0 when all went well
+
+// Commands of the test device, which paging::init maps
+#[cfg_attr(feature = "sbi", allow(dead_code))]
+const FINISHER_FAIL: u32 = 0x3333;
+#[cfg_attr(feature = "sbi", allow(dead_code))]
+const FINISHER_PASS: u32 = 0x5555;
+#[cfg_attr(any(feature = "sbi", not(feature = "debug-monitor")), allow(dead_code))]
+const FINISHER_RESET: u32 = 0x7777;
+
+// Write back everything the machine stopping would lose
+fn sync() {
+    vfs::sync();
+    if block::is_present() {
+        if let Err(e) = block::flush() {
+            log::error!("block flush failed: {}", e);
+        }
+    }
+    uart::flush();
+}
+
+// Without a test device there is nothing to stop the machine, it just hangs
+#[cfg(not(feature = "sbi"))]
+fn finish(command: u32) {
+    if let Some(device) = BOARD.test_device {
+        unsafe { (device as *mut u32).write_volatile(command) };
+    }
+}
+
+// Wait for the machine to go away
+fn hang() -> ! {
+    loop {
+        assembly::wait_for_interrupt();
+    }
+}
+
+// ====================================================
+// The public interface for power is here...
+// ====================================================
+
+// Sync and power off, QEMU exits with code
+pub fn shutdown(code: u16) -> ! {
+    log::info!("shutting down, exit code {}", code);
+    sync();
+    halt(code)
+}
+
+// Sync and reset the machine, it boots the kernel again
+#[cfg_attr(not(feature = "debug-monitor"), allow(dead_code))]
+pub fn reboot() -> ! {
+    log::info!("rebooting");
+    sync();
+    #[cfg(not(feature = "sbi"))]
+    finish(FINISHER_RESET);
+    #[cfg(feature = "sbi")]
+    sbi::system_reset(sbi::RESET_COLD_REBOOT, sbi::REASON_NONE);
+    hang()
+}
+
+// Power off right away without writing anything back, for the panic handler
+// and anything else that cannot wait for the devices
+#[cfg(not(feature = "sbi"))]
+pub fn halt(code: u16) -> ! {
+    match code {
+        0 => finish(FINISHER_PASS),
+        code => finish(FINISHER_FAIL | (code as u32) << 16),
+    }
+    hang()
+}
+
+// The firmware reports a failure without the code, QEMU exits with 1
+#[cfg(feature = "sbi")]
+pub fn halt(code: u16) -> ! {
+    let reason = match code {
+        0 => sbi::REASON_NONE,
+        _ => sbi::REASON_SYSTEM_FAILURE,
+    };
+    sbi::system_reset(sbi::RESET_SHUTDOWN, reason);
+    hang()
+}
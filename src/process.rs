@@ -0,0 +1,640 @@
+use crate::addrspace::{self, AddressSpace};
+use crate::alloc::{alloc_pages, free_pages};
+use crate::assembly;
+use crate::atomics;
+use crate::config::{PAGE_SIZE, TASK_STACK_PAGES, TIMESLICE_TICKS};
+use crate::hart;
+use crate::irq;
+use crate::log;
+use crate::memory::memset;
+use crate::paging;
+use crate::trace::{self, trace_event};
+use crate::trap::TrapFrame;
+use crate::vfs::OpenFile;
+use crate::{print, println};
+use core::ptr::addr_of_mut;
+use rust_alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+// mod process.rs
+// Kernel tasks and the context switch between them
+// Each task runs on its own kernel stack. Switching is cooperative, a task
+// runs until it calls schedule, block or exit, which save its callee saved
+// registers and resume the ready task with the highest priority, taking turns
+// in pid order within a priority
+// A task's nice value sets the length of its timeslice, the timer interrupt
+// counts it down and need_resched reports when it is used up
+// Only the boot hart runs tasks, the secondary harts wait for interrupts in
+// smp::kernel_hart_main. When nothing else can run the boot hart switches to
+// its idle task, which waits for interrupts, the ticks charged to it are the
+// boot hart's idle time
+// kill ends a task that is not running on the spot. A running task can only
+// be signalled by itself and exits at its next schedule, block or syscall
+// init adopts the boot context running kernel_main as task 0
+// The trap frame holds the user registers of a task running in user mode
+// File descriptors 0-2 are the console, files a task opens start at FIRST_FD
+// The timer interrupt and device interrupt handlers change the task table
+// through tick and wake, everything else reaches it with interrupts disabled
+
+static mut TASKS: BTreeMap<usize, Box<Task>> = BTreeMap::new();
+static mut NEXT_PID: usize = 1;
+// Pid of the boot hart's idle task
+static mut IDLE: Option<usize> = None;
+
+const FIRST_FD: usize = 3;
+// Fills new kernel stacks so the deepest use can be measured
+const STACK_PAINT: u8 = 0xcc;
+const STACK_SIZE: usize = TASK_STACK_PAGES * PAGE_SIZE;
+const NICE_MIN: i8 = -20;
+const NICE_MAX: i8 = 19;
+
+extern "C" {
+    // see src/asm/switch.S
+    fn _switch_context(save: *mut Context, load: *const Context);
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Pid(pub usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TaskState {
+    Ready,
+    Running,
+    // Waiting for wake, or for mtime to reach wake_at
+    Blocked,
+    Dead,
+}
+
+impl TaskState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Blocked => "blocked",
+            TaskState::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+// Timeslice in timer ticks for a nice value, from twice TIMESLICE_TICKS at
+// NICE_MIN down to a single tick
+fn slice_ticks(nice: i8) -> u64 {
+    let scale = (20 - nice as i64) as u64;
+    (TIMESLICE_TICKS * scale / 20).max(1)
+}
+
+// Pid of the task running on this hart
+fn current_pid() -> usize {
+    hart::local().current
+}
+
+// Run f on the task table inside an irq section, so an interrupt handler
+// cannot change it halfway through
+fn with_tasks<R>(f: impl FnOnce(&'static mut BTreeMap<usize, Box<Task>>) -> R) -> R {
+    irq::with_disabled(|| f(unsafe { &mut *addr_of_mut!(TASKS) }))
+}
+
+// The same for a single task, None if there is no such task
+fn with_task<R>(pid: usize, f: impl FnOnce(&'static mut Task) -> R) -> Option<R> {
+    with_tasks(|tasks| tasks.get_mut(&pid).map(|task| f(task)))
+}
+
+// Signals kill can send, numbered as on Linux
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Signal {
+    // End the task at its next scheduling point, letting it finish what it is doing
+    Term,
+    // End the task without letting it run again
+    Kill,
+}
+
+impl Signal {
+    pub fn from_number(number: usize) -> Option<Self> {
+        match number {
+            9 => Some(Signal::Kill),
+            15 => Some(Signal::Term),
+            _ => None,
+        }
+    }
+}
+
+// A snapshot of one task for listings
+pub struct TaskInfo {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub state: TaskState,
+    // Deepest use of the kernel stack in bytes, both 0 for the boot task
+    pub stack_used: usize,
+    pub stack_size: usize,
+    pub priority: Priority,
+    pub nice: i8,
+    // Timer ticks taken while the task was running
+    pub ticks: u64,
+}
+
+// Registers preserved across _switch_context, the layout is shared with switch.S
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+pub struct Task {
+    pub name: &'static str,
+    pub state: TaskState,
+    // Saved user registers, unused until tasks run in user mode
+    #[allow(dead_code)]
+    pub frame: TrapFrame,
+    // Open files indexed by descriptor - FIRST_FD
+    files: Vec<Option<OpenFile>>,
+    // User memory, freed with the task
+    space: Option<AddressSpace>,
+    // Set by kill, acted on once the task runs into a scheduling point
+    pending: Option<Signal>,
+    // with_signals_held sections the task is in, pending is ignored until the
+    // last one ends
+    held: usize,
+    // mtime at which a blocked task is woken regardless
+    wake_at: Option<u64>,
+    ticks: u64,
+    priority: Priority,
+    nice: i8,
+    // Timer ticks left before the task should give up the hart
+    slice: u64,
+    // Only run when nothing else can
+    idle: bool,
+    context: Context,
+    // Lowest page of the kernel stack, null for the boot task
+    stack: *mut u8,
+    entry: Option<fn()>,
+}
+
+impl Task {
+    fn new(name: &'static str, state: TaskState, stack: *mut u8, context: Context) -> Self {
+        Self {
+            name,
+            state,
+            frame: TrapFrame::default(),
+            files: Vec::new(),
+            space: None,
+            pending: None,
+            held: 0,
+            wake_at: None,
+            ticks: 0,
+            priority: Priority::Normal,
+            nice: 0,
+            slice: slice_ticks(0),
+            idle: false,
+            context,
+            stack,
+            entry: None,
+        }
+    }
+
+    // Bytes of the stack that were ever written, the untouched paint is at its bottom
+    fn stack_used(&self) -> usize {
+        if self.stack.is_null() {
+            return 0;
+        }
+        let stack = unsafe { core::slice::from_raw_parts(self.stack, STACK_SIZE) };
+        let untouched = stack
+            .iter()
+            .position(|&b| b != STACK_PAINT)
+            .unwrap_or(STACK_SIZE);
+        STACK_SIZE - untouched
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if !self.stack.is_null() {
+            free_pages(self.stack);
+        }
+    }
+}
+
+// Where a new task starts, on its own stack with interrupts still disabled
+extern "C" fn task_entry() -> ! {
+    reap();
+    assembly::enable_interrupts();
+    if let Some(entry) = with_task(current_pid(), |t| t.entry).flatten() {
+        entry();
+    }
+    exit();
+}
+
+// The task to run next: the ready task with the highest priority, the first
+// after the current one in pid order among equals. A running current task
+// competes too, but comes last within its priority. Idle tasks never compete
+fn next_ready() -> Option<usize> {
+    let current = current_pid();
+    let mut next: Option<(usize, Priority)> = None;
+    unsafe {
+        for (&pid, task) in TASKS.range(current + 1..).chain(TASKS.range(..=current)) {
+            let runnable = !task.idle
+                && (task.state == TaskState::Ready
+                    || (pid == current && task.state == TaskState::Running));
+            if runnable && next.is_none_or(|(_, p)| task.priority > p) {
+                next = Some((pid, task.priority));
+            }
+        }
+    }
+    next.map(|(pid, _)| pid)
+}
+
+// Free the tasks that exited, which cannot include the one running
+fn reap() {
+    let current = current_pid();
+    unsafe { TASKS.retain(|&pid, t| t.state != TaskState::Dead || pid == current) };
+}
+
+// Make the task's address space the one page faults and translation use
+fn activate(task: &mut Task) {
+    match task.space.as_mut() {
+        Some(space) => {
+            addrspace::set_current(space);
+            assembly::write_satp(space.satp());
+        }
+        None => {
+            addrspace::set_current(core::ptr::null_mut());
+            let kernel = paging::kernel_root();
+            if !kernel.is_null() {
+                assembly::write_satp(paging::satp(kernel));
+            }
+        }
+    }
+}
+
+// Save the current task and resume task to, interrupts must be disabled
+unsafe fn switch(to: usize) {
+    let from = current_pid();
+    if from == to {
+        // Nothing better to run, or woken up again before anything else could run
+        if let Some(task) = TASKS.get_mut(&to) {
+            task.state = TaskState::Running;
+            task.slice = slice_ticks(task.nice);
+        }
+        return;
+    }
+    let save = match TASKS.get_mut(&from) {
+        Some(task) => {
+            if task.state == TaskState::Running {
+                task.state = TaskState::Ready;
+            }
+            &mut task.context as *mut Context
+        }
+        None => return,
+    };
+    let load = match TASKS.get_mut(&to) {
+        Some(task) => {
+            task.state = TaskState::Running;
+            task.slice = slice_ticks(task.nice);
+            activate(task);
+            &task.context as *const Context
+        }
+        None => return,
+    };
+    hart::local().current = to;
+    trace_event!(trace::SWITCH, to);
+    _switch_context(save, load);
+    // Running as `from` again
+    reap();
+}
+
+// Body of the idle task, interrupts are enabled in tasks so wfi returns on
+// the next one, which may have made a task ready
+fn idle() {
+    loop {
+        assembly::wait_for_interrupt();
+        schedule();
+    }
+}
+
+// ====================================================
+// The public interface for processes is here...
+// ====================================================
+
+// Adopt the running boot context as task 0
+pub fn init() {
+    log::info!("init process");
+    let boot = Task::new(
+        "kernel",
+        TaskState::Running,
+        core::ptr::null_mut(),
+        Context::default(),
+    );
+    unsafe {
+        hart::local().current = 0;
+        TASKS.insert(0, Box::new(boot));
+    }
+    if !spawn_boot_idle() {
+        log::error!("idle task alloc fail...");
+    }
+}
+
+// Create the idle task of the boot hart
+fn spawn_boot_idle() -> bool {
+    match spawn("idle", idle) {
+        Some(pid) => {
+            with_task(pid.0, |task| task.idle = true);
+            unsafe { IDLE = Some(pid.0) };
+            true
+        }
+        None => false,
+    }
+}
+
+// Create a ready kernel task running entry, it exits when entry returns
+pub fn spawn(name: &'static str, entry: fn()) -> Option<Pid> {
+    let stack = alloc_pages(TASK_STACK_PAGES);
+    if stack.is_null() {
+        return None;
+    }
+    unsafe {
+        memset(stack, STACK_PAINT, STACK_SIZE);
+        let pid = atomics::fetch_add(addr_of_mut!(NEXT_PID), 1);
+        let context = Context {
+            ra: task_entry as usize,
+            sp: stack as usize + STACK_SIZE,
+            ..Context::default()
+        };
+        let mut task = Task::new(name, TaskState::Ready, stack, context);
+        task.entry = Some(entry);
+        with_tasks(|tasks| tasks.insert(pid, Box::new(task)));
+        Some(Pid(pid))
+    }
+}
+
+// Change the priority of a task, false if there is no such task
+pub fn set_priority(pid: Pid, priority: Priority) -> bool {
+    with_task(pid.0, |task| task.priority = priority).is_some()
+}
+
+// Change the nice value of a task, clamped to NICE_MIN..=NICE_MAX
+// Takes effect from the task's next timeslice
+pub fn set_nice(pid: Pid, nice: i8) -> bool {
+    with_task(pid.0, |task| task.nice = nice.clamp(NICE_MIN, NICE_MAX)).is_some()
+}
+
+// Timer ticks spent in the idle task, the time the boot hart had nothing to run
+pub fn boot_idle_ticks() -> u64 {
+    with_tasks(|tasks| tasks.values().filter(|t| t.idle).map(|t| t.ticks).sum())
+}
+
+// True once the running task has used up its timeslice
+pub fn need_resched() -> bool {
+    with_task(current_pid(), |t| t.slice == 0).unwrap_or(false)
+}
+
+pub fn current() -> Pid {
+    Pid(current_pid())
+}
+
+// State of a task, None once it exited and was freed
+pub fn state(pid: Pid) -> Option<TaskState> {
+    with_task(pid.0, |t| t.state)
+}
+
+// Give an open file to the current task, returns its descriptor
+pub fn add_file(file: OpenFile) -> Option<usize> {
+    with_task(current_pid(), |task| {
+        let idx = match task.files.iter().position(|f| f.is_none()) {
+            Some(idx) => idx,
+            None => {
+                task.files.push(None);
+                task.files.len() - 1
+            }
+        };
+        task.files[idx] = Some(file);
+        idx + FIRST_FD
+    })
+}
+
+// The file the current task has open as fd
+pub fn file(fd: usize) -> Option<&'static mut OpenFile> {
+    let idx = fd.checked_sub(FIRST_FD)?;
+    with_task(current_pid(), |task| task.files.get_mut(idx)?.as_mut()).flatten()
+}
+
+// Close fd of the current task, false if it was not open
+pub fn close_file(fd: usize) -> bool {
+    with_task(current_pid(), |task| {
+        match fd
+            .checked_sub(FIRST_FD)
+            .and_then(|idx| task.files.get_mut(idx))
+        {
+            Some(slot) => slot.take().is_some(),
+            None => false,
+        }
+    })
+    .unwrap_or(false)
+}
+
+// Switch to the next ready task with interrupts disabled
+// With nothing ready a task that can no longer run hands over to the idle task
+fn schedule_locked() {
+    reap();
+    if let Some(next) = next_ready() {
+        unsafe { switch(next) };
+    } else if state(current()) != Some(TaskState::Running) {
+        match unsafe { IDLE } {
+            Some(idle) => unsafe { switch(idle) },
+            None => panic!("Nothing to run on the boot hart"),
+        }
+    }
+}
+
+// Let the next ready task run, returns once this task is resumed
+// Call with interrupts enabled
+pub fn schedule() {
+    if signal_pending() {
+        exit();
+    }
+    assembly::disable_interrupts();
+    schedule_locked();
+    assembly::enable_interrupts();
+}
+
+// Stop running the current task until it is woken, or mtime reaches wake_at
+// Call with interrupts disabled so a wake between checking for a condition
+// and blocking is not lost. Before init there is no task to block, so this
+// only waits for the next interrupt
+pub fn block(wake_at: Option<u64>) {
+    if signal_pending() {
+        exit();
+    }
+    match unsafe { TASKS.get_mut(&current_pid()) } {
+        Some(task) => {
+            task.state = TaskState::Blocked;
+            task.wake_at = wake_at;
+        }
+        None => {
+            assembly::enable_interrupts();
+            assembly::wait_for_interrupt();
+            assembly::disable_interrupts();
+            return;
+        }
+    }
+    schedule_locked();
+}
+
+// Make a blocked task ready again, safe from interrupt handlers
+pub fn wake(pid: Pid) {
+    with_task(pid.0, |task| {
+        if task.state == TaskState::Blocked {
+            task.state = TaskState::Ready;
+            task.wake_at = None;
+        }
+    });
+}
+
+// Called from the timer interrupt, charges the tick to the running task and
+// wakes tasks whose wake_at has passed
+pub fn tick(now: u64) {
+    if let Some(task) = unsafe { TASKS.get_mut(&current_pid()) } {
+        task.ticks += 1;
+        task.slice = task.slice.saturating_sub(1);
+    }
+    for task in unsafe { TASKS.values_mut() } {
+        if task.state == TaskState::Blocked && task.wake_at.is_some_and(|t| t <= now) {
+            task.state = TaskState::Ready;
+            task.wake_at = None;
+        }
+    }
+}
+
+// End the current task, its stack is freed by the next task to run
+// Switches away without schedule, whose signal check would land back here
+pub fn exit() -> ! {
+    assembly::disable_interrupts();
+    unsafe {
+        if let Some(task) = TASKS.get_mut(&current_pid()) {
+            task.state = TaskState::Dead;
+        }
+    }
+    schedule_locked();
+    let name = unsafe { TASKS.get(&current_pid()).map_or("?", |t| t.name) };
+    panic!("Task {} ({}) resumed after exit", current().0, name);
+}
+
+// Send sig to a task, false if there is no such task or it is the boot or an
+// idle task, which cannot be killed
+// The task ends at its next scheduling point, a blocked one is woken to get
+// there. SIGKILL ends a ready or blocked task outside a with_signals_held
+// section at once, nothing of it runs again. A task inside one, e.g. waiting
+// for a device to finish DMA into its buffers, is left alone until the section
+// ends
+pub fn kill(pid: Pid, sig: Signal) -> bool {
+    with_tasks(|tasks| {
+        let task = match tasks.get_mut(&pid.0) {
+            Some(task) if pid.0 != 0 && !task.idle => task,
+            _ => return false,
+        };
+        if task.state == TaskState::Dead {
+            return true;
+        }
+        if task.pending != Some(Signal::Kill) {
+            task.pending = Some(sig);
+        }
+        let waiting = matches!(task.state, TaskState::Ready | TaskState::Blocked);
+        if waiting && task.held == 0 {
+            if sig == Signal::Kill {
+                // Its files and address space go once the next switch reaps it
+                task.state = TaskState::Dead;
+            } else {
+                task.state = TaskState::Ready;
+                task.wake_at = None;
+            }
+        }
+        true
+    })
+}
+
+// True if the running task was asked to end, checked by schedule, block and
+// on the way back from syscalls. Always false inside with_signals_held
+pub fn signal_pending() -> bool {
+    with_task(current_pid(), |t| t.pending.is_some() && t.held == 0).unwrap_or(false)
+}
+
+// Run f with signals to the running task held back, for waits that must not
+// be cut short, such as for a device writing into memory the task owns
+// The task ends at its first scheduling point after f if it was killed
+pub fn with_signals_held<R>(f: impl FnOnce() -> R) -> R {
+    let pid = current_pid();
+    with_task(pid, |task| task.held += 1);
+    let result = f();
+    with_task(pid, |task| task.held -= 1);
+    result
+}
+
+// Hand the running task the address space it runs its user code in
+pub fn set_address_space(space: AddressSpace) -> bool {
+    with_task(current_pid(), |task| {
+        task.space = Some(space);
+        activate(task);
+    })
+    .is_some()
+}
+
+// The address space of the running task, if it has one
+pub fn address_space() -> Option<&'static mut AddressSpace> {
+    with_task(current_pid(), |task| task.space.as_mut()).flatten()
+}
+
+// Every task in pid order
+pub fn list() -> Vec<TaskInfo> {
+    with_tasks(|tasks| {
+        tasks
+            .iter()
+            .map(|(&pid, task)| TaskInfo {
+                pid: Pid(pid),
+                name: task.name,
+                state: task.state,
+                stack_used: task.stack_used(),
+                stack_size: if task.stack.is_null() { 0 } else { STACK_SIZE },
+                priority: task.priority,
+                nice: task.nice,
+                ticks: task.ticks,
+            })
+            .collect()
+    })
+}
+
+// Print a table of every task
+#[allow(dead_code)]
+pub fn debug_tasks() {
+    println!("\n  PID NAME             STATE    PRIO    NICE         STACK      TICKS");
+    println!("---------------------------------------------------------------------");
+    for task in list() {
+        println!(
+            "{:>5} {:<16} {:<8} {:<7} {:>4} {:>6}/{:<6} {:>8}",
+            task.pid.0,
+            task.name,
+            task.state.label(),
+            task.priority.label(),
+            task.nice,
+            task.stack_used,
+            task.stack_size,
+            task.ticks
+        );
+    }
+    println!("---------------------------------------------------------------------");
+}
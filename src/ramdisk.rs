@@ -0,0 +1,124 @@
+use crate::alloc::alloc_pages_zeroed;
+use crate::block::{self, BlockDriver};
+use crate::config::PAGE_SIZE;
+use crate::error::KError;
+use crate::log;
+use crate::memory::memcpy;
+
+// mod ramdisk.rs
+// A block device backed by kernel memory pages
+// Useful to exercise the filesystem and write paths without touching the real disk image
+
+static mut RAM_DISK: Option<RamDisk> = None;
+
+pub struct RamDisk {
+    data: *mut u8,
+    size: u64,
+}
+
+impl RamDisk {
+    fn new(pages: usize) -> Option<Self> {
+        let data = alloc_pages_zeroed(pages);
+        if data.is_null() {
+            None
+        } else {
+            Some(Self {
+                data,
+                size: (pages * PAGE_SIZE) as u64,
+            })
+        }
+    }
+
+    fn in_bounds(&self, size: u32, offset: u64) -> bool {
+        offset + size as u64 <= self.size
+    }
+}
+
+impl BlockDriver for RamDisk {
+    fn read(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+        if !self.in_bounds(size, offset) {
+            log::error!("Ram disk read out of bounds @ 0x{:x}", offset);
+            return Err(KError::InvalidArgument);
+        }
+        unsafe { memcpy(buffer, self.data.add(offset as usize), size as usize) };
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+        if !self.in_bounds(size, offset) {
+            log::error!("Ram disk write out of bounds @ 0x{:x}", offset);
+            return Err(KError::InvalidArgument);
+        }
+        unsafe { memcpy(self.data.add(offset as usize), buffer, size as usize) };
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.size
+    }
+
+    // Memory is always coherent so there is nothing to flush
+    fn flush(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+// ====================================================
+// The public interface for the ram disk is here...
+// ====================================================
+
+// Allocate a ram disk of the requested number of pages
+// Subsequent calls are ignored while a ram disk exists
+pub fn init(pages: usize) -> bool {
+    unsafe {
+        if RAM_DISK.is_some() {
+            return true;
+        }
+        log::info!("init ram disk");
+        RAM_DISK = RamDisk::new(pages);
+        RAM_DISK.is_some()
+    }
+}
+
+// Copy the start of source into the ram disk so a filesystem image can be
+// used without risking the original
+pub fn load_from(source: &mut impl BlockDriver) -> Result<(), KError> {
+    match unsafe { RAM_DISK.as_mut() } {
+        Some(rd) => block::copy(source, rd).map(|_| ()),
+        None => {
+            log::error!("Unable to retrieve ram disk");
+            Err(KError::NoDevice)
+        }
+    }
+}
+
+// Read data from the ram disk to buffer
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn read(buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+    unsafe {
+        if let Some(rd) = RAM_DISK.as_mut() {
+            rd.read(buffer, size, offset)
+        } else {
+            log::error!("Unable to retrieve ram disk");
+            Err(KError::NoDevice)
+        }
+    }
+}
+
+// Write data from buffer to the ram disk
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn write(buffer: *mut u8, size: u32, offset: u64) -> Result<(), KError> {
+    unsafe {
+        if let Some(rd) = RAM_DISK.as_mut() {
+            rd.write(buffer, size, offset)
+        } else {
+            log::error!("Unable to retrieve ram disk");
+            Err(KError::NoDevice)
+        }
+    }
+}
+
+// Capacity of the ram disk in bytes
+pub fn capacity() -> u64 {
+    unsafe { RAM_DISK.as_ref().map_or(0, |rd| rd.capacity()) }
+}
@@ -0,0 +1,147 @@
+use crate::assembly;
+use crate::clint;
+use crate::log;
+use crate::rng;
+use crate::sync::SpinLock;
+
+// mod random.rs
+// Random numbers for the kernel, not for cryptography
+// A xoshiro256** generator, seeded once at boot from the virtio-rng device
+// when there is one and otherwise from the jitter between the cycle counter
+// and mtime, which run off different clocks
+// Rng can also be seeded by hand for a sequence that repeats, e.g. to replay a
+// failed fuzz test from the seed it logged
+
+const SEED_BYTES: usize = 32;
+// mtime ticks sampled for a jitter seed, a few bits of noise each
+const JITTER_SAMPLES: usize = 64;
+
+static KERNEL: SpinLock<Rng> = SpinLock::new(Rng {
+    state: [
+        0x9e37_79b9_7f4a_7c15,
+        0xbf58_476d_1ce4_e5b9,
+        0x94d0_49bb_1331_11eb,
+        0x2545_f491_4f6c_dd1d,
+    ],
+});
+static mut SOURCE: Source = Source::Fixed;
+
+// Where the kernel's generator got its seed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    // Not seeded yet, the sequence is the same every boot
+    Fixed,
+    Device,
+    Jitter,
+}
+
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    // Expand seed with splitmix64, which never gives the all zero state
+    pub fn new(seed: u64) -> Self {
+        let mut x = seed;
+        let mut state = [0; 4];
+        for s in state.iter_mut() {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            *s = mix(x);
+        }
+        Self { state }
+    }
+
+    fn from_bytes(bytes: &[u8; SEED_BYTES]) -> Self {
+        let mut state = [0; 4];
+        for (s, chunk) in state.iter_mut().zip(bytes.chunks_exact(8)) {
+            *s = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        if state == [0; 4] {
+            return Self::new(0);
+        }
+        Self { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    // A value in 0..bound, by multiply and shift so small bounds stay even
+    pub fn below(&mut self, bound: usize) -> usize {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as usize
+    }
+
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        let mut chunks = bytes.chunks_exact_mut(8);
+        for chunk in &mut chunks {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes());
+        }
+        let tail = chunks.into_remainder();
+        let len = tail.len();
+        tail.copy_from_slice(&self.next_u64().to_le_bytes()[..len]);
+    }
+}
+
+// The splitmix64 finalizer
+fn mix(x: u64) -> u64 {
+    let mut z = x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Count cycles across mtime ticks, the counts wander with the host and the
+// low bits of each are folded into the seed
+fn jitter() -> u64 {
+    let mut seed = assembly::read_cycle();
+    for _ in 0..JITTER_SAMPLES {
+        let start = clint::mtime();
+        let cycles = assembly::read_cycle();
+        while clint::mtime() == start {}
+        let delta = assembly::read_cycle().wrapping_sub(cycles);
+        seed = mix(seed ^ delta);
+    }
+    seed
+}
+
+// ====================================================
+// The public interface for random is here...
+// ====================================================
+
+// Seed the kernel's generator, after virtio::init so the entropy device is up
+pub fn init() {
+    log::info!("init random");
+    let mut seed = [0u8; SEED_BYTES];
+    let (rng, source) = if rng::read(&mut seed) == SEED_BYTES {
+        (Rng::from_bytes(&seed), Source::Device)
+    } else {
+        (Rng::new(jitter()), Source::Jitter)
+    };
+    *KERNEL.lock() = rng;
+    unsafe { SOURCE = source };
+    log::debug!("random seeded from {:?}", source);
+}
+
+// A random u64 from the kernel's generator
+pub fn u64() -> u64 {
+    KERNEL.lock().next_u64()
+}
+
+// Fill bytes from the kernel's generator
+pub fn fill(bytes: &mut [u8]) {
+    KERNEL.lock().fill(bytes);
+}
+
+// Where the kernel's generator was seeded from
+pub fn source() -> Source {
+    unsafe { SOURCE }
+}
@@ -0,0 +1,115 @@
+use crate::alloc::DmaRegion;
+use crate::config::{RNG_BUFFER_SIZE, RNG_TIMEOUT_MS};
+use crate::error::KError;
+use crate::log;
+use crate::memory::memcpy;
+use crate::time;
+use crate::timer;
+use crate::virtio::{DmaGuard, Features, MmioDevice};
+use crate::virtqueue::{Segment, VirtQueue};
+use core::time::Duration;
+
+// mod rng.rs
+// A virtio-rng driver, the host's entropy for random.rs
+// Requests are synchronous, a buffer is handed to the device and the queue is
+// polled until it comes back filled, the interrupt is only acknowledged
+
+static mut RNG_DEVICE: Option<RngDevice> = None;
+
+const REQUEST_QUEUE: u32 = 0;
+
+struct RngDevice {
+    dev: DmaGuard,
+    queue: VirtQueue,
+    buffer: DmaRegion,
+}
+
+impl RngDevice {
+    fn init(ptr: *mut u32) -> Result<(), KError> {
+        log::info!("init entropy device");
+        let dev = MmioDevice::new(ptr)?;
+        dev.begin_init();
+        dev.negotiate(Features::NONE)?;
+        let queue = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        dev.setup_queue(REQUEST_QUEUE, &queue)?;
+        let mut dev = DmaGuard::new(dev);
+        let buffer = dev
+            .alloc_dma(RNG_BUFFER_SIZE, RNG_BUFFER_SIZE)
+            .inspect_err(|_| dev.fail())?;
+        dev.driver_ok();
+        unsafe { RNG_DEVICE = Some(RngDevice { dev, queue, buffer }) };
+        Ok(())
+    }
+
+    // One request for up to RNG_BUFFER_SIZE bytes, None if the device did not
+    // answer within RNG_TIMEOUT_MS
+    fn request(&mut self, len: usize) -> Option<usize> {
+        let head = self
+            .queue
+            .add_chain(&[Segment::writable(self.buffer.phys, len as u32)]);
+        self.queue.submit(head);
+        self.dev.notify(REQUEST_QUEUE);
+        let deadline = time::deadline(Duration::from_millis(RNG_TIMEOUT_MS));
+        while timer::now() < deadline {
+            if let Some((used, written)) = self.queue.pop_used() {
+                if used == head {
+                    return Some((written as usize).min(len));
+                }
+            }
+        }
+        None
+    }
+
+    fn read(&mut self, bytes: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < bytes.len() {
+            let want = (bytes.len() - done).min(RNG_BUFFER_SIZE);
+            let got = match self.request(want) {
+                Some(0) => break,
+                Some(got) => got,
+                None => {
+                    log::warn!("entropy request timed out");
+                    break;
+                }
+            };
+            unsafe { memcpy(bytes[done..].as_mut_ptr(), self.buffer.virt, got) };
+            done += got;
+        }
+        done
+    }
+}
+
+// ====================================================
+// The public interface for the entropy device is here...
+// ====================================================
+
+// Called by virtio::init() when an entropy device is found
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn init(ptr: *mut u32) -> Result<(), KError> {
+    RngDevice::init(ptr)
+}
+
+// Called by virtio::rescan() when the entropy device has gone away
+pub fn remove() {
+    unsafe { RNG_DEVICE = None };
+}
+
+// Requests are polled by the reader, only acknowledge the interrupt
+pub fn interrupt_handler() {
+    unsafe {
+        if let Some(rng) = RNG_DEVICE.as_ref() {
+            rng.dev.ack_interrupt();
+        }
+    }
+}
+
+// True once an entropy device has been initialized
+pub fn ready() -> bool {
+    unsafe { RNG_DEVICE.is_some() }
+}
+
+// Fill bytes with entropy from the host, returns how many bytes it got, 0
+// without a device
+pub fn read(bytes: &mut [u8]) -> usize {
+    unsafe { RNG_DEVICE.as_mut().map_or(0, |rng| rng.read(bytes)) }
+}
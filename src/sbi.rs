@@ -0,0 +1,197 @@
+use crate::assembly;
+use crate::log;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// mod sbi.rs
+// Calls into the supervisor binary interface of the firmware, built with
+// --features "sbi" where OpenSBI loads the kernel and enters it in S-mode
+// The firmware keeps machine mode, the CLINT and the test device to itself, so
+// the timer, IPIs, starting harts and system reset go through here
+// Every call passes the extension in a7 and the function in a6 and returns an
+// error code and a value. Firmware before v0.2 only has the legacy extensions,
+// those are the fallback for the timer, IPIs and shutdown
+
+const EXT_LEGACY_SET_TIMER: usize = 0x00;
+const EXT_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const EXT_LEGACY_SEND_IPI: usize = 0x04;
+const EXT_LEGACY_SHUTDOWN: usize = 0x08;
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4d45;
+const EXT_IPI: usize = 0x0073_5049;
+const EXT_HSM: usize = 0x0048_534d;
+const EXT_SRST: usize = 0x5352_5354;
+
+const BASE_SPEC_VERSION: usize = 0;
+const BASE_IMPL_ID: usize = 1;
+const BASE_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+const TIME_SET_TIMER: usize = 0;
+const IPI_SEND_IPI: usize = 0;
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_STATUS: usize = 2;
+const SRST_SYSTEM_RESET: usize = 0;
+
+// Reset types and reasons of system_reset
+pub const RESET_SHUTDOWN: usize = 0;
+#[cfg_attr(not(feature = "debug-monitor"), allow(dead_code))]
+pub const RESET_COLD_REBOOT: usize = 1;
+#[allow(dead_code)]
+pub const RESET_WARM_REBOOT: usize = 2;
+pub const REASON_NONE: usize = 0;
+pub const REASON_SYSTEM_FAILURE: usize = 1;
+
+// States of hart_status
+#[allow(dead_code)]
+pub const HART_STARTED: usize = 0;
+#[allow(dead_code)]
+pub const HART_STOPPED: usize = 1;
+
+const SUCCESS: isize = 0;
+const ERR_NOT_SUPPORTED: isize = -2;
+
+// Extensions init() found, the legacy calls stand in for missing ones
+static HAS_TIME: AtomicBool = AtomicBool::new(false);
+static HAS_IPI: AtomicBool = AtomicBool::new(false);
+static HAS_HSM: AtomicBool = AtomicBool::new(false);
+static HAS_SRST: AtomicBool = AtomicBool::new(false);
+// Major version in bits 24..31 and minor in bits 0..23, 0 for legacy firmware
+static SPEC_VERSION: AtomicUsize = AtomicUsize::new(0);
+
+fn call(extension: usize, function: usize, args: [usize; 3]) -> Result<usize, isize> {
+    match assembly::sbi_call(extension, function, args) {
+        (SUCCESS, value) => Ok(value),
+        (error, _) => Err(error),
+    }
+}
+
+// The legacy calls return nothing useful, only the error in a0
+fn legacy(extension: usize, arg: usize) -> isize {
+    assembly::sbi_call(extension, 0, [arg, 0, 0]).0
+}
+
+fn probe(extension: usize) -> bool {
+    call(EXT_BASE, BASE_PROBE_EXTENSION, [extension, 0, 0]).is_ok_and(|found| found != 0)
+}
+
+fn implementation(id: usize) -> &'static str {
+    match id {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        _ => "unknown",
+    }
+}
+
+// ====================================================
+// The public interface for the sbi is here...
+// ====================================================
+
+// Find the extensions of the firmware and start the timer on the boot hart
+pub fn init() {
+    log::info!("init sbi");
+    match call(EXT_BASE, BASE_SPEC_VERSION, [0; 3]) {
+        Ok(version) => {
+            SPEC_VERSION.store(version, Ordering::Relaxed);
+            let id = call(EXT_BASE, BASE_IMPL_ID, [0; 3]).unwrap_or(usize::MAX);
+            let impl_version = call(EXT_BASE, BASE_IMPL_VERSION, [0; 3]).unwrap_or(0);
+            let (major, minor) = spec_version();
+            log::info!(
+                "SBI v{}.{}, {} 0x{:x}",
+                major,
+                minor,
+                implementation(id),
+                impl_version
+            );
+            HAS_TIME.store(probe(EXT_TIME), Ordering::Relaxed);
+            HAS_IPI.store(probe(EXT_IPI), Ordering::Relaxed);
+            HAS_HSM.store(probe(EXT_HSM), Ordering::Relaxed);
+            HAS_SRST.store(probe(EXT_SRST), Ordering::Relaxed);
+        }
+        Err(_) => log::warn!("legacy SBI firmware, secondary harts stay stopped"),
+    }
+    // The firmware boots with the timer stopped, a deadline in the past starts
+    // the tick the way the reset value of mtimecmp does without it
+    set_timer(0);
+}
+
+// Major and minor version of the SBI specification the firmware implements
+pub fn spec_version() -> (usize, usize) {
+    let version = SPEC_VERSION.load(Ordering::Relaxed);
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
+
+// Raise the next timer interrupt on the calling hart once time reaches when
+// Also clears the pending one
+pub fn set_timer(when: u64) {
+    if HAS_TIME.load(Ordering::Relaxed) {
+        let _ = call(EXT_TIME, TIME_SET_TIMER, [when as usize, 0, 0]);
+    } else {
+        legacy(EXT_LEGACY_SET_TIMER, when as usize);
+    }
+}
+
+// Raise a supervisor software interrupt on hart
+pub fn send_ipi(hart: usize) -> bool {
+    if HAS_IPI.load(Ordering::Relaxed) {
+        return call(EXT_IPI, IPI_SEND_IPI, [1, hart, 0]).is_ok();
+    }
+    // The legacy call takes the address of the hart mask
+    let mask: usize = 1 << hart;
+    legacy(EXT_LEGACY_SEND_IPI, &mask as *const usize as usize) == SUCCESS
+}
+
+// Console output through the firmware, for when there is no uart to write to
+pub fn console_putchar(byte: u8) {
+    legacy(EXT_LEGACY_CONSOLE_PUTCHAR, byte as usize);
+}
+
+// Start a stopped hart in supervisor mode at start, with its id in a0 and
+// opaque in a1. False if the firmware cannot start harts or refused
+pub fn hart_start(hart: usize, start: usize, opaque: usize) -> bool {
+    if !HAS_HSM.load(Ordering::Relaxed) {
+        return false;
+    }
+    match call(EXT_HSM, HSM_HART_START, [hart, start, opaque]) {
+        Ok(_) => true,
+        Err(error) => {
+            log::error!("unable to start CPU#{}, sbi error {}", hart, error);
+            false
+        }
+    }
+}
+
+// Stop the calling hart, only returns if the firmware refused
+#[allow(dead_code)]
+pub fn hart_stop() {
+    if HAS_HSM.load(Ordering::Relaxed) {
+        let _ = call(EXT_HSM, HSM_HART_STOP, [0; 3]);
+    }
+}
+
+// One of the HART_ states, None for a hart the firmware does not know
+pub fn hart_status(hart: usize) -> Option<usize> {
+    if !HAS_HSM.load(Ordering::Relaxed) {
+        return None;
+    }
+    call(EXT_HSM, HSM_HART_STATUS, [hart, 0, 0]).ok()
+}
+
+// Shut down or reboot the machine, only returns if the firmware refused
+// Without the SRST extension only a shutdown is possible
+pub fn system_reset(kind: usize, reason: usize) {
+    let error = if HAS_SRST.load(Ordering::Relaxed) {
+        call(EXT_SRST, SRST_SYSTEM_RESET, [kind, reason, 0]).err()
+    } else if kind == RESET_SHUTDOWN {
+        Some(legacy(EXT_LEGACY_SHUTDOWN, 0))
+    } else {
+        Some(ERR_NOT_SUPPORTED)
+    };
+    if let Some(error) = error {
+        log::error!("system reset {} failed, sbi error {}", kind, error);
+    }
+}
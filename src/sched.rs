@@ -0,0 +1,28 @@
+use crate::assembly;
+use crate::process;
+use crate::trap;
+
+// mod sched.rs
+// Voluntary preemption points for long running kernel code
+// Tasks only switch when they ask to, so loops that can run for a while call
+// yield_now to let other tasks and the console in once their timeslice is up
+// Traps must not switch tasks, there both yields do nothing
+
+// False inside a trap, where the interrupt stack is in use
+fn can_switch() -> bool {
+    assembly::interrupts_enabled() && trap::nesting() == 0
+}
+
+// Give up the hart if the running task has used up its timeslice
+pub fn yield_now() {
+    if can_switch() && process::need_resched() {
+        process::schedule();
+    }
+}
+
+// Give up the hart to any other ready task, timeslice left or not
+pub fn yield_hart() {
+    if can_switch() {
+        process::schedule();
+    }
+}
@@ -0,0 +1,232 @@
+use crate::alloc::{alloc_pages, free_pages};
+use crate::config::{PAGE_SIZE, SLAB_PAGES};
+use crate::log;
+use crate::{print, println};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+
+// mod slab.rs
+// Object caches for frequently allocated kernel structures
+// Each cache carves slabs of SLAB_PAGES pages from the page allocator into
+// equally sized objects. A slab starts with a header listing its free objects,
+// threaded through the objects themselves. A slab whose objects are all free
+// again goes back to the page allocator, unless it is the cache's last one
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// At the start of every slab
+struct SlabHeader {
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    used: usize,
+}
+
+const SLAB_BYTES: usize = SLAB_PAGES * PAGE_SIZE;
+
+// Usage of a single cache
+#[derive(Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    pub objects_total: usize,
+    pub objects_used: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+pub struct Slab<T> {
+    name: &'static str,
+    // Every slab of the cache, those with free objects are found by walking it
+    head: *mut SlabHeader,
+    slabs: usize,
+    objects_total: usize,
+    objects_used: usize,
+    allocs: usize,
+    frees: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Slab<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            head: null_mut(),
+            slabs: 0,
+            objects_total: 0,
+            objects_used: 0,
+            allocs: 0,
+            frees: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    // Objects must fit the free list link and keep T aligned
+    const fn object_size() -> usize {
+        let size = if size_of::<T>() > size_of::<FreeObject>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeObject>()
+        };
+        size.div_ceil(Self::object_align()) * Self::object_align()
+    }
+
+    const fn object_align() -> usize {
+        if align_of::<T>() > align_of::<FreeObject>() {
+            align_of::<T>()
+        } else {
+            align_of::<FreeObject>()
+        }
+    }
+
+    // Objects start past the header, kept aligned for T
+    const fn first_object() -> usize {
+        size_of::<SlabHeader>().div_ceil(Self::object_align()) * Self::object_align()
+    }
+
+    const fn objects_per_slab() -> usize {
+        (SLAB_BYTES - Self::first_object()) / Self::object_size()
+    }
+
+    // Add a fresh slab of SLAB_PAGES pages with every object free
+    fn grow(&mut self) -> *mut SlabHeader {
+        let slab = alloc_pages(SLAB_PAGES) as *mut SlabHeader;
+        if slab.is_null() {
+            return slab;
+        }
+        let count = Self::objects_per_slab();
+        let mut free = null_mut();
+        for i in (0..count).rev() {
+            let offset = Self::first_object() + i * Self::object_size();
+            let object = unsafe { (slab as *mut u8).add(offset) } as *mut FreeObject;
+            unsafe { (*object).next = free };
+            free = object;
+        }
+        unsafe {
+            *slab = SlabHeader {
+                next: self.head,
+                free,
+                used: 0,
+            }
+        };
+        self.head = slab;
+        self.slabs += 1;
+        self.objects_total += count;
+        slab
+    }
+
+    // The first slab with a free object
+    fn partial(&self) -> *mut SlabHeader {
+        let mut slab = self.head;
+        while !slab.is_null() && unsafe { (*slab).free.is_null() } {
+            slab = unsafe { (*slab).next };
+        }
+        slab
+    }
+
+    // The slab ptr was carved from and the one before it in the list
+    fn owner(&self, ptr: *mut u8) -> Option<(*mut SlabHeader, *mut SlabHeader)> {
+        let mut prev = null_mut();
+        let mut slab = self.head;
+        while !slab.is_null() {
+            let start = slab as usize;
+            if (start..start + SLAB_BYTES).contains(&(ptr as usize)) {
+                return Some((slab, prev));
+            }
+            prev = slab;
+            slab = unsafe { (*slab).next };
+        }
+        None
+    }
+
+    // Uninitialized memory for one T, null if memory is exhausted
+    pub fn alloc(&mut self) -> *mut T {
+        let mut slab = self.partial();
+        if slab.is_null() {
+            slab = self.grow();
+            if slab.is_null() {
+                return null_mut();
+            }
+        }
+        let object = unsafe { (*slab).free };
+        unsafe {
+            (*slab).free = (*object).next;
+            (*slab).used += 1;
+        }
+        self.objects_used += 1;
+        self.allocs += 1;
+        object as *mut T
+    }
+
+    pub fn alloc_zeroed(&mut self) -> *mut T {
+        let object = self.alloc();
+        if !object.is_null() {
+            unsafe { (object as *mut u8).write_bytes(0, Self::object_size()) };
+        }
+        object
+    }
+
+    // Return an object to the cache, it must have come from this cache
+    pub fn free(&mut self, ptr: *mut T) {
+        if ptr.is_null() {
+            return;
+        }
+        let Some((slab, prev)) = self.owner(ptr as *mut u8) else {
+            log::error!("{:p} is not from slab cache {}", ptr, self.name);
+            return;
+        };
+        let object = ptr as *mut FreeObject;
+        unsafe {
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).used -= 1;
+        }
+        self.objects_used -= 1;
+        self.frees += 1;
+        if unsafe { (*slab).used } == 0 && self.slabs > 1 {
+            self.release(slab, prev);
+        }
+    }
+
+    // Unlink an empty slab and give its pages back
+    fn release(&mut self, slab: *mut SlabHeader, prev: *mut SlabHeader) {
+        let next = unsafe { (*slab).next };
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            unsafe { (*prev).next = next };
+        }
+        free_pages(slab as *mut u8);
+        self.slabs -= 1;
+        self.objects_total -= Self::objects_per_slab();
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: Self::object_size(),
+            slabs: self.slabs,
+            objects_total: self.objects_total,
+            objects_used: self.objects_used,
+            allocs: self.allocs,
+            frees: self.frees,
+        }
+    }
+}
+
+// Print a table of the given caches
+#[allow(dead_code)]
+pub fn debug_stats(caches: &[SlabStats]) {
+    println!("\nSlab Caches          SIZE  SLABS   USED/TOTAL   ALLOCS    FREES");
+    println!("----------------------------------------------------------------");
+    for c in caches {
+        println!(
+            "- {:<16} {:>5} {:>6} {:>6}/{:<6} {:>8} {:>8}",
+            c.name, c.object_size, c.slabs, c.objects_used, c.objects_total, c.allocs, c.frees
+        );
+    }
+    println!("----------------------------------------------------------------");
+}
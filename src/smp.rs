@@ -0,0 +1,160 @@
+use crate::assembly;
+use crate::clint;
+use crate::config::{HART_START_TIMEOUT_MS, MAX_HARTS};
+use crate::fdt;
+use crate::hart;
+use crate::ipi;
+use crate::log;
+use crate::plic;
+#[cfg(feature = "sbi")]
+use crate::sbi;
+use crate::timer;
+use crate::trap;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// mod smp.rs
+// Bring up of the secondary harts
+// boot.S parks every hart but hart 0 in a wfi loop until a software interrupt
+// wakes it, under OpenSBI they stay stopped until the firmware starts them
+// init wakes the harts listed in the device tree one at a time, each sets up
+// its trap stack and PLIC context on its own boot stack, reports itself
+// online and idles waiting for IPIs
+// A running secondary hart can be parked again at runtime and later unparked,
+// so the same kernel can be tested on fewer harts
+
+static ONLINE: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+static PARKED: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+// Wait up to HART_START_TIMEOUT_MS for hart to be online or not
+fn wait_online(hart: usize, online: bool) -> bool {
+    let deadline = timer::now() + timer::ms_to_ticks(HART_START_TIMEOUT_MS);
+    while is_online(hart) != online {
+        if timer::now() >= deadline {
+            return false;
+        }
+    }
+    true
+}
+
+// Wake a parked hart and wait for it to report online
+#[cfg(not(feature = "sbi"))]
+fn start(hart: usize) -> bool {
+    clint::send_ipi(hart);
+    wait_online(hart, true)
+}
+
+// Under OpenSBI the hart is stopped rather than parked, the firmware starts it
+#[cfg(feature = "sbi")]
+fn start(hart: usize) -> bool {
+    sbi::hart_start(hart, assembly::hart_start_address(), 0) && wait_online(hart, true)
+}
+
+// Runs on the hart being parked, back in its idle loop
+// Only hart 0 runs tasks, what a secondary hart owns are the device
+// interrupts routed to it, those go back to hart 0 before it goes offline
+fn idle_parked(hart: usize) {
+    plic::migrate(hart, 0);
+    assembly::write_ie(trap::IE_SOFTWARE);
+    ONLINE[hart].store(false, Ordering::Release);
+    while PARKED[hart].load(Ordering::Acquire) {
+        assembly::wait_for_interrupt();
+    }
+    assembly::write_ie(trap::IE_SOFTWARE | trap::IE_EXTERNAL);
+    ONLINE[hart].store(true, Ordering::Release);
+}
+
+#[no_mangle]
+// Entered from boot.S or sbi.S on the hart's boot stack, interrupts are disabled here...
+extern "C" fn kernel_hart_init(hart: usize) {
+    hart::init(hart); // Hart local data through tp
+    trap::init(hart); // Interrupt stack for this hart
+    plic::init_hart(hart); // Only routed device interrupts reach this hart
+
+    #[cfg(feature = "supervisor")]
+    assembly::enter_supervisor(kernel_hart_main as extern "C" fn(usize) as usize);
+}
+
+#[no_mangle]
+// Interrupts are enabled here...
+extern "C" fn kernel_hart_main(hart: usize) {
+    // IPIs and the device interrupts routed here wake a secondary hart
+    assembly::write_ie(trap::IE_SOFTWARE | trap::IE_EXTERNAL);
+    log::info!("CPU#{} online", hart);
+    ONLINE[hart].store(true, Ordering::Release);
+    loop {
+        assembly::wait_for_interrupt();
+        if PARKED[hart].load(Ordering::Acquire) {
+            idle_parked(hart);
+        }
+    }
+}
+
+// ====================================================
+// The public interface for smp is here...
+// ====================================================
+
+// Start every hart the device tree lists, the boot hart is hart 0
+pub fn init() {
+    log::info!("init smp");
+    ONLINE[0].store(true, Ordering::Release);
+    for hart in fdt::harts() {
+        if hart == 0 {
+            continue;
+        }
+        if hart >= MAX_HARTS {
+            log::warn!("CPU#{} is beyond MAX_HARTS, left parked", hart);
+            continue;
+        }
+        if !start(hart) {
+            log::error!("CPU#{} did not come online", hart);
+        }
+    }
+}
+
+pub fn is_online(hart: usize) -> bool {
+    hart < MAX_HARTS && ONLINE[hart].load(Ordering::Acquire)
+}
+
+// Number of harts running the kernel
+pub fn online() -> usize {
+    (0..MAX_HARTS).filter(|&hart| is_online(hart)).count()
+}
+
+// Take a secondary hart offline, it waits in wfi until unparked
+// Returns false for hart 0, which runs every task, for the calling hart and
+// for harts that are not online or did not go offline in time
+pub fn park(hart: usize) -> bool {
+    if hart == 0 || hart == hart::id() || !is_online(hart) {
+        return false;
+    }
+    PARKED[hart].store(true, Ordering::Release);
+    // Any request gets the hart out of wfi and back to its idle loop
+    if !ipi::call(hart, |_| {}, 0) {
+        PARKED[hart].store(false, Ordering::Release);
+        return false;
+    }
+    if !wait_online(hart, false) {
+        log::error!("CPU#{} did not park", hart);
+        return false;
+    }
+    true
+}
+
+// Bring a parked hart back online, device interrupts stay on hart 0 until
+// routed to it again
+pub fn unpark(hart: usize) -> bool {
+    if !is_parked(hart) {
+        return false;
+    }
+    PARKED[hart].store(false, Ordering::Release);
+    clint::send_ipi(hart);
+    if !wait_online(hart, true) {
+        log::error!("CPU#{} did not unpark", hart);
+        return false;
+    }
+    true
+}
+
+pub fn is_parked(hart: usize) -> bool {
+    hart < MAX_HARTS && PARKED[hart].load(Ordering::Acquire)
+}
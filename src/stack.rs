@@ -0,0 +1,26 @@
+use crate::config::STACK_CANARY;
+
+// mod stack.rs
+// Overflow detection for the boot stack
+// boot.S writes STACK_CANARY to the lowest word of the stack, which is the
+// first thing an overflowing stack overwrites on its way into the bss
+
+// Symbols defined in cfg/link.ld & asm/layout.S
+extern "C" {
+    static KERNEL_STACK_START: usize;
+}
+
+fn canary() -> *mut u64 {
+    unsafe { KERNEL_STACK_START as *mut u64 }
+}
+
+pub fn is_intact() -> bool {
+    unsafe { canary().read_volatile() == STACK_CANARY }
+}
+
+// Panic if the stack has grown past its bottom since boot
+pub fn check() {
+    if !is_intact() {
+        panic!("kernel stack overflow");
+    }
+}
@@ -0,0 +1,110 @@
+use crate::atomics;
+use crate::irq;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+
+// mod sync.rs
+// A test-and-set spin lock for kernel state shared between harts, built on
+// the AMOs in src/atomics.rs
+// lock() is for state only normal code touches. State an interrupt handler
+// also touches must be taken with lock_irq(), which keeps this hart in an
+// irq::with_disabled section while the lock is held, so the handler cannot end up spinning
+// on a lock the code it interrupted holds
+// Locks are not reentrant, taking one twice on the same hart never returns
+
+pub struct SpinLock<T> {
+    // 1 while held
+    locked: UnsafeCell<usize>,
+    data: UnsafeCell<T>,
+}
+
+// Kernel state is full of raw pointers, it is the lock that makes sharing it sound
+unsafe impl<T> Sync for SpinLock<T> {}
+
+// Access to the data, the lock is released when the guard is dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    // Taken with lock_irq(), the irq section ends on release
+    irq: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: UnsafeCell::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        atomics::swap_acquire(self.locked.get(), 1) == 0
+    }
+
+    fn acquire(&self) {
+        while !self.try_acquire() {
+            // Spin on a plain load so waiting harts do not keep stealing the line
+            while self.is_locked() {
+                spin_loop();
+            }
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        self.acquire();
+        SpinLockGuard {
+            lock: self,
+            irq: false,
+        }
+    }
+
+    // Disable interrupts, then lock
+    pub fn lock_irq(&self) -> SpinLockGuard<'_, T> {
+        irq::disable();
+        self.acquire();
+        SpinLockGuard {
+            lock: self,
+            irq: true,
+        }
+    }
+
+    // Lock if nobody holds the lock, without waiting
+    #[allow(dead_code)]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        if self.try_acquire() {
+            Some(SpinLockGuard {
+                lock: self,
+                irq: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        unsafe { self.locked.get().read_volatile() != 0 }
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        atomics::store_release(self.lock.locked.get(), 0);
+        if self.irq {
+            irq::restore();
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use crate::addrspace::AddressSpace;
+use crate::assembly;
+use crate::config::PAGE_SIZE;
+use crate::console;
+use crate::log;
+use crate::memory;
+use crate::paging::PTE_WRITE;
+use crate::power;
+use crate::process::{self, Pid, Signal};
+use crate::sched;
+use crate::trap::{self, TrapFrame};
+use crate::vfs;
+use crate::{print, println};
+use rust_alloc::vec::Vec;
+
+// mod syscall.rs
+// Dispatch of system calls made with ecall
+// The number is passed in a7 and up to six arguments in a0-a5, the result is
+// written back to a0. Numbers follow the riscv64 Linux ABI
+// Buffers and paths in the task's address space are reached through its page
+// tables, the kernel cannot use user addresses directly. Anything else must be
+// RAM the kernel maps, else the call fails with EFAULT. Paths are read a byte
+// at a time so a short path at the end of a mapping is not read past
+// Files can only be opened for reading, the vfs has no write path
+
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_READ: usize = 63;
+pub const SYS_WRITE: usize = 64;
+pub const SYS_EXIT: usize = 93;
+pub const SYS_YIELD: usize = 124;
+pub const SYS_KILL: usize = 129;
+pub const SYS_BRK: usize = 214;
+
+const STDIN: usize = 0;
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+// Access mode bits of the openat flags
+const O_ACCMODE: usize = 3;
+const O_RDONLY: usize = 0;
+// Longest path accepted by openat, including the NUL
+const PATH_MAX: usize = 256;
+
+// Negated errno values returned in a0
+const ENOENT: isize = 2;
+const ESRCH: isize = 3;
+const EBADF: isize = 9;
+const EFAULT: isize = 14;
+const EINVAL: isize = 22;
+const EMFILE: isize = 24;
+const EROFS: isize = 30;
+const ENAMETOOLONG: isize = 36;
+const ENOSYS: isize = 38;
+
+fn error(errno: isize) -> usize {
+    (-errno) as usize
+}
+
+fn sys_write(fd: usize, buf: usize, len: usize) -> usize {
+    if fd != STDOUT && fd != STDERR {
+        return error(EBADF);
+    }
+    let pieces = match user_buffer(buf, len, false) {
+        Ok(pieces) => pieces,
+        Err(errno) => return error(errno),
+    };
+    for (ptr, size) in pieces {
+        console::write_bytes(unsafe { core::slice::from_raw_parts(ptr, size) });
+    }
+    len
+}
+
+// Reads a piece of the buffer at a time, stopping at the first short one
+fn sys_read(fd: usize, buf: usize, len: usize) -> usize {
+    if fd != STDIN && process::file(fd).is_none() {
+        return error(EBADF);
+    }
+    let pieces = match user_buffer(buf, len.min(u32::MAX as usize), true) {
+        Ok(pieces) => pieces,
+        Err(errno) => return error(errno),
+    };
+    let mut total = 0;
+    for (ptr, size) in pieces {
+        let read = match process::file(fd) {
+            _ if fd == STDIN => {
+                console::read_bytes(unsafe { core::slice::from_raw_parts_mut(ptr, size) })
+            }
+            Some(file) => file.read(ptr, size as u32) as usize,
+            None => 0,
+        };
+        total += read;
+        if read < size {
+            break;
+        }
+    }
+    total
+}
+
+// Physical address of addr in the task's address space, populating a lazy
+// page or copying a copy-on-write one first as a user access would
+// None if the task has no such page, or for a write, no writable one
+fn user_page(space: &mut AddressSpace, addr: usize, write: bool) -> Option<usize> {
+    let usable = |space: &AddressSpace| {
+        let writable = space.flags(addr)? & PTE_WRITE != 0;
+        if write && !writable {
+            return None;
+        }
+        space.translate(addr)
+    };
+    if let Some(paddr) = usable(space) {
+        return Some(paddr);
+    }
+    if space.handle_fault(addr) {
+        usable(space)
+    } else {
+        None
+    }
+}
+
+// Where the byte at addr can be read without faulting, None if nowhere
+fn user_byte(addr: usize) -> Option<*const u8> {
+    if let Some(paddr) = process::address_space().and_then(|s| user_page(s, addr, false)) {
+        return Some(paddr as *const u8);
+    }
+    memory::is_kernel_memory(addr, 1).then_some(addr as *const u8)
+}
+
+// The kernel side pieces of the buffer at addr..addr + len, split where pages
+// end, write if the kernel is to store into it
+// EFAULT if any of it cannot be reached, before anything is accessed
+fn user_buffer(addr: usize, len: usize, write: bool) -> Result<Vec<(*mut u8, usize)>, isize> {
+    let end = addr.checked_add(len).ok_or(EFAULT)?;
+    if len > isize::MAX as usize {
+        return Err(EFAULT);
+    }
+    let mut pieces = Vec::new();
+    let mut at = addr;
+    while at < end {
+        let size = (PAGE_SIZE - at % PAGE_SIZE).min(end - at);
+        let in_kernel = if write {
+            memory::is_kernel_writable(at, size)
+        } else {
+            memory::is_kernel_memory(at, size)
+        };
+        let ptr = match process::address_space().and_then(|s| user_page(s, at, write)) {
+            Some(paddr) => paddr,
+            None if in_kernel => at,
+            None => return Err(EFAULT),
+        };
+        pieces.push((ptr as *mut u8, size));
+        at += size;
+    }
+    Ok(pieces)
+}
+
+// Copy the NUL terminated path at addr into buffer, checked a byte at a time
+// so a short path at the end of a mapping is not read past
+fn path(addr: usize, buffer: &mut [u8; PATH_MAX]) -> Result<&str, isize> {
+    let mut len = 0;
+    loop {
+        if len == PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        let byte = addr.checked_add(len).and_then(user_byte).ok_or(EFAULT)?;
+        buffer[len] = unsafe { *byte };
+        if buffer[len] == 0 {
+            break;
+        }
+        len += 1;
+    }
+    core::str::from_utf8(&buffer[..len]).map_err(|_| EINVAL)
+}
+
+// Paths are absolute so the directory descriptor is ignored
+fn sys_openat(_dirfd: usize, addr: usize, flags: usize) -> usize {
+    if flags & O_ACCMODE != O_RDONLY {
+        return error(EROFS);
+    }
+    let mut buffer = [0; PATH_MAX];
+    let path = match path(addr, &mut buffer) {
+        Ok(p) => p,
+        Err(errno) => return error(errno),
+    };
+    match vfs::open(path) {
+        Some(file) => process::add_file(file).unwrap_or(error(EMFILE)),
+        None => error(ENOENT),
+    }
+}
+
+fn sys_close(fd: usize) -> usize {
+    if process::close_file(fd) {
+        0
+    } else {
+        error(EBADF)
+    }
+}
+
+fn sys_exit(frame: &mut TrapFrame, code: usize) -> usize {
+    println!("exit({})", code as isize);
+    if process::current().0 == 0 {
+        // The boot task exiting stops the machine, QEMU exits with its code
+        power::shutdown(code as u16);
+    } else {
+        // Leave the trap into process::exit, which runs on the task's own stack
+        frame.epc = process::exit as usize;
+    }
+    0
+}
+
+// Let the other ready tasks run before returning
+// Made with ecall this runs in the trap with interrupts disabled, where the
+// switch has to wait until the trap is left
+fn sys_yield() -> usize {
+    if assembly::interrupts_enabled() {
+        sched::yield_hart();
+    } else {
+        trap::schedule_on_return();
+    }
+    0
+}
+
+fn sys_kill(pid: usize, sig: usize) -> usize {
+    let sig = match Signal::from_number(sig) {
+        Some(s) => s,
+        None => return error(EINVAL),
+    };
+    if process::kill(Pid(pid), sig) {
+        0
+    } else {
+        error(ESRCH)
+    }
+}
+
+// Returns the new program break, or the current one if it cannot be moved
+// brk(0) queries the break. A task without an address space is given one
+fn sys_brk(addr: usize) -> usize {
+    if process::address_space().is_none() {
+        match AddressSpace::new() {
+            Some(space) => process::set_address_space(space),
+            None => return 0,
+        };
+    }
+    match process::address_space() {
+        Some(space) => space.set_brk(addr).unwrap_or(space.brk()),
+        None => 0,
+    }
+}
+
+// Handle the syscall described by the registers saved in frame
+// The trap returns to frame.epc, which a syscall may change
+pub fn dispatch(frame: &mut TrapFrame) {
+    let ret = match frame.regs[TrapFrame::A7] {
+        SYS_OPENAT => sys_openat(frame.arg(0), frame.arg(1), frame.arg(2)),
+        SYS_CLOSE => sys_close(frame.arg(0)),
+        SYS_READ => sys_read(frame.arg(0), frame.arg(1), frame.arg(2)),
+        SYS_WRITE => sys_write(frame.arg(0), frame.arg(1), frame.arg(2)),
+        SYS_EXIT => {
+            let code = frame.arg(0);
+            sys_exit(frame, code)
+        }
+        SYS_YIELD => sys_yield(),
+        SYS_KILL => sys_kill(frame.arg(0), frame.arg(1)),
+        SYS_BRK => sys_brk(frame.arg(0)),
+        number => {
+            log::warn!("Unknown syscall {}", number);
+            error(ENOSYS)
+        }
+    };
+    frame.regs[TrapFrame::A0] = ret;
+    if process::signal_pending() {
+        // Killed, possibly by itself, leave the trap into process::exit
+        frame.epc = process::exit as usize;
+    }
+}
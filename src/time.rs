@@ -0,0 +1,69 @@
+use crate::clint;
+use crate::config::BOARD;
+use crate::fdt;
+use crate::log;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+// mod time.rs
+// The monotonic clock
+// Reads mtime, or the time CSR in supervisor mode, which counts up at the
+// timebase frequency since the machine reset and is shared by every hart, so
+// it never goes back and needs no interrupts
+// The frequency comes from /cpus/timebase-frequency in the device tree, the
+// board's stands in until init() has read it and on machines without one
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(BOARD.timebase_frequency);
+
+// ====================================================
+// The public interface for time is here...
+// ====================================================
+
+// Calibrate against the device tree, runs right after fdt::init so the log
+// timestamps are right from early boot
+pub fn init() {
+    log::info!("init time");
+    match fdt::timebase_frequency() {
+        Some(0) | None => log::warn!(
+            "no timebase-frequency in the device tree, assuming {} Hz",
+            BOARD.timebase_frequency
+        ),
+        Some(frequency) => FREQUENCY.store(frequency, Ordering::Relaxed),
+    }
+    log::debug!("timebase {} Hz", frequency());
+}
+
+// Ticks of mtime per second
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Time since the machine reset
+pub fn monotonic() -> Duration {
+    to_duration(clint::mtime())
+}
+
+// Time passed since start, a value of monotonic()
+pub fn since(start: Duration) -> Duration {
+    monotonic().saturating_sub(start)
+}
+
+// The duration of a number of mtime ticks
+pub fn to_duration(ticks: u64) -> Duration {
+    let frequency = frequency();
+    let nanos = (ticks % frequency) as u128 * NANOS_PER_SEC / frequency as u128;
+    Duration::new(ticks / frequency, nanos as u32)
+}
+
+// The mtime ticks in duration, rounded up so waiting for them waits at least
+// that long
+pub fn to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128).div_ceil(NANOS_PER_SEC) as u64
+}
+
+// The mtime value duration from now, for timer deadlines and wait queues
+pub fn deadline(duration: Duration) -> u64 {
+    clint::mtime() + to_ticks(duration)
+}
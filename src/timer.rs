@@ -0,0 +1,144 @@
+use crate::assembly;
+use crate::clint;
+use crate::config::{TIMER_CALLBACKS, TIMER_INTERVAL_MS};
+use crate::irq;
+use crate::log;
+use crate::process;
+use crate::time;
+use core::time::Duration;
+
+// mod timer.rs
+// The machine timer
+// Programs the CLINT timer compare register, counts timer interrupts and
+// runs callbacks registered by other subsystems once their time has come
+// Callbacks run from the trap handler every TIMER_INTERVAL_MS at best
+
+static mut TICKS: u64 = 0;
+static mut CALLBACKS: [Option<Callback>; TIMER_CALLBACKS] = [None; TIMER_CALLBACKS];
+
+// Handle for a registered callback
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CallbackId(usize);
+
+#[derive(Copy, Clone)]
+struct Callback {
+    // mtime at which the callback is next due
+    deadline: u64,
+    // Interval in mtime ticks for periodic callbacks
+    period: Option<u64>,
+    func: fn(),
+}
+
+fn register(ms: u64, periodic: bool, func: fn()) -> Option<CallbackId> {
+    let ticks = ms_to_ticks(ms);
+    let callback = Callback {
+        deadline: now() + ticks,
+        period: if periodic { Some(ticks) } else { None },
+        func,
+    };
+    let slot = irq::with_disabled(|| unsafe {
+        let idx = CALLBACKS.iter().position(|c| c.is_none())?;
+        CALLBACKS[idx] = Some(callback);
+        Some(idx)
+    });
+    if slot.is_none() {
+        log::error!("No free timer callback slots");
+    }
+    slot.map(CallbackId)
+}
+
+// Run every callback that is due, one-shot callbacks are removed first
+fn run_callbacks(now: u64) {
+    unsafe {
+        for slot in CALLBACKS.iter_mut() {
+            let callback = match slot {
+                Some(c) if c.deadline <= now => *c,
+                _ => continue,
+            };
+            match callback.period {
+                Some(period) => slot.as_mut().unwrap().deadline = now + period,
+                None => *slot = None,
+            }
+            (callback.func)();
+        }
+    }
+}
+
+// ====================================================
+// The public interface for the timer is here...
+// ====================================================
+
+// Current value of the machine timer
+pub fn now() -> u64 {
+    clint::mtime()
+}
+
+// Convert milliseconds into machine timer ticks
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    time::to_ticks(Duration::from_millis(ms))
+}
+
+// Convert microseconds into machine timer ticks
+pub fn us_to_ticks(us: u64) -> u64 {
+    time::to_ticks(Duration::from_micros(us))
+}
+
+// Timer interrupts taken since boot
+pub fn ticks() -> u64 {
+    unsafe { TICKS }
+}
+
+// Milliseconds since the machine timer started
+pub fn uptime_ms() -> u64 {
+    time::monotonic().as_millis() as u64
+}
+
+// Busy wait for at least us microseconds, safe with interrupts disabled
+pub fn delay_us(us: u64) {
+    let end = now() + us_to_ticks(us);
+    while now() < end {
+        assembly::no_operation();
+    }
+}
+
+pub fn delay_ms(ms: u64) {
+    delay_us(ms * 1000);
+}
+
+// Sleep for at least ms milliseconds, other tasks run in the meantime
+// Interrupts must be enabled, the timer tick is what wakes the task. Not for
+// use inside an irq section, leaving would turn interrupts on under it
+#[cfg_attr(not(feature = "test-suite"), allow(dead_code))]
+pub fn sleep_ms(ms: u64) {
+    assert!(irq::depth() == 0, "sleep_ms inside an irq section");
+    let end = now() + ms_to_ticks(ms);
+    assembly::disable_interrupts();
+    while now() < end {
+        process::block(Some(end));
+    }
+    assembly::enable_interrupts();
+}
+
+// Call func once, ms milliseconds from now
+pub fn after(ms: u64, func: fn()) -> Option<CallbackId> {
+    register(ms, false, func)
+}
+
+// Call func every ms milliseconds until cancelled
+pub fn every(ms: u64, func: fn()) -> Option<CallbackId> {
+    register(ms, true, func)
+}
+
+// Remove a callback, false if it already ran or was cancelled
+pub fn cancel(id: CallbackId) -> bool {
+    irq::with_disabled(|| unsafe { CALLBACKS[id.0].take().is_some() })
+}
+
+// Called from the trap handler on a machine timer interrupt
+pub fn interrupt_handler(hart: usize) {
+    let now = now();
+    unsafe { TICKS += 1 };
+    clint::set_mtimecmp(hart, now + ms_to_ticks(TIMER_INTERVAL_MS));
+    run_callbacks(now);
+    process::tick(now);
+}
@@ -0,0 +1,145 @@
+use crate::config::{TRACE_BUFFER_SIZE, TRACE_EVENTS};
+use crate::hart;
+use crate::timer::now;
+use crate::{print, println};
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use rust_alloc::vec::Vec;
+
+// mod trace.rs
+// A flight recorder of small fixed size events, trace_event!(id, arg) stores
+// the mtime, hart, id and argument of an event in a ring buffer
+// Recording takes no lock and never prints, so it is safe in interrupt
+// handlers and barely changes the timing it is meant to show
+// The oldest events are overwritten once the ring is full
+// Each slot carries a sequence number written last, readers skip slots
+// that were being overwritten while they read them
+// Events are dumped in a line based protocol: "TRACE <mtime> <hart> <event> <arg>"
+
+// Events of the kernel's own tracepoints, others pick ids from USER_EVENTS up
+// A PLIC interrupt was claimed, arg is the source
+pub const IRQ: u32 = 1;
+// A block request chain was handed to the device, arg is its head descriptor
+pub const BLOCK_SUBMIT: u32 = 2;
+// The block device returned used chains, arg is how many
+pub const BLOCK_COMPLETE: u32 = 3;
+// Hart 0 switched tasks, arg is the pid switched to
+pub const SWITCH: u32 = 4;
+pub const USER_EVENTS: u32 = 0x100;
+
+static SLOTS: [Slot; TRACE_BUFFER_SIZE] = [const { Slot::new() }; TRACE_BUFFER_SIZE];
+// Events recorded since boot, the next one goes to slot NEXT % TRACE_BUFFER_SIZE
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// Record an event if config.rs turns tracing on, cheap enough for hot paths
+macro_rules! trace_event {
+    ($id:expr, $arg:expr) => {{
+        if $crate::config::TRACE_EVENTS {
+            $crate::trace::record($id, $arg as usize);
+        }
+    }};
+}
+
+pub(crate) use trace_event;
+
+struct Slot {
+    // Index of the event held plus one, 0 while it is written
+    seq: AtomicUsize,
+    time: AtomicU64,
+    hart: AtomicUsize,
+    id: AtomicU32,
+    arg: AtomicUsize,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            time: AtomicU64::new(0),
+            hart: AtomicUsize::new(0),
+            id: AtomicU32::new(0),
+            arg: AtomicUsize::new(0),
+        }
+    }
+
+    // The event in the slot if it is event seq - 1 and was not overwritten
+    // while being read
+    fn read(&self, seq: usize) -> Option<Event> {
+        if self.seq.load(Ordering::Acquire) != seq {
+            return None;
+        }
+        let event = Event {
+            time: self.time.load(Ordering::Relaxed),
+            hart: self.hart.load(Ordering::Relaxed),
+            id: self.id.load(Ordering::Relaxed),
+            arg: self.arg.load(Ordering::Relaxed),
+        };
+        fence(Ordering::Acquire);
+        (self.seq.load(Ordering::Relaxed) == seq).then_some(event)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Event {
+    pub time: u64,
+    pub hart: usize,
+    pub id: u32,
+    pub arg: usize,
+}
+
+// Name of a kernel event
+fn name(id: u32) -> Option<&'static str> {
+    match id {
+        IRQ => Some("irq"),
+        BLOCK_SUBMIT => Some("block-submit"),
+        BLOCK_COMPLETE => Some("block-complete"),
+        SWITCH => Some("switch"),
+        _ => None,
+    }
+}
+
+// ====================================================
+// The public interface for trace is here...
+// ====================================================
+
+// Use trace_event! rather than calling this, it compiles out with tracing off
+pub fn record(id: u32, arg: usize) {
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &SLOTS[n % TRACE_BUFFER_SIZE];
+    slot.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    slot.time.store(now(), Ordering::Relaxed);
+    slot.hart.store(hart::id(), Ordering::Relaxed);
+    slot.id.store(id, Ordering::Relaxed);
+    slot.arg.store(arg, Ordering::Relaxed);
+    slot.seq.store(n + 1, Ordering::Release);
+}
+
+// Events recorded since boot, including the ones overwritten
+pub fn recorded() -> usize {
+    NEXT.load(Ordering::Relaxed)
+}
+
+// The events still in the ring, oldest first
+pub fn events() -> Vec<Event> {
+    let next = recorded();
+    (next.saturating_sub(TRACE_BUFFER_SIZE)..next)
+        .filter_map(|n| SLOTS[n % TRACE_BUFFER_SIZE].read(n + 1))
+        .collect()
+}
+
+// Print the events still in the ring for host side parsing
+#[allow(dead_code)]
+pub fn dump() {
+    if !TRACE_EVENTS {
+        println!("tracing is off, see TRACE_EVENTS in config.rs");
+        return;
+    }
+    for event in events() {
+        print!("TRACE {} {} ", event.time, event.hart);
+        match name(event.id) {
+            Some(name) => print!("{}", name),
+            None => print!("0x{:x}", event.id),
+        }
+        println!(" 0x{:x}", event.arg);
+    }
+}
@@ -1,95 +1,555 @@
-use crate::config::{RESET_COLOUR, TRAP_COLOUR};
+use crate::addrspace;
+use crate::alloc;
+use crate::assembly;
+use crate::clint;
+use crate::config::{
+    IRQ_STACK_SIZE, MAX_HARTS, MAX_IRQ_NESTING, PLIC_SOURCES, RESET_COLOUR, TRAP_COLOUR,
+};
+use crate::console::styled;
+#[cfg(feature = "gdbstub")]
+use crate::gdbstub;
+use crate::hart;
+use crate::irqlog::{self, IrqSource};
+use crate::log;
+#[cfg(feature = "debug-monitor")]
+use crate::monitor;
+use crate::paging;
 use crate::plic;
+use crate::process;
+use crate::smp;
+use crate::stack;
+use crate::syscall;
+use crate::timer;
+use crate::watchdog;
 use crate::{print, println};
+use core::fmt::{self, Display, Formatter, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 // mod trap.rs
 // Rust handler switch for CPU traps
 
 // machine_trap_rust is called from _machine_trap_asm
-// see src/asm/trap.S
+// see src/asm/trap.S, or src/asm/supervisor.S with --features "supervisor"
 
 // Async
+const SUPERVISOR_SOFTWARE_INTERRUPT: usize = 1;
 const MACHINE_SOFTWARE_INTERRUPT: usize = 3;
+const SUPERVISOR_TIMER_INTERRUPT: usize = 5;
 const MACHINE_TIMER_INTERRUPT: usize = 7;
+const SUPERVISOR_EXTERNAL_INTERRUPT: usize = 9;
 const MACHINE_EXTERNAL_INTERRUPT: usize = 11;
+// Interrupt enable bits in mie, or sie in supervisor mode
+#[cfg(not(feature = "supervisor"))]
+pub const IE_SOFTWARE: usize = 1 << 3;
+#[cfg(not(feature = "supervisor"))]
+pub const IE_TIMER: usize = 1 << 7;
+#[cfg(not(feature = "supervisor"))]
+pub const IE_EXTERNAL: usize = 1 << 11;
+#[cfg(feature = "supervisor")]
+pub const IE_SOFTWARE: usize = 1 << 1;
+#[cfg(feature = "supervisor")]
+pub const IE_TIMER: usize = 1 << 5;
+#[cfg(feature = "supervisor")]
+pub const IE_EXTERNAL: usize = 1 << 9;
 // Sync
-const ILLEGAL_INSTRUCTION: usize = 2;
-const LOAD_ACCESS_FAULT: usize = 5;
-const STORE_ACCESS_FAULT: usize = 7;
+const INSTRUCTION_ACCESS_FAULT: usize = 1;
+pub const ILLEGAL_INSTRUCTION: usize = 2;
+pub const BREAKPOINT: usize = 3;
+pub const LOAD_ACCESS_FAULT: usize = 5;
+pub const STORE_ACCESS_FAULT: usize = 7;
 const USER_ECALL: usize = 8;
 const SUPERVISOR_ECALL: usize = 9;
 const MACHINE_ECALL: usize = 11;
+const INSTRUCTION_PAGE_FAULT: usize = 12;
+pub const LOAD_PAGE_FAULT: usize = 13;
+pub const STORE_PAGE_FAULT: usize = 15;
+// Cause codes below this are counted in TrapStats
+const CAUSES: usize = 16;
+// Trap CSRs are the m* ones, or the s* ones in supervisor mode
+const CSR_PREFIX: &str = if cfg!(feature = "supervisor") {
+    "s"
+} else {
+    "m"
+};
+
+// Registers saved by _machine_trap_asm, the layout is shared with trap.S
+// regs holds x0-x31 with x2 being the stack pointer at the time of the trap
+// Changes to the registers are restored when the trap returns
+#[repr(C)]
+#[derive(Default)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+    pub epc: usize,
+    pub satp: usize,
+    pub mstatus: usize,
+    // Keeps the frame a multiple of 16 bytes as the stack requires
+    pub reserved: usize,
+}
+
+#[repr(C, align(16))]
+struct IrqStack([u8; IRQ_STACK_SIZE]);
+
+static mut IRQ_STACKS: [IrqStack; MAX_HARTS] = [const { IrqStack([0; IRQ_STACK_SIZE]) }; MAX_HARTS];
+// Per hart, Some while catch_fault runs there, holding the fault once taken
+static mut CATCHING: [Option<Option<Fault>>; MAX_HARTS] = [None; MAX_HARTS];
+// The trap each hart is panicking on, for the panic handler to dump
+static mut FATAL: [Option<FatalTrap>; MAX_HARTS] = [None; MAX_HARTS];
+// Every hart takes traps through here, so the counters are atomic
+static INTERRUPTS: [AtomicU64; CAUSES] = [const { AtomicU64::new(0) }; CAUSES];
+static EXCEPTIONS: [AtomicU64; CAUSES] = [const { AtomicU64::new(0) }; CAUSES];
+static EXTERNAL: [AtomicU64; PLIC_SOURCES] = [const { AtomicU64::new(0) }; PLIC_SOURCES];
+
+extern "C" {
+    // Where a syscall that yields leaves its trap, see src/asm/trap.S
+    #[cfg(not(feature = "supervisor"))]
+    #[link_name = "_machine_trap_schedule"]
+    fn trap_schedule();
+    // Or src/asm/supervisor.S
+    #[cfg(feature = "supervisor")]
+    #[link_name = "_supervisor_trap_schedule"]
+    fn trap_schedule();
+}
+
+// A fault taken while catch_fault was running
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Fault {
+    pub cause: usize,
+    pub tval: usize,
+    pub epc: usize,
+}
+
+// A trap the kernel does not survive, the frame stays on the interrupt stack
+// as the panic never returns
+#[derive(Clone, Copy)]
+struct FatalTrap {
+    frame: *const TrapFrame,
+    cause: usize,
+    tval: usize,
+}
+
+// Traps taken since boot by cause code, and external interrupts by PLIC source
+#[derive(Clone, Copy)]
+pub struct TrapStats {
+    pub interrupts: [u64; CAUSES],
+    pub exceptions: [u64; CAUSES],
+    pub external: [u64; PLIC_SOURCES],
+}
+
+impl TrapFrame {
+    pub const A0: usize = 10;
+    pub const A7: usize = 17;
+
+    // ABI names of x0-x31
+    pub const NAMES: [&'static str; 32] = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+        "t5", "t6",
+    ];
+
+    // Argument register a0-a7 by index
+    pub fn arg(&self, idx: usize) -> usize {
+        self.regs[Self::A0 + idx]
+    }
+
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+}
+
+impl Display for TrapFrame {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, chunk) in self.regs.chunks(4).enumerate() {
+            for (j, reg) in chunk.iter().enumerate() {
+                write!(f, "{:>5}: 0x{:016x}  ", Self::NAMES[i * 4 + j], reg)?;
+            }
+            write!(f, "\r\n")?;
+        }
+        write!(
+            f,
+            "  epc: 0x{:016x}  mstatus: 0x{:016x}  satp: 0x{:016x}\r\n",
+            self.epc, self.mstatus, self.satp
+        )
+    }
+}
+
+fn cause_name(is_async: bool, cause: usize) -> &'static str {
+    if is_async {
+        match cause {
+            SUPERVISOR_SOFTWARE_INTERRUPT => "supervisor software",
+            MACHINE_SOFTWARE_INTERRUPT => "machine software",
+            SUPERVISOR_TIMER_INTERRUPT => "supervisor timer",
+            MACHINE_TIMER_INTERRUPT => "machine timer",
+            SUPERVISOR_EXTERNAL_INTERRUPT => "supervisor external",
+            MACHINE_EXTERNAL_INTERRUPT => "machine external",
+            _ => "reserved",
+        }
+    } else {
+        match cause {
+            0 => "instruction misaligned",
+            INSTRUCTION_ACCESS_FAULT => "instruction access fault",
+            ILLEGAL_INSTRUCTION => "illegal instruction",
+            BREAKPOINT => "breakpoint",
+            4 => "load misaligned",
+            LOAD_ACCESS_FAULT => "load access fault",
+            6 => "store misaligned",
+            STORE_ACCESS_FAULT => "store access fault",
+            USER_ECALL => "user ecall",
+            SUPERVISOR_ECALL => "supervisor ecall",
+            MACHINE_ECALL => "machine ecall",
+            INSTRUCTION_PAGE_FAULT => "instruction page fault",
+            LOAD_PAGE_FAULT => "load page fault",
+            STORE_PAGE_FAULT => "store page fault",
+            _ => "reserved",
+        }
+    }
+}
+
+fn count(is_async: bool, cause: usize) {
+    let local = hart::local();
+    if is_async {
+        local.interrupts += 1;
+    } else {
+        local.exceptions += 1;
+    }
+    if cause >= CAUSES {
+        return;
+    }
+    let counts = if is_async { &INTERRUPTS } else { &EXCEPTIONS };
+    counts[cause].fetch_add(1, Ordering::Relaxed);
+}
+
+// What a faulting access of the given cause was trying to do
+fn access_type(cause: usize) -> &'static str {
+    match cause {
+        INSTRUCTION_ACCESS_FAULT | INSTRUCTION_PAGE_FAULT => "fetch",
+        LOAD_ACCESS_FAULT | LOAD_PAGE_FAULT => "load",
+        _ => "store",
+    }
+}
+
+// What is known to live at a faulting address
+fn address_region(addr: usize) -> &'static str {
+    if alloc::is_heap_address(addr) {
+        "heap"
+    } else {
+        paging::mmio_window(addr).unwrap_or("unknown")
+    }
+}
+
+fn print_fault(name: &str, hart: usize, epc: usize, tval: usize, cause: usize) {
+    println!(
+        "{}{}\n\tCPU#{} -> 0x{:08x}: {} at 0x{:08x} ({}){}",
+        styled(TRAP_COLOUR),
+        name,
+        hart,
+        epc,
+        access_type(cause),
+        tval,
+        address_region(tval),
+        styled(RESET_COLOUR)
+    );
+}
+
+// Resume after the faulting instruction if catch_fault is waiting for a fault
+// on this hart, the fault is handed to it instead of being handled
+fn caught(hart: usize, cause: usize, tval: usize, epc: usize) -> Option<usize> {
+    let catching = unsafe { &mut CATCHING[hart] };
+    if *catching != Some(None) {
+        return None;
+    }
+    *catching = Some(Some(Fault { cause, tval, epc }));
+    Some(epc + instruction_length(epc))
+}
+
+// Keep the trap for the panic handler, call right before panicking in a trap
+fn fatal(hart: usize, frame: &TrapFrame, cause: usize, tval: usize) {
+    unsafe { FATAL[hart] = Some(FatalTrap { frame, cause, tval }) };
+}
+
+// The trap CSRs in a fixed layout, names follow the privilege mode
+fn write_csrs(
+    out: &mut impl Write,
+    (status, cause, tval, epc): (usize, usize, usize, usize),
+    satp: usize,
+) -> fmt::Result {
+    let p = CSR_PREFIX;
+    let is_async = cause >> 63 & 1 == 1;
+    write!(
+        out,
+        "{p}status: 0x{:016x}  {p}cause: 0x{:016x} ({})\r\n",
+        status,
+        cause,
+        cause_name(is_async, cause & 0xfff)
+    )?;
+    write!(
+        out,
+        "  {p}tval: 0x{:016x}   {p}epc: 0x{:016x}\r\n   satp: 0x{:016x}\r\n",
+        tval, epc, satp
+    )
+}
+
+// Length in bytes of the instruction at pc, 2 for compressed instructions
+pub fn instruction_length(pc: usize) -> usize {
+    if unsafe { (pc as *const u16).read() } & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+// Leave the trap into trap_schedule instead of returning to frame.epc,
+// tasks cannot be switched on the interrupt stack. The frame is copied right
+// below the task's stack pointer, where it is restored from once the task
+// runs again, so the syscall returns as if nothing had switched
+// Tasks do not run in user mode yet, the stack is their kernel stack
+fn leave_into_schedule(frame: &mut TrapFrame) -> usize {
+    let copy = (frame.regs[2] - size_of::<TrapFrame>()) as *mut TrapFrame;
+    unsafe { core::ptr::copy_nonoverlapping(frame, copy, 1) };
+    frame.regs[2] = copy as usize;
+    trap_schedule as usize
+}
+
+// Called by trap_schedule on the task's stack
+#[no_mangle]
+extern "C" fn trap_schedule_rust() {
+    process::schedule();
+}
 
 #[no_mangle]
-extern "C" fn machine_trap_rust(epc: usize, tval: usize, cause: usize, hart: usize) -> usize {
+extern "C" fn machine_trap_rust(
+    epc: usize,
+    tval: usize,
+    cause: usize,
+    hart: usize,
+    frame: &mut TrapFrame,
+) -> usize {
     let is_async = cause >> 63 & 1 == 1;
     let cause_index = cause & 0xfff;
-    let mut pc = epc;
+    let pc = epc;
+    count(is_async, cause_index);
     if is_async {
         match cause_index {
-            MACHINE_SOFTWARE_INTERRUPT => {
-                println!(
-                    "{}Machine software interrupt\n\tCPU#{}{}",
-                    TRAP_COLOUR, hart, RESET_COLOUR
-                );
+            MACHINE_SOFTWARE_INTERRUPT | SUPERVISOR_SOFTWARE_INTERRUPT => {
+                irqlog::record(IrqSource::Software);
+                clint::interrupt_handler(hart);
+            }
+            MACHINE_TIMER_INTERRUPT | SUPERVISOR_TIMER_INTERRUPT => {
+                irqlog::record(IrqSource::Timer);
+                stack::check();
+                timer::interrupt_handler(hart);
+                watchdog::check(frame);
             }
-            MACHINE_TIMER_INTERRUPT => unsafe {
-                let mtimecmp = 0x0200_4000 as *mut u64;
-                let mtime = 0x0200_bff8 as *const u64;
-                mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
-                // println!(".");
-            },
-            MACHINE_EXTERNAL_INTERRUPT => {
-                // println!("Machine external interrupt from PLIC\n\tCPU#{}", hart);
-                plic::interrupt_handler();
+            MACHINE_EXTERNAL_INTERRUPT | SUPERVISOR_EXTERNAL_INTERRUPT => {
+                log::trace!("external interrupt on CPU#{}", hart);
+                // Device handlers can be slow, let timer and software interrupts in
+                nested(IE_TIMER | IE_SOFTWARE, plic::interrupt_handler);
             }
             _ => {
+                fatal(hart, frame, cause, tval);
                 panic!("Unhandled async trap\n\tCPU#{} -> {}\n", hart, cause_index);
             }
         }
     } else {
+        // Faults a test takes on purpose, the kernel's own handling comes after
+        if matches!(
+            cause_index,
+            ILLEGAL_INSTRUCTION | BREAKPOINT | LOAD_ACCESS_FAULT | STORE_ACCESS_FAULT
+        ) {
+            if let Some(resume) = caught(hart, cause_index, tval, pc) {
+                return resume;
+            }
+        }
         match cause_index {
             ILLEGAL_INSTRUCTION => {
+                fatal(hart, frame, cause, tval);
                 panic!(
                     "Illegal instruction\n\tCPU#{} -> 0x{:08x}: 0x{:08x}\n",
                     hart, epc, tval
                 );
             }
-            LOAD_ACCESS_FAULT => {
+            // The debugger decides where to resume
+            #[cfg(feature = "gdbstub")]
+            BREAKPOINT => return gdbstub::enter(frame, pc),
+            #[cfg(not(feature = "gdbstub"))]
+            BREAKPOINT => {
                 println!(
-                    "{}Load access fault\n\tCPU#{} -> 0x{:08x}{}",
-                    TRAP_COLOUR, hart, epc, RESET_COLOUR
+                    "{}Breakpoint\n\tCPU#{} -> 0x{:08x}{}",
+                    styled(TRAP_COLOUR),
+                    hart,
+                    epc,
+                    styled(RESET_COLOUR)
                 );
+                frame.print();
+                #[cfg(feature = "debug-monitor")]
+                monitor::run(frame);
+                // Resume after the ebreak, which may be compressed
+                return pc + instruction_length(pc);
             }
-            STORE_ACCESS_FAULT => {
-                println!(
-                    "{}Store / AMO access fault\n\tCPU#{} -> 0x{:08x}{}",
-                    TRAP_COLOUR, hart, epc, RESET_COLOUR
-                );
+            INSTRUCTION_ACCESS_FAULT => {
+                print_fault("Instruction access fault", hart, epc, tval, cause_index);
+                fatal(hart, frame, cause, tval);
+                panic!("Unrecoverable instruction access fault");
             }
-            USER_ECALL => {
-                println!(
-                    "{}E-call from User mode!\n\tCPU#{} -> 0x{:08x}{}",
-                    TRAP_COLOUR, hart, epc, RESET_COLOUR
-                );
+            LOAD_ACCESS_FAULT => {
+                print_fault("Load access fault", hart, epc, tval, cause_index);
+                fatal(hart, frame, cause, tval);
+                panic!("Unexpected access fault at 0x{:08x}", tval);
             }
-            SUPERVISOR_ECALL => {
-                println!(
-                    "{}E-call from Supervisor mode!\n\tCPU#{} -> 0x{:08x}{}",
-                    TRAP_COLOUR, hart, epc, RESET_COLOUR
-                );
+            STORE_ACCESS_FAULT => {
+                print_fault("Store / AMO access fault", hart, epc, tval, cause_index);
+                fatal(hart, frame, cause, tval);
+                panic!("Unexpected access fault at 0x{:08x}", tval);
             }
-            MACHINE_ECALL => {
-                panic!(
-                    "{}E-call from Machine mode!\n\tCPU#{} -> 0x{:08x}{}\n",
-                    TRAP_COLOUR, hart, epc, RESET_COLOUR
-                );
+            // Kernel tasks run in machine mode unless built with supervisor,
+            // where their ecalls go to the machine mode stub instead
+            USER_ECALL | SUPERVISOR_ECALL | MACHINE_ECALL => {
+                // Return after the ecall unless the syscall sends the task elsewhere
+                frame.epc = pc + 4;
+                syscall::dispatch(frame);
+                if core::mem::take(&mut hart::local().resched) {
+                    return leave_into_schedule(frame);
+                }
+                return frame.epc;
+            }
+            INSTRUCTION_PAGE_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT => {
+                if addrspace::handle_page_fault(tval) {
+                    // Retry the faulting instruction now the page is mapped
+                    return pc;
+                }
+                if let Some(resume) = caught(hart, cause_index, tval, pc) {
+                    return resume;
+                }
+                // There are no user contexts to kill yet, so the kernel goes
+                print_fault("Unhandled page fault", hart, epc, tval, cause_index);
+                fatal(hart, frame, cause, tval);
+                panic!("Unhandled page fault at 0x{:08x}", tval);
             }
             _ => {
+                fatal(hart, frame, cause, tval);
                 panic!("Unhandled sync trap\n\tCPU#{} -> {}\n", hart, cause_index);
             }
         }
-        pc += 4;
     };
     pc
 }
+
+// Point this hart's trap entry at its interrupt stack
+pub fn init(hart: usize) {
+    let top = unsafe { IRQ_STACKS[hart].0.as_ptr() as usize + IRQ_STACK_SIZE };
+    assembly::write_scratch(top);
+}
+
+// Switch to the other ready tasks once the syscall being handled on this hart
+// has left its trap, which cannot switch itself
+pub fn schedule_on_return() {
+    hart::local().resched = true;
+}
+
+// Traps currently being handled on this hart with interrupts enabled again
+pub fn nesting() -> usize {
+    hart::local().nesting
+}
+
+// Run handler with only the interrupts in `allowed` (IE_* bits) enabled
+// Call with interrupts disabled as they are in a trap, they are off again on return
+// Beyond MAX_IRQ_NESTING levels the handler runs with interrupts off
+pub fn nested(allowed: usize, handler: fn()) {
+    let local = hart::local();
+    if local.nesting >= MAX_IRQ_NESTING {
+        handler();
+        return;
+    }
+    local.nesting += 1;
+    let ie = assembly::read_ie();
+    assembly::write_ie(ie & allowed);
+    assembly::enable_interrupts();
+    handler();
+    assembly::disable_interrupts();
+    assembly::write_ie(ie);
+    hart::local().nesting -= 1;
+}
+
+// Run f and hand back the first fault it takes on this hart instead of
+// handling it, f resumes after the faulting instruction. For tests that
+// fault on purpose, every fault outside of it is handled as usual
+// Illegal instructions, breakpoints, access faults and page faults no address
+// space resolves are caught. f must not sleep, another task faulting
+// meanwhile would be caught in its place
+pub fn catch_fault(f: impl FnOnce()) -> Option<Fault> {
+    let hart = hart::id();
+    unsafe {
+        assert!(CATCHING[hart].is_none(), "catch_fault does not nest");
+        CATCHING[hart] = Some(None);
+    }
+    f();
+    unsafe { CATCHING[hart].take().flatten() }
+}
+
+// Registers and trap CSRs of a trap in the layout of the panic dump
+pub fn write_trap(
+    out: &mut impl Write,
+    frame: &TrapFrame,
+    cause: usize,
+    tval: usize,
+) -> fmt::Result {
+    write!(out, "{}", frame)?;
+    write_csrs(out, (frame.mstatus, cause, tval, frame.epc), frame.satp)
+}
+
+// For the panic handler, writes no locks or heap so it works whatever state
+// the kernel is in. With a fatal trap on this hart that trap's registers,
+// otherwise the trap CSRs as they are now
+pub fn dump_registers(out: &mut impl Write) -> fmt::Result {
+    let hart = hart::id();
+    match unsafe { FATAL.get(hart).copied().flatten() } {
+        Some(fatal) => {
+            write!(out, "CPU#{} registers at the trap:\r\n", hart)?;
+            write_trap(out, unsafe { &*fatal.frame }, fatal.cause, fatal.tval)
+        }
+        None => {
+            write!(out, "CPU#{} trap registers, not in a trap:\r\n", hart)?;
+            write_csrs(out, assembly::read_trap_csrs(), assembly::read_satp())
+        }
+    }
+}
+
+// Count an external interrupt claimed from the PLIC
+pub fn count_external(irq: u32) {
+    if let Some(count) = EXTERNAL.get(irq as usize) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Snapshot of the trap counters
+pub fn stats() -> TrapStats {
+    let load = |count: &AtomicU64| count.load(Ordering::Relaxed);
+    TrapStats {
+        interrupts: INTERRUPTS.each_ref().map(load),
+        exceptions: EXCEPTIONS.each_ref().map(load),
+        external: EXTERNAL.each_ref().map(load),
+    }
+}
+
+// Print every trap cause and external interrupt source seen so far
+#[allow(dead_code)]
+pub fn debug_stats() {
+    let stats = stats();
+    println!("\nTraps                                COUNT");
+    println!("-------------------------------------------");
+    for (is_async, counts) in [(true, &stats.interrupts), (false, &stats.exceptions)] {
+        for (cause, &count) in counts.iter().enumerate().filter(|(_, &c)| c > 0) {
+            println!("- {:<28} {:>12}", cause_name(is_async, cause), count);
+        }
+    }
+    for (irq, &count) in stats.external.iter().enumerate().filter(|(_, &c)| c > 0) {
+        println!("- external irq {:<15} {:>12}", irq, count);
+    }
+    for hart in (0..MAX_HARTS).filter(|&h| smp::is_online(h)) {
+        let local = hart::get(hart);
+        println!(
+            "- CPU#{} interrupts/exceptions {:>5}/{:<5}",
+            hart, local.interrupts, local.exceptions
+        );
+    }
+    println!("-------------------------------------------");
+}
@@ -1,18 +1,122 @@
-use crate::config::{BANNER, DEBUG, INFO, MAIN, PLATFORM, STEP, TEST, TEST_PASSED, VERSION};
+use crate::config::{
+    BANNER, BOARD, MAIN, STEP, TEST, TEST_PASSED, UART_AUX, UART_RX_BUFFER_SIZE, VERSION,
+};
+use crate::console::styled;
+use crate::fdt;
+use crate::log;
+use crate::plic;
+#[cfg(feature = "sbi")]
+use crate::sbi;
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::waitqueue::WaitQueue;
 use crate::{print, println};
+use core::cell::Cell;
 use core::fmt::{Error, Write};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_alloc::vec::Vec;
 
 // mod uart.rs
 // This is a particularly limited driver for printing to the riscv QEMU virt serial device
 // It will strictly be used for debugging and therefore is particularly limited
+// Received bytes raise a PLIC interrupt, the handler moves them into a ring
+// buffer that read_byte() and wait_byte() take them from
+// There are two ports, the console at BOARD.uart and an optional aux port for
+// logs or a debugger, probe() takes the addresses of both from the device tree
+// Under OpenSBI a console without a uart writes through the firmware
 
-static mut UART: Uart = Uart {
-    base_address: 0x1000_0000,
-};
+pub const CONSOLE_PORT: usize = 0;
+pub const AUX_PORT: usize = 1;
+const PORTS: usize = 2;
+
+static SERIAL: [Port; PORTS] = [
+    Port::new(BOARD.uart.0, BOARD.uart.1),
+    match UART_AUX {
+        Some((base, irq)) => Port::new(base, irq),
+        None => Port::new(0, 0),
+    },
+];
+// Tasks sleeping until a byte is received on each port
+static mut READERS: [WaitQueue; PORTS] = [const { WaitQueue::new() }; PORTS];
+// The console uart for the panic path, which must not wait on any lock
+static EMERGENCY_BASE: AtomicUsize = AtomicUsize::new(BOARD.uart.0);
+
+// Writes straight to the console uart without locking it, see emergency()
+pub struct EmergencyWriter;
+
+impl Write for EmergencyWriter {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        emergency_write(out.as_bytes());
+        Ok(())
+    }
+}
+
+struct Port {
+    // Interrupt handlers print too, always take it with lock_irq()
+    uart: SpinLock<Uart>,
+    // Filled by the interrupt handler, take it with lock_irq()
+    rx: SpinLock<RxRing>,
+}
+
+impl Port {
+    const fn new(base_address: usize, irq: u32) -> Self {
+        Self {
+            uart: SpinLock::new(Uart { base_address, irq }),
+            rx: SpinLock::new(RxRing::new()),
+        }
+    }
+
+    // The uart if there is one at this port
+    fn uart(&self) -> Option<Uart> {
+        let uart = *self.uart.lock_irq();
+        (uart.base_address != 0).then_some(uart)
+    }
+}
+
+// Received bytes not read yet, the oldest at head
+struct RxRing {
+    bytes: [u8; UART_RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+    // Bytes lost because the ring was full
+    dropped: usize,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; UART_RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == UART_RX_BUFFER_SIZE {
+            self.dropped += 1;
+            return;
+        }
+        self.bytes[(self.head + self.len) % UART_RX_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % UART_RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct Uart {
     base_address: usize,
+    // PLIC source raised on receive
+    irq: u32,
 }
 
 impl Write for Uart {
@@ -28,71 +132,232 @@ const BASE: usize = 0;
 const IER: usize = 1; // interrupt enable register
 const FCR: usize = 2; // FIFO control register
 const LCR: usize = 3; // line control register
+const LSR: usize = 5; // line status register
 const BI0: u8 = 1; // Bit index 0 (1 << 0)
 const BI0A1: u8 = 3; // Bit indexes 0+1 (1 << 0) | (1 << 1)
+const BI5: u8 = 1 << 5; // Bit index 5, LSR transmit holding register empty
+const BI6: u8 = 1 << 6; // Bit index 6, LSR transmitter empty
 
 impl Uart {
-    pub fn init(&mut self) {
+    fn init_registers(&self) {
         let ptr = self.base_address as *mut u8;
         unsafe {
             ptr.add(LCR).write_volatile(BI0A1);
             ptr.add(FCR).write_volatile(BI0);
             ptr.add(IER).write_volatile(BI0);
         }
-        Uart::print_banner();
-        serial_main(VERSION);
-        serial_main(PLATFORM);
-        serial_step("Booting...");
     }
 
     fn print_banner() {
-        println!("{}", BANNER);
+        println!("{}", styled(BANNER));
     }
 
+    // Waits for room in the transmitter, a real 16550 drops bytes written to
+    // a full one
     pub fn put(&mut self, c: u8) {
+        #[cfg(feature = "sbi")]
+        if self.base_address == 0 {
+            sbi::console_putchar(c);
+            return;
+        }
         let ptr = self.base_address as *mut u8;
         unsafe {
+            while ptr.add(LSR).read_volatile() & BI5 == 0 {
+                spin_loop();
+            }
             ptr.add(BASE).write_volatile(c);
         }
     }
+
+    // Wait until every byte written has left the transmitter
+    pub fn flush(&self) {
+        #[cfg(feature = "sbi")]
+        if self.base_address == 0 {
+            return;
+        }
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            while ptr.add(LSR).read_volatile() & BI6 == 0 {
+                spin_loop();
+            }
+        }
+    }
+
+    // The next received byte, if data is ready
+    pub fn get(&mut self) -> Option<u8> {
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            if ptr.add(LSR).read_volatile() & BI0 == 0 {
+                None
+            } else {
+                Some(ptr.add(BASE).read_volatile())
+            }
+        }
+    }
 }
 
 pub fn init() {
-    unsafe { UART.init() }
+    // The banner is printed through the console, which takes the lock itself
+    for uart in SERIAL.iter().filter_map(Port::uart) {
+        uart.init_registers();
+    }
+    Uart::print_banner();
+    serial_main(VERSION);
+    serial_main(BOARD.name);
+    serial_step("Booting...");
 }
 
-pub fn get_uart() -> Uart {
-    unsafe { UART }
+// Take the ports from the ns16550a nodes of the device tree, the one with the
+// lowest address is the console, and register their interrupts
+pub fn probe() {
+    let mut found: Vec<(usize, u32)> = fdt::compatible("ns16550a")
+        .iter()
+        .filter_map(|node| Some((node.reg()?.0 as usize, node.interrupt()?)))
+        .collect();
+    found.sort();
+    #[cfg(feature = "sbi")]
+    if found.is_empty() {
+        *SERIAL[CONSOLE_PORT].uart.lock_irq() = Uart {
+            base_address: 0,
+            irq: 0,
+        };
+        EMERGENCY_BASE.store(0, Ordering::Relaxed);
+        log::warn!("no serial port, the console writes through the SBI");
+    }
+    for (port, &(base_address, irq)) in found.iter().take(PORTS).enumerate() {
+        let uart = Uart { base_address, irq };
+        *SERIAL[port].uart.lock_irq() = uart;
+        if port == CONSOLE_PORT {
+            EMERGENCY_BASE.store(base_address, Ordering::Relaxed);
+        }
+        uart.init_registers();
+        log::info!("serial port {} at 0x{:x}, irq {}", port, base_address, irq);
+    }
+    for irq in irqs() {
+        plic::register(irq, interrupt_handler);
+    }
 }
 
-pub fn serial_info(txt: &str) {
-    println!("  {} {}", INFO, txt);
+// Interrupts of the present ports, for the PLIC to enable
+pub fn irqs() -> impl Iterator<Item = u32> {
+    SERIAL.iter().filter_map(Port::uart).map(|uart| uart.irq)
 }
 
-pub fn serial_main(txt: &str) {
-    println!("{} {}", MAIN, txt);
+// True if the interrupt belongs to a serial port
+pub fn handles(irq: u32) -> bool {
+    irqs().any(|i| i == irq)
 }
 
-pub fn serial_step(txt: &str) {
-    println!("\n{} {}", STEP, txt);
+// MMIO pages of the present ports, for the kernel mapping
+pub fn mmio_windows() -> impl Iterator<Item = usize> {
+    SERIAL
+        .iter()
+        .filter_map(Port::uart)
+        .map(|uart| uart.base_address)
 }
 
-pub fn serial_test(txt: &str) {
-    println!("  {} {}", TEST, txt);
+// Called from the PLIC handler, drains the receive FIFO into the ring
+pub fn interrupt_handler(irq: u32) {
+    for (port, serial) in SERIAL.iter().enumerate() {
+        if serial.uart().map(|uart| uart.irq) != Some(irq) {
+            continue;
+        }
+        let mut ring = serial.rx.lock_irq();
+        while let Some(byte) = serial.uart.lock_irq().get() {
+            ring.push(byte);
+        }
+        drop(ring);
+        unsafe { READERS[port].wake_all() };
+    }
+}
+
+pub fn is_present(port: usize) -> bool {
+    port < PORTS && SERIAL[port].uart().is_some()
+}
+
+// The oldest byte received on port, None if there is none yet
+// With interrupts disabled nothing fills the ring, the uart is polled instead
+pub fn read_port(port: usize) -> Option<u8> {
+    let byte = SERIAL[port].rx.lock_irq().pop();
+    byte.or_else(|| get_port(port)?.get())
+}
+
+// Wait for the next byte received on port, other tasks run in the meantime
+pub fn wait_port(port: usize) -> u8 {
+    let byte = Cell::new(None);
+    unsafe {
+        READERS[port].wait_until(None, || {
+            byte.set(read_port(port));
+            byte.get().is_some()
+        })
+    };
+    byte.get().unwrap()
+}
+
+// The oldest byte received on the console
+pub fn read_byte() -> Option<u8> {
+    read_port(CONSOLE_PORT)
 }
 
-pub fn serial_debug(txt: &str) {
-    println!("\n  {} {}", DEBUG, txt);
+// Wait for the next byte received on the console
+pub fn wait_byte() -> u8 {
+    wait_port(CONSOLE_PORT)
 }
 
-pub fn serial_debug_text(label: &str, txt: &str) {
-    println!("\n  {} {}:{}", DEBUG, label, txt);
+// Bytes lost because nothing read them before the ring filled up
+#[allow(dead_code)]
+pub fn dropped(port: usize) -> usize {
+    SERIAL[port].rx.lock_irq().dropped
 }
 
-pub fn serial_debug_number(label: &str, num: usize) {
-    println!("\n  {} {}:{}", DEBUG, label, num);
+// Wait for the transmitters to drain, before the machine stops
+pub fn flush() {
+    for uart in SERIAL.iter().filter_map(Port::uart) {
+        uart.flush();
+    }
+}
+
+// Exclusive access to the console uart, printing from the same hart waits until it is dropped
+pub fn get_uart() -> SpinLockGuard<'static, Uart> {
+    SERIAL[CONSOLE_PORT].uart.lock_irq()
+}
+
+// Writer for the panic handler, it works before init() and while another
+// hart, or the code that panicked, holds the console. Output from several
+// harts may interleave, so use it for nothing else
+pub fn emergency() -> EmergencyWriter {
+    EmergencyWriter
+}
+
+// Write bytes and wait until they have left the console uart, without locks
+pub fn emergency_write(bytes: &[u8]) {
+    let mut uart = Uart {
+        base_address: EMERGENCY_BASE.load(Ordering::Relaxed),
+        irq: 0,
+    };
+    for &b in bytes {
+        uart.put(b);
+    }
+    uart.flush();
+}
+
+// Exclusive access to port, None if no uart is there
+pub fn get_port(port: usize) -> Option<SpinLockGuard<'static, Uart>> {
+    is_present(port).then(|| SERIAL[port].uart.lock_irq())
+}
+
+pub fn serial_main(txt: &str) {
+    println!("{} {}", styled(MAIN), txt);
+}
+
+pub fn serial_step(txt: &str) {
+    println!("\n{} {}", styled(STEP), txt);
+}
+
+pub fn serial_test(txt: &str) {
+    println!("  {} {}", styled(TEST), txt);
 }
 
 pub fn serial_test_passed() {
-    println!("{}", TEST_PASSED);
+    println!("{}", styled(TEST_PASSED));
 }
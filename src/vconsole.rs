@@ -0,0 +1,173 @@
+use crate::config::{VCONSOLE_BUFFER_SIZE, VCONSOLE_RX_BUFFERS};
+use crate::error::KError;
+use crate::log;
+use crate::memory::memcpy;
+use crate::print;
+use crate::virtio::{DmaGuard, Features, MmioDevice};
+use crate::virtqueue::{Segment, VirtQueue};
+
+// mod vconsole.rs
+// A virtio-console driver usable as the kernel console instead of the uart
+// Transmits are synchronous, received bytes are buffered from interrupt context
+
+static mut CONSOLE_DEVICE: Option<ConsoleDevice> = None;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+// Received bytes waiting to be read
+struct ByteQueue {
+    bytes: [u8; VCONSOLE_BUFFER_SIZE],
+    head: usize,
+    tail: usize,
+}
+
+impl ByteQueue {
+    fn push(&mut self, byte: u8) {
+        let next = (self.tail + 1) % VCONSOLE_BUFFER_SIZE;
+        if next != self.head {
+            self.bytes[self.tail] = byte;
+            self.tail = next;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.head == self.tail {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % VCONSOLE_BUFFER_SIZE;
+        Some(byte)
+    }
+}
+
+pub struct ConsoleDevice {
+    dev: DmaGuard,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    tx_buffer: *mut u8,
+    received: ByteQueue,
+}
+
+impl ConsoleDevice {
+    fn init(ptr: *mut u32) -> Result<(), KError> {
+        log::info!("init console device");
+        let dev = MmioDevice::new(ptr)?;
+        dev.begin_init();
+        dev.negotiate(Features::NONE)?;
+        let rx = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        let tx = VirtQueue::new().inspect_err(|_| dev.fail())?;
+        dev.setup_queue(RECEIVE_QUEUE, &rx)?;
+        dev.setup_queue(TRANSMIT_QUEUE, &tx)?;
+        let mut dev = DmaGuard::new(dev);
+        let tx_buffer = dev.alloc_bytes(VCONSOLE_BUFFER_SIZE);
+        let rx_buffers = dev.alloc_bytes(VCONSOLE_RX_BUFFERS * VCONSOLE_BUFFER_SIZE);
+        let (Ok(tx_buffer), Ok(rx_buffers)) = (tx_buffer, rx_buffers) else {
+            print!("buffer alloc fail...");
+            dev.fail();
+            return Err(KError::NoMemory);
+        };
+
+        let mut console = ConsoleDevice {
+            dev,
+            rx,
+            tx,
+            tx_buffer,
+            received: ByteQueue {
+                bytes: [0; VCONSOLE_BUFFER_SIZE],
+                head: 0,
+                tail: 0,
+            },
+        };
+        for i in 0..VCONSOLE_RX_BUFFERS {
+            console.give_buffer(unsafe { rx_buffers.add(i * VCONSOLE_BUFFER_SIZE) } as u64);
+        }
+        console.dev.driver_ok();
+        console.dev.notify(RECEIVE_QUEUE);
+        unsafe { CONSOLE_DEVICE = Some(console) };
+        Ok(())
+    }
+
+    fn give_buffer(&mut self, addr: u64) {
+        let head = self
+            .rx
+            .add_chain(&[Segment::writable(addr, VCONSOLE_BUFFER_SIZE as u32)]);
+        self.rx.submit(head);
+    }
+
+    // Send bytes in buffer sized chunks, waiting for each to be consumed
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(VCONSOLE_BUFFER_SIZE) {
+            unsafe { memcpy(self.tx_buffer, chunk.as_ptr(), chunk.len()) };
+            let head = self
+                .tx
+                .add_chain(&[Segment::readable(self.tx_buffer as u64, chunk.len() as u32)]);
+            self.tx.submit(head);
+            self.dev.notify(TRANSMIT_QUEUE);
+            while !self.tx.is_complete(head) {
+                self.tx.pop_used();
+            }
+        }
+    }
+
+    fn use_queue(&mut self) {
+        self.dev.ack_interrupt();
+        let mut recycled = false;
+        while let Some((head, len)) = self.rx.pop_used() {
+            let addr = self.rx.descriptor_address(head);
+            for i in 0..len as usize {
+                let byte = unsafe { (addr as *const u8).add(i).read_volatile() };
+                self.received.push(byte);
+            }
+            self.give_buffer(addr);
+            recycled = true;
+        }
+        if recycled {
+            self.dev.notify(RECEIVE_QUEUE);
+        }
+    }
+}
+
+// ====================================================
+// The public interface for the console device is here...
+// ====================================================
+
+// Called by virtio::init() when a console device is found
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn init(ptr: *mut u32) -> Result<(), KError> {
+    ConsoleDevice::init(ptr)
+}
+
+// Called by virtio::rescan() when the console device has gone away
+pub fn remove() {
+    unsafe { CONSOLE_DEVICE = None };
+}
+
+pub fn interrupt_handler() {
+    unsafe {
+        if let Some(console) = CONSOLE_DEVICE.as_mut() {
+            console.use_queue();
+        } else {
+            log::error!("Unable to retrieve console device");
+        }
+    }
+}
+
+// True once a console device has been initialized
+pub fn ready() -> bool {
+    unsafe { CONSOLE_DEVICE.is_some() }
+}
+
+pub fn write(bytes: &[u8]) {
+    unsafe {
+        if let Some(console) = CONSOLE_DEVICE.as_mut() {
+            console.write(bytes);
+        }
+    }
+}
+
+// Take the next received byte if any
+#[allow(dead_code)]
+pub fn read_byte() -> Option<u8> {
+    unsafe { CONSOLE_DEVICE.as_mut().and_then(|c| c.received.pop()) }
+}
@@ -0,0 +1,177 @@
+use crate::bootargs::{self, Root};
+use crate::config::P9_MOUNT_POINT;
+use crate::log;
+use crate::minixfs3::MinixFileSystem;
+use crate::p9;
+use crate::{print, println};
+use rust_alloc::{boxed::Box, string::String, vec::Vec};
+
+// mod vfs.rs
+// A minimal virtual filesystem layer
+// Filesystems are mounted at absolute paths and requests are routed to the
+// filesystem with the longest matching mount point
+
+static mut MOUNTS: Vec<Mount> = Vec::new();
+
+// Operations every mountable filesystem provides
+// Paths are absolute and relative to the root of the filesystem
+pub trait FileSystem {
+    fn name(&self) -> &'static str;
+    fn file_size(&mut self, path: &str) -> Option<u32>;
+    fn read_file(&mut self, path: &str, buffer: *mut u8, size: u32, offset: u32) -> Option<u32>;
+    // Write back cached changes, nothing for the read only filesystems so far
+    fn sync(&mut self) {}
+}
+
+struct Mount {
+    point: String,
+    fs: Box<dyn FileSystem>,
+}
+
+impl Mount {
+    // The path inside the mounted filesystem if path lies below this mount
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.point == "/" {
+            return Some(path);
+        }
+        let rest = path.strip_prefix(self.point.as_str())?;
+        if rest.is_empty() {
+            Some("/")
+        } else if rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+}
+
+impl FileSystem for MinixFileSystem {
+    fn name(&self) -> &'static str {
+        "minixfs3"
+    }
+
+    fn file_size(&mut self, path: &str) -> Option<u32> {
+        MinixFileSystem::lookup(path).ok().map(|inode| inode.size)
+    }
+
+    fn read_file(&mut self, path: &str, buffer: *mut u8, size: u32, offset: u32) -> Option<u32> {
+        MinixFileSystem::read_file(path, buffer, size, offset).ok()
+    }
+}
+
+// A file opened for reading, tracking how far it has been read
+pub struct OpenFile {
+    path: String,
+    offset: u32,
+    size: u32,
+}
+
+impl OpenFile {
+    // Read up to size bytes at the current offset and advance past them
+    pub fn read(&mut self, buffer: *mut u8, size: u32) -> u32 {
+        let size = size.min(self.size - self.offset);
+        if size == 0 {
+            return 0;
+        }
+        let read = read_file(&self.path, buffer, size, self.offset);
+        self.offset += read;
+        read
+    }
+}
+
+fn resolve(path: &str) -> Option<(&'static mut Mount, &str)> {
+    unsafe {
+        let idx = MOUNTS
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.relative(path).is_some())
+            .max_by_key(|(_, m)| m.point.len())
+            .map(|(i, _)| i)?;
+        let mount = &mut MOUNTS[idx];
+        let rel = mount.relative(path)?;
+        Some((mount, rel))
+    }
+}
+
+// ====================================================
+// The public interface for the vfs is here...
+// ====================================================
+
+// Mount the boot filesystem at / and any host share at its mount point
+// root= in the bootargs can put the host share at / or leave / empty
+pub fn init() {
+    log::info!("init vfs");
+    let mut share = p9::filesystem();
+    match bootargs::args().root.unwrap_or(Root::Block) {
+        Root::Host => match share.take() {
+            Some(share) => mount("/", share),
+            None => {
+                log::warn!("root=host without a 9p share, mounting the block device");
+                mount("/", Box::new(MinixFileSystem));
+            }
+        },
+        Root::Block => mount("/", Box::new(MinixFileSystem)),
+        Root::Nothing => {}
+    }
+    if let Some(share) = share {
+        mount(P9_MOUNT_POINT, share);
+    }
+}
+
+pub fn mount(point: &str, fs: Box<dyn FileSystem>) {
+    unsafe {
+        MOUNTS.retain(|m| m.point != point);
+        MOUNTS.push(Mount {
+            point: String::from(point),
+            fs,
+        });
+    }
+}
+
+// Write back the cached changes of every mounted filesystem
+pub fn sync() {
+    unsafe {
+        for mount in MOUNTS.iter_mut() {
+            mount.fs.sync();
+        }
+    }
+}
+
+pub fn unmount(point: &str) {
+    unsafe { MOUNTS.retain(|m| m.point != point) };
+}
+
+// Size of the file at path in bytes
+pub fn file_size(path: &str) -> Option<u32> {
+    let (mount, rel) = resolve(path)?;
+    mount.fs.file_size(rel)
+}
+
+// Open the file at path for reading, None if it does not exist
+pub fn open(path: &str) -> Option<OpenFile> {
+    Some(OpenFile {
+        path: String::from(path),
+        offset: 0,
+        size: file_size(path)?,
+    })
+}
+
+// Read from the file at path, returns the number of bytes read
+pub fn read_file(path: &str, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+    if let Some(read) = resolve(path).and_then(|(m, rel)| m.fs.read_file(rel, buffer, size, offset))
+    {
+        read
+    } else {
+        log::warn!("Unable to find '{}' in vfs", path);
+        0
+    }
+}
+
+#[allow(dead_code)]
+pub fn debug_mounts() {
+    unsafe {
+        for m in MOUNTS.iter() {
+            println!("{} on {}", m.fs.name(), m.point);
+        }
+    }
+}
@@ -1,82 +1,831 @@
+use crate::alloc::{alloc_bytes_zeroed, alloc_dma, free_bytes, free_dma, DmaRegion};
 use crate::block;
-use crate::uart::serial_info;
+use crate::config::{BOARD, P9_MOUNT_POINT, PAGE_SIZE, VIRTIO_INIT_RETRIES};
+use crate::error::KError;
+use crate::fdt;
+use crate::gpu;
+use crate::input;
+use crate::log;
+use crate::p9;
+use crate::pci;
+use crate::plic;
+use crate::rng;
+use crate::vconsole;
+use crate::vfs;
+use crate::virtqueue::VirtQueue;
 use crate::{print, println};
+use core::ops::Deref;
+use rust_alloc::vec::Vec;
 
 // mod virtio.rs
-// A simple driver for interacting with legacy MMIO devices in QEMU
+// A simple driver for interacting with legacy and modern MMIO devices in QEMU
+// Modern virtio-pci functions found by pci::init are driven through the same
+// MmioDevice handle, their configuration space address stands in for the mmio
+// window, so the device drivers do not see the difference
 
-const VIRTIO_START: usize = 0x1000_1000; // address of first virtio device
-const VIRTIO_END: usize = 0x1000_8000; // address of last virtio device
-const VIRTIO_STRIDE: usize = 0x1000; // step by 4k per device
 const VIRTIO_MAGIC_LE: u32 = 0x74_72_69_76; // 'VIRT' in little endian ascii
 
+// Register offsets in units of u32
+const MMIO_VERSION: usize = 1; // 0x004 / 4
+const MMIO_DEVICE_ID: usize = 0x008 / 4;
+const MMIO_HOST_FEATURES: usize = 0x010 / 4;
+const MMIO_HOST_FEATURES_SELECT: usize = 0x014 / 4;
+const MMIO_GUEST_FEATURES: usize = 0x020 / 4;
+const MMIO_GUEST_FEATURES_SELECT: usize = 0x024 / 4;
+const MMIO_GUEST_PAGE_SIZE: usize = 0x028 / 4;
+const MMIO_QUEUE_SELECT: usize = 0x030 / 4;
+const MMIO_QUEUE_NUMBER_MAX: usize = 0x034 / 4;
+const MMIO_QUEUE_NUMBER: usize = 0x038 / 4;
+const MMIO_QUEUE_PFN: usize = 0x040 / 4;
+const MMIO_QUEUE_READY: usize = 0x044 / 4;
+const MMIO_QUEUE_NOTIFY: usize = 0x050 / 4;
+const MMIO_INTERRUPT_STATUS: usize = 0x060 / 4;
+const MMIO_INTERRUPT_ACK: usize = 0x064 / 4;
+const MMIO_STATUS: usize = 0x070 / 4;
+const MMIO_QUEUE_DESC_LOW: usize = 0x080 / 4;
+const MMIO_QUEUE_DESC_HIGH: usize = 0x084 / 4;
+const MMIO_QUEUE_AVAIL_LOW: usize = 0x090 / 4;
+const MMIO_QUEUE_AVAIL_HIGH: usize = 0x094 / 4;
+const MMIO_QUEUE_USED_LOW: usize = 0x0a0 / 4;
+const MMIO_QUEUE_USED_HIGH: usize = 0x0a4 / 4;
+const MMIO_CONFIG: usize = 0x100 / 4;
+
+// The vendor capabilities of a virtio-pci function point into its BARs
+const PCI_VENDOR_VIRTIO: u16 = 0x1af4;
+// Modern devices are 0x1040 + type, transitional ones 0x1000..=0x103f with the
+// type in the subsystem id
+const PCI_DEVICE_TRANSITIONAL: u16 = 0x1000;
+const PCI_DEVICE_MODERN: u16 = 0x1040;
+const PCI_CAP_VENDOR: u8 = 0x09;
+const PCI_CAP_TYPE: usize = 3;
+const PCI_CAP_BAR: usize = 4;
+const PCI_CAP_OFFSET: usize = 8;
+const PCI_CAP_NOTIFY_MULTIPLIER: usize = 16;
+const PCI_CAP_COMMON: u8 = 1;
+const PCI_CAP_NOTIFY: u8 = 2;
+const PCI_CAP_ISR: u8 = 3;
+const PCI_CAP_DEVICE: u8 = 4;
+
+// Offsets in bytes into the common configuration of a virtio-pci function
+const PCI_DEVICE_FEATURE_SELECT: usize = 0x00;
+const PCI_DEVICE_FEATURE: usize = 0x04;
+const PCI_DRIVER_FEATURE_SELECT: usize = 0x08;
+const PCI_DRIVER_FEATURE: usize = 0x0c;
+const PCI_DEVICE_STATUS: usize = 0x14;
+const PCI_QUEUE_SELECT: usize = 0x16;
+const PCI_QUEUE_SIZE: usize = 0x18;
+const PCI_QUEUE_ENABLE: usize = 0x1c;
+const PCI_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const PCI_QUEUE_DESC: usize = 0x20;
+const PCI_QUEUE_DRIVER: usize = 0x28;
+const PCI_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_FIELD_ACKNOWLEDGE: u32 = 1;
+const STATUS_FIELD_DRIVER: u32 = 2;
+const STATUS_FIELD_DRIVER_OK: u32 = 4;
+const STATUS_FIELD_FEATURES_OK: u32 = 8;
+const STATUS_FIELD_FAILED: u32 = 128;
+
+// Bit 32 of the feature set, i.e. bit 0 of the second feature word
+pub const VIRTIO_FEATURE_VERSION_1: u64 = 1 << 32;
+
+pub const MMIO_VERSION_LEGACY: u32 = 1;
+pub const MMIO_VERSION_MODERN: u32 = 2;
+
 // const NETWORK: u32 = 1;
 const BLOCK: u32 = 2;
-// const RANDOM: u32 = 4;
+const CONSOLE: u32 = 3;
+const RANDOM: u32 = 4;
+const P9: u32 = 9;
 const GPU: u32 = 16;
 const INPUT: u32 = 18;
 
-static mut VIRTIO_DEVICE_TYPES: [Option<u32>; 8] = [None, None, None, None, None, None, None, None];
+static mut VIRTIO_SLOTS: Vec<Slot> = Vec::new();
+
+// A virtio mmio window, the interrupt it raises and the device type found there
+// For a virtio-pci function addr is its configuration space, see pci.rs
+#[derive(Clone, Copy)]
+struct Slot {
+    addr: usize,
+    irq: u32,
+    device_type: Option<u32>,
+    pci: bool,
+}
 
 fn set_virtio_device_type(addr: usize, value: u32) {
-    let idx = (addr - VIRTIO_START) >> 12;
+    set_slot_type(addr, Some(value));
+}
+
+fn set_slot_type(addr: usize, value: Option<u32>) {
     unsafe {
-        VIRTIO_DEVICE_TYPES[idx] = Some(value);
+        if let Some(slot) = VIRTIO_SLOTS.iter_mut().find(|s| s.addr == addr) {
+            slot.device_type = value;
+        }
     }
 }
 
-pub fn init() {
-    serial_info("init virtio");
-    for addr in (VIRTIO_START..=VIRTIO_END).step_by(VIRTIO_STRIDE) {
-        print!("    - Virtio device @ 0x{:08x}...", addr);
-        let magicvalue;
-        let deviceid;
-        let ptr = addr as *mut u32;
-        unsafe {
-            magicvalue = ptr.read_volatile();
-            deviceid = ptr.add(2).read_volatile();
-        }
-        if VIRTIO_MAGIC_LE != magicvalue {
-            println!("...not virtio.");
-        } else if 0 == deviceid {
-            println!("...not connected.");
+fn slots() -> Vec<Slot> {
+    unsafe { VIRTIO_SLOTS.clone() }
+}
+
+// Feature bits a driver can make use of and the subset it cannot work without
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Features {
+    pub supported: u64,
+    pub required: u64,
+}
+
+impl Features {
+    pub const NONE: Features = Features::new(0, 0);
+
+    pub const fn new(supported: u64, required: u64) -> Self {
+        Self {
+            supported: supported | required,
+            required,
+        }
+    }
+
+    // The features to accept from those offered by the device
+    // Modern devices must agree to VERSION_1, legacy devices never see it
+    // Fails with the required bits the device does not offer
+    pub fn select(&self, offered: u64, version: u32) -> Result<u64, u64> {
+        let (supported, required) = if version == MMIO_VERSION_MODERN {
+            (
+                self.supported | VIRTIO_FEATURE_VERSION_1,
+                self.required | VIRTIO_FEATURE_VERSION_1,
+            )
         } else {
-            match deviceid {
-                BLOCK => {
-                    if !block::init(ptr) {
-                        println!("failed to init block device...");
-                        continue;
-                    }
-                    set_virtio_device_type(addr, BLOCK);
+            (
+                self.supported & !VIRTIO_FEATURE_VERSION_1,
+                self.required & !VIRTIO_FEATURE_VERSION_1,
+            )
+        };
+        let missing = required & !offered;
+        if missing != 0 {
+            return Err(missing);
+        }
+        Ok(offered & supported)
+    }
+}
+
+// Where the capabilities of a virtio-pci function put its registers, the
+// addresses lie in its BARs
+#[derive(Clone, Copy)]
+struct PciTransport {
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    isr: usize,
+    device: usize,
+}
+
+impl PciTransport {
+    // Follow the vendor capabilities of the function configured at config
+    fn find(config: usize) -> Option<Self> {
+        let bars = pci::function(config)?.bars;
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for cap in pci::capabilities(config) {
+            if pci::read8(config, cap) != PCI_CAP_VENDOR {
+                continue;
+            }
+            let bar = pci::read8(config, cap + PCI_CAP_BAR) as usize;
+            let Some(&Some((base, _))) = bars.get(bar) else {
+                continue;
+            };
+            let address = base + pci::read32(config, cap + PCI_CAP_OFFSET) as usize;
+            match pci::read8(config, cap + PCI_CAP_TYPE) {
+                PCI_CAP_COMMON => common = common.or(Some(address)),
+                PCI_CAP_NOTIFY => {
+                    notify = notify.or(Some(address));
+                    notify_multiplier = pci::read32(config, cap + PCI_CAP_NOTIFY_MULTIPLIER);
                 }
-                GPU => {
-                    println!("GPU device...");
-                    set_virtio_device_type(addr, GPU);
+                PCI_CAP_ISR => isr = isr.or(Some(address)),
+                PCI_CAP_DEVICE => device = device.or(Some(address)),
+                _ => {}
+            }
+        }
+        Some(Self {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            isr: isr?,
+            device: device.unwrap_or(0),
+        })
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        unsafe { ((self.common + offset) as *const u8).read_volatile() }
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        unsafe { ((self.common + offset) as *mut u8).write_volatile(value) }
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { ((self.common + offset) as *const u16).read_volatile() }
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        unsafe { ((self.common + offset) as *mut u16).write_volatile(value) }
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.common + offset) as *const u32).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { ((self.common + offset) as *mut u32).write_volatile(value) }
+    }
+
+    // 64 bit registers are written as two halves, low first
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+// Handle on the transport registers of a single mmio device
+// Drivers use this to run the common initialization sequence
+// A virtio-pci function is driven through the same handle, ptr is then its
+// configuration space and the registers are the ones its capabilities point to
+#[derive(Clone, Copy)]
+pub struct MmioDevice {
+    ptr: *mut u32,
+    version: u32,
+    pci: Option<PciTransport>,
+}
+
+impl MmioDevice {
+    // Wrap the device at ptr if it speaks a supported transport version
+    // virtio-pci functions are always modern
+    pub fn new(ptr: *mut u32) -> Result<Self, KError> {
+        if pci::is_config(ptr as usize) {
+            let Some(pci) = PciTransport::find(ptr as usize) else {
+                print!("virtio-pci capabilities missing...");
+                return Err(KError::NotFound);
+            };
+            return Ok(Self {
+                ptr,
+                version: MMIO_VERSION_MODERN,
+                pci: Some(pci),
+            });
+        }
+        let version = unsafe { ptr.add(MMIO_VERSION).read_volatile() };
+        if version != MMIO_VERSION_LEGACY && version != MMIO_VERSION_MODERN {
+            print!("unknown mmio version {}...", version);
+            return Err(KError::Unsupported);
+        }
+        Ok(Self {
+            ptr,
+            version,
+            pci: None,
+        })
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { self.ptr.add(reg).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { self.ptr.add(reg).write_volatile(value) }
+    }
+
+    fn status(&self) -> u32 {
+        match self.pci {
+            Some(pci) => pci.read8(PCI_DEVICE_STATUS) as u32,
+            None => self.read(MMIO_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u32) {
+        match self.pci {
+            Some(pci) => pci.write8(PCI_DEVICE_STATUS, status as u8),
+            None => self.write(MMIO_STATUS, status),
+        }
+    }
+
+    fn add_status(&self, bits: u32) {
+        self.set_status(self.status() | bits);
+    }
+
+    // Return the device to its initial state, dropping all queues
+    pub fn reset(&self) {
+        self.set_status(0);
+    }
+
+    // Reset the device and announce that a driver has found it
+    pub fn begin_init(&self) {
+        self.reset();
+        self.add_status(STATUS_FIELD_ACKNOWLEDGE);
+        self.add_status(STATUS_FIELD_DRIVER);
+    }
+
+    // The full 64 bit feature set offered by the device
+    fn host_features(&self) -> u64 {
+        if let Some(pci) = self.pci {
+            pci.write32(PCI_DEVICE_FEATURE_SELECT, 0);
+            let low = pci.read32(PCI_DEVICE_FEATURE) as u64;
+            pci.write32(PCI_DEVICE_FEATURE_SELECT, 1);
+            let high = pci.read32(PCI_DEVICE_FEATURE) as u64;
+            return (high << 32) | low;
+        }
+        self.write(MMIO_HOST_FEATURES_SELECT, 0);
+        let low = self.read(MMIO_HOST_FEATURES) as u64;
+        self.write(MMIO_HOST_FEATURES_SELECT, 1);
+        let high = self.read(MMIO_HOST_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    fn set_guest_features(&self, features: u64) {
+        if let Some(pci) = self.pci {
+            pci.write32(PCI_DRIVER_FEATURE_SELECT, 0);
+            pci.write32(PCI_DRIVER_FEATURE, features as u32);
+            pci.write32(PCI_DRIVER_FEATURE_SELECT, 1);
+            pci.write32(PCI_DRIVER_FEATURE, (features >> 32) as u32);
+            return;
+        }
+        self.write(MMIO_GUEST_FEATURES_SELECT, 0);
+        self.write(MMIO_GUEST_FEATURES, features as u32);
+        self.write(MMIO_GUEST_FEATURES_SELECT, 1);
+        self.write(MMIO_GUEST_FEATURES, (features >> 32) as u32);
+    }
+
+    // Agree on the intersection of the driver's and the device's features
+    // Initialization fails if the device lacks a feature the driver requires
+    // Returns the accepted features if the device agreed to them
+    pub fn negotiate(&self, features: Features) -> Result<u64, KError> {
+        let guest_features = match features.select(self.host_features(), self.version) {
+            Ok(f) => f,
+            Err(missing) => {
+                print!("missing features 0x{:x}...", missing);
+                self.fail();
+                return Err(KError::Unsupported);
+            }
+        };
+        self.set_guest_features(guest_features);
+
+        self.add_status(STATUS_FIELD_FEATURES_OK);
+        if self.status() & STATUS_FIELD_FEATURES_OK == 0 {
+            print!("features fail...");
+            self.fail();
+            return Err(KError::Unsupported);
+        }
+        Ok(guest_features)
+    }
+
+    // Hand a virtqueue to the device as queue number `index`
+    // The device is failed if it cannot take a queue that large
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) -> Result<(), KError> {
+        if let Some(pci) = self.pci {
+            pci.write16(PCI_QUEUE_SELECT, index as u16);
+            if queue.size() > pci.read16(PCI_QUEUE_SIZE) as u32 {
+                print!("queue size fail...");
+                self.fail();
+                return Err(KError::Unsupported);
+            }
+            pci.write16(PCI_QUEUE_SIZE, queue.size() as u16);
+            pci.write64(PCI_QUEUE_DESC, queue.desc_address());
+            pci.write64(PCI_QUEUE_DRIVER, queue.avail_address());
+            pci.write64(PCI_QUEUE_DEVICE, queue.used_address());
+            pci.write16(PCI_QUEUE_ENABLE, 1);
+            return Ok(());
+        }
+        self.write(MMIO_QUEUE_SELECT, index);
+        if queue.size() > self.read(MMIO_QUEUE_NUMBER_MAX) {
+            print!("queue size fail...");
+            self.fail();
+            return Err(KError::Unsupported);
+        }
+        self.write(MMIO_QUEUE_NUMBER, queue.size());
+        if self.version == MMIO_VERSION_LEGACY {
+            self.write(MMIO_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            self.write(MMIO_QUEUE_PFN, queue.pfn());
+        } else {
+            let desc = queue.desc_address();
+            let avail = queue.avail_address();
+            let used = queue.used_address();
+            self.write(MMIO_QUEUE_DESC_LOW, desc as u32);
+            self.write(MMIO_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(MMIO_QUEUE_AVAIL_LOW, avail as u32);
+            self.write(MMIO_QUEUE_AVAIL_HIGH, (avail >> 32) as u32);
+            self.write(MMIO_QUEUE_USED_LOW, used as u32);
+            self.write(MMIO_QUEUE_USED_HIGH, (used >> 32) as u32);
+            self.write(MMIO_QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    // Tell the device the driver is ready to use it
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_FIELD_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.add_status(STATUS_FIELD_FAILED);
+    }
+
+    // Base address of the transport registers
+    pub fn ptr(&self) -> *mut u32 {
+        self.ptr
+    }
+
+    // Each virtio-pci queue has its own notification address
+    pub fn notify(&self, index: u32) {
+        let Some(pci) = self.pci else {
+            self.write(MMIO_QUEUE_NOTIFY, index);
+            return;
+        };
+        pci.write16(PCI_QUEUE_SELECT, index as u16);
+        let offset = pci.read16(PCI_QUEUE_NOTIFY_OFF) as usize * pci.notify_multiplier as usize;
+        unsafe { ((pci.notify + offset) as *mut u16).write_volatile(index as u16) };
+    }
+
+    // Acknowledge all pending interrupt causes of the device
+    // Reading the ISR of a virtio-pci function clears it and lowers INTx
+    pub fn ack_interrupt(&self) -> u32 {
+        if let Some(pci) = self.pci {
+            return unsafe { (pci.isr as *const u8).read_volatile() } as u32;
+        }
+        let status = self.read(MMIO_INTERRUPT_STATUS);
+        self.write(MMIO_INTERRUPT_ACK, status);
+        status
+    }
+
+    // Read the device specific configuration space, offset is in bytes
+    pub fn config_read(&self, offset: usize) -> u32 {
+        if let Some(pci) = self.pci {
+            return unsafe { ((pci.device + offset / 4 * 4) as *const u32).read_volatile() };
+        }
+        self.read(MMIO_CONFIG + offset / 4)
+    }
+}
+
+// Memory a driver handed its device
+enum Grant {
+    Bytes(*mut u8),
+    Dma(DmaRegion),
+}
+
+// A device together with the memory its driver hands it
+// Dropping it resets the device before freeing that memory, so the device is
+// never left writing into memory that went to someone else. Drivers keep it
+// as their first field, their queues are then freed after the reset as well,
+// and a driver dropped after a failed init is covered the same way
+pub struct DmaGuard {
+    dev: MmioDevice,
+    grants: Vec<Grant>,
+}
+
+impl DmaGuard {
+    pub fn new(dev: MmioDevice) -> Self {
+        Self {
+            dev,
+            grants: Vec::new(),
+        }
+    }
+
+    // Zeroed bytes the device reaches through alloc::dma_address
+    pub fn alloc_bytes(&mut self, size: usize) -> Result<*mut u8, KError> {
+        let ptr = alloc_bytes_zeroed(size);
+        if ptr.is_null() {
+            return Err(KError::NoMemory);
+        }
+        self.grants.push(Grant::Bytes(ptr));
+        Ok(ptr)
+    }
+
+    // Physically contiguous memory, see alloc::alloc_dma
+    pub fn alloc_dma(&mut self, size: usize, align: usize) -> Result<DmaRegion, KError> {
+        let region = alloc_dma(size, align)?;
+        self.grants.push(Grant::Dma(region));
+        Ok(region)
+    }
+}
+
+impl Deref for DmaGuard {
+    type Target = MmioDevice;
+
+    fn deref(&self) -> &MmioDevice {
+        &self.dev
+    }
+}
+
+impl Drop for DmaGuard {
+    fn drop(&mut self) {
+        self.dev.reset();
+        for grant in self.grants.drain(..) {
+            match grant {
+                Grant::Bytes(ptr) => free_bytes(ptr),
+                Grant::Dma(region) => free_dma(region),
+            }
+        }
+    }
+}
+
+// Name of a supported virtio device type
+pub fn device_name(device_type: u32) -> &'static str {
+    match device_type {
+        BLOCK => "block",
+        CONSOLE => "console",
+        GPU => "gpu",
+        INPUT => "input",
+        P9 => "9p",
+        RANDOM => "rng",
+        _ => "unknown",
+    }
+}
+
+// List of (mmio address, device type) for every initialized virtio device
+pub fn devices() -> impl Iterator<Item = (usize, u32)> {
+    slots()
+        .into_iter()
+        .filter_map(|s| s.device_type.map(|t| (s.addr, t)))
+}
+
+// Find the virtio-mmio windows and their interrupts in the device tree, or
+// take the board's slots without one, add the virtio-pci functions pci::init
+// found and register the interrupts with the PLIC
+pub fn discover() {
+    let mut slots: Vec<Slot> = fdt::compatible("virtio,mmio")
+        .iter()
+        .filter_map(|node| {
+            Some(Slot {
+                addr: node.reg()?.0 as usize,
+                irq: node.interrupt()?,
+                device_type: None,
+                pci: false,
+            })
+        })
+        .collect();
+    if slots.is_empty() {
+        let board = &BOARD.virtio;
+        slots = (0..board.count)
+            .map(|idx| Slot {
+                addr: board.base + idx * board.stride,
+                irq: board.first_irq + idx as u32,
+                device_type: None,
+                pci: false,
+            })
+            .collect();
+    }
+    slots.extend(
+        pci::functions()
+            .iter()
+            .filter(|f| f.vendor == PCI_VENDOR_VIRTIO)
+            .filter_map(|f| {
+                Some(Slot {
+                    addr: f.config,
+                    irq: f.irq?,
+                    device_type: None,
+                    pci: true,
+                })
+            }),
+    );
+    slots.sort_by_key(|s| s.addr);
+    // virtio-pci functions share the INTx lines, the first registers them
+    for slot in slots.iter() {
+        if !plic::is_registered(slot.irq) {
+            plic::register(slot.irq, interrupt_handler);
+        }
+    }
+    unsafe { VIRTIO_SLOTS = slots };
+}
+
+// Base addresses of every virtio mmio window, connected or not
+// virtio-pci functions are in pci::mmio_windows()
+pub fn mmio_windows() -> impl Iterator<Item = usize> {
+    slots().into_iter().filter(|s| !s.pci).map(|s| s.addr)
+}
+
+// Interrupt numbers raised by virtio devices
+pub fn irqs() -> impl Iterator<Item = u32> {
+    slots().into_iter().map(|s| s.irq)
+}
+
+// True if the interrupt belongs to a virtio slot
+pub fn handles(irq: u32) -> bool {
+    unsafe { VIRTIO_SLOTS.iter().any(|s| s.irq == irq) }
+}
+
+// Run a driver init, resetting the device and retrying a bounded number of times
+// Returns the error of the last attempt if none succeeded
+pub fn init_with_retry(
+    ptr: *mut u32,
+    driver_init: fn(*mut u32) -> Result<(), KError>,
+) -> Result<(), KError> {
+    let mut result = Ok(());
+    for attempt in 0..=VIRTIO_INIT_RETRIES {
+        if attempt > 0 {
+            print!("retry {}...", attempt);
+        }
+        // A failed init frees what it allocated, the reset makes sure the
+        // device has let go of it before the next attempt
+        result = driver_init(ptr);
+        if result.is_ok() {
+            break;
+        }
+        if let Ok(dev) = MmioDevice::new(ptr) {
+            dev.reset();
+        }
+    }
+    result
+}
+
+// The device type a virtio-pci function reports, None if it is no virtio
+// device or one this driver does not speak
+fn pci_device_type(config: usize) -> Option<u32> {
+    let function = pci::function(config)?;
+    if function.vendor != PCI_VENDOR_VIRTIO {
+        return None;
+    }
+    match function.device_id {
+        id if id >= PCI_DEVICE_MODERN => Some((id - PCI_DEVICE_MODERN) as u32),
+        id if id >= PCI_DEVICE_TRANSITIONAL => Some(pci::read16(config, pci::SUBSYSTEM_ID) as u32),
+        _ => None,
+    }
+}
+
+// The magic value and device id of the slot at addr, a virtio-pci function
+// reports the magic of an mmio window when it is a virtio device
+fn identify(addr: usize) -> (u32, u32) {
+    if pci::is_config(addr) {
+        return match pci_device_type(addr) {
+            Some(deviceid) => (VIRTIO_MAGIC_LE, deviceid),
+            None => (0, 0),
+        };
+    }
+    let ptr = addr as *mut u32;
+    unsafe { (ptr.read_volatile(), ptr.add(MMIO_DEVICE_ID).read_volatile()) }
+}
+
+// Probe the slot at addr and hand any device found to its driver
+fn probe(addr: usize) -> bool {
+    print!("    - Virtio device @ 0x{:08x}...", addr);
+    let ptr = addr as *mut u32;
+    let (magicvalue, deviceid) = identify(addr);
+    if VIRTIO_MAGIC_LE != magicvalue {
+        println!("...not virtio.");
+        false
+    } else if 0 == deviceid {
+        println!("...not connected.");
+        false
+    } else {
+        match deviceid {
+            BLOCK => {
+                if let Err(e) = init_with_retry(ptr, block::init) {
+                    log::error!("failed to init block device: {}", e);
+                    return false;
+                }
+                set_virtio_device_type(addr, BLOCK);
+            }
+            CONSOLE => {
+                if let Err(e) = init_with_retry(ptr, vconsole::init) {
+                    log::error!("failed to init console device: {}", e);
+                    return false;
+                }
+                set_virtio_device_type(addr, CONSOLE);
+            }
+            GPU => {
+                if let Err(e) = init_with_retry(ptr, gpu::init) {
+                    log::error!("failed to init gpu device: {}", e);
+                    return false;
                 }
-                INPUT => {
-                    println!("input device...");
-                    set_virtio_device_type(addr, INPUT);
+                set_virtio_device_type(addr, GPU);
+            }
+            INPUT => {
+                if let Err(e) = init_with_retry(ptr, input::init) {
+                    log::error!("failed to init input device: {}", e);
+                    return false;
+                }
+                set_virtio_device_type(addr, INPUT);
+            }
+            P9 => {
+                if let Err(e) = init_with_retry(ptr, p9::init) {
+                    log::error!("failed to init 9p device: {}", e);
+                    return false;
                 }
-                _ => println!("...ignored device type {}.", deviceid),
+                set_virtio_device_type(addr, P9);
+            }
+            RANDOM => {
+                if let Err(e) = init_with_retry(ptr, rng::init) {
+                    log::error!("failed to init entropy device: {}", e);
+                    return false;
+                }
+                set_virtio_device_type(addr, RANDOM);
+            }
+            _ => {
+                println!("...ignored device type {}.", deviceid);
+                return false;
             }
         }
+        true
     }
 }
 
-pub fn interrupt_handler(interrupt: u32) {
-    let idx = interrupt as usize - 1;
-    unsafe {
-        if let Some(vd) = &VIRTIO_DEVICE_TYPES[idx] {
-            match *vd {
-                BLOCK => {
-                    block::interrupt_handler();
-                }
-                _ => {
-                    println!("Invalid device generated interrupt: {}!", vd);
+// Type of the device currently present in the slot at addr
+fn present_device(addr: usize) -> Option<u32> {
+    match identify(addr) {
+        (VIRTIO_MAGIC_LE, 0) => None,
+        (VIRTIO_MAGIC_LE, deviceid) => Some(deviceid),
+        _ => None,
+    }
+}
+
+// Drop the driver state of a device that has gone away
+fn remove(addr: usize, device_type: u32) {
+    let ptr = addr as *mut u32;
+    match device_type {
+        BLOCK => block::remove(),
+        CONSOLE => vconsole::remove(),
+        GPU => gpu::remove(),
+        INPUT => input::remove(ptr),
+        P9 => p9::remove(),
+        RANDOM => rng::remove(),
+        _ => {}
+    }
+}
+
+pub fn init() {
+    log::info!("init virtio");
+    for slot in slots() {
+        probe(slot.addr);
+    }
+}
+
+// Probe the mmio window again after devices were added or removed at runtime
+// Vanished devices are torn down first so a replaced slot is initialized fresh
+// Returns the number of devices added and removed
+#[allow(dead_code)]
+pub fn rescan() -> (usize, usize) {
+    log::info!("rescan virtio");
+    let (mut added, mut removed) = (0, 0);
+    for slot in slots() {
+        let addr = slot.addr;
+        let known = slot.device_type;
+        let present = present_device(addr);
+        if let Some(device_type) = known {
+            if present == known {
+                continue;
+            }
+            println!(
+                "    - Virtio {} device @ 0x{:08x} removed.",
+                device_name(device_type),
+                addr
+            );
+            set_slot_type(addr, None);
+            remove(addr, device_type);
+            removed += 1;
+        }
+        if present.is_some() && probe(addr) {
+            if present == Some(P9) {
+                if let Some(share) = p9::filesystem() {
+                    vfs::mount(P9_MOUNT_POINT, share);
                 }
             }
-        } else {
-            println!("Spurious interrupt {}", interrupt);
+            added += 1;
         }
     }
+    (added, removed)
+}
+
+// virtio-pci functions share their interrupt, every device on it is
+// serviced, those without pending work find their queues empty
+pub fn interrupt_handler(interrupt: u32) {
+    let device_types = unsafe {
+        VIRTIO_SLOTS
+            .iter()
+            .filter(|s| s.irq == interrupt)
+            .filter_map(|s| s.device_type)
+    };
+    let mut serviced = false;
+    for vd in device_types {
+        serviced = true;
+        match vd {
+            BLOCK => {
+                block::interrupt_handler();
+            }
+            CONSOLE => {
+                vconsole::interrupt_handler();
+            }
+            GPU => {
+                gpu::interrupt_handler();
+            }
+            INPUT => {
+                input::interrupt_handler();
+            }
+            P9 => {
+                p9::interrupt_handler();
+            }
+            RANDOM => {
+                rng::interrupt_handler();
+            }
+            _ => {
+                log::warn!("Invalid device generated interrupt: {}!", vd);
+            }
+        }
+    }
+    if !serviced {
+        log::warn!("Spurious interrupt {}", interrupt);
+    }
 }
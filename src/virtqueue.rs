@@ -0,0 +1,260 @@
+use crate::alloc::{alloc_dma, free_dma, DmaRegion};
+use crate::atomics;
+use crate::config::PAGE_SIZE;
+use crate::error::KError;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{fence, Ordering};
+
+// mod virtqueue.rs
+// The split virtqueue shared by all virtio drivers
+// Owns the descriptor table and rings, builds descriptor chains,
+// and tracks which submitted chains the device has completed
+
+pub const VIRTIO_RING_SIZE: usize = 1 << 7;
+
+const VIRTIO_DESC_FLAG_NEXT: u16 = 1;
+const VIRTIO_DESC_FLAG_WRITE: u16 = 2;
+const VIRTIO_USED_FLAG_NO_NOTIFY: u16 = 1;
+
+// Lets both sides publish the ring index at which they next want to be told
+pub const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+
+#[repr(C)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+pub struct Available {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [u16; VIRTIO_RING_SIZE],
+    pub event: u16,
+}
+
+#[repr(C)]
+pub struct UsedElem {
+    pub id: u32,
+    pub len: u32,
+}
+
+#[repr(C)]
+pub struct Used {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [UsedElem; VIRTIO_RING_SIZE],
+    pub event: u16,
+}
+
+#[repr(C)]
+pub struct Queue {
+    pub desc: [Descriptor; VIRTIO_RING_SIZE],
+    pub avail: Available,
+    pub padding0:
+        [u8; PAGE_SIZE - size_of::<Descriptor>() * VIRTIO_RING_SIZE - size_of::<Available>()],
+    pub used: Used,
+}
+
+// One buffer of a descriptor chain
+pub struct Segment {
+    pub addr: u64,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+impl Segment {
+    // A buffer the device reads from
+    pub fn readable(addr: u64, len: u32) -> Self {
+        Self {
+            addr,
+            len,
+            device_writes: false,
+        }
+    }
+
+    // A buffer the device writes into
+    pub fn writable(addr: u64, len: u32) -> Self {
+        Self {
+            addr,
+            len,
+            device_writes: true,
+        }
+    }
+}
+
+pub struct VirtQueue {
+    queue: *mut Queue,
+    dma: DmaRegion,
+    idx: u16,
+    ack_used_idx: u16,
+    // Indexed by the head descriptor of a submitted chain, 1 once the device
+    // has used it. Read without holding the driver's lock, see src/atomics.rs
+    complete: [usize; VIRTIO_RING_SIZE],
+    event_idx: bool,
+    // Available index the device was last notified about
+    notified_idx: u16,
+}
+
+// True if moving an index from old to new passed the event index
+// Written to be correct across the u16 wrap around
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+impl VirtQueue {
+    // Allocate zeroed, page aligned memory for a queue
+    pub fn new() -> Result<Self, KError> {
+        let dma = alloc_dma(size_of::<Queue>(), PAGE_SIZE)?;
+        Ok(Self {
+            queue: dma.virt as *mut Queue,
+            dma,
+            idx: 0,
+            ack_used_idx: 0,
+            complete: [1; VIRTIO_RING_SIZE],
+            event_idx: false,
+            notified_idx: 0,
+        })
+    }
+
+    pub fn size(&self) -> u32 {
+        VIRTIO_RING_SIZE as u32
+    }
+
+    // Page frame number for the legacy transport
+    pub fn pfn(&self) -> u32 {
+        (self.dma.phys / PAGE_SIZE as u64) as u32
+    }
+
+    pub fn desc_address(&self) -> u64 {
+        self.dma.phys_at(offset_of!(Queue, desc))
+    }
+
+    pub fn avail_address(&self) -> u64 {
+        self.dma.phys_at(offset_of!(Queue, avail))
+    }
+
+    pub fn used_address(&self) -> u64 {
+        self.dma.phys_at(offset_of!(Queue, used))
+    }
+
+    // Use the event index fields instead of the ring flags to suppress
+    // notifications, call once VIRTIO_RING_F_EVENT_IDX has been negotiated
+    pub fn enable_event_idx(&mut self) {
+        self.event_idx = true;
+    }
+
+    // Whether the device wants to hear about the chains submitted since the last
+    // notification, the caller notifies the device if this returns true
+    pub fn kick_needed(&mut self) -> bool {
+        fence(Ordering::SeqCst);
+        unsafe {
+            let new = (*self.queue).avail.idx;
+            let old = self.notified_idx;
+            self.notified_idx = new;
+            if self.event_idx {
+                let avail_event = (&(*self.queue).used.event as *const u16).read_volatile();
+                need_event(avail_event, new, old)
+            } else {
+                let flags = (&(*self.queue).used.flags as *const u16).read_volatile();
+                flags & VIRTIO_USED_FLAG_NO_NOTIFY == 0
+            }
+        }
+    }
+
+    // Write the segments into consecutive descriptors and return the head index
+    pub fn add_chain(&mut self, segments: &[Segment]) -> u16 {
+        assert!(!segments.is_empty() && segments.len() <= VIRTIO_RING_SIZE);
+        let mut head = None;
+        for (i, segment) in segments.iter().enumerate() {
+            self.idx = (self.idx + 1) % VIRTIO_RING_SIZE as u16;
+            let mut flags = if segment.device_writes {
+                VIRTIO_DESC_FLAG_WRITE
+            } else {
+                0
+            };
+            let mut next = 0;
+            if i + 1 < segments.len() {
+                flags |= VIRTIO_DESC_FLAG_NEXT;
+                next = (self.idx + 1) % VIRTIO_RING_SIZE as u16;
+            }
+            unsafe {
+                (*self.queue).desc[self.idx as usize] = Descriptor {
+                    addr: segment.addr,
+                    len: segment.len,
+                    flags,
+                    next,
+                };
+            }
+            head.get_or_insert(self.idx);
+        }
+        head.unwrap()
+    }
+
+    // Make a chain available to the device
+    // The caller is responsible for notifying the device afterwards
+    pub fn submit(&mut self, head: u16) {
+        unsafe {
+            atomics::store_release(self.complete.as_mut_ptr().add(head as usize), 0);
+            let avail = &mut (*self.queue).avail;
+            avail.ring[avail.idx as usize % VIRTIO_RING_SIZE] = head;
+            fence(Ordering::SeqCst);
+            avail.idx = avail.idx.wrapping_add(1);
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    // Consume the next used element, returning the head index and written length
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            let used = &(*self.queue).used;
+            let used_idx = (&used.idx as *const u16).read_volatile();
+            if self.ack_used_idx == used_idx {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+            let elem = &used.ring[self.ack_used_idx as usize % VIRTIO_RING_SIZE];
+            let head = elem.id as u16;
+            self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
+            atomics::store_release(self.complete.as_mut_ptr().add(head as usize), 1);
+            Some((head, elem.len))
+        }
+    }
+
+    // Ask for an interrupt once the device uses the next chain, for a driver
+    // about to sleep until one completes. With VIRTIO_RING_F_EVENT_IDX the
+    // device raises none until this is called again, so a driver that is
+    // not waiting is not interrupted. Chains used before the call raise no
+    // interrupt, drain them with pop_used afterwards
+    pub fn enable_interrupt(&mut self) {
+        if self.event_idx {
+            unsafe {
+                (&mut (*self.queue).avail.event as *mut u16).write_volatile(self.ack_used_idx);
+            }
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    // Head indices of chains submitted but not yet used by the device
+    pub fn pending(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..VIRTIO_RING_SIZE as u16).filter(|&head| !self.is_complete(head))
+    }
+
+    pub fn is_complete(&self, head: u16) -> bool {
+        atomics::load_acquire(unsafe { self.complete.as_ptr().add(head as usize) }) != 0
+    }
+
+    // Address stored in a descriptor, used to recover per request state
+    pub fn descriptor_address(&self, idx: u16) -> u64 {
+        unsafe { (*self.queue).desc[idx as usize].addr }
+    }
+}
+
+// Only drop a queue once the device has been reset and no longer uses it
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        free_dma(self.dma);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::assembly;
+use crate::process::{self, Pid};
+use crate::timer;
+use rust_alloc::collections::VecDeque;
+
+// mod waitqueue.rs
+// Queues of tasks sleeping until an event, typically an interrupt, happens
+// A waiter checks its condition and blocks with interrupts disabled, so a wake
+// from an interrupt handler cannot slip in between. Wakes may be spurious,
+// waiters always check their condition again
+// Without interrupts nothing could wake a waiter, so it polls instead
+
+pub struct WaitQueue {
+    waiters: VecDeque<Pid>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    // Sleep until done returns true or mtime reaches deadline
+    // Returns false if the deadline passed first
+    pub fn wait_until(&mut self, deadline: Option<u64>, done: impl Fn() -> bool) -> bool {
+        let expired = || deadline.is_some_and(|d| timer::now() >= d);
+        if !assembly::interrupts_enabled() {
+            while !done() {
+                if expired() {
+                    return false;
+                }
+                assembly::no_operation();
+            }
+            return true;
+        }
+        let me = process::current();
+        assembly::disable_interrupts();
+        let completed = loop {
+            if done() {
+                break true;
+            }
+            if expired() {
+                break false;
+            }
+            self.waiters.push_back(me);
+            process::block(deadline);
+        };
+        self.waiters.retain(|&pid| pid != me);
+        assembly::enable_interrupts();
+        completed
+    }
+
+    // Wake every waiting task, safe from interrupt handlers
+    pub fn wake_all(&mut self) {
+        while let Some(pid) = self.waiters.pop_front() {
+            process::wake(pid);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::config::WATCHDOG_SLOTS;
+use crate::log;
+use crate::sync::SpinLock;
+use crate::time;
+use crate::timer;
+use crate::trap::TrapFrame;
+use crate::{print, println};
+use core::time::Duration;
+
+// mod watchdog.rs
+// A software watchdog checked on every timer interrupt
+// Code running a long operation starts a Watch with a short description of
+// where it is and how long it may go without progress, pets it as it goes and
+// drops it when done. Every owner has a watch of its own, so tasks sleeping in
+// different operations do not disarm each other. If a watch is not petted in
+// time the kernel is considered hung: the location and the interrupted trap
+// frame are printed before panicking
+// A watch is a backstop, it should be longer than any timeout the watched
+// code enforces itself. Hangs with interrupts disabled are out of its reach
+
+static WATCHES: SpinLock<[Option<Entry>; WATCHDOG_SLOTS]> = SpinLock::new([None; WATCHDOG_SLOTS]);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    location: &'static str,
+    timeout: Duration,
+    // mtime by which the next pet is due
+    deadline: u64,
+}
+
+// An armed watch, disarmed when dropped
+pub struct Watch {
+    slot: usize,
+}
+
+impl Watch {
+    // Report progress at location, the watch is good for another timeout
+    pub fn pet(&self, location: &'static str) {
+        if let Some(entry) = WATCHES.lock_irq()[self.slot].as_mut() {
+            entry.location = location;
+            entry.deadline = time::deadline(entry.timeout);
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        WATCHES.lock_irq()[self.slot] = None;
+    }
+}
+
+// The first watch past its deadline, disarmed so it is reported once
+fn overdue(now: u64) -> Option<Entry> {
+    let mut watches = WATCHES.lock_irq();
+    let slot = watches
+        .iter()
+        .position(|w| w.is_some_and(|entry| now >= entry.deadline))?;
+    watches[slot].take()
+}
+
+// ====================================================
+// The public interface for the watchdog is here...
+// ====================================================
+
+// Arm a watch that trips when it goes timeout without a pet, None when every
+// slot is taken, the operation then runs unwatched
+pub fn start(location: &'static str, timeout: Duration) -> Option<Watch> {
+    let mut watches = WATCHES.lock_irq();
+    let Some(slot) = watches.iter().position(|w| w.is_none()) else {
+        log::warn!("No watchdog slot left for {}", location);
+        return None;
+    };
+    watches[slot] = Some(Entry {
+        location,
+        timeout,
+        deadline: time::deadline(timeout),
+    });
+    Some(Watch { slot })
+}
+
+// True while any watch is armed
+pub fn is_running() -> bool {
+    WATCHES.lock_irq().iter().any(|w| w.is_some())
+}
+
+// Called from the trap handler on a timer interrupt with the interrupted frame
+pub fn check(frame: &TrapFrame) {
+    if let Some(entry) = overdue(timer::now()) {
+        println!(
+            "Watchdog: no progress for {:?}, last seen in {}",
+            entry.timeout, entry.location
+        );
+        frame.print();
+        panic!("kernel hung in {}", entry.location);
+    }
+}
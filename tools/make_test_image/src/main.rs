@@ -0,0 +1,316 @@
+use std::env;
+use std::fs;
+use std::process::exit;
+
+// make_test_image
+// Writes the Minix 3 disk image the kernel test suite runs against
+// The geometry matches mkfs.minix -3 on a 32 MiB image, the raw block tests
+// in src/test.rs read the inode count from the superblock
+// /hello.txt                   "hi\n", inode 2
+// /fixtures/a/b/c/d/deep.txt   DEEP_TEXT, four directories down
+// /fixtures/large.bin          LARGE_SIZE bytes of pattern(), the data runs
+//                              through the indirect and double indirect zones
+// /fixtures/sparse.bin         SPARSE_SIZE bytes, only the first and last blocks
+//                              hold pattern(), every other block is a hole
+// /fixtures/link               Symbolic link to /fixtures/a/b/c/d/deep.txt
+// Usage: make_test_image [image], the image defaults to corrosion.dsk
+
+const BLOCK_SIZE: usize = 1024;
+const BLOCKS: usize = 32 * 1024;
+const INODES: usize = 10928;
+const INODE_SIZE: usize = 64;
+const DIR_ENTRY_SIZE: usize = 64;
+const FILE_NAME_SIZE: usize = 60;
+const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
+const PTRS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+const DIRECT_ZONES: usize = 7;
+const INDIRECT_ZONE: usize = 7;
+const DOUBLE_INDIRECT_ZONE: usize = 8;
+const MAGIC: u16 = 0x4d5a;
+const MAX_SIZE: u32 = 0x7fff_ffff;
+const S_IFDIR: u16 = 0o040_000;
+const S_IFREG: u16 = 0o100_000;
+const S_IFLNK: u16 = 0o120_000;
+// Fixed so the same image is written every time
+const TIME: u32 = 0x6000_0000;
+
+const DEEP_TEXT: &[u8] = b"four directories down\n";
+const LARGE_SIZE: usize = 300 * 1024 + 123;
+const SPARSE_SIZE: usize = 40 * 1024 - 100;
+
+// Contents of byte i of the large and sparse files, src/test.rs checks the same
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+struct Dir {
+    inode: u32,
+    zone: u32,
+    entries: usize,
+    links: u16,
+}
+
+struct Image {
+    data: Vec<u8>,
+    imap_blocks: usize,
+    zmap_blocks: usize,
+    first_data_zone: usize,
+    next_inode: usize,
+    next_zone: usize,
+    dirs: Vec<Dir>,
+}
+
+impl Image {
+    fn new() -> Self {
+        let imap_blocks = (INODES + 1).div_ceil(BITS_PER_BLOCK);
+        let inode_blocks = (INODES * INODE_SIZE).div_ceil(BLOCK_SIZE);
+        // The zone map covers the zones after itself, grow it until it fits
+        let mut zmap_blocks = 1;
+        loop {
+            let first = 2 + imap_blocks + zmap_blocks + inode_blocks;
+            let needed = (BLOCKS - first + 1).div_ceil(BITS_PER_BLOCK);
+            if needed <= zmap_blocks {
+                break;
+            }
+            zmap_blocks = needed;
+        }
+        let first_data_zone = 2 + imap_blocks + zmap_blocks + inode_blocks;
+        let mut image = Self {
+            data: vec![0; BLOCKS * BLOCK_SIZE],
+            imap_blocks,
+            zmap_blocks,
+            first_data_zone,
+            next_inode: 1,
+            next_zone: first_data_zone,
+            dirs: Vec::new(),
+        };
+        image.write_superblock();
+        // Bit 0 of both maps is reserved, bits past the last inode and zone
+        // are marked used so they are never handed out
+        image.set_bit(image.imap(), 0);
+        for bit in INODES + 1..imap_blocks * BITS_PER_BLOCK {
+            image.set_bit(image.imap(), bit);
+        }
+        image.set_bit(image.zmap(), 0);
+        for bit in BLOCKS - first_data_zone + 1..zmap_blocks * BITS_PER_BLOCK {
+            image.set_bit(image.zmap(), bit);
+        }
+        image
+    }
+
+    fn imap(&self) -> usize {
+        2 * BLOCK_SIZE
+    }
+
+    fn zmap(&self) -> usize {
+        (2 + self.imap_blocks) * BLOCK_SIZE
+    }
+
+    fn inode_table(&self) -> usize {
+        (2 + self.imap_blocks + self.zmap_blocks) * BLOCK_SIZE
+    }
+
+    fn put_u16(&mut self, at: usize, value: u16) {
+        self.data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, at: usize, value: u32) {
+        self.data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn get_u32(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap())
+    }
+
+    fn set_bit(&mut self, map: usize, bit: usize) {
+        self.data[map + bit / 8] |= 1 << (bit % 8);
+    }
+
+    fn write_superblock(&mut self) {
+        let sb = BLOCK_SIZE;
+        self.put_u32(sb, INODES as u32);
+        self.put_u16(sb + 6, self.imap_blocks as u16);
+        self.put_u16(sb + 8, self.zmap_blocks as u16);
+        self.put_u16(sb + 10, self.first_data_zone as u16);
+        self.put_u32(sb + 16, MAX_SIZE);
+        self.put_u32(sb + 20, BLOCKS as u32);
+        self.put_u16(sb + 24, MAGIC);
+        self.put_u16(sb + 28, BLOCK_SIZE as u16);
+    }
+
+    fn alloc_inode(&mut self) -> u32 {
+        let inode = self.next_inode;
+        assert!(inode <= INODES, "out of inodes");
+        self.next_inode += 1;
+        self.set_bit(self.imap(), inode);
+        inode as u32
+    }
+
+    // A zeroed zone
+    fn alloc_zone(&mut self) -> u32 {
+        let zone = self.next_zone;
+        assert!(zone < BLOCKS, "out of zones");
+        self.next_zone += 1;
+        self.set_bit(self.zmap(), zone - self.first_data_zone + 1);
+        zone as u32
+    }
+
+    fn write_inode(&mut self, inode: u32, mode: u16, links: u16, size: usize, zones: &[u32; 10]) {
+        let at = self.inode_table() + (inode as usize - 1) * INODE_SIZE;
+        self.put_u16(at, mode);
+        self.put_u16(at + 2, links);
+        self.put_u32(at + 8, size as u32);
+        for time in [12, 16, 20] {
+            self.put_u32(at + time, TIME);
+        }
+        for (i, &zone) in zones.iter().enumerate() {
+            self.put_u32(at + 24 + 4 * i, zone);
+        }
+    }
+
+    // Zone entry index of zone table points to, allocated while it is a hole
+    fn table_entry(&mut self, table: u32, index: usize) -> u32 {
+        let at = table as usize * BLOCK_SIZE + 4 * index;
+        if self.get_u32(at) == 0 {
+            let zone = self.alloc_zone();
+            self.put_u32(at, zone);
+        }
+        self.get_u32(at)
+    }
+
+    fn zone_table(&mut self, slot: &mut u32) -> u32 {
+        if *slot == 0 {
+            *slot = self.alloc_zone();
+        }
+        *slot
+    }
+
+    // Point block of a file at zone, allocating the zone tables on the way
+    fn map_block(&mut self, zones: &mut [u32; 10], block: usize, zone: u32) {
+        if block < DIRECT_ZONES {
+            zones[block] = zone;
+            return;
+        }
+        let block = block - DIRECT_ZONES;
+        let table = if block < PTRS_PER_BLOCK {
+            self.zone_table(&mut zones[INDIRECT_ZONE])
+        } else {
+            assert!(
+                block < PTRS_PER_BLOCK * (PTRS_PER_BLOCK + 1),
+                "file too large"
+            );
+            let block = block - PTRS_PER_BLOCK;
+            let outer = self.zone_table(&mut zones[DOUBLE_INDIRECT_ZONE]);
+            self.table_entry(outer, block / PTRS_PER_BLOCK)
+        };
+        let at = table as usize * BLOCK_SIZE + 4 * (block % PTRS_PER_BLOCK);
+        self.put_u32(at, zone);
+    }
+
+    fn add_entry(&mut self, parent: u32, name: &str, inode: u32) {
+        assert!(name.len() < FILE_NAME_SIZE, "name too long");
+        let dir = self.dirs.iter_mut().find(|d| d.inode == parent).unwrap();
+        // The kernel only reads the first block of a directory
+        assert!(dir.entries < BLOCK_SIZE / DIR_ENTRY_SIZE, "directory full");
+        let at = dir.zone as usize * BLOCK_SIZE + dir.entries * DIR_ENTRY_SIZE;
+        dir.entries += 1;
+        self.put_u32(at, inode);
+        self.data[at + 4..at + 4 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    // Create a directory in parent, the root is its own parent
+    fn mkdir(&mut self, parent: Option<u32>, name: &str) -> u32 {
+        let inode = self.alloc_inode();
+        let zone = self.alloc_zone();
+        let parent = parent.unwrap_or(inode);
+        self.dirs.push(Dir {
+            inode,
+            zone,
+            entries: 0,
+            links: 2,
+        });
+        self.add_entry(inode, ".", inode);
+        self.add_entry(inode, "..", parent);
+        if parent != inode {
+            self.add_entry(parent, name, inode);
+            let parent = self.dirs.iter_mut().find(|d| d.inode == parent).unwrap();
+            parent.links += 1;
+        }
+        inode
+    }
+
+    // Create a file in parent, blocks for which hole() is true get no zone
+    fn file(
+        &mut self,
+        parent: u32,
+        name: &str,
+        mode: u16,
+        bytes: &[u8],
+        hole: impl Fn(usize) -> bool,
+    ) {
+        let inode = self.alloc_inode();
+        let mut zones = [0; 10];
+        for (block, chunk) in bytes.chunks(BLOCK_SIZE).enumerate() {
+            if hole(block) {
+                continue;
+            }
+            let zone = self.alloc_zone();
+            let at = zone as usize * BLOCK_SIZE;
+            self.data[at..at + chunk.len()].copy_from_slice(chunk);
+            self.map_block(&mut zones, block, zone);
+        }
+        self.write_inode(inode, mode, 1, bytes.len(), &zones);
+        self.add_entry(parent, name, inode);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for i in 0..self.dirs.len() {
+            let dir = &self.dirs[i];
+            let (inode, links, size) = (dir.inode, dir.links, dir.entries * DIR_ENTRY_SIZE);
+            let mut zones = [0; 10];
+            zones[0] = dir.zone;
+            self.write_inode(inode, S_IFDIR | 0o755, links, size, &zones);
+        }
+        self.data
+    }
+}
+
+fn main() {
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("corrosion.dsk"));
+    let mut image = Image::new();
+
+    let root = image.mkdir(None, "/");
+    image.file(root, "hello.txt", S_IFREG | 0o644, b"hi\n", |_| false);
+    let fixtures = image.mkdir(Some(root), "fixtures");
+    let mut dir = fixtures;
+    for name in ["a", "b", "c", "d"] {
+        dir = image.mkdir(Some(dir), name);
+    }
+    image.file(dir, "deep.txt", S_IFREG | 0o644, DEEP_TEXT, |_| false);
+
+    let large: Vec<u8> = (0..LARGE_SIZE).map(pattern).collect();
+    image.file(fixtures, "large.bin", S_IFREG | 0o644, &large, |_| false);
+
+    let last = (SPARSE_SIZE - 1) / BLOCK_SIZE;
+    let sparse: Vec<u8> = (0..SPARSE_SIZE)
+        .map(|i| match i / BLOCK_SIZE {
+            0 => pattern(i),
+            block if block == last => pattern(i),
+            _ => 0,
+        })
+        .collect();
+    image.file(fixtures, "sparse.bin", S_IFREG | 0o644, &sparse, |block| {
+        block != 0 && block != last
+    });
+
+    let target = b"/fixtures/a/b/c/d/deep.txt";
+    image.file(fixtures, "link", S_IFLNK | 0o777, target, |_| false);
+
+    if let Err(e) = fs::write(&path, image.finish()) {
+        eprintln!("make_test_image: unable to write {}: {}", path, e);
+        exit(1);
+    }
+    println!("make_test_image: wrote {}", path);
+}